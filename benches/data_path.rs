@@ -0,0 +1,151 @@
+//! Benchmarks of the path image data takes from the data connection to an encoder,
+//! for a letter page scanned at 600 dpi.
+//!
+//! Run with `cargo bench`; compare runs before and after changes to `scan`,
+//! `image` and `output` to see where the time of a scan goes.
+
+extern crate criterion;
+extern crate sane;
+
+use std::io::{self, Cursor};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use sane::image::{self, FrameAssembler};
+use sane::output;
+use sane::scan::{ByteOrder, ScanData};
+use sane::types::{FrameFormat, Parameters};
+
+/// 8.5 by 11 inches at 600 dpi.
+const PIXELS_PER_LINE: i32 = 5100;
+const LINES: i32 = 6600;
+
+/// The size of the records saned sends image data in.
+const RECORD_SIZE: usize = 32 * 1024;
+
+fn parameters(format: FrameFormat, depth: i32) -> Parameters {
+    let channels = format.channels().unwrap_or(1) as i32;
+    Parameters {
+        format,
+        last_frame: true,
+        bytes_per_line: (PIXELS_PER_LINE * channels * depth + 7) / 8,
+        pixels_per_line: PIXELS_PER_LINE,
+        lines: LINES,
+        depth,
+    }
+}
+
+fn data(parameters: &Parameters) -> Vec<u8> {
+    let size = (parameters.bytes_per_line * parameters.lines) as usize;
+    (0..size).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+/// Frame `data` in records, as sent on the data connection.
+fn capture(data: &[u8]) -> Vec<u8> {
+    let mut capture = Vec::with_capacity(data.len() + data.len() / RECORD_SIZE * 4 + 8);
+    for record in data.chunks(RECORD_SIZE) {
+        capture.extend_from_slice(&(record.len() as u32).to_be_bytes());
+        capture.extend_from_slice(record);
+    }
+    capture.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 5]);
+    capture
+}
+
+fn read_frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    group.sample_size(10);
+
+    for &(name, format, depth) in &[
+        ("lineart", FrameFormat::Gray, 1),
+        ("gray", FrameFormat::Gray, 8),
+        ("rgb16", FrameFormat::RGB, 16),
+    ] {
+        let parameters = parameters(format, depth);
+        let capture = capture(&data(&parameters));
+        group.throughput(Throughput::Bytes(capture.len() as u64));
+
+        // Swapping 16-bit samples is done in every case, as if sent by a big-endian host
+        group.bench_function(format!("frame/{}", name), |b| {
+            b.iter(|| {
+                ScanData::new(Cursor::new(&capture[..]), ByteOrder::BigEndian)
+                    .read_frame(black_box(&parameters))
+                    .unwrap()
+            })
+        });
+
+        group.bench_function(format!("lines/{}", name), |b| {
+            b.iter(|| {
+                let data = ScanData::new(Cursor::new(&capture[..]), ByteOrder::BigEndian);
+                let mut lines = data.lines(&parameters).unwrap();
+                let mut line = Vec::new();
+                while lines.read_line_into(&mut line).unwrap().is_some() {
+                    black_box(&line);
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn assemble_frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("assemble");
+    group.sample_size(10);
+
+    for &(name, depth) in &[("three-pass", 8), ("three-pass16", 16)] {
+        let frames: Vec<_> = [FrameFormat::Red, FrameFormat::Green, FrameFormat::Blue]
+            .iter()
+            .map(|&format| {
+                let mut parameters = parameters(format, depth);
+                parameters.last_frame = format == FrameFormat::Blue;
+                let data = data(&parameters);
+                (parameters, data)
+            })
+            .collect();
+        let bytes: usize = frames.iter().map(|(_, data)| data.len()).sum();
+        group.throughput(Throughput::Bytes(bytes as u64));
+
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut assembler = FrameAssembler::new();
+                for (parameters, data) in &frames {
+                    assembler
+                        .add_frame(parameters.clone(), data.clone())
+                        .unwrap();
+                }
+                assembler.finish().unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn encode_images(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.sample_size(10);
+
+    for &(name, format, depth) in &[
+        ("pbm", FrameFormat::Gray, 1),
+        ("ppm-lineart", FrameFormat::RGB, 1),
+        ("ppm16", FrameFormat::RGB, 16),
+    ] {
+        let parameters = parameters(format, depth);
+        let capture = capture(&data(&parameters));
+        let image = image::decode_capture(
+            vec![(parameters, Cursor::new(&capture[..]))],
+            ByteOrder::native(),
+        )
+        .unwrap();
+        group.throughput(Throughput::Bytes(image.data.len() as u64));
+
+        group.bench_function(name, |b| {
+            b.iter(|| output::write_pnm(&image, &mut io::sink()).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, read_frames, assemble_frames, encode_images);
+criterion_main!(benches);
@@ -0,0 +1,666 @@
+//! Async versions of the protocol requests and scan data reader, for use with tokio.
+//!
+//! Requests are encoded and replies decoded by the same code as the blocking
+//! functions, run against in-memory buffers: the request is sent, and the reply
+//! is decoded again each time more of it has arrived, until decoding no longer
+//! stops short at the end of the received data.
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::auth::{Authenticator, Credentials, NoCredentials};
+use crate::error::Error;
+use crate::scan::{frame_capacity, ByteOrder};
+use crate::status::Status;
+use crate::types::*;
+use crate::{Device, OpenResult, Result, StartResult, Version, SANE_VERSION};
+
+/// Record length sent on the data connection in place of a regular record,
+/// to signal the end of the image data.
+const END_OF_DATA: u32 = 0xffffffff;
+
+/// Size of the chunks a reply is read in.
+const READ_CHUNK_SIZE: usize = 8192;
+
+/// A connection to saned over an async stream.
+///
+/// Each method sends one request and waits for its reply,
+/// like the blocking function of the same name.
+pub struct Connection<S> {
+    stream: S,
+    /// Data received from the server but not yet decoded.
+    buffer: Vec<u8>,
+    decode_options: DecodeOptions,
+    /// The version spoken by the server, once negotiated by `init`.
+    version: Version,
+}
+
+/// Stand-in for the network stream passed to the blocking request functions,
+/// collecting the request and serving the part of the reply received so far.
+struct Exchange<'a> {
+    input: &'a [u8],
+    position: usize,
+    output: Vec<u8>,
+}
+
+impl<'a> Read for Exchange<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = Read::read(&mut &self.input[self.position..], buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+impl<'a> Write for Exchange<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Write::write(&mut self.output, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn is_unexpected_eof(error: &Error) -> bool {
+    match *error {
+        Error::IOError(ref e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        _ => false,
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Connection<S> {
+        Connection {
+            stream,
+            buffer: Vec::new(),
+            decode_options: DecodeOptions::default(),
+            version: SANE_VERSION,
+        }
+    }
+
+    /// The version of the protocol spoken by the server, as negotiated by `init`.
+    /// Requests are encoded for this version.
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Decode replies from saned with `options`.
+    pub fn set_decode_options(&mut self, options: DecodeOptions) {
+        self.decode_options = options;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// Consume the connection, returning the underlying stream.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Perform a request through `request`, one of the blocking request functions.
+    ///
+    /// `request` is called again each time it runs out of reply data, so it must
+    /// write the same request every time; only the part not sent yet is sent.
+    async fn call<T, F>(&mut self, mut request: F) -> Result<T>
+    where
+        F: FnMut(&mut Exchange) -> Result<T>,
+    {
+        let mut sent = 0;
+
+        loop {
+            let mut exchange = Exchange {
+                input: &self.buffer,
+                position: 0,
+                output: Vec::new(),
+            };
+            let result = self.decode_options.scope(|| request(&mut exchange));
+            let Exchange {
+                position, output, ..
+            } = exchange;
+
+            if output.len() > sent {
+                self.stream.write_all(&output[sent..]).await?;
+                self.stream.flush().await?;
+                sent = output.len();
+            }
+
+            match result {
+                Err(ref e) if is_unexpected_eof(e) => {
+                    let mut chunk = [0u8; READ_CHUNK_SIZE];
+                    let read = self.stream.read(&mut chunk).await?;
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Connection closed by the server",
+                        )
+                        .into());
+                    }
+                    trace!("Received {} more bytes of reply", read);
+                    self.buffer.extend_from_slice(&chunk[..read]);
+                }
+                result => {
+                    self.buffer.drain(..position);
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Send SANE_NET_INIT, returning the protocol version of the server.
+    pub async fn init(&mut self) -> Result<Version> {
+        let version = self.call(|stream| crate::init(stream)).await?;
+        self.version = version;
+        Ok(version)
+    }
+
+    pub async fn request_device_list(&mut self) -> Result<Vec<Device>> {
+        self.call(|stream| crate::request_device_list(stream)).await
+    }
+
+    pub async fn open_device(&mut self, device: &Device) -> Result<OpenResult> {
+        self.call(|stream| crate::open_device(device, stream)).await
+    }
+
+    /// Open `device`, returning its handle.
+    /// If the device requires authentication, credentials are requested from `auth`.
+    ///
+    /// See `sane::open_device_with_auth`.
+    pub async fn open_device_with_auth<A>(&mut self, device: &Device, auth: &mut A) -> Result<i32>
+    where
+        A: Authenticator + ?Sized,
+    {
+        self.call(|stream| {
+            let mut auth = |resource: &str| auth.credentials(resource);
+            crate::open_device_with_auth(device, stream, &mut auth)
+        })
+        .await
+    }
+
+    pub async fn close_device(&mut self, handle: i32) -> Result<()> {
+        self.call(|stream| crate::close_device(handle, stream))
+            .await
+    }
+
+    pub async fn get_option_descriptors(
+        &mut self,
+        handle: i32,
+    ) -> Result<Vec<Option<OptionDescriptor>>> {
+        self.call(|stream| crate::get_option_descriptors(handle, stream))
+            .await
+    }
+
+    /// See `sane::control_option`.
+    ///
+    /// The request is encoded for the version of the server. If the option requires
+    /// authentication, access is declined, so the server fails the request.
+    pub async fn control_option(
+        &mut self,
+        handle: i32,
+        index: i32,
+        descriptor: &OptionDescriptor,
+        action: &OptionAction,
+    ) -> Result<(OptionValue, OptionInfo)> {
+        let version = self.version;
+        self.call(|stream| {
+            crate::control_option_with(
+                handle,
+                stream,
+                index,
+                descriptor,
+                action,
+                version,
+                &mut NoCredentials,
+            )
+        })
+        .await
+    }
+
+    /// Get or set the value of an option like `control_option`.
+    /// If the option requires authentication, credentials are requested from `auth`.
+    pub async fn control_option_with_auth<A>(
+        &mut self,
+        handle: i32,
+        index: i32,
+        descriptor: &OptionDescriptor,
+        action: &OptionAction,
+        auth: &mut A,
+    ) -> Result<(OptionValue, OptionInfo)>
+    where
+        A: Authenticator + ?Sized,
+    {
+        let version = self.version;
+        self.call(|stream| {
+            let mut auth = |resource: &str| auth.credentials(resource);
+            crate::control_option_with(
+                handle, stream, index, descriptor, action, version, &mut auth,
+            )
+        })
+        .await
+    }
+
+    /// See `sane::get_parameters`.
+    pub async fn get_parameters(&mut self, handle: i32) -> Result<Parameters> {
+        self.call(|stream| crate::get_parameters(handle, stream))
+            .await
+    }
+
+    /// See `sane::start`.
+    pub async fn start(&mut self, handle: i32) -> Result<StartResult> {
+        self.call(|stream| crate::start(handle, stream)).await
+    }
+
+    /// Start acquiring an image like `start`, returning the port and byte order
+    /// of the data connection. If the device requires authentication,
+    /// credentials are requested from `auth`.
+    async fn start_with_auth<A>(&mut self, handle: i32, auth: &mut A) -> Result<(u16, ByteOrder)>
+    where
+        A: Authenticator + ?Sized,
+    {
+        self.call(|stream| {
+            let mut auth = |resource: &str| auth.credentials(resource);
+            crate::send_start_request(handle, stream)?;
+            crate::read_authorized_reply(stream, &mut auth, crate::read_start_reply)
+        })
+        .await
+    }
+
+    /// Send `credentials` for `resource`, which a reply asked authorization for.
+    ///
+    /// The server then sends the reply to the original request again, which this
+    /// doesn't read; the `*_with_auth` methods authorize and read that reply.
+    /// See `sane::auth::authorize`.
+    pub async fn authorize(&mut self, resource: &str, credentials: &Credentials) -> Result<()> {
+        self.call(|stream| crate::auth::authorize(stream, resource, credentials))
+            .await
+    }
+
+    /// See `sane::cancel`.
+    pub async fn cancel(&mut self, handle: i32) -> Result<()> {
+        self.call(|stream| crate::cancel(handle, stream)).await
+    }
+
+    /// Cancel the scan on the device opened as `handle`, and close the
+    /// data connection its image data was being read from through `data`.
+    ///
+    /// The data connection is closed even if the cancel request fails.
+    pub async fn cancel_scan<R>(&mut self, handle: i32, data: ScanData<R>) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let result = self.cancel(handle).await;
+        drop(data.into_inner());
+        result
+    }
+
+    /// See `sane::exit`.
+    pub async fn exit(&mut self) -> Result<()> {
+        self.call(|stream| crate::exit(stream)).await
+    }
+}
+
+impl Connection<TcpStream> {
+    /// Connect to saned at `address`, and initialize the connection.
+    pub async fn connect(address: &str) -> Result<Connection<TcpStream>> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+
+        let mut connection = Connection::new(stream);
+        connection.init().await?;
+
+        Ok(connection)
+    }
+
+    /// Start acquiring an image from the device opened as `handle`,
+    /// and open the data connection the image will be sent over.
+    ///
+    /// See `sane::start_scan`.
+    pub async fn start_scan(&mut self, handle: i32) -> Result<ScanData<TcpStream>> {
+        match self.start(handle).await? {
+            StartResult::Started { port, byte_order } => {
+                self.open_data_connection(port, byte_order).await
+            }
+            StartResult::AuthRequired(resource) => Err(Error::AuthRequired(resource)),
+        }
+    }
+
+    /// Start acquiring an image like `start_scan`.
+    /// If the device requires authentication, credentials are requested from `auth`.
+    pub async fn start_scan_with_auth<A>(
+        &mut self,
+        handle: i32,
+        auth: &mut A,
+    ) -> Result<ScanData<TcpStream>>
+    where
+        A: Authenticator + ?Sized,
+    {
+        let (port, byte_order) = self.start_with_auth(handle, auth).await?;
+        self.open_data_connection(port, byte_order).await
+    }
+
+    async fn open_data_connection(
+        &self,
+        port: u16,
+        byte_order: ByteOrder,
+    ) -> Result<ScanData<TcpStream>> {
+        let host = self.stream.peer_addr()?.ip();
+
+        info!("Opening data connection to {}:{}", host, port);
+        let connection = TcpStream::connect((host, port)).await?;
+
+        Ok(ScanData::new(connection, byte_order))
+    }
+}
+
+/// The image data of a scan, read asynchronously from the data connection.
+///
+/// See `sane::scan::ScanData` for the format of the data.
+pub struct ScanData<R> {
+    connection: R,
+    byte_order: ByteOrder,
+    finished: bool,
+}
+
+impl<R: AsyncRead + Unpin> ScanData<R> {
+    pub fn new(connection: R, byte_order: ByteOrder) -> ScanData<R> {
+        ScanData {
+            connection,
+            byte_order,
+            finished: false,
+        }
+    }
+
+    /// The byte order of multi-byte samples in the data.
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+
+    /// Returns true once the end of the data has been reached.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Read the next record of image data.
+    ///
+    /// Returns `Ok(None)` once the end of the data has been reached. If the scan
+    /// ended with any status other than `Status::EndOfFile`, that status is returned
+    /// as an error instead.
+    pub async fn next_record(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut record = Vec::new();
+        Ok(self.read_record_into(&mut record).await?.map(|_| record))
+    }
+
+    /// Append the next record of image data to `buf`, returning its length,
+    /// or `Ok(None)` once the end of the data has been reached.
+    /// See `ScanData::read_record_into`.
+    pub async fn read_record_into(&mut self, buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let length = self.connection.read_u32().await?;
+
+        if length == END_OF_DATA {
+            self.finished = true;
+
+            let status = Status::try_from(i32::from(self.connection.read_u8().await?))?;
+            debug!("Reached end of scan data with status {:?}", status);
+
+            return match status {
+                Status::EndOfFile => Ok(None),
+                err => Err(err.into()),
+            };
+        }
+
+        trace!("Reading record of {} bytes", length);
+        // The length comes from the network, so only allocate as data arrives
+        let read = (&mut self.connection)
+            .take(u64::from(length))
+            .read_to_end(buf)
+            .await?;
+        if read != length as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Data connection closed in the middle of a record",
+            )
+            .into());
+        }
+        Ok(Some(read))
+    }
+
+    /// Read all remaining image data, with the record framing removed.
+    pub async fn read_all(&mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_all_into(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Append all remaining image data to `buf`, returning the number of bytes appended.
+    pub async fn read_all_into(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let start = buf.len();
+        while self.read_record_into(buf).await?.is_some() {}
+        Ok(buf.len() - start)
+    }
+
+    /// Read all remaining image data of the frame described by `parameters`,
+    /// with 16-bit samples converted to host byte order.
+    pub async fn read_frame(&mut self, parameters: &Parameters) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(frame_capacity(parameters));
+        self.read_all_into(&mut data).await?;
+        if parameters.depth == 16 {
+            self.byte_order.to_native(&mut data);
+        }
+        Ok(data)
+    }
+
+    /// Consume the reader, returning the underlying data connection.
+    pub fn into_inner(self) -> R {
+        self.connection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+    use crate::tests::{
+        control_option_reply, device_list_reply, put_string, put_word, test_device,
+    };
+
+    /// Replays canned server responses a few bytes at a time,
+    /// so replies have to be decoded from several reads.
+    struct TrickleStream {
+        input: Vec<u8>,
+        position: usize,
+        output: Vec<u8>,
+    }
+
+    impl TrickleStream {
+        fn new(input: Vec<u8>) -> TrickleStream {
+            TrickleStream {
+                input,
+                position: 0,
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl AsyncRead for TrickleStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let end = (self.position + 3)
+                .min(self.input.len())
+                .min(self.position + buf.remaining());
+            buf.put_slice(&self.input[self.position..end]);
+            self.position = end;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for TrickleStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.output.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn requests_are_decoded_across_reads() {
+        let mut input = device_list_reply(&["pixma:04A91912"]);
+        let mut close_reply = Vec::new();
+        put_word(&mut close_reply, 0); // dummy
+        input.extend(close_reply);
+
+        let mut connection = Connection::new(TrickleStream::new(input));
+
+        let devices = block_on(connection.request_device_list()).unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "pixma:04A91912");
+
+        block_on(connection.close_device(3)).unwrap();
+
+        let stream = connection.into_inner();
+        assert_eq!(stream.position, stream.input.len());
+
+        // Each request is sent once, even though decoding was retried
+        let mut request = Vec::new();
+        put_word(&mut request, 1);
+        put_word(&mut request, 3);
+        put_word(&mut request, 3);
+        assert_eq!(stream.output, request);
+    }
+
+    #[test]
+    fn options_are_set_for_the_server_version() {
+        let old_version = Version::new(1, 0, 2);
+        let mut input = Vec::new();
+        put_word(&mut input, 0); // status
+        put_word(&mut input, old_version.code() as i32);
+        input.extend(control_option_reply(0, 0, 1));
+
+        let descriptor = OptionDescriptor::Boolean {
+            name: "preview".into(),
+            title: "Preview".into(),
+            description: "Request a preview-quality scan.".into(),
+            unit: OptionUnit::None,
+            capabilities: Capabilities::SoftSelect | Capabilities::Automatic,
+            _no_constrainst: NoConstraint,
+        };
+
+        let mut connection = Connection::new(TrickleStream::new(input));
+        assert_eq!(block_on(connection.init()).unwrap(), old_version);
+        block_on(connection.control_option(3, 1, &descriptor, &OptionAction::SetAuto)).unwrap();
+
+        // Before revision 3, an empty value is sent when setting automatically
+        let mut request = io::Cursor::new(Vec::new());
+        crate::send_control_option_request(
+            3,
+            &mut request,
+            1,
+            &descriptor,
+            &OptionAction::SetAuto,
+            old_version,
+        )
+        .unwrap();
+        let output = connection.into_inner().output;
+        assert!(output.ends_with(request.get_ref()));
+    }
+
+    #[test]
+    fn open_device_with_auth_authorizes_and_reads_reply_again() {
+        let mut input = Vec::new();
+        put_word(&mut input, 0); // status
+        put_word(&mut input, 0); // handle
+        put_string(&mut input, Some("test:0"));
+        put_word(&mut input, 0); // authorization acknowledged
+        put_word(&mut input, 0); // status
+        put_word(&mut input, 7); // handle
+        put_string(&mut input, None);
+
+        let device = test_device();
+        let mut auth = |_: &str| Some(Credentials::new("user", "secret"));
+
+        let mut connection = Connection::new(TrickleStream::new(input));
+        let handle = block_on(connection.open_device_with_auth(&device, &mut auth)).unwrap();
+        assert_eq!(handle, 7);
+
+        // The authorization is sent once, even though decoding was retried
+        let mut request = Vec::new();
+        put_word(&mut request, 2);
+        put_string(&mut request, Some("test:0"));
+        put_word(&mut request, 9);
+        put_string(&mut request, Some("test:0"));
+        put_string(&mut request, Some("user"));
+        put_string(&mut request, Some("secret"));
+        let stream = connection.into_inner();
+        assert_eq!(stream.output, request);
+        assert_eq!(stream.position, stream.input.len());
+    }
+
+    #[test]
+    fn closed_connection_is_an_error() {
+        let mut connection = Connection::new(TrickleStream::new(vec![0, 0]));
+
+        match block_on(connection.request_device_list()) {
+            Err(Error::IOError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            other => panic!("Expected UnexpectedEof, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn scan_data_reads_records() {
+        let mut input = Vec::new();
+        put_word(&mut input, 2);
+        input.extend_from_slice(&[1, 2]);
+        put_word(&mut input, 1);
+        input.push(3);
+        put_word(&mut input, -1); // end of data
+        input.push(5); // status: end of file
+
+        let mut data = ScanData::new(&input[..], ByteOrder::BigEndian);
+        assert_eq!(block_on(data.read_all()).unwrap(), vec![1, 2, 3]);
+        assert!(data.is_finished());
+    }
+
+    #[test]
+    fn truncated_record_is_an_error() {
+        let mut input = Vec::new();
+        put_word(&mut input, 0x7fff_ffff);
+        input.extend_from_slice(&[1, 2]);
+
+        let mut data = ScanData::new(&input[..], ByteOrder::BigEndian);
+        match block_on(data.next_record()) {
+            Err(Error::IOError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            other => panic!("Expected UnexpectedEof, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Authorization of access to password-protected resources,
+//! through SANE_NET_AUTHORIZE.
+
+use std::io::{Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{send_request, Result, WriteToStream};
+
+/// Marks a resource whose password must be sent as an MD5 digest,
+/// salted with the text following the marker.
+const MD5_MARKER: &str = "$MD5$";
+
+/// saned only uses the first 128 bytes of the salt.
+const MAX_SALT_LENGTH: usize = 128;
+
+/// A username and password to access a resource with.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> Credentials {
+        Credentials {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+/// The username and password are left out, so credentials can be logged safely.
+impl ::std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("Credentials").finish_non_exhaustive()
+    }
+}
+
+/// Provides credentials when a request needs authorization.
+///
+/// Implemented for closures taking the name of the resource to access,
+/// such as a device name, and returning the credentials to use.
+pub trait Authenticator {
+    /// The credentials to access `resource` with, or None to decline.
+    fn credentials(&mut self, resource: &str) -> Option<Credentials>;
+}
+
+impl<F: FnMut(&str) -> Option<Credentials>> Authenticator for F {
+    fn credentials(&mut self, resource: &str) -> Option<Credentials> {
+        self(resource)
+    }
+}
+
+/// Restricts the resources credentials are sent for, and how they are sent.
+///
+/// A policy is enforced by wrapping an `Authenticator` with `AuthPolicy::apply`,
+/// which declines the requests the policy doesn't permit without asking it for
+/// credentials. As for any declined request, the server then denies access.
+#[derive(Debug, Clone, Default)]
+pub struct AuthPolicy {
+    require_md5: bool,
+    /// The resources credentials may be sent for; any if None.
+    resources: Option<Vec<String>>,
+}
+
+impl AuthPolicy {
+    /// A policy permitting every request, like an `Authenticator` on its own.
+    pub fn new() -> AuthPolicy {
+        AuthPolicy::default()
+    }
+
+    /// Only send passwords as MD5 digests, declining resources the server
+    /// sends no salt for, whose password would be sent in plain text.
+    pub fn require_md5(mut self, require_md5: bool) -> AuthPolicy {
+        self.require_md5 = require_md5;
+        self
+    }
+
+    /// Permit sending credentials for `resource`, such as a device name.
+    ///
+    /// Once a resource is allowed, credentials are only sent for allowed resources.
+    /// Any MD5 salt the server adds to the resource is ignored when matching.
+    pub fn allow_resource<R: Into<String>>(mut self, resource: R) -> AuthPolicy {
+        self.resources
+            .get_or_insert_with(Vec::new)
+            .push(resource.into());
+        self
+    }
+
+    /// Whether credentials may be sent for `resource`, as requested by the server.
+    pub fn permits(&self, resource: &str) -> bool {
+        if self.require_md5 && salt(resource).is_none() {
+            return false;
+        }
+
+        let name = resource_name(resource);
+        self.resources
+            .as_ref()
+            .is_none_or(|resources| resources.iter().any(|allowed| allowed == name))
+    }
+
+    /// Enforce the policy on the requests for credentials made to `authenticator`.
+    pub fn apply<A: Authenticator>(self, authenticator: A) -> PolicyAuthenticator<A> {
+        PolicyAuthenticator {
+            policy: self,
+            authenticator,
+        }
+    }
+}
+
+/// An `Authenticator` only asked for the credentials its `AuthPolicy` permits,
+/// returned by `AuthPolicy::apply`.
+#[derive(Debug)]
+pub struct PolicyAuthenticator<A> {
+    policy: AuthPolicy,
+    authenticator: A,
+}
+
+impl<A: Authenticator> Authenticator for PolicyAuthenticator<A> {
+    fn credentials(&mut self, resource: &str) -> Option<Credentials> {
+        if !self.policy.permits(resource) {
+            warn!(
+                "Declining to send credentials for '{}', as the auth policy doesn't permit it",
+                resource_name(resource)
+            );
+            return None;
+        }
+        self.authenticator.credentials(resource)
+    }
+}
+
+/// Declines every authorization request.
+pub(crate) struct NoCredentials;
+
+impl Authenticator for NoCredentials {
+    fn credentials(&mut self, resource: &str) -> Option<Credentials> {
+        debug!("No credentials available for {}", resource);
+        None
+    }
+}
+
+/// Send `credentials` for the resource a reply asked authorization for.
+///
+/// After the server acknowledges them, it sends the reply to the original
+/// request again, which either grants access or fails with `Status::AccessDenied`.
+pub fn authorize<S: Read + Write>(
+    stream: &mut S,
+    resource: &str,
+    credentials: &Credentials,
+) -> Result<()> {
+    info!("Authorizing access to '{}'", resource_name(resource));
+
+    send_request(stream, |request| {
+        // Send Command
+        request.write_i32::<BigEndian>(9)?;
+
+        resource.write_to_stream(request)?;
+        credentials.username.write_to_stream(request)?;
+        encode_password(resource, &credentials.password).write_to_stream(request)
+    })?;
+
+    // Receive dummy
+    let dummy = stream.read_i32::<BigEndian>()?;
+    debug!("Received dummy value {}", dummy);
+
+    Ok(())
+}
+
+/// Encode `password` as expected for `resource`: as is, or if the resource
+/// carries a salt, as the MD5 digest of the salt followed by the password.
+fn encode_password(resource: &str, password: &str) -> String {
+    let salt = match salt(resource) {
+        Some(salt) => salt,
+        None => return password.into(),
+    };
+
+    let mut salted = salt.to_vec();
+    salted.extend_from_slice(password.as_bytes());
+    format!("{}{:x}", MD5_MARKER, md5::compute(salted))
+}
+
+/// The salt `resource` carries for hashing the password, if any.
+fn salt(resource: &str) -> Option<&[u8]> {
+    let start = resource.find(MD5_MARKER)? + MD5_MARKER.len();
+    let salt = &resource.as_bytes()[start..];
+    Some(&salt[..salt.len().min(MAX_SALT_LENGTH)])
+}
+
+/// The name of `resource`, without the salt.
+fn resource_name(resource: &str) -> &str {
+    resource.split(MD5_MARKER).next().unwrap_or(resource)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_is_hashed_with_resource_salt() {
+        assert_eq!(encode_password("test:0", "secret"), "secret");
+        // md5("0123456789abcdef" + "secret")
+        assert_eq!(
+            encode_password("test:0$MD5$0123456789abcdef", "secret"),
+            "$MD5$116fecf84dd81c77b969dac1b22c8eef"
+        );
+    }
+
+    #[test]
+    fn policy_declines_plaintext_and_unlisted_resources() {
+        let mut asked = Vec::new();
+        let mut auth = AuthPolicy::new()
+            .require_md5(true)
+            .allow_resource("test:0")
+            .apply(|resource: &str| {
+                asked.push(resource.to_string());
+                Some(Credentials::new("user", "secret"))
+            });
+
+        assert!(auth.credentials("test:0").is_none());
+        assert!(auth.credentials("test:1$MD5$0123456789abcdef").is_none());
+        assert!(auth.credentials("test:0$MD5$0123456789abcdef").is_some());
+        drop(auth);
+        assert_eq!(asked, ["test:0$MD5$0123456789abcdef"]);
+
+        assert!(AuthPolicy::new().permits("test:0"));
+    }
+
+    #[test]
+    fn credentials_are_redacted_from_debug_output() {
+        let credentials = Credentials::new("user", "secret");
+        assert_eq!(format!("{:?}", credentials), "Credentials { .. }");
+    }
+}
@@ -0,0 +1,106 @@
+extern crate dotenv;
+#[macro_use]
+extern crate log;
+extern crate pretty_env_logger;
+extern crate sane;
+
+use sane::auth::Credentials;
+use sane::conformance;
+use sane::*;
+
+use dotenv::dotenv;
+use std::env;
+use std::process;
+
+const USAGE: &str = "\
+Usage:
+    sane-conformance [<device>] [--server <address>] [--scan]
+
+Options:
+    --server <address>  The saned server to check. Defaults to the
+                        SANE_SERVER_ADDRESS environment variable, or localhost:6566.
+    --scan              Also scan an image from each device checked,
+                        which needs a document in the scanner.
+
+When no device is given, every device listed by the server is checked.
+Every option that can be set is set back to its current value.
+Credentials for password-protected devices are read from the
+SANE_USERNAME and SANE_PASSWORD environment variables.
+
+Exits with status 1 when a check fails.";
+
+struct Arguments {
+    server: String,
+    device: Option<String>,
+    scan: bool,
+}
+
+fn parse_arguments<I: Iterator<Item = String>>(
+    mut args: I,
+) -> std::result::Result<Arguments, String> {
+    let mut arguments = Arguments {
+        server: env::var("SANE_SERVER_ADDRESS").unwrap_or_else(|_| "localhost:6566".into()),
+        device: None,
+        scan: false,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => {
+                arguments.server = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))?
+            }
+            "--scan" => arguments.scan = true,
+            flag if flag.starts_with('-') => return Err(format!("Unknown flag {:?}", flag)),
+            _ if arguments.device.is_none() => arguments.device = Some(arg),
+            _ => return Err(format!("Unexpected argument {:?}", arg)),
+        }
+    }
+
+    Ok(arguments)
+}
+
+fn main() {
+    dotenv().ok();
+    pretty_env_logger::init();
+
+    let arguments = match parse_arguments(env::args().skip(1)) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    match run(&arguments) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("sane-conformance: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Check the server, returning whether every check passed.
+fn run(arguments: &Arguments) -> Result<bool> {
+    info!(
+        "Connecting to SANE server at address {}.",
+        &arguments.server
+    );
+    let mut session = SessionBuilder::new().connect(arguments.server.as_str())?;
+
+    if let Ok(username) = env::var("SANE_USERNAME") {
+        let password = env::var("SANE_PASSWORD").unwrap_or_default();
+        session.set_authenticator(move |_: &str| {
+            Some(Credentials::new(username.as_str(), password.as_str()))
+        });
+    }
+
+    let report = conformance::run(&session, arguments.device.as_deref(), arguments.scan);
+    println!("{}", report);
+
+    session.exit()?;
+    Ok(report.passed())
+}
@@ -0,0 +1,254 @@
+extern crate dotenv;
+#[macro_use]
+extern crate log;
+extern crate pretty_env_logger;
+extern crate sane;
+extern crate zbus;
+
+use sane::auth::Credentials;
+use sane::service::{JobEvent, ScanService};
+use sane::types::OptionValue;
+use sane::*;
+
+use dotenv::dotenv;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::net::TcpStream;
+use std::path::Path;
+use std::process;
+use std::thread;
+use zbus::fdo;
+use zbus::zvariant::{OwnedValue, Value};
+
+const USAGE: &str = "\
+Usage:
+    sane-dbus [--server <address>] [--system]
+
+Options:
+    --server <address>  The saned server to scan through. Defaults to the
+                        SANE_SERVER_ADDRESS environment variable, or localhost:6566.
+    --system            Serve on the system bus rather than the session bus.
+
+Serves the org.sane_rs.Scanner1 interface at /org/sane_rs/Scanner
+under the name org.sane_rs.Scanner, with the methods:
+
+    ListDevices() -> a(ssss)      name, vendor, model and type of each device
+    GetOptions(s device) -> a{sv} the option values scan jobs use
+    SetOption(s device, s name, s value) -> v
+                                  set an option for scan jobs, as `sane-rs scan --option`
+    StartJob(s device, s directory, u max_pages) -> u
+                                  scan pages into PNM files in directory, until the
+                                  feeder is empty or after max_pages (0 for no limit)
+
+and the signals of each job:
+
+    Progress(u job, u page, t bytes, x total)   total is -1 if not known
+    PageScanned(u job, u page, s path)
+    JobFinished(u job, u pages, s error)        error is empty if the job succeeded
+
+Credentials for password-protected devices are read from the
+SANE_USERNAME and SANE_PASSWORD environment variables.";
+
+const BUS_NAME: &str = "org.sane_rs.Scanner";
+const OBJECT_PATH: &str = "/org/sane_rs/Scanner";
+const INTERFACE: &str = "org.sane_rs.Scanner1";
+
+struct Arguments {
+    server: String,
+    system: bool,
+}
+
+fn parse_arguments<I: Iterator<Item = String>>(
+    mut args: I,
+) -> std::result::Result<Arguments, String> {
+    let mut arguments = Arguments {
+        server: env::var("SANE_SERVER_ADDRESS").unwrap_or_else(|_| "localhost:6566".into()),
+        system: false,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => {
+                arguments.server = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))?
+            }
+            "--system" => arguments.system = true,
+            _ => return Err(format!("Unexpected argument {:?}", arg)),
+        }
+    }
+
+    Ok(arguments)
+}
+
+fn main() {
+    dotenv().ok();
+    pretty_env_logger::init();
+
+    let arguments = match parse_arguments(env::args().skip(1)) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(&arguments) {
+        eprintln!("sane-dbus: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(arguments: &Arguments) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    info!(
+        "Connecting to SANE server at address {}.",
+        &arguments.server
+    );
+    let mut session = SessionBuilder::new().connect(arguments.server.as_str())?;
+
+    if let Ok(username) = env::var("SANE_USERNAME") {
+        let password = env::var("SANE_PASSWORD").unwrap_or_default();
+        session.set_authenticator(move |_: &str| {
+            Some(Credentials::new(username.as_str(), password.as_str()))
+        });
+    }
+
+    let scanner = Scanner {
+        service: ScanService::new(session),
+    };
+    let builder = if arguments.system {
+        zbus::blocking::connection::Builder::system()?
+    } else {
+        zbus::blocking::connection::Builder::session()?
+    };
+    let _connection = builder
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, scanner)?
+        .build()?;
+    info!("Serving {} as {}", INTERFACE, BUS_NAME);
+
+    // Requests are handled on the connection's own threads
+    loop {
+        thread::park();
+    }
+}
+
+struct Scanner {
+    service: ScanService<TcpStream>,
+}
+
+#[zbus::interface(name = "org.sane_rs.Scanner1")]
+impl Scanner {
+    fn list_devices(&self) -> fdo::Result<Vec<(String, String, String, String)>> {
+        let devices = self.service.devices().map_err(failed)?;
+        Ok(devices
+            .into_iter()
+            .map(|device| (device.name, device.vendor, device.model, device.kind))
+            .collect())
+    }
+
+    fn get_options(&self, device: &str) -> fdo::Result<HashMap<String, OwnedValue>> {
+        self.service
+            .options(device)
+            .map_err(failed)?
+            .into_iter()
+            .map(|(name, value)| Ok((name, to_variant(value)?)))
+            .collect()
+    }
+
+    fn set_option(&self, device: &str, name: &str, value: &str) -> fdo::Result<OwnedValue> {
+        let value = self
+            .service
+            .set_option(device, name, value)
+            .map_err(|e| match e {
+                sane::error::Error::UnknownOption(_)
+                | sane::error::Error::InvalidOptionValue(_, _) => {
+                    fdo::Error::InvalidArgs(e.to_string())
+                }
+                e => failed(e),
+            })?;
+        to_variant(value)
+    }
+
+    fn start_job(
+        &self,
+        device: &str,
+        directory: &str,
+        max_pages: u32,
+        #[zbus(connection)] connection: &zbus::Connection,
+    ) -> fdo::Result<u32> {
+        let connection = zbus::blocking::Connection::from(connection.clone());
+        let max_pages = if max_pages > 0 {
+            Some(max_pages as usize)
+        } else {
+            None
+        };
+
+        self.service
+            .start_job(device, Path::new(directory), max_pages, move |event| {
+                if let Err(e) = emit(&connection, event) {
+                    warn!("Failed to signal {:?}: {}", event, e);
+                }
+            })
+            .map_err(failed)
+    }
+}
+
+/// Signal `event` to every client on the bus.
+fn emit(connection: &zbus::blocking::Connection, event: &JobEvent) -> zbus::Result<()> {
+    let destination = None::<&str>;
+
+    match *event {
+        JobEvent::Progress {
+            job,
+            page,
+            bytes,
+            total,
+        } => {
+            let total = total.map_or(-1, |total| total as i64);
+            let body = (job, page as u32, bytes as u64, total);
+            connection.emit_signal(destination, OBJECT_PATH, INTERFACE, "Progress", &body)
+        }
+        JobEvent::PageScanned {
+            job,
+            page,
+            ref path,
+        } => {
+            let body = (job, page as u32, path.to_string_lossy().into_owned());
+            connection.emit_signal(destination, OBJECT_PATH, INTERFACE, "PageScanned", &body)
+        }
+        JobEvent::Finished {
+            job,
+            pages,
+            ref error,
+        } => {
+            let body = (job, pages as u32, error.clone().unwrap_or_default());
+            connection.emit_signal(destination, OBJECT_PATH, INTERFACE, "JobFinished", &body)
+        }
+    }
+}
+
+fn failed(error: sane::error::Error) -> fdo::Error {
+    fdo::Error::Failed(error.to_string())
+}
+
+/// The D-Bus variant holding `value`: booleans, integers, fixed-point numbers
+/// as doubles, arrays of them, or strings. Buttons hold an empty string.
+fn to_variant(value: OptionValue) -> fdo::Result<OwnedValue> {
+    let value = match value {
+        OptionValue::Boolean(value) => Value::from(value),
+        OptionValue::Integer(value) => Value::from(value),
+        OptionValue::Fixed(value) => Value::from(value.to_f64()),
+        OptionValue::IntegerArray(values) => Value::from(values),
+        OptionValue::FixedArray(values) => Value::from(
+            values
+                .iter()
+                .map(|value| value.to_f64())
+                .collect::<Vec<_>>(),
+        ),
+        OptionValue::String(value) => Value::from(value),
+        OptionValue::Button => Value::from(""),
+    };
+    OwnedValue::try_from(value).map_err(|e| fdo::Error::Failed(e.to_string()))
+}
@@ -0,0 +1,403 @@
+extern crate dotenv;
+#[macro_use]
+extern crate log;
+extern crate pretty_env_logger;
+extern crate sane;
+
+use sane::auth::Credentials;
+use sane::error::Error;
+use sane::image::{self, Image};
+use sane::options::{DeviceOption, Options};
+use sane::output;
+use sane::scan::ByteOrder;
+use sane::types::*;
+use sane::*;
+
+use dotenv::dotenv;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::process;
+
+const USAGE: &str = "\
+Usage:
+    sane-rs devices
+    sane-rs options [<device>]
+    sane-rs scan [<device>] [--option <name>=<value>]... [--output <file>]
+    sane-rs decode <parameters> <capture> [--byte-order little|big] [--output <file>]
+
+Options:
+    --server <address>         The saned server to connect to. Defaults to the
+                               SANE_SERVER_ADDRESS environment variable, or localhost:6566.
+    -o, --option <name>=<value>
+                               Set a device option before scanning. Use `auto` as the
+                               value to let the device choose it.
+    --output <file>            Where to save the scan; the format is chosen by the
+                               file extension (.pnm, .png, .tif). Defaults to scan.pnm,
+                               or standard output as PNM when the file is `-`.
+    --byte-order little|big    The byte order of 16 bit samples in a capture being decoded.
+                               Defaults to the byte order of this machine.
+
+When no device is given, the first device found is used.
+Credentials for password-protected devices are read from the
+SANE_USERNAME and SANE_PASSWORD environment variables.
+
+`decode` turns a captured scan back into an image without a server:
+<parameters> holds the frame parameters as sent in reply to
+SANE_NET_GET_PARAMETERS, <capture> the records read from the data port.";
+
+enum Command {
+    Devices,
+    Options,
+    Scan,
+    Decode,
+}
+
+struct Arguments {
+    command: Command,
+    server: String,
+    device: Option<String>,
+    options: Vec<(String, String)>,
+    /// The parameter and capture files given to `decode`.
+    inputs: Vec<String>,
+    byte_order: ByteOrder,
+    output: String,
+}
+
+fn parse_arguments<I: Iterator<Item = String>>(
+    mut args: I,
+) -> std::result::Result<Arguments, String> {
+    let command = match args.next().as_deref() {
+        Some("devices") => Command::Devices,
+        Some("options") => Command::Options,
+        Some("scan") => Command::Scan,
+        Some("decode") => Command::Decode,
+        Some(command) => return Err(format!("Unknown command {:?}", command)),
+        None => return Err("No command given".into()),
+    };
+
+    let mut arguments = Arguments {
+        command,
+        server: env::var("SANE_SERVER_ADDRESS").unwrap_or_else(|_| "localhost:6566".into()),
+        device: None,
+        options: Vec::new(),
+        inputs: Vec::new(),
+        byte_order: ByteOrder::native(),
+        output: "scan.pnm".into(),
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {}", flag))
+        };
+
+        match arg.as_str() {
+            "--server" => arguments.server = value(&arg)?,
+            "--output" => arguments.output = value(&arg)?,
+            "--byte-order" => {
+                arguments.byte_order = match value(&arg)?.as_str() {
+                    "little" => ByteOrder::LittleEndian,
+                    "big" => ByteOrder::BigEndian,
+                    order => return Err(format!("Unknown byte order {:?}", order)),
+                }
+            }
+            "-o" | "--option" => {
+                let option = value(&arg)?;
+                let mut parts = option.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) if !name.is_empty() => {
+                        arguments.options.push((name.into(), value.into()))
+                    }
+                    _ => return Err(format!("Expected <name>=<value>, got {:?}", option)),
+                }
+            }
+            flag if flag.starts_with('-') && flag != "-" => {
+                return Err(format!("Unknown flag {:?}", flag))
+            }
+            _ if matches!(arguments.command, Command::Decode) && arguments.inputs.len() < 2 => {
+                arguments.inputs.push(arg)
+            }
+            _ if arguments.device.is_none() && !matches!(arguments.command, Command::Decode) => {
+                arguments.device = Some(arg)
+            }
+            _ => return Err(format!("Unexpected argument {:?}", arg)),
+        }
+    }
+
+    if matches!(arguments.command, Command::Decode) && arguments.inputs.len() != 2 {
+        return Err("decode needs a parameters file and a capture file".into());
+    }
+
+    Ok(arguments)
+}
+
+fn main() {
+    dotenv().ok();
+    pretty_env_logger::init();
+
+    let arguments = match parse_arguments(env::args().skip(1)) {
+        Ok(arguments) => arguments,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(&arguments) {
+        eprintln!("sane-rs: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(arguments: &Arguments) -> Result<()> {
+    if let Command::Decode = arguments.command {
+        return decode(arguments);
+    }
+
+    info!(
+        "Connecting to SANE server at address {}.",
+        &arguments.server
+    );
+    let mut session = SessionBuilder::new().connect(arguments.server.as_str())?;
+
+    if let Ok(username) = env::var("SANE_USERNAME") {
+        let password = env::var("SANE_PASSWORD").unwrap_or_default();
+        session.set_authenticator(move |_: &str| {
+            Some(Credentials::new(username.as_str(), password.as_str()))
+        });
+    }
+
+    let devices = session.devices()?;
+
+    if let Command::Devices = arguments.command {
+        for device in &devices {
+            println!(
+                "{} - {} {} ({})",
+                device.name, device.vendor, device.model, device.kind
+            );
+        }
+        return session.exit();
+    }
+
+    let device = match arguments.device {
+        Some(ref name) => devices.iter().find(|device| &device.name == name),
+        None => devices.first(),
+    };
+    let device = match device {
+        Some(device) => device,
+        None => {
+            return Err(Error::InvalidDeviceName(
+                arguments.device.clone().unwrap_or_default(),
+            ))
+        }
+    };
+
+    let handle = session.open(device)?;
+    match arguments.command {
+        Command::Options => print_options(&handle)?,
+        _ => scan(&handle, arguments)?,
+    }
+
+    handle.close()?;
+    session.exit()
+}
+
+fn print_options(handle: &DeviceHandle<'_, TcpStream>) -> Result<()> {
+    let options = handle.options()?;
+
+    for group in options.option_set().groups() {
+        if let Some(ref title) = group.title {
+            println!("{}:", title);
+        }
+
+        for option in &group.options {
+            print_option(&options, option);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_option(options: &Options<'_, DeviceHandle<'_, TcpStream>>, option: &DeviceOption) {
+    let name = option.name();
+    let descriptor = &option.descriptor;
+
+    let capabilities = descriptor.capabilities();
+    let current = if capabilities.contains(Capabilities::SoftDetect)
+        && !capabilities.contains(Capabilities::Inactive)
+    {
+        match options.get(name) {
+            Ok(value) => format!(" [{}]", format_value(&value)),
+            Err(e) => {
+                warn!("Failed to read option {}: {}", name, e);
+                String::new()
+            }
+        }
+    } else if capabilities.contains(Capabilities::Inactive) {
+        " [inactive]".into()
+    } else {
+        String::new()
+    };
+
+    println!("    --{} {}{}", name, describe_values(descriptor), current);
+    if let Some(description) = descriptor.description() {
+        println!("        {}", description);
+    }
+}
+
+/// Describe the values an option accepts, as scanimage does.
+fn describe_values(descriptor: &OptionDescriptor) -> String {
+    let mut values = match *descriptor {
+        OptionDescriptor::Boolean { .. } => "yes|no".into(),
+        OptionDescriptor::Integer { ref constraint, .. } => describe_numbers(constraint),
+        OptionDescriptor::Fixed { ref constraint, .. } => describe_numbers(constraint),
+        OptionDescriptor::String {
+            constraint: Some(ref constraint),
+            ..
+        } => constraint.values().join("|"),
+        OptionDescriptor::String { .. } => "<string>".into(),
+        _ => String::new(),
+    };
+
+    if let Some(length) = descriptor.array_length() {
+        values = format!("{},... ({} values)", values, length);
+    }
+
+    let unit = match *descriptor {
+        OptionDescriptor::Integer { ref unit, .. } | OptionDescriptor::Fixed { ref unit, .. } => {
+            unit_suffix(unit)
+        }
+        _ => "",
+    };
+    if descriptor.capabilities().contains(Capabilities::Automatic) {
+        format!("auto|{}{}", values, unit)
+    } else {
+        format!("{}{}", values, unit)
+    }
+}
+
+fn describe_numbers<T: Word + std::fmt::Display>(
+    constraint: &Option<NumericalConstraint<T>>,
+) -> String {
+    match *constraint {
+        Some(NumericalConstraint::Range(Some(ref range))) if range.quant().to_word() != 0 => {
+            format!(
+                "{}..{} (in steps of {})",
+                range.min(),
+                range.max(),
+                range.quant()
+            )
+        }
+        Some(NumericalConstraint::Range(Some(ref range))) => {
+            format!("{}..{}", range.min(), range.max())
+        }
+        Some(NumericalConstraint::IntegerList(ref list)) => list
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+            .join("|"),
+        _ => "<number>".into(),
+    }
+}
+
+fn unit_suffix(unit: &OptionUnit) -> &'static str {
+    match *unit {
+        OptionUnit::Pixel => "pel",
+        OptionUnit::Bit => "bit",
+        OptionUnit::Millimeter => "mm",
+        OptionUnit::DPI => "dpi",
+        OptionUnit::Percent => "%",
+        OptionUnit::Microsecond => "us",
+        _ => "",
+    }
+}
+
+fn format_value(value: &OptionValue) -> String {
+    fn join<T: ToString>(values: &[T]) -> String {
+        values
+            .iter()
+            .map(|value| value.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    match *value {
+        OptionValue::Boolean(true) => "yes".into(),
+        OptionValue::Boolean(false) => "no".into(),
+        OptionValue::Integer(value) => value.to_string(),
+        OptionValue::Fixed(value) => value.to_string(),
+        OptionValue::IntegerArray(ref values) => join(values),
+        OptionValue::FixedArray(ref values) => join(values),
+        OptionValue::String(ref value) => value.clone(),
+        OptionValue::Button => String::new(),
+    }
+}
+
+fn scan(handle: &DeviceHandle<'_, TcpStream>, arguments: &Arguments) -> Result<()> {
+    let mut options = handle.options()?;
+
+    for (name, text) in &arguments.options {
+        let (value, _) = if text == "auto" {
+            options.set_auto(name)?
+        } else {
+            let (_, descriptor) = options
+                .find(name)
+                .ok_or_else(|| Error::UnknownOption(name.clone()))?;
+            let value = descriptor.parse_value(text)?;
+            options.set(name, value)?
+        };
+        info!("Set {} to {}", name, format_value(&value));
+    }
+
+    let image = handle.scan_image()?;
+    info!(
+        "Acquired {}x{} image",
+        image.parameters.pixels_per_line, image.parameters.lines
+    );
+    if let Some(paper_size) = image.paper_size {
+        info!("Detected {:?} paper", paper_size);
+    }
+
+    save(&image, &arguments.output)
+}
+
+fn decode(arguments: &Arguments) -> Result<()> {
+    let parameters = Parameters::from_bytes(&std::fs::read(&arguments.inputs[0])?)?;
+    let capture = BufReader::new(File::open(&arguments.inputs[1])?);
+
+    let image = image::decode_capture(vec![(parameters, capture)], arguments.byte_order)?;
+    info!(
+        "Decoded {}x{} image",
+        image.parameters.pixels_per_line, image.parameters.lines
+    );
+
+    save(&image, &arguments.output)
+}
+
+fn save(image: &Image, path: &str) -> Result<()> {
+    if path == "-" {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        output::write_pnm(image, &mut out)?;
+        return Ok(out.flush()?);
+    }
+
+    let extension = path.rsplit('.').next().unwrap_or("").to_lowercase();
+    match extension.as_str() {
+        #[cfg(feature = "png")]
+        "png" => output::write_png(image, BufWriter::new(File::create(path)?)),
+        #[cfg(feature = "tiff")]
+        "tif" | "tiff" => output::write_tiff(image, &mut File::create(path)?),
+        "pnm" | "pbm" | "pgm" | "ppm" => {
+            let mut out = BufWriter::new(File::create(path)?);
+            output::write_pnm(image, &mut out)?;
+            Ok(out.flush()?)
+        }
+        extension => Err(Error::ImageEncodingError(format!(
+            "Unsupported output format {:?}",
+            extension
+        ))),
+    }
+}
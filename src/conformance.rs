@@ -0,0 +1,369 @@
+//! Checks of how a saned server and its backends follow the SANE network protocol,
+//! as run by the `sane-conformance` binary.
+//!
+//! The checks are made through a `Session`, so they can be run against a live saned,
+//! or against the fake one in `testing`. Every device is opened, every option it
+//! can report is read, and every option it lets software set is set back to its
+//! current value. Scanning is optional, since it needs a document in the scanner.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::types::{Capabilities, OptionAction, OptionDescriptor, OptionValue, Parameters};
+use crate::{Device, DeviceHandle, DeviceName, Result, Session, Transport};
+
+/// The result of a single check.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Pass,
+    /// The check failed, for the given reason.
+    Fail(String),
+    /// The check couldn't be made, for the given reason.
+    Skip(String),
+}
+
+/// A check made by `run`, named after the device and option it concerns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+/// The checks made by `run`, in the order they were made.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// The number of checks that failed.
+    pub fn failures(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Fail(_)))
+    }
+
+    /// Whether no check failed.
+    pub fn passed(&self) -> bool {
+        self.failures() == 0
+    }
+
+    fn count<F: Fn(&Outcome) -> bool>(&self, matches: F) -> usize {
+        self.checks
+            .iter()
+            .filter(|check| matches(&check.outcome))
+            .count()
+    }
+
+    fn push<N: Into<String>>(&mut self, name: N, outcome: Outcome) {
+        let name = name.into();
+        match outcome {
+            Outcome::Fail(ref reason) => warn!("{} failed: {}", name, reason),
+            _ => debug!("{}: {:?}", name, outcome),
+        }
+        self.checks.push(Check { name, outcome });
+    }
+
+    /// Record whether `result` succeeded, returning its value if it did.
+    fn check<N: Into<String>, T>(&mut self, name: N, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.push(name, Outcome::Pass);
+                Some(value)
+            }
+            Err(e) => {
+                self.push(name, Outcome::Fail(e.to_string()));
+                None
+            }
+        }
+    }
+}
+
+/// One line per check, followed by a count of the outcomes.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            match check.outcome {
+                Outcome::Pass => writeln!(f, "PASS {}", check.name)?,
+                Outcome::Fail(ref reason) => writeln!(f, "FAIL {}: {}", check.name, reason)?,
+                Outcome::Skip(ref reason) => writeln!(f, "SKIP {}: {}", check.name, reason)?,
+            }
+        }
+
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.count(|outcome| *outcome == Outcome::Pass),
+            self.failures(),
+            self.count(|outcome| matches!(outcome, Outcome::Skip(_)))
+        )
+    }
+}
+
+/// Check the devices of `session`, or only the device named `device`,
+/// scanning an image from each if `scan` is set.
+pub fn run<S: Transport>(session: &Session<S>, device: Option<&str>, scan: bool) -> Report {
+    let mut report = Report::default();
+    report.push(
+        format!("init: protocol version {}", session.version()),
+        Outcome::Pass,
+    );
+
+    let devices = match report.check("list devices", session.devices()) {
+        Some(devices) => devices,
+        None => return report,
+    };
+    if devices.is_empty() {
+        report.push("list devices", Outcome::Skip("no devices found".into()));
+    }
+
+    let mut found = false;
+    for listed in devices
+        .iter()
+        .filter(|listed| device.is_none_or(|name| listed.name == name))
+    {
+        found = true;
+        let name = format!("{}: device name", listed.name);
+        report.check(name, DeviceName::from_str(&listed.name));
+        check_device(session, listed, scan, &mut report);
+    }
+
+    if let (Some(name), false) = (device, found) {
+        report.push(
+            format!("{}: open", name),
+            Outcome::Fail("device isn't listed by the server".into()),
+        );
+    }
+
+    report
+}
+
+fn check_device<S: Transport>(
+    session: &Session<S>,
+    device: &Device,
+    scan: bool,
+    report: &mut Report,
+) {
+    let name = &device.name;
+    let handle = match report.check(format!("{}: open", name), session.open(device)) {
+        Some(handle) => handle,
+        None => return,
+    };
+
+    let descriptors = report.check(
+        format!("{}: option descriptors", name),
+        handle.option_descriptors(),
+    );
+    if let Some(descriptors) = descriptors {
+        check_options(&handle, name, &descriptors, report);
+    }
+
+    let parameters = report.check(format!("{}: parameters", name), handle.parameters());
+    if let Some(ref parameters) = parameters {
+        let outcome = check_parameters(parameters);
+        report.push(format!("{}: parameters are consistent", name), outcome);
+    }
+
+    if scan {
+        let image = report.check(format!("{}: scan", name), handle.scan_image());
+        if let Some(image) = image {
+            let outcome = check_parameters(&image.parameters).and_then(|| {
+                let parameters = &image.parameters;
+                let expected = parameters.bytes_per_line as usize * parameters.lines as usize;
+                if parameters.lines >= 0 && image.data.len() != expected {
+                    return Outcome::Fail(format!(
+                        "{} bytes of data for {} lines of {} bytes",
+                        image.data.len(),
+                        parameters.lines,
+                        parameters.bytes_per_line
+                    ));
+                }
+                Outcome::Pass
+            });
+            report.push(format!("{}: scanned data fills the image", name), outcome);
+        }
+    } else {
+        report.push(
+            format!("{}: scan", name),
+            Outcome::Skip("not requested".into()),
+        );
+    }
+
+    report.check(format!("{}: close", name), handle.close());
+}
+
+fn check_options<S: Transport>(
+    handle: &DeviceHandle<'_, S>,
+    device: &str,
+    descriptors: &[Option<OptionDescriptor>],
+    report: &mut Report,
+) {
+    // Option 0 holds the number of options, including itself
+    let count = match descriptors.first() {
+        Some(Some(descriptor @ OptionDescriptor::Integer { .. })) => handle
+            .control_option(0, descriptor, &OptionAction::Get)
+            .map(|(value, _)| value),
+        _ => Ok(OptionValue::Button),
+    };
+    let outcome = match count {
+        Ok(OptionValue::Integer(count)) if count as usize == descriptors.len() => Outcome::Pass,
+        Ok(OptionValue::Integer(count)) => Outcome::Fail(format!(
+            "{} options reported, {} descriptors sent",
+            count,
+            descriptors.len()
+        )),
+        Ok(_) => Outcome::Fail("option 0 isn't an integer".into()),
+        Err(e) => Outcome::Fail(e.to_string()),
+    };
+    report.push(format!("{}: option count", device), outcome);
+
+    for (index, descriptor) in descriptors.iter().enumerate().skip(1) {
+        let descriptor = match *descriptor {
+            Some(ref descriptor) if has_value(descriptor) => descriptor,
+            _ => continue,
+        };
+        let name = format!(
+            "{}: option {}",
+            device,
+            descriptor.name().unwrap_or_default()
+        );
+
+        let capabilities = descriptor.capabilities();
+        if capabilities.contains(Capabilities::Inactive) {
+            report.push(name, Outcome::Skip("inactive".into()));
+            continue;
+        }
+        if !capabilities.contains(Capabilities::SoftDetect) {
+            report.push(name, Outcome::Skip("can't be read".into()));
+            continue;
+        }
+
+        let index = index as i32;
+        let value = handle
+            .control_option(index, descriptor, &OptionAction::Get)
+            .map(|(value, _)| value);
+        let value = match report.check(format!("{}: get", name), value) {
+            Some(value) => value,
+            None => continue,
+        };
+        if !capabilities.contains(Capabilities::SoftSelect) {
+            continue;
+        }
+
+        // The current value must be one the option accepts, and setting
+        // it again must leave it as it is
+        let set = descriptor.check_value(value.clone()).and_then(|checked| {
+            handle.control_option(index, descriptor, &OptionAction::Set(checked))
+        });
+        let outcome = match set {
+            Ok((set, _)) if set == value => Outcome::Pass,
+            Ok((set, _)) => Outcome::Fail(format!("set {:?}, got back {:?}", value, set)),
+            Err(e) => Outcome::Fail(e.to_string()),
+        };
+        report.push(format!("{}: set", name), outcome);
+    }
+}
+
+/// Whether the option holds a value that can be read; buttons and groups don't.
+fn has_value(descriptor: &OptionDescriptor) -> bool {
+    matches!(
+        *descriptor,
+        OptionDescriptor::Boolean { .. }
+            | OptionDescriptor::Integer { .. }
+            | OptionDescriptor::Fixed { .. }
+            | OptionDescriptor::String { .. }
+    )
+}
+
+/// Check that `parameters` describe lines that can hold their pixels.
+fn check_parameters(parameters: &Parameters) -> Outcome {
+    let channels = match parameters.format.channels() {
+        Some(channels) => channels,
+        None => return Outcome::Fail(format!("unknown format {:?}", parameters.format)),
+    };
+    if ![1, 8, 16].contains(&parameters.depth) {
+        return Outcome::Fail(format!("unsupported depth {}", parameters.depth));
+    }
+    if parameters.pixels_per_line < 0 || parameters.bytes_per_line < 0 || parameters.lines < -1 {
+        return Outcome::Fail(format!("negative dimensions in {:?}", parameters));
+    }
+
+    let bits = parameters.pixels_per_line as usize * channels * parameters.depth as usize;
+    if (parameters.bytes_per_line as usize) < bits.div_ceil(8) {
+        return Outcome::Fail(format!(
+            "{} bytes per line can't hold {} pixels",
+            parameters.bytes_per_line, parameters.pixels_per_line
+        ));
+    }
+    Outcome::Pass
+}
+
+impl Outcome {
+    /// The outcome of `check` if this one passed, or else this one.
+    fn and_then<F: FnOnce() -> Outcome>(self, check: F) -> Outcome {
+        match self {
+            Outcome::Pass => check(),
+            outcome => outcome,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeDevice, FakeSaned};
+    use crate::types::{FrameFormat, NumericalConstraint, OptionUnit, Range};
+
+    fn fake_saned(bytes_per_line: i32) -> FakeSaned {
+        let resolution = OptionDescriptor::Integer {
+            name: "resolution".into(),
+            title: "Scan resolution".into(),
+            description: "Sets the resolution of the scanned image.".into(),
+            unit: OptionUnit::DPI,
+            size: 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(NumericalConstraint::Range(Some(Range::new(75, 600, 0)))),
+        };
+        let parameters = Parameters {
+            format: FrameFormat::Gray,
+            last_frame: true,
+            bytes_per_line,
+            pixels_per_line: 2,
+            lines: 2,
+            depth: 8,
+        };
+
+        FakeSaned::new().device(
+            FakeDevice::new("test:0")
+                .option(resolution, OptionValue::Integer(150))
+                .frame(parameters, vec![1, 2, 3, 4]),
+        )
+    }
+
+    #[test]
+    fn conforming_server_passes() {
+        let session = Session::new(fake_saned(2)).unwrap();
+        let report = run(&session, None, true);
+
+        assert!(report.passed(), "{}", report);
+        let names: Vec<_> = report.checks.iter().map(|check| &check.name[..]).collect();
+        assert!(names.contains(&"test:0: option resolution: set"));
+        assert!(names.contains(&"test:0: scanned data fills the image"));
+    }
+
+    #[test]
+    fn inconsistent_parameters_fail() {
+        let session = Session::new(fake_saned(1)).unwrap();
+        let report = run(&session, Some("test:0"), false);
+
+        assert_eq!(report.failures(), 1);
+        assert!(report
+            .to_string()
+            .contains("FAIL test:0: parameters are consistent"));
+    }
+
+    #[test]
+    fn unlisted_device_fails() {
+        let session = Session::new(fake_saned(2)).unwrap();
+        let report = run(&session, Some("test:1"), false);
+        assert_eq!(report.failures(), 1);
+    }
+}
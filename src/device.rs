@@ -1,8 +1,15 @@
-use std::io::Read;
-use TryFromStream;
-use Result;
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use crate::error::Error;
+use crate::types::non_null;
+use crate::{TryFromStream, WriteToStream};
+use crate::Result;
+use crate::request_device_list;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Device {
     pub name: String,
     pub vendor: String,
@@ -10,13 +17,243 @@ pub struct Device {
     pub kind: String,
 }
 
+impl Device {
+    /// Parse the name of this device into its components.
+    pub fn parsed_name(&self) -> Result<DeviceName> {
+        self.name.parse()
+    }
+}
+
 impl TryFromStream for Device {
     fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
         Ok(Self {
-            name: <Option<String>>::try_from_stream(stream)??,
-            vendor: <Option<String>>::try_from_stream(stream)??,
-            model: <Option<String>>::try_from_stream(stream)??,
-            kind: <Option<String>>::try_from_stream(stream)??,
+            name: non_null(<_>::try_from_stream(stream)?, "device name")?,
+            vendor: non_null(<_>::try_from_stream(stream)?, "device vendor")?,
+            model: non_null(<_>::try_from_stream(stream)?, "device model")?,
+            kind: non_null(<_>::try_from_stream(stream)?, "device type")?,
+        })
+    }
+}
+
+impl WriteToStream for Device {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        self.name.write_to_stream(stream)?;
+        self.vendor.write_to_stream(stream)?;
+        self.model.write_to_stream(stream)?;
+        self.kind.write_to_stream(stream)
+    }
+}
+
+/// Keeps the device list returned by the server for a configurable amount of time,
+/// so frontends that list devices frequently (e.g. on every window focus)
+/// don't send a new request to the server each time.
+#[derive(Debug)]
+pub struct DeviceCache {
+    ttl: Duration,
+    devices: Vec<Device>,
+    /// When `devices` was last fetched, or None if it never has been.
+    fetched_at: Option<Instant>,
+}
+
+impl DeviceCache {
+    /// Create an empty cache, whose entries are considered stale after `ttl`.
+    pub fn new(ttl: Duration) -> DeviceCache {
+        DeviceCache {
+            ttl,
+            devices: Vec::new(),
+            fetched_at: None,
+        }
+    }
+
+    /// Get the list of devices, only requesting it from the server
+    /// if the cached list is missing or older than the TTL.
+    pub fn devices<S: Read + Write>(&mut self, stream: &mut S) -> Result<&[Device]> {
+        let is_fresh = match self.fetched_at {
+            Some(fetched_at) => fetched_at.elapsed() < self.ttl,
+            None => false,
+        };
+
+        if !is_fresh {
+            return self.refresh(stream);
+        }
+
+        debug!("Using cached device list");
+        Ok(&self.devices)
+    }
+
+    /// Request the device list from the server, regardless of the age of the cached list.
+    pub fn refresh<S: Read + Write>(&mut self, stream: &mut S) -> Result<&[Device]> {
+        self.devices = request_device_list(stream)?;
+        self.fetched_at = Some(Instant::now());
+
+        Ok(&self.devices)
+    }
+
+    /// Discard the cached list, so the next call to `devices` requests it from the server.
+    pub fn invalidate(&mut self) {
+        self.fetched_at = None;
+    }
+}
+
+/// The name of a SANE device, split into its components.
+///
+/// Device names take the form `backend:device`, where the format of `device`
+/// is specific to the backend (e.g. `genesys:libusb:001:004`). Devices exported
+/// by another saned instance through the `net` backend are prefixed
+/// with the host: `net:host:backend:device`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceName {
+    host: Option<String>,
+    backend: String,
+    device: String,
+}
+
+impl DeviceName {
+    /// Construct the name of a device attached to the server directly.
+    pub fn local<B: Into<String>, D: Into<String>>(backend: B, device: D) -> DeviceName {
+        DeviceName {
+            host: None,
+            backend: backend.into(),
+            device: device.into(),
+        }
+    }
+
+    /// Construct the name of a device exported to the server
+    /// by the saned instance on `host`, through the `net` backend.
+    pub fn network<H, B, D>(host: H, backend: B, device: D) -> DeviceName
+    where
+        H: Into<String>,
+        B: Into<String>,
+        D: Into<String>,
+    {
+        DeviceName {
+            host: Some(host.into()),
+            backend: backend.into(),
+            device: device.into(),
+        }
+    }
+
+    /// The host exporting the device, if it is accessed through the `net` backend.
+    /// IPv6 addresses are returned without the surrounding brackets.
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// The name of the backend driving the device, e.g. `pixma`.
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    /// The backend-specific part of the name, e.g. `libusb:001:004`.
+    /// This is empty if the name consists of only the backend.
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    /// Returns true if the device is accessed through the `net` backend.
+    pub fn is_network(&self) -> bool {
+        self.host.is_some()
+    }
+
+    /// The name of the device as known on the host exporting it,
+    /// that is, without any `net:host:` prefix.
+    pub fn local_name(&self) -> DeviceName {
+        DeviceName::local(self.backend.as_str(), self.device.as_str())
+    }
+}
+
+impl FromStr for DeviceName {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<DeviceName> {
+        let invalid = || Error::InvalidDeviceName(name.into());
+
+        // Split off the first component, which is always the backend
+        let (backend, rest) = match name.find(':') {
+            Some(i) => (&name[..i], Some(&name[i + 1..])),
+            None => (name, None),
+        };
+
+        if backend.is_empty() {
+            return Err(invalid());
+        }
+
+        if backend != "net" {
+            return Ok(DeviceName::local(backend, rest.unwrap_or("")));
+        }
+
+        let rest = rest.ok_or_else(invalid)?;
+
+        // IPv6 addresses are enclosed in brackets, since they contain colons
+        let (host, rest) = if rest.starts_with('[') {
+            let end = rest.find(']').ok_or_else(invalid)?;
+            (&rest[1..end], &rest[end + 1..])
+        } else {
+            let end = rest.find(':').unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+
+        if host.is_empty() || !rest.starts_with(':') {
+            return Err(invalid());
+        }
+
+        let remote = DeviceName::from_str(&rest[1..]).map_err(|_| invalid())?;
+
+        Ok(DeviceName {
+            host: Some(host.into()),
+            ..remote
         })
     }
 }
+
+impl fmt::Display for DeviceName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host {
+            Some(ref host) if host.contains(':') => write!(f, "net:[{}]:", host)?,
+            Some(ref host) => write!(f, "net:{}:", host)?,
+            None => {}
+        }
+
+        if self.device.is_empty() {
+            write!(f, "{}", self.backend)
+        } else {
+            write!(f, "{}:{}", self.backend, self.device)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_local_device_name() {
+        let name: DeviceName = "genesys:libusb:001:004".parse().unwrap();
+
+        assert_eq!(name.host(), None);
+        assert_eq!(name.backend(), "genesys");
+        assert_eq!(name.device(), "libusb:001:004");
+        assert_eq!(name.to_string(), "genesys:libusb:001:004");
+    }
+
+    #[test]
+    fn parses_network_device_name() {
+        let name: DeviceName = "net:scanhost:pixma:04A91912_1234".parse().unwrap();
+        assert_eq!(
+            name,
+            DeviceName::network("scanhost", "pixma", "04A91912_1234")
+        );
+        assert_eq!(name.local_name().to_string(), "pixma:04A91912_1234");
+
+        let name: DeviceName = "net:[::1]:test:0".parse().unwrap();
+        assert_eq!(name.host(), Some("::1"));
+        assert_eq!(name.to_string(), "net:[::1]:test:0");
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        for name in &["", ":device", "net", "net:host", "net:[::1:test:0"] {
+            assert!(name.parse::<DeviceName>().is_err(), "{} parsed", name);
+        }
+    }
+}
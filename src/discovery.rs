@@ -0,0 +1,144 @@
+//! Discovery of saned servers on the local network, through the
+//! `_sane-port._tcp` service they advertise over mDNS.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::vec;
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+use crate::error::Error;
+use crate::Result;
+
+/// The mDNS service type advertised by saned.
+pub const SERVICE_TYPE: &str = "_sane-port._tcp.local.";
+
+/// A saned server found on the network.
+///
+/// Servers can be passed directly to `SessionBuilder::connect`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Server {
+    /// The full name of the advertised service instance.
+    pub name: String,
+    /// The host name of the server, such as `scanserver.local.`.
+    pub host: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// The TXT record of the service.
+    pub properties: HashMap<String, String>,
+}
+
+impl Server {
+    fn from_service_info(info: &ServiceInfo) -> Server {
+        let mut addresses: Vec<IpAddr> = info.get_addresses().iter().cloned().collect();
+        // Prefer IPv4, which saned is more commonly listening on
+        addresses.sort_by_key(|address| address.is_ipv6());
+
+        Server {
+            name: info.get_fullname().into(),
+            host: info.get_hostname().into(),
+            addresses,
+            port: info.get_port(),
+            properties: info
+                .get_properties()
+                .iter()
+                .map(|property| (property.key().into(), property.val_str().into()))
+                .collect(),
+        }
+    }
+}
+
+impl ToSocketAddrs for Server {
+    type Iter = vec::IntoIter<SocketAddr>;
+
+    /// The announced addresses of the server, or if none were
+    /// announced, the addresses its host name resolves to.
+    fn to_socket_addrs(&self) -> io::Result<Self::Iter> {
+        if self.addresses.is_empty() {
+            return (self.host.trim_end_matches('.'), self.port).to_socket_addrs();
+        }
+
+        Ok(self
+            .addresses
+            .iter()
+            .map(|&address| SocketAddr::new(address, self.port))
+            .collect::<Vec<_>>()
+            .into_iter())
+    }
+}
+
+impl From<mdns_sd::Error> for Error {
+    fn from(error: mdns_sd::Error) -> Error {
+        Error::DiscoveryError(error.to_string())
+    }
+}
+
+/// Browse the network for saned servers for `timeout`, returning
+/// every server whose address could be resolved in that time.
+pub fn discover(timeout: Duration) -> Result<Vec<Server>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let deadline = Instant::now() + timeout;
+
+    let mut servers: Vec<Server> = Vec::new();
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        let event = match events.recv_timeout(remaining) {
+            Ok(event) => event,
+            // Timed out, or the daemon stopped
+            Err(_) => break,
+        };
+
+        match event {
+            ServiceEvent::ServiceResolved(info) => {
+                debug!("Resolved saned server {}", info.get_fullname());
+                let server = Server::from_service_info(&info);
+                servers.retain(|known| known.name != server.name);
+                servers.push(server);
+            }
+            ServiceEvent::ServiceRemoved(_, name) => {
+                debug!("saned server {} went away", name);
+                servers.retain(|known| known.name != name);
+            }
+            _ => {}
+        }
+    }
+
+    if let Err(e) = daemon.shutdown() {
+        warn!("Failed to shut down mDNS daemon: {}", e);
+    }
+
+    Ok(servers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_resolves_to_announced_addresses() {
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "scanserver",
+            "scanserver.local.",
+            "fe80::1,192.168.1.20",
+            6566,
+            &[("note", "Office")][..],
+        )
+        .unwrap();
+
+        let server = Server::from_service_info(&info);
+        assert_eq!(server.name, "scanserver._sane-port._tcp.local.");
+        assert_eq!(server.properties["note"], "Office");
+
+        let addresses: Vec<SocketAddr> = server.to_socket_addrs().unwrap().collect();
+        assert_eq!(
+            addresses,
+            vec![
+                "192.168.1.20:6566".parse().unwrap(),
+                "[fe80::1]:6566".parse().unwrap(),
+            ]
+        );
+    }
+}
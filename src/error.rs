@@ -1,16 +1,100 @@
 use std::convert::From;
-use status::Status;
+use std::error;
+use std::fmt;
+use crate::status::Status;
+use crate::version::Version;
 
 #[derive(Debug)]
 pub enum Error {
     SanedError(Status),
+    /// The device requires authentication before the request can be completed;
+    /// holds the auth resource returned by the server.
+    AuthRequired(String),
     /// Error for WORD fields that are constrained to a fixed set of possible values,
     /// such as "type" fields with a value corresponding to a specific type.
     InvalidSaneFieldValue(String, i32),
     BadNetworkDataError(String),
+    /// A device name that doesn't follow the `backend:device` format.
+    InvalidDeviceName(String),
+    /// No option with the given name exists on the device.
+    UnknownOption(String),
+    /// A value can't be assigned to the named option, for the given reason.
+    InvalidOptionValue(String, String),
+    /// An image couldn't be written in the requested format.
+    ImageEncodingError(String),
+    /// libsane couldn't be loaded, for the local backend.
+    LibraryError(String),
+    /// Browsing the network for saned servers failed.
+    DiscoveryError(String),
+    /// The server sent a null pointer for the named field, which must always be set.
+    UnexpectedNull(String),
+    /// The named device is no longer open on the server, because it couldn't
+    /// be opened again after reconnecting.
+    DeviceClosed(String),
+    /// The server speaks a version of the protocol this client doesn't support.
+    UnsupportedVersion(Version),
+    /// Spooling a page would take a spool directory past its limit, in bytes.
+    SpoolFull(u64),
+    /// A read from or write to the server didn't complete within the timeout
+    /// configured on the connection.
+    Timeout(::std::io::Error),
     FromUtf8Error(::std::string::FromUtf8Error),
     IOError(::std::io::Error),
-    NoneError(::std::option::NoneError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::SanedError(status) => {
+                write!(f, "saned returned status {:?}: {}", status, status)
+            }
+            Error::AuthRequired(ref resource) => {
+                write!(f, "authentication required for resource '{}'", resource)
+            }
+            Error::InvalidSaneFieldValue(ref message, value) => {
+                write!(f, "{}: {}", message, value)
+            }
+            Error::BadNetworkDataError(ref message) => {
+                write!(f, "bad data received from saned: {}", message)
+            }
+            Error::InvalidDeviceName(ref name) => write!(f, "invalid device name '{}'", name),
+            Error::UnknownOption(ref name) => write!(f, "unknown option '{}'", name),
+            Error::InvalidOptionValue(ref name, ref reason) => {
+                write!(f, "invalid value for option '{}': {}", name, reason)
+            }
+            Error::ImageEncodingError(ref message) => {
+                write!(f, "failed to encode image: {}", message)
+            }
+            Error::LibraryError(ref message) => write!(f, "failed to load libsane: {}", message),
+            Error::DiscoveryError(ref message) => {
+                write!(f, "failed to discover saned servers: {}", message)
+            }
+            Error::UnexpectedNull(ref field) => {
+                write!(f, "saned sent a null value for the {}", field)
+            }
+            Error::DeviceClosed(ref name) => write!(f, "device '{}' is no longer open", name),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            Error::SpoolFull(limit) => {
+                write!(f, "spool directory is limited to {} bytes", limit)
+            }
+            Error::Timeout(ref error) => write!(f, "timed out: {}", error),
+            Error::FromUtf8Error(ref error) => write!(f, "invalid UTF-8 string: {}", error),
+            Error::IOError(ref error) => write!(f, "I/O error: {}", error),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Timeout(ref error) => Some(error),
+            Error::FromUtf8Error(ref error) => Some(error),
+            Error::IOError(ref error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl From<Status> for Error {
@@ -27,12 +111,48 @@ impl From<::std::string::FromUtf8Error> for Error {
 
 impl From<::std::io::Error> for Error {
     fn from(error: ::std::io::Error) -> Error {
-        Error::IOError(error)
+        match error.kind() {
+            // Depending on the platform, a read or write timeout
+            // on a socket is reported as either kind
+            ::std::io::ErrorKind::TimedOut | ::std::io::ErrorKind::WouldBlock => {
+                Error::Timeout(error)
+            }
+            _ => Error::IOError(error),
+        }
     }
 }
 
-impl From<::std::option::NoneError> for Error {
-    fn from(error: ::std::option::NoneError) -> Error {
-        Error::NoneError(error)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+    use std::io;
+
+    #[test]
+    fn displays_saned_status() {
+        let error = Error::SanedError(Status::DeviceBusy);
+        assert_eq!(
+            error.to_string(),
+            "saned returned status DeviceBusy: Device busy"
+        );
+    }
+
+    #[test]
+    fn io_error_is_the_source() {
+        let error = Error::from(io::Error::other("connection reset"));
+        assert_eq!(error.source().unwrap().to_string(), "connection reset");
+
+        let boxed: Box<dyn StdError> = Box::new(error);
+        assert_eq!(boxed.to_string(), "I/O error: connection reset");
+    }
+
+    #[test]
+    fn socket_timeouts_are_timeout_errors() {
+        for kind in &[io::ErrorKind::TimedOut, io::ErrorKind::WouldBlock] {
+            match Error::from(io::Error::from(*kind)) {
+                Error::Timeout(_) => {}
+                other => panic!("Expected Timeout, got {:?}", other),
+            }
+        }
     }
 }
@@ -0,0 +1,965 @@
+use std::convert::TryFrom;
+use std::io::Read;
+use std::ops::Range;
+use std::time::Instant;
+
+use crate::auth::NoCredentials;
+use crate::error::Error;
+use crate::options::MM_PER_INCH;
+use crate::scan::{ByteOrder, ProgressCallback, ScanData, ScanStats};
+use crate::status::Status;
+use crate::types::{FrameFormat, Parameters};
+use crate::unpack;
+use crate::{cancel, get_parameters, start_scan_with_auth, Result, Transport};
+
+/// A complete image acquired from a device.
+///
+/// 16-bit samples are in host byte order, whatever the byte order of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    /// Parameters describing `data`. For an image assembled from separate
+    /// red, green and blue frames, these describe the interleaved RGB result.
+    pub parameters: Parameters,
+    pub data: Vec<u8>,
+    /// The resolution the image was scanned at, in dots per inch, if known.
+    /// Images scanned through a `DeviceHandle` record the `resolution` option.
+    pub resolution: Option<f64>,
+    /// The standard paper size matching the physical size of the image,
+    /// detected when its resolution is recorded.
+    pub paper_size: Option<PaperSize>,
+}
+
+impl Image {
+    /// An image of `data`, described by `parameters`, of unknown resolution.
+    pub fn new(parameters: Parameters, data: Vec<u8>) -> Image {
+        Image {
+            parameters,
+            data,
+            resolution: None,
+            paper_size: None,
+        }
+    }
+
+    /// Record that the image was scanned at `dpi`, and detect its paper size
+    /// within `PAPER_SIZE_TOLERANCE`.
+    pub fn set_resolution(&mut self, dpi: f64) {
+        self.resolution = Some(dpi).filter(|&dpi| dpi > 0.0);
+        self.paper_size = self
+            .size_mm()
+            .and_then(|(width, height)| PaperSize::detect(width, height, PAPER_SIZE_TOLERANCE));
+    }
+
+    /// The width and height of the image in millimeters, if its resolution is known.
+    pub fn size_mm(&self) -> Option<(f64, f64)> {
+        let dpi = self.resolution?;
+        let lines = if self.parameters.lines >= 0 {
+            self.parameters.lines
+        } else {
+            // Hand-scanners don't know the number of lines in advance.
+            (self.data.len() / self.parameters.bytes_per_line.max(1) as usize) as i32
+        };
+        let mm = |pixels: i32| f64::from(pixels) * MM_PER_INCH / dpi;
+        Some((mm(self.parameters.pixels_per_line), mm(lines)))
+    }
+
+    /// Separate the visible channels of the image from its infrared channel,
+    /// for images in the `RGBI` or `GrayI` format, returning an `RGB` or `Gray`
+    /// image, and the infrared channel as a `Gray` image.
+    ///
+    /// Other images are returned as they are, without an infrared channel.
+    pub fn split_infrared(&self) -> Result<(Image, Option<Image>)> {
+        let (format, visible) = match self.parameters.format {
+            FrameFormat::RGBI => (FrameFormat::RGB, 3),
+            FrameFormat::GrayI => (FrameFormat::Gray, 1),
+            _ => return Ok((self.clone(), None)),
+        };
+
+        let image = self.take_channels(format, 0..visible)?;
+        let infrared = self.take_channels(FrameFormat::Gray, visible..visible + 1)?;
+        Ok((image, Some(infrared)))
+    }
+
+    /// The image turned to `orientation`, for example to turn the back sides of
+    /// duplex scans, or pages fed into the scanner sideways, upright.
+    ///
+    /// Rotating needs the whole image, since the first line of the result is made of
+    /// the first or last pixel of every line. Mirroring alone can be applied to each
+    /// line as it is read, with `mirror_line`.
+    pub fn orient(&self, orientation: Orientation) -> Result<Image> {
+        let (pixels_per_line, bytes_per_line, channels) = self.layout()?;
+        let depth = self.parameters.depth;
+        let width = pixels_per_line;
+        let height = self.data.len() / bytes_per_line;
+
+        let (out_width, out_height) = match orientation.rotation {
+            Rotation::None | Rotation::HalfTurn => (width, height),
+            Rotation::Clockwise | Rotation::CounterClockwise => (height, width),
+        };
+        let out_bytes_per_line = (out_width * channels * depth as usize).div_ceil(8);
+        let mut data = vec![0u8; out_bytes_per_line * out_height];
+
+        for y in 0..height {
+            let input = &self.data[y * bytes_per_line..(y + 1) * bytes_per_line];
+            for x in 0..width {
+                let from = if orientation.mirror { width - 1 - x } else { x };
+                let (to_x, to_y) = match orientation.rotation {
+                    Rotation::None => (x, y),
+                    Rotation::Clockwise => (height - 1 - y, x),
+                    Rotation::HalfTurn => (width - 1 - x, height - 1 - y),
+                    Rotation::CounterClockwise => (y, width - 1 - x),
+                };
+
+                let line = to_y * out_bytes_per_line;
+                let out = &mut data[line..line + out_bytes_per_line];
+                for channel in 0..channels {
+                    let sample = from * channels + channel;
+                    copy_sample(input, sample, out, to_x * channels + channel, depth);
+                }
+            }
+        }
+
+        Ok(Image {
+            parameters: Parameters {
+                bytes_per_line: out_bytes_per_line as i32,
+                pixels_per_line: out_width as i32,
+                lines: out_height as i32,
+                ..self.parameters.clone()
+            },
+            data,
+            resolution: self.resolution,
+            paper_size: self.paper_size,
+        })
+    }
+
+    /// The number of pixels, bytes and channels in each line of the image,
+    /// checking that its samples can be addressed one by one.
+    fn layout(&self) -> Result<(usize, usize, usize)> {
+        let parameters = &self.parameters;
+        let channels = parameters.format.channels().unwrap_or(1);
+        let depth = parameters.depth;
+        if depth != 1 && depth != 8 && depth != 16 {
+            return Err(Error::BadNetworkDataError(format!(
+                "Unsupported sample depth {}",
+                depth
+            )));
+        }
+
+        let pixels_per_line = usize::try_from(parameters.pixels_per_line).unwrap_or(0);
+        let bytes_per_line = usize::try_from(parameters.bytes_per_line).unwrap_or(0);
+        if bytes_per_line < (pixels_per_line * channels * depth as usize).div_ceil(8)
+            || bytes_per_line == 0
+        {
+            return Err(Error::BadNetworkDataError(format!(
+                "Invalid image geometry {:?}",
+                parameters
+            )));
+        }
+
+        Ok((pixels_per_line, bytes_per_line, channels))
+    }
+
+    /// A `format` image of the samples of `channels` of each pixel.
+    fn take_channels(&self, format: FrameFormat, channels: Range<usize>) -> Result<Image> {
+        let parameters = &self.parameters;
+        let (pixels_per_line, bytes_per_line, total_channels) = self.layout()?;
+        let depth = parameters.depth;
+
+        let count = channels.len();
+        let lines = self.data.len() / bytes_per_line;
+        let out_bytes_per_line = (pixels_per_line * count * depth as usize).div_ceil(8);
+        let mut data = vec![0u8; out_bytes_per_line * lines];
+
+        for line in 0..lines {
+            let input = &self.data[line * bytes_per_line..(line + 1) * bytes_per_line];
+            let out = &mut data[line * out_bytes_per_line..(line + 1) * out_bytes_per_line];
+
+            for pixel in 0..pixels_per_line {
+                for (index, channel) in channels.clone().enumerate() {
+                    let from = pixel * total_channels + channel;
+                    copy_sample(input, from, out, pixel * count + index, depth);
+                }
+            }
+        }
+
+        Ok(Image {
+            parameters: Parameters {
+                format,
+                last_frame: true,
+                bytes_per_line: out_bytes_per_line as i32,
+                pixels_per_line: parameters.pixels_per_line,
+                lines: lines as i32,
+                depth,
+            },
+            data,
+            resolution: self.resolution,
+            paper_size: self.paper_size,
+        })
+    }
+}
+
+/// A rotation of an image, clockwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Rotation {
+    #[default]
+    None,
+    /// A quarter turn clockwise, by 90 degrees.
+    Clockwise,
+    /// A half turn, by 180 degrees.
+    HalfTurn,
+    /// A quarter turn counterclockwise, or by 270 degrees clockwise.
+    CounterClockwise,
+}
+
+/// How to turn an image upright with `Image::orient`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Orientation {
+    pub rotation: Rotation,
+    /// Mirror the image left to right, before rotating it.
+    pub mirror: bool,
+}
+
+impl Orientation {
+    pub fn rotated(rotation: Rotation) -> Orientation {
+        Orientation {
+            rotation,
+            mirror: false,
+        }
+    }
+
+    pub fn mirrored() -> Orientation {
+        Orientation {
+            rotation: Rotation::None,
+            mirror: true,
+        }
+    }
+
+    /// Whether the orientation leaves images as they are.
+    pub fn is_upright(self) -> bool {
+        self == Orientation::default()
+    }
+}
+
+/// Mirror a line of image data described by `parameters` left to right in place,
+/// such as a line read with `ScanData::lines`.
+pub fn mirror_line(parameters: &Parameters, line: &mut [u8]) -> Result<()> {
+    let channels = parameters.format.channels().unwrap_or(1);
+    let width = usize::try_from(parameters.pixels_per_line).unwrap_or(0);
+    let depth = parameters.depth;
+    if depth != 1 && depth != 8 && depth != 16 {
+        return Err(Error::BadNetworkDataError(format!(
+            "Unsupported sample depth {}",
+            depth
+        )));
+    }
+    let samples = width * channels;
+    if line.len() < (samples * depth as usize).div_ceil(8) {
+        return Err(Error::BadNetworkDataError(format!(
+            "Line of {} bytes is too short for {:?}",
+            line.len(),
+            parameters
+        )));
+    }
+
+    let input = line.to_vec();
+    if depth == 1 {
+        for byte in line[..samples.div_ceil(8)].iter_mut() {
+            *byte = 0;
+        }
+    }
+    for x in 0..width {
+        for channel in 0..channels {
+            let to = (width - 1 - x) * channels + channel;
+            copy_sample(&input, x * channels + channel, line, to, depth);
+        }
+    }
+    Ok(())
+}
+
+/// How far, in millimeters, the size of an image may be from a paper size
+/// for `Image::set_resolution` to detect it.
+pub const PAPER_SIZE_TOLERANCE: f64 = 5.0;
+
+/// Standard paper sizes a scanned page can be matched against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PaperSize {
+    A4,
+    A5,
+    Letter,
+    Legal,
+    /// A till receipt on 58 mm paper roll, of any length.
+    Receipt58,
+    /// A till receipt on 80 mm paper roll, of any length.
+    Receipt80,
+}
+
+impl PaperSize {
+    pub const ALL: [PaperSize; 6] = [
+        PaperSize::A4,
+        PaperSize::A5,
+        PaperSize::Letter,
+        PaperSize::Legal,
+        PaperSize::Receipt58,
+        PaperSize::Receipt80,
+    ];
+
+    /// The width and height of the paper in millimeters, in portrait orientation.
+    /// Receipts have no fixed length, so their height is `None`.
+    pub fn dimensions(self) -> (f64, Option<f64>) {
+        match self {
+            PaperSize::A4 => (210.0, Some(297.0)),
+            PaperSize::A5 => (148.0, Some(210.0)),
+            PaperSize::Letter => (215.9, Some(279.4)),
+            PaperSize::Legal => (215.9, Some(355.6)),
+            PaperSize::Receipt58 => (58.0, None),
+            PaperSize::Receipt80 => (80.0, None),
+        }
+    }
+
+    /// The paper size whose dimensions are closest to `width` by `height`
+    /// millimeters, in either orientation, if it is within `tolerance` millimeters
+    /// in both directions.
+    ///
+    /// A receipt matches any page of its width at least as long as it is wide.
+    pub fn detect(width: f64, height: f64, tolerance: f64) -> Option<PaperSize> {
+        let distance = |paper: PaperSize, width: f64, height: f64| {
+            let (paper_width, paper_height) = paper.dimensions();
+            let height_error = match paper_height {
+                Some(paper_height) => (height - paper_height).abs(),
+                None if height >= paper_width => 0.0,
+                None => f64::INFINITY,
+            };
+            let error = (width - paper_width).abs().max(height_error);
+            Some(error).filter(|&error| error <= tolerance)
+        };
+
+        PaperSize::ALL
+            .iter()
+            .filter_map(|&paper| {
+                let portrait = distance(paper, width, height);
+                let landscape = distance(paper, height, width);
+                let error = match (portrait, landscape) {
+                    (Some(a), Some(b)) => a.min(b),
+                    (error, None) | (None, error) => error?,
+                };
+                Some((paper, error))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(paper, _)| paper)
+    }
+}
+
+/// Assembles the frames of a scan into a single image.
+///
+/// Most devices deliver an image in one frame, but older three-pass scanners
+/// send separate red, green and blue frames, which are interleaved
+/// into a single RGB image. Film scanners can send an infrared frame after
+/// the color or gray frames, which is interleaved into an RGBI or GrayI image.
+#[derive(Debug, Default)]
+pub struct FrameAssembler {
+    frames: Vec<(Parameters, Vec<u8>)>,
+}
+
+impl FrameAssembler {
+    pub fn new() -> FrameAssembler {
+        FrameAssembler::default()
+    }
+
+    /// Add the image data of a frame, described by `parameters`.
+    pub fn add_frame(&mut self, parameters: Parameters, data: Vec<u8>) -> Result<()> {
+        if self.is_complete() {
+            return Err(Error::BadNetworkDataError(
+                "Received a frame after the last frame of the image".into(),
+            ));
+        }
+
+        self.frames.push((parameters, data));
+        Ok(())
+    }
+
+    /// Returns true once the last frame of the image has been added.
+    pub fn is_complete(&self) -> bool {
+        self.frames
+            .last()
+            .map(|(parameters, _)| parameters.last_frame)
+            .unwrap_or(false)
+    }
+
+    /// Assemble the frames added so far into an image.
+    pub fn finish(mut self) -> Result<Image> {
+        if !self.is_complete() {
+            return Err(Error::BadNetworkDataError(
+                "Image is missing its last frame".into(),
+            ));
+        }
+
+        if self.frames.len() == 1 {
+            let (parameters, data) = self.frames.remove(0);
+            return Ok(Image::new(parameters, data));
+        }
+
+        interleave_planes(self.frames)
+    }
+}
+
+/// Combine the frames of a multi-frame scan into one image: the separate red,
+/// green and blue frames of a three-pass scan, and the infrared frame some
+/// film scanners send after the color or gray frames.
+fn interleave_planes(mut frames: Vec<(Parameters, Vec<u8>)>) -> Result<Image> {
+    // Order the frames by the position of their channels in the result
+    for (parameters, _) in &frames {
+        if plane_order(parameters.format).is_none() {
+            return Err(Error::BadNetworkDataError(format!(
+                "Unexpected {:?} frame in a multi-frame image",
+                parameters.format
+            )));
+        }
+    }
+    frames.sort_by_key(|(parameters, _)| plane_order(parameters.format));
+
+    if let Some(pair) = frames
+        .windows(2)
+        .find(|pair| pair[0].0.format == pair[1].0.format)
+    {
+        return Err(Error::BadNetworkDataError(format!(
+            "Received more than one {:?} frame",
+            pair[0].0.format
+        )));
+    }
+
+    let formats: Vec<FrameFormat> = frames
+        .iter()
+        .map(|(parameters, _)| parameters.format)
+        .collect();
+    let format = match formats[..] {
+        [FrameFormat::Red, FrameFormat::Green, FrameFormat::Blue] => FrameFormat::RGB,
+        [FrameFormat::Red, FrameFormat::Green, FrameFormat::Blue, FrameFormat::Infrared]
+        | [FrameFormat::RGB, FrameFormat::Infrared] => FrameFormat::RGBI,
+        [FrameFormat::Gray, FrameFormat::Infrared] => FrameFormat::GrayI,
+        _ => {
+            return Err(Error::BadNetworkDataError(format!(
+                "Multi-frame image is missing a channel, received {:?}",
+                formats
+            )))
+        }
+    };
+
+    let first = frames[0].0.clone();
+    if frames.iter().any(|(parameters, _)| {
+        parameters.pixels_per_line != first.pixels_per_line || parameters.depth != first.depth
+    }) {
+        return Err(Error::BadNetworkDataError(
+            "Frames of the image have different geometry".into(),
+        ));
+    }
+
+    let depth = first.depth;
+    if depth != 1 && depth != 8 && depth != 16 {
+        return Err(Error::BadNetworkDataError(format!(
+            "Unsupported sample depth {}",
+            depth
+        )));
+    }
+    if first.pixels_per_line < 0 {
+        return Err(Error::BadNetworkDataError(format!(
+            "Invalid frame geometry {:?}",
+            first
+        )));
+    }
+    let pixels_per_line = first.pixels_per_line as usize;
+
+    // The channels of each frame, and the size of its lines
+    let mut planes = Vec::with_capacity(frames.len());
+    for (parameters, data) in &frames {
+        let channels = parameters.format.channels().unwrap_or(1);
+        let min_bytes_per_line = (pixels_per_line * channels * depth as usize).div_ceil(8);
+        if parameters.bytes_per_line <= 0
+            || (parameters.bytes_per_line as usize) < min_bytes_per_line
+        {
+            return Err(Error::BadNetworkDataError(format!(
+                "{} bytes per line is too few for {} pixels",
+                parameters.bytes_per_line, pixels_per_line
+            )));
+        }
+        planes.push((channels, parameters.bytes_per_line as usize, data));
+    }
+
+    // The number of lines may not have been known in advance,
+    // so go by the shortest frame actually received.
+    let lines = planes
+        .iter()
+        .map(|&(_, bytes_per_line, data)| data.len() / bytes_per_line)
+        .min()
+        .unwrap_or(0);
+
+    let total_channels: usize = planes.iter().map(|&(channels, _, _)| channels).sum();
+    let out_bytes_per_line = (pixels_per_line * total_channels * depth as usize).div_ceil(8);
+    let mut data = vec![0u8; out_bytes_per_line * lines];
+
+    for line in 0..lines {
+        let out = &mut data[line * out_bytes_per_line..(line + 1) * out_bytes_per_line];
+
+        let mut first_channel = 0;
+        for &(channels, bytes_per_line, plane) in &planes {
+            let input = &plane[line * bytes_per_line..(line + 1) * bytes_per_line];
+
+            if depth == 1 {
+                for pixel in 0..pixels_per_line {
+                    for channel in 0..channels {
+                        let from = pixel * channels + channel;
+                        let to = pixel * total_channels + first_channel + channel;
+                        copy_sample(input, from, out, to, depth);
+                    }
+                }
+            } else {
+                let size = depth as usize / 8;
+                unpack::interleave(
+                    input,
+                    channels * size,
+                    out,
+                    total_channels * size,
+                    first_channel * size,
+                    pixels_per_line,
+                );
+            }
+            first_channel += channels;
+        }
+    }
+
+    Ok(Image::new(
+        Parameters {
+            format,
+            last_frame: true,
+            bytes_per_line: out_bytes_per_line as i32,
+            pixels_per_line: first.pixels_per_line,
+            lines: lines as i32,
+            depth,
+        },
+        data,
+    ))
+}
+
+/// The position of the channels of a frame of `format` among those of a multi-frame
+/// image, or None if frames of that format aren't part of multi-frame images.
+fn plane_order(format: FrameFormat) -> Option<u8> {
+    match format {
+        FrameFormat::Red | FrameFormat::Gray | FrameFormat::RGB => Some(0),
+        FrameFormat::Green => Some(1),
+        FrameFormat::Blue => Some(2),
+        FrameFormat::Infrared => Some(3),
+        _ => None,
+    }
+}
+
+/// Copy sample number `from` of `input` to sample number `to` of `output`,
+/// for samples of `depth` bits: 1, 8 or 16.
+fn copy_sample(input: &[u8], from: usize, output: &mut [u8], to: usize, depth: i32) {
+    if depth == 1 {
+        // Samples are packed most significant bit first
+        let bit = (input[from / 8] >> (7 - from % 8)) & 1;
+        output[to / 8] |= bit << (7 - to % 8);
+    } else {
+        let size = depth as usize / 8;
+        output[to * size..(to + 1) * size].copy_from_slice(&input[from * size..(from + 1) * size]);
+    }
+}
+
+/// Acquire a complete image from the device opened as `handle`,
+/// reading frames until the last one and assembling them.
+///
+/// The scan is completed with `cancel` afterwards, including when acquisition fails.
+pub fn acquire<T: Transport>(handle: i32, stream: &mut T) -> Result<Image> {
+    acquire_with_stats(handle, stream).map(|(image, _)| image)
+}
+
+/// Acquire a complete image like `acquire`, along with statistics of the scan.
+pub fn acquire_with_stats<T: Transport>(handle: i32, stream: &mut T) -> Result<(Image, ScanStats)> {
+    let mut stats = ScanStats::default();
+    let image = acquire_with(handle, stream, None, &mut stats)?;
+    Ok((image, stats))
+}
+
+/// Acquire a complete image like `acquire`, reporting the progress
+/// of reading each frame to `callback`.
+pub fn acquire_with_progress<T: Transport>(
+    handle: i32,
+    stream: &mut T,
+    callback: ProgressCallback,
+) -> Result<Image> {
+    acquire_with(handle, stream, Some(callback), &mut ScanStats::default())
+}
+
+/// Decode image data captured from data connections, without any connection to saned,
+/// for instance to debug acquisition problems or reprocess archived raw scans.
+///
+/// Each frame is given with its parameters and the data received on its data connection,
+/// including the record framing, and 16-bit samples in `byte_order`, as announced
+/// when the scan was started. The frames are assembled like those of `acquire`.
+pub fn decode_capture<R, I>(frames: I, byte_order: ByteOrder) -> Result<Image>
+where
+    R: Read,
+    I: IntoIterator<Item = (Parameters, R)>,
+{
+    let mut assembler = FrameAssembler::new();
+    for (parameters, capture) in frames {
+        let data = ScanData::new(capture, byte_order).read_frame(&parameters)?;
+        assembler.add_frame(parameters, data)?;
+    }
+    assembler.finish()
+}
+
+/// Acquire the next page from the device opened as `handle`, which has a document
+/// feeder, without completing the scan. Returns None once the feeder is empty.
+///
+/// `cancel` must be called once done acquiring pages.
+pub fn acquire_page<T: Transport>(handle: i32, stream: &mut T) -> Result<Option<Image>> {
+    feeder_page(acquire_frames(
+        handle,
+        stream,
+        &mut None,
+        &mut ScanStats::default(),
+    ))
+}
+
+/// The page acquired from a document feeder, or None if acquiring it
+/// failed because the feeder is empty.
+pub(crate) fn feeder_page(acquired: Result<Image>) -> Result<Option<Image>> {
+    match acquired {
+        Err(Error::SanedError(Status::NoDocuments)) => {
+            info!("Document feeder is empty");
+            Ok(None)
+        }
+        result => result.map(Some),
+    }
+}
+
+/// Acquire a complete image like `acquire`, reporting progress to `progress` if set.
+/// Statistics of the scan are collected in `stats`.
+fn acquire_with<T: Transport>(
+    handle: i32,
+    stream: &mut T,
+    mut progress: Option<ProgressCallback>,
+    stats: &mut ScanStats,
+) -> Result<Image> {
+    let started = Instant::now();
+    let result = acquire_frames(handle, stream, &mut progress, stats);
+    let cancelled = cancel(handle, stream);
+    stats.duration = started.elapsed();
+
+    let image = result?;
+    cancelled?;
+    Ok(image)
+}
+
+fn acquire_frames<T: Transport>(
+    handle: i32,
+    stream: &mut T,
+    progress: &mut Option<ProgressCallback>,
+    stats: &mut ScanStats,
+) -> Result<Image> {
+    acquire_frames_with(
+        || {
+            let data = start_scan_with_auth(handle, stream, &mut NoCredentials)?;
+            let parameters = get_parameters(handle, stream)?;
+            Ok((data, parameters))
+        },
+        |data, parameters| {
+            if let Some(callback) = progress.take() {
+                data.set_progress(parameters, callback);
+            }
+            let frame = data.read_frame(parameters);
+            *progress = data.take_progress();
+            frame
+        },
+        stats,
+    )
+}
+
+/// Acquire the frames of an image until the last one and assemble them,
+/// collecting statistics in `stats`.
+///
+/// Each frame is started with `start`, which returns the reader for its data
+/// along with its parameters, and its data is read with `read`.
+pub(crate) fn acquire_frames_with<R, S, F>(
+    mut start: S,
+    mut read: F,
+    stats: &mut ScanStats,
+) -> Result<Image>
+where
+    R: Read,
+    S: FnMut() -> Result<(ScanData<R>, Parameters)>,
+    F: FnMut(&mut ScanData<R>, &Parameters) -> Result<Vec<u8>>,
+{
+    let mut assembler = FrameAssembler::new();
+
+    while !assembler.is_complete() {
+        let (mut data, parameters) = start()?;
+
+        info!("Acquiring {:?} frame", parameters.format);
+        let frame = read(&mut data, &parameters)?;
+
+        stats.frames += 1;
+        stats.bytes += data.bytes_read();
+        stats.records += data.records_read();
+
+        assembler.add_frame(parameters, frame)?;
+    }
+
+    assembler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(
+        format: FrameFormat,
+        last_frame: bool,
+        depth: i32,
+        data: Vec<u8>,
+    ) -> (Parameters, Vec<u8>) {
+        let bytes_per_line = if depth == 1 { 1 } else { 2 * depth / 8 };
+        (
+            Parameters {
+                format,
+                last_frame,
+                bytes_per_line,
+                pixels_per_line: 2,
+                lines: 2,
+                depth,
+            },
+            data,
+        )
+    }
+
+    #[test]
+    fn single_frame_is_passed_through() {
+        let mut assembler = FrameAssembler::new();
+        let (parameters, data) = frame(FrameFormat::Gray, true, 8, vec![1, 2, 3, 4]);
+        assembler
+            .add_frame(parameters.clone(), data.clone())
+            .unwrap();
+
+        assert_eq!(assembler.finish().unwrap(), Image::new(parameters, data));
+    }
+
+    #[test]
+    fn interleaves_three_pass_frames() {
+        let mut assembler = FrameAssembler::new();
+        for (parameters, data) in [
+            frame(FrameFormat::Red, false, 8, vec![10, 11, 12, 13]),
+            frame(FrameFormat::Green, false, 8, vec![20, 21, 22, 23]),
+            frame(FrameFormat::Blue, true, 8, vec![30, 31, 32, 33]),
+        ] {
+            assembler.add_frame(parameters, data).unwrap();
+        }
+
+        let image = assembler.finish().unwrap();
+        assert_eq!(image.parameters.format, FrameFormat::RGB);
+        assert_eq!(image.parameters.bytes_per_line, 6);
+        assert_eq!(image.parameters.lines, 2);
+        assert_eq!(
+            image.data,
+            vec![10, 20, 30, 11, 21, 31, 12, 22, 32, 13, 23, 33]
+        );
+    }
+
+    #[test]
+    fn interleaves_three_pass_lineart_frames() {
+        let mut assembler = FrameAssembler::new();
+        for (parameters, data) in [
+            frame(FrameFormat::Red, false, 1, vec![0b1000_0000, 0b0100_0000]),
+            frame(FrameFormat::Green, false, 1, vec![0b1000_0000, 0b0000_0000]),
+            frame(FrameFormat::Blue, true, 1, vec![0b0000_0000, 0b0100_0000]),
+        ] {
+            assembler.add_frame(parameters, data).unwrap();
+        }
+
+        let image = assembler.finish().unwrap();
+        assert_eq!(image.parameters.bytes_per_line, 1);
+        assert_eq!(image.data, vec![0b1100_0000, 0b0001_0100]);
+    }
+
+    #[test]
+    fn interleaves_infrared_frame_after_color_frame() {
+        let mut assembler = FrameAssembler::new();
+        let (mut parameters, data) = frame(FrameFormat::RGB, false, 8, vec![1, 2, 3, 4, 5, 6]);
+        parameters.bytes_per_line = 6;
+        parameters.lines = 1;
+        assembler.add_frame(parameters, data).unwrap();
+        let (mut parameters, data) = frame(FrameFormat::Infrared, true, 8, vec![7, 8]);
+        parameters.lines = 1;
+        assembler.add_frame(parameters, data).unwrap();
+
+        let image = assembler.finish().unwrap();
+        assert_eq!(image.parameters.format, FrameFormat::RGBI);
+        assert_eq!(image.parameters.bytes_per_line, 8);
+        assert_eq!(image.data, vec![1, 2, 3, 7, 4, 5, 6, 8]);
+
+        let (color, infrared) = image.split_infrared().unwrap();
+        assert_eq!(color.parameters.format, FrameFormat::RGB);
+        assert_eq!(color.data, vec![1, 2, 3, 4, 5, 6]);
+        let infrared = infrared.unwrap();
+        assert_eq!(infrared.parameters.format, FrameFormat::Gray);
+        assert_eq!(infrared.data, vec![7, 8]);
+    }
+
+    #[test]
+    fn splits_gray_and_infrared_lineart() {
+        let image = Image::new(
+            Parameters {
+                format: FrameFormat::GrayI,
+                last_frame: true,
+                bytes_per_line: 1,
+                pixels_per_line: 2,
+                lines: 1,
+                depth: 1,
+            },
+            vec![0b1001_0000],
+        );
+
+        let (gray, infrared) = image.split_infrared().unwrap();
+        assert_eq!(gray.data, vec![0b1000_0000]);
+        assert_eq!(infrared.unwrap().data, vec![0b0100_0000]);
+    }
+
+    #[test]
+    fn decodes_captured_frame() {
+        let (mut parameters, _) = frame(FrameFormat::Gray, true, 16, Vec::new());
+        parameters.lines = 1;
+        let parameters = Parameters::from_bytes(&parameters.to_bytes()).unwrap();
+
+        // A record of two little-endian samples, the end of data marker and status
+        let capture: &[u8] = &[
+            0, 0, 0, 4, 0x01, 0x02, 0x03, 0x04, 0xff, 0xff, 0xff, 0xff, 5,
+        ];
+        let image =
+            decode_capture(vec![(parameters.clone(), capture)], ByteOrder::LittleEndian).unwrap();
+
+        assert_eq!(image.parameters, parameters);
+        let samples: Vec<u16> = image
+            .data
+            .chunks_exact(2)
+            .map(|sample| u16::from_ne_bytes([sample[0], sample[1]]))
+            .collect();
+        assert_eq!(samples, vec![0x0201, 0x0403]);
+    }
+
+    #[test]
+    fn rejects_missing_channel() {
+        let mut assembler = FrameAssembler::new();
+        let (parameters, data) = frame(FrameFormat::Red, false, 8, vec![0; 4]);
+        assembler.add_frame(parameters, data).unwrap();
+        let (parameters, data) = frame(FrameFormat::Blue, true, 8, vec![0; 4]);
+        assembler.add_frame(parameters, data).unwrap();
+
+        assert!(assembler.finish().is_err());
+    }
+
+    #[test]
+    fn rejects_lines_too_short_for_their_pixels() {
+        let mut assembler = FrameAssembler::new();
+        for (format, last_frame) in [
+            (FrameFormat::Red, false),
+            (FrameFormat::Green, false),
+            (FrameFormat::Blue, true),
+        ] {
+            let (mut parameters, data) = frame(format, last_frame, 8, vec![0; 4]);
+            parameters.bytes_per_line = 1;
+            parameters.pixels_per_line = 4;
+            assembler.add_frame(parameters, data).unwrap();
+        }
+
+        match assembler.finish() {
+            Err(Error::BadNetworkDataError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    fn page(pixels_per_line: i32, lines: i32, dpi: f64) -> Option<PaperSize> {
+        let mut image = Image::new(
+            Parameters {
+                format: FrameFormat::Gray,
+                last_frame: true,
+                bytes_per_line: pixels_per_line,
+                pixels_per_line,
+                lines,
+                depth: 8,
+            },
+            Vec::new(),
+        );
+        image.set_resolution(dpi);
+        image.paper_size
+    }
+
+    fn gray(pixels_per_line: i32, depth: i32, data: Vec<u8>) -> Image {
+        let bytes_per_line = (pixels_per_line * depth + 7) / 8;
+        Image::new(
+            Parameters {
+                format: FrameFormat::Gray,
+                last_frame: true,
+                bytes_per_line,
+                pixels_per_line,
+                lines: data.len() as i32 / bytes_per_line,
+                depth,
+            },
+            data,
+        )
+    }
+
+    #[test]
+    fn orient_rotates_and_mirrors() {
+        // 1 2 3
+        // 4 5 6
+        let image = gray(3, 8, vec![1, 2, 3, 4, 5, 6]);
+        let oriented = |orientation| image.orient(orientation).unwrap();
+
+        let clockwise = oriented(Orientation::rotated(Rotation::Clockwise));
+        assert_eq!(clockwise.data, vec![4, 1, 5, 2, 6, 3]);
+        assert_eq!(
+            (
+                clockwise.parameters.pixels_per_line,
+                clockwise.parameters.lines
+            ),
+            (2, 3)
+        );
+        assert_eq!(
+            oriented(Orientation::rotated(Rotation::HalfTurn)).data,
+            vec![6, 5, 4, 3, 2, 1]
+        );
+        assert_eq!(
+            oriented(Orientation::rotated(Rotation::CounterClockwise)).data,
+            vec![3, 6, 2, 5, 1, 4]
+        );
+        assert_eq!(
+            oriented(Orientation::mirrored()).data,
+            vec![3, 2, 1, 6, 5, 4]
+        );
+        assert_eq!(oriented(Orientation::default()), image);
+    }
+
+    #[test]
+    fn orient_keeps_packed_and_wide_samples_together() {
+        let lineart = gray(3, 1, vec![0b1100_0000, 0b0010_0000]);
+        let rotated = lineart
+            .orient(Orientation::rotated(Rotation::Clockwise))
+            .unwrap();
+        assert_eq!(rotated.data, vec![0b0100_0000, 0b0100_0000, 0b1000_0000]);
+
+        let mut line = vec![1, 2, 3, 4];
+        mirror_line(&gray(2, 16, line.clone()).parameters, &mut line).unwrap();
+        assert_eq!(line, vec![3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn detects_paper_sizes() {
+        assert_eq!(page(2480, 3508, 300.0), Some(PaperSize::A4));
+        assert_eq!(page(3508, 2480, 300.0), Some(PaperSize::A4));
+        assert_eq!(page(2550, 3300, 300.0), Some(PaperSize::Letter));
+        assert_eq!(page(1275, 2100, 150.0), Some(PaperSize::Legal));
+        assert_eq!(page(630, 2000, 200.0), Some(PaperSize::Receipt80));
+        assert_eq!(page(1500, 1500, 300.0), None);
+    }
+}
@@ -1,29 +1,64 @@
-#![feature(try_trait)]
-#![feature(iterator_try_fold)]
+// Capability and info flags keep the names used by the SANE standard
+#![allow(non_upper_case_globals)]
+
 #[macro_use]
 extern crate bitflags;
 extern crate byteorder;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "png")]
+extern crate png;
+#[cfg(feature = "tiff")]
+extern crate tiff;
 
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod auth;
+pub mod conformance;
+#[cfg(feature = "discovery")]
+pub mod discovery;
 pub mod error;
+pub mod image;
+#[cfg(feature = "local")]
+pub mod local;
+pub mod options;
+pub mod output;
+pub mod scan;
+pub mod sensors;
+pub mod service;
+pub mod spool;
+#[cfg(feature = "serde")]
+mod serialize;
 pub mod status;
+#[cfg(feature = "testing")]
+pub mod testing;
 pub mod types;
 mod device;
+mod session;
+mod transport;
+mod unpack;
+mod version;
 
+use std::convert::TryFrom;
+use std::io;
 use std::io::prelude::*;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-pub use device::Device;
-use error::Error;
-use status::Status;
-use types::*;
+pub use crate::device::{Device, DeviceCache, DeviceName};
+use crate::auth::{authorize, Authenticator, Credentials};
+use crate::error::Error;
+use crate::scan::{ByteOrder, ScanData, ScanEvent};
+pub use crate::session::{BatchScan, CancelToken, DeviceHandle, ReconnectPolicy, Session, SessionBuilder};
+use crate::status::Status;
+pub use crate::transport::Transport;
+use crate::types::*;
+pub use crate::version::Version;
 
 pub type Result<T> = std::result::Result<T, error::Error>;
 
-// 1.0.3
-const SANE_VERSION: u32 = 0x01000003;
+/// The version spoken by this client.
+const SANE_VERSION: Version = Version::new(1, 0, 3);
 
 /// Trait for types that can be read from a SANE network stream.
 trait TryFromStream {
@@ -32,90 +67,182 @@ trait TryFromStream {
         Self: std::marker::Sized;
 }
 
+/// Trait for types that can be written to a SANE network stream.
+trait WriteToStream {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()>;
+}
+
 pub enum OpenResult {
     /// The device was successfully opened and a handle was returned
     Handle(i32),
 
     /// The device requires authentication, and an auth `resource`
-    /// was returned.
-    ///
-    /// TODO: Test this case; I need someone with a device that would trigger this.
+    /// was returned. The server now waits for `auth::authorize`;
+    /// use `open_device_with_auth` to handle this automatically.
     AuthRequired(String),
 }
 
-pub fn init<S: Read + Write>(stream: &mut S) {
-    info!("Initializing connection");
+pub enum StartResult {
+    /// The scan was started, and the image data can be read
+    /// from the data connection on `port`.
+    Started { port: u16, byte_order: ByteOrder },
+
+    /// The device requires authentication, and an auth `resource`
+    /// was returned. The server now waits for `auth::authorize`;
+    /// use `start_scan_with_auth` to handle this automatically.
+    AuthRequired(String),
+}
+
+/// A reply to a request that may need authorization first.
+enum Reply<T> {
+    Done(T),
+    /// The server asks for authorization to access the resource
+    /// before sending the final reply.
+    AuthRequired(String),
+}
+
+/// Read the reply to a request with `read_reply`, authorizing access
+/// with credentials from `auth` for as long as the server asks for it.
+///
+/// If `auth` declines, empty credentials are sent, so the server still
+/// completes the request (most likely with `Status::AccessDenied`)
+/// and the connection remains usable.
+fn read_authorized_reply<S, T, F>(
+    stream: &mut S,
+    auth: &mut dyn Authenticator,
+    mut read_reply: F,
+) -> Result<T>
+where
+    S: Read + Write,
+    F: FnMut(&mut S) -> Result<Reply<T>>,
+{
+    loop {
+        match read_reply(stream)? {
+            Reply::Done(reply) => return Ok(reply),
+            Reply::AuthRequired(resource) => {
+                let credentials = auth
+                    .credentials(&resource)
+                    .unwrap_or_else(|| Credentials::new("", ""));
+                authorize(stream, &resource, &credentials)?;
+            }
+        }
+    }
+}
 
-    let _ = stream.write_u32::<BigEndian>(0);
-    let _ = stream.write_u32::<BigEndian>(SANE_VERSION);
+/// Send SANE_NET_INIT, returning the protocol version of the server.
+///
+/// Fails with `Error::UnsupportedVersion` if the server speaks
+/// an incompatible major version of the protocol.
+pub fn init<S: Read + Write>(stream: &mut S) -> Result<Version> {
+    info!("Initializing connection");
 
-    // zero-length array: username
-    //let _ = stream.write_u32::<BigEndian>(0);
+    send_request(stream, |request| {
+        request.write_u32::<BigEndian>(0)?;
+        request.write_u32::<BigEndian>(SANE_VERSION.code())?;
+        local_username().write_to_stream(request)
+    })?;
 
-    write_string("Foobar", stream).ok();
+    let status = read_status(stream)?;
+    let version = Version::from_code(stream.read_u32::<BigEndian>()?);
+    check_status(status)?;
 
-    // Make sure we received Success status
-    check_success_status(stream).ok();
+    if !SANE_VERSION.is_compatible(version) {
+        return Err(Error::UnsupportedVersion(version));
+    }
 
-    let version = stream.read_u32::<BigEndian>().unwrap();
+    info!("Connection initiated, version {}", version);
+    Ok(version)
+}
 
-    println!("Connection initiated, version {:x}", version);
+/// The name of the user running the client, which saned logs for each connection.
+/// An empty name is sent when it is unknown.
+fn local_username() -> String {
+    ["USER", "LOGNAME", "USERNAME"]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .unwrap_or_default()
 }
 
 pub fn request_device_list<S: Read + Write>(stream: &mut S) -> Result<Vec<Device>> {
     info!("Requesting device list");
 
     // Send Command
-    stream.write_i32::<BigEndian>(1).ok();
-
-    // Make sure we received Success status
-    check_success_status(stream)?;
-
-    // Read the array of devices
-    <Vec<Option<Device>>>::try_from_stream(stream).map(|dev_list| {
-        dev_list.into_iter()
-            // Filter out any None elements
-            .filter(|d| d.is_some())
-            // None elements are gone, so unwrap all values
-            .map(|d| d.unwrap()).collect()
-    })
+    send_request(stream, |request| Ok(request.write_i32::<BigEndian>(1)?))?;
+
+    // The array of devices is sent even if the request failed,
+    // so read it before checking the status
+    let status = read_status(stream)?;
+    let devices = read_null_terminated(stream)?;
+    check_status(status)?;
+
+    Ok(devices)
 }
 
 pub fn open_device<S: Read + Write>(device: &Device, stream: &mut S) -> Result<OpenResult> {
-    info!("Opening device '{}'", device.name);
+    send_open_request(device, stream)?;
 
-    // Send Command
-    stream.write_i32::<BigEndian>(2).ok();
+    match read_open_reply(stream)? {
+        Reply::Done(handle) => Ok(OpenResult::Handle(handle)),
+        Reply::AuthRequired(resource) => Ok(OpenResult::AuthRequired(resource)),
+    }
+}
+
+/// Open `device` like `open_device`, returning its handle.
+///
+/// If the device requires authentication, credentials are requested from `auth`.
+pub fn open_device_with_auth<S: Read + Write>(
+    device: &Device,
+    stream: &mut S,
+    auth: &mut dyn Authenticator,
+) -> Result<i32> {
+    send_open_request(device, stream)?;
+    read_authorized_reply(stream, auth, read_open_reply)
+}
 
-    // Send name of device to open
-    write_string(&device.name, stream)?;
+fn send_open_request<S: Read + Write>(device: &Device, stream: &mut S) -> Result<()> {
+    info!("Opening device '{}'", device.name);
+
+    send_request(stream, |request| {
+        // Send Command
+        request.write_i32::<BigEndian>(2)?;
 
-    // Make sure we received Success status
-    check_success_status(stream)?;
+        // Send name of device to open
+        device.name.write_to_stream(request)
+    })
+}
 
-    let handle = stream.read_i32::<BigEndian>().unwrap();
+fn read_open_reply<S: Read + Write>(stream: &mut S) -> Result<Reply<i32>> {
+    let status = read_status(stream)?;
+    let handle = stream.read_i32::<BigEndian>()?;
     let resource = <Option<String>>::try_from_stream(stream)?;
 
-    match resource {
-        // If no resource is returned, the device was successfully opened
-        None => Ok(OpenResult::Handle(handle)),
-        // Otherwise, authentication is required
-        Some(resource) => Ok(OpenResult::AuthRequired(resource)),
+    // A resource is sent in place of the reply when authentication is required
+    if let Some(resource) = resource {
+        return Ok(Reply::AuthRequired(resource));
+    }
+
+    match status {
+        Status::Success => Ok(Reply::Done(handle)),
+        err => Err(err.into()),
     }
 }
 
-pub fn close_device<S: Read + Write>(handle: i32, stream: &mut S) {
+pub fn close_device<S: Read + Write>(handle: i32, stream: &mut S) -> Result<()> {
     info!("Closing device using handle: {}", handle);
 
-    // Send Command
-    stream.write_i32::<BigEndian>(3).ok();
+    send_request(stream, |request| {
+        // Send Command
+        request.write_i32::<BigEndian>(3)?;
 
-    // Send handle
-    stream.write_i32::<BigEndian>(handle).ok();
+        // Send handle
+        Ok(request.write_i32::<BigEndian>(handle)?)
+    })?;
 
     // Receive dummy
-    let dummy = stream.read_i32::<BigEndian>().unwrap();
+    let dummy = stream.read_i32::<BigEndian>()?;
     debug!("Received dummy value {}", dummy);
+
+    Ok(())
 }
 
 pub fn get_option_descriptors<S: Read + Write>(
@@ -124,55 +251,436 @@ pub fn get_option_descriptors<S: Read + Write>(
 ) -> Result<Vec<Option<OptionDescriptor>>> {
     info!("Requesting options for device: {}", handle);
 
-    // Send Command
-    stream.write_i32::<BigEndian>(4).ok();
+    send_request(stream, |request| {
+        // Send Command
+        request.write_i32::<BigEndian>(4)?;
 
-    // Send handle
-    stream.write_i32::<BigEndian>(handle).ok();
+        // Send handle
+        Ok(request.write_i32::<BigEndian>(handle)?)
+    })?;
 
     <_>::try_from_stream(stream)
 }
 
-fn write_string<S, I: Read + Write>(string: S, stream: &mut I) -> Result<()>
-where
-    S: AsRef<str>,
-{
-    use std::iter::repeat;
-    // Get the &str
-    let string = string.as_ref();
+/// Get or set the value of option number `index` of the device opened as `handle`,
+/// where `descriptor` describes the option.
+///
+/// Returns the value of the option after the action has been performed, along with
+/// information from the backend about its side-effects. No checks are made on the
+/// value being set; see `OptionDescriptor::check_value`.
+///
+/// If the option requires authentication, `Error::AuthRequired` is returned, and
+/// the server waits for `auth::authorize`; use `control_option_with_auth` instead
+/// to handle this automatically.
+pub fn control_option<S: Read + Write>(
+    handle: i32,
+    stream: &mut S,
+    index: i32,
+    descriptor: &OptionDescriptor,
+    action: &OptionAction,
+) -> Result<(OptionValue, OptionInfo)> {
+    send_control_option_request(handle, stream, index, descriptor, action, SANE_VERSION)?;
+
+    match read_control_option_reply(stream, descriptor)? {
+        Reply::Done(reply) => Ok(reply),
+        Reply::AuthRequired(resource) => Err(Error::AuthRequired(resource)),
+    }
+}
+
+/// Get or set the value of an option like `control_option`.
+///
+/// If the option requires authentication, credentials are requested from `auth`.
+pub fn control_option_with_auth<S: Read + Write>(
+    handle: i32,
+    stream: &mut S,
+    index: i32,
+    descriptor: &OptionDescriptor,
+    action: &OptionAction,
+    auth: &mut dyn Authenticator,
+) -> Result<(OptionValue, OptionInfo)> {
+    control_option_with(
+        handle,
+        stream,
+        index,
+        descriptor,
+        action,
+        SANE_VERSION,
+        auth,
+    )
+}
+
+/// Get or set the value of an option like `control_option_with_auth`,
+/// encoding the request for a server speaking `version`.
+pub(crate) fn control_option_with<S: Read + Write>(
+    handle: i32,
+    stream: &mut S,
+    index: i32,
+    descriptor: &OptionDescriptor,
+    action: &OptionAction,
+    version: Version,
+    auth: &mut dyn Authenticator,
+) -> Result<(OptionValue, OptionInfo)> {
+    send_control_option_request(handle, stream, index, descriptor, action, version)?;
+    read_authorized_reply(stream, auth, |stream| {
+        read_control_option_reply(stream, descriptor)
+    })
+}
+
+fn send_control_option_request<S: Read + Write>(
+    handle: i32,
+    stream: &mut S,
+    index: i32,
+    descriptor: &OptionDescriptor,
+    action: &OptionAction,
+    version: Version,
+) -> Result<()> {
+    info!(
+        "Controlling option {} of device {}: {:?}",
+        index, handle, action
+    );
+
+    send_request(stream, |request| {
+        // Send Command
+        request.write_i32::<BigEndian>(5)?;
+
+        request.write_i32::<BigEndian>(handle)?;
+        request.write_i32::<BigEndian>(index)?;
+        request.write_i32::<BigEndian>(action.code())?;
+
+        match *action {
+            OptionAction::Get => descriptor.write_empty_value(request),
+            OptionAction::Set(ref value) => descriptor.write_value(value, request),
+            // As of protocol version 3, no value is sent when setting automatically
+            OptionAction::SetAuto if version.set_auto_sends_value() => {
+                descriptor.write_empty_value(request)
+            }
+            OptionAction::SetAuto => Ok(()),
+        }
+    })
+}
+
+fn read_control_option_reply<S: Read + Write>(
+    stream: &mut S,
+    descriptor: &OptionDescriptor,
+) -> Result<Reply<(OptionValue, OptionInfo)>> {
+    // The full reply is sent even if the request failed,
+    // so read all of it before checking the status.
+    let status = read_status(stream)?;
+    let info = OptionInfo::try_from_stream(stream)?;
+    let value_type = i32::try_from_stream(stream)?;
+    let value_size = i32::try_from_stream(stream)?;
+    debug!(
+        "Received value of type {}, size {}, info {:?}",
+        value_type, value_size, info
+    );
+    // The value is only decoded once the rest of the reply has been read, as it
+    // is empty, rather than of the option's type, when authentication is required
+    let value = read_value_bytes(stream, value_type)?;
+    let resource = <Option<String>>::try_from_stream(stream)?;
+
+    if let Some(resource) = resource {
+        return Ok(Reply::AuthRequired(resource));
+    }
+
+    match status {
+        Status::Success => {}
+        err => return Err(err.into()),
+    }
+
+    let value = descriptor.read_value(&mut io::Cursor::new(value))?;
+    Ok(Reply::Done((value, info)))
+}
+
+/// Read the array holding an option value of type `value_type`,
+/// returning it as sent: its length followed by its elements.
+pub(crate) fn read_value_bytes<S: Read>(stream: &mut S, value_type: i32) -> Result<Vec<u8>> {
+    let length = stream.read_i32::<BigEndian>()?;
+
+    // Strings are sent as an array of characters, everything else as words
+    let size = if value_type == 3 {
+        check_string_length(length)?
+    } else {
+        check_array_length(length)? * 4
+    };
+
+    let mut bytes = length.to_be_bytes().to_vec();
+    bytes.extend(read_bytes(stream, size)?);
+    Ok(bytes)
+}
+
+/// Request the option descriptors for the device opened as `handle`, decoding
+/// them one at a time as the returned iterator is advanced.
+///
+/// This avoids building the full list up front for devices exporting a large
+/// number of options, and allows stopping early once the wanted option is found.
+/// Any descriptors not yet read when the iterator is dropped are still consumed
+/// from `stream`, so the connection remains usable for the next request.
+pub fn iter_option_descriptors<S: Read + Write>(
+    handle: i32,
+    stream: &mut S,
+) -> Result<OptionDescriptorIter<'_, S>> {
+    info!("Requesting options for device: {}", handle);
+
+    send_request(stream, |request| {
+        // Send Command
+        request.write_i32::<BigEndian>(4)?;
+
+        // Send handle
+        Ok(request.write_i32::<BigEndian>(handle)?)
+    })?;
+
+    let size = stream.read_i32::<BigEndian>()?;
+
+    info!("Received array of size {}", size);
+
+    Ok(OptionDescriptorIter {
+        stream,
+        remaining: size,
+        failed: false,
+    })
+}
+
+/// Iterator over the option descriptors of a device, returned by
+/// `iter_option_descriptors`.
+pub struct OptionDescriptorIter<'a, S: Read + 'a> {
+    stream: &'a mut S,
+    /// Number of array elements still waiting to be read from the stream.
+    remaining: i32,
+    /// Set once an element fails to decode, after which the position
+    /// within the stream is unknown and nothing more is read.
+    failed: bool,
+}
+
+impl<'a, S: Read> OptionDescriptorIter<'a, S> {
+    fn read_element(&mut self) -> Option<Result<Option<OptionDescriptor>>> {
+        if self.failed || self.remaining <= 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
 
-    // Make sure the length of the string fits into 32 bits
-    // Worst case, usize is < 32 bits, in which case, the length definitely fits.
-    if string.len() > i32::max_value() as usize {
+        let element = <Option<OptionDescriptor>>::try_from_stream(self.stream);
+        if element.is_err() {
+            self.failed = true;
+        }
+        Some(element)
+    }
+}
+
+impl<'a, S: Read> Iterator for OptionDescriptorIter<'a, S> {
+    type Item = Result<Option<OptionDescriptor>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_element()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.failed || self.remaining <= 0 {
+            return (0, Some(0));
+        }
+
+        let remaining = self.remaining as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a, S: Read> Drop for OptionDescriptorIter<'a, S> {
+    fn drop(&mut self) {
+        // Drain whatever is left of the reply
+        while let Some(Ok(_)) = self.next() {}
+    }
+}
+
+/// Get the parameters of the frame that is about to be acquired from the device
+/// opened as `handle`, or of the frame currently being acquired, if a scan is in progress.
+///
+/// Parameters returned before a scan is started are only a best-effort estimate;
+/// they should be requested again after calling `start`.
+pub fn get_parameters<S: Read + Write>(handle: i32, stream: &mut S) -> Result<Parameters> {
+    info!("Requesting scan parameters for device: {}", handle);
+
+    send_request(stream, |request| {
+        // Send Command
+        request.write_i32::<BigEndian>(6)?;
+
+        // Send handle
+        Ok(request.write_i32::<BigEndian>(handle)?)
+    })?;
+
+    // The parameters are sent even if the request failed,
+    // so read them before checking the status
+    let status = read_status(stream)?;
+    let parameters = Parameters::try_from_stream(stream)?;
+    check_status(status)?;
+    debug!("{:?}", parameters);
+
+    Ok(parameters)
+}
+
+/// Start acquiring an image from the device opened as `handle`.
+///
+/// On success, the image data is sent by the server on a separate
+/// data connection, which must be opened to the returned port.
+/// See `start_scan` for a version that opens that connection.
+pub fn start<S: Read + Write>(handle: i32, stream: &mut S) -> Result<StartResult> {
+    send_start_request(handle, stream)?;
+
+    match read_start_reply(stream)? {
+        Reply::Done((port, byte_order)) => Ok(StartResult::Started { port, byte_order }),
+        Reply::AuthRequired(resource) => Ok(StartResult::AuthRequired(resource)),
+    }
+}
+
+fn send_start_request<S: Read + Write>(handle: i32, stream: &mut S) -> Result<()> {
+    info!("Starting scan on device: {}", handle);
+
+    send_request(stream, |request| {
+        // Send Command
+        request.write_i32::<BigEndian>(7)?;
+
+        // Send handle
+        Ok(request.write_i32::<BigEndian>(handle)?)
+    })
+}
+
+fn read_start_reply<S: Read + Write>(stream: &mut S) -> Result<Reply<(u16, ByteOrder)>> {
+    // The whole reply is sent even when the scan couldn't be started,
+    // so read it before checking the status to keep the connection in sync
+    let status = read_status(stream)?;
+    let port = stream.read_i32::<BigEndian>()?;
+    let byte_order = stream.read_i32::<BigEndian>()?;
+    let resource = <Option<String>>::try_from_stream(stream)?;
+
+    if let Some(resource) = resource {
+        return Ok(Reply::AuthRequired(resource));
+    }
+
+    if status != Status::Success {
+        return Err(status.into());
+    }
+
+    if port <= 0 || port > i32::from(u16::MAX) {
         return Err(Error::BadNetworkDataError(format!(
-            "String length of {} exceeds maximum possible length of {}!",
-            string.len(),
-            i32::max_value()
+            "Received invalid data port {}",
+            port
         )));
     }
 
-    let length = string.len() as i32;
+    let byte_order = ByteOrder::from_word(byte_order)?;
+    Ok(Reply::Done((port as u16, byte_order)))
+}
 
-    // Double check that we didn't cut the string short
-    assert!(string.len() == length as usize);
+/// Start acquiring an image from the device opened as `handle`,
+/// and open the data connection the image will be sent over
+/// through `Transport::open_data_connection`.
+///
+/// Once all frames have been read, `cancel` must still be called
+/// to complete the scan, as required by the SANE standard.
+pub fn start_scan<T: Transport>(
+    handle: i32,
+    stream: &mut T,
+) -> Result<ScanData<T::DataConnection>> {
+    match start(handle, stream)? {
+        StartResult::Started { port, byte_order } => {
+            let connection = stream.open_data_connection(port)?;
+            Ok(ScanData::new(connection, byte_order))
+        }
+        StartResult::AuthRequired(resource) => Err(Error::AuthRequired(resource)),
+    }
+}
+
+/// Start acquiring an image like `start_scan`.
+///
+/// If the device requires authentication, credentials are requested from `auth`.
+pub fn start_scan_with_auth<T: Transport>(
+    handle: i32,
+    stream: &mut T,
+    auth: &mut dyn Authenticator,
+) -> Result<ScanData<T::DataConnection>> {
+    send_start_request(handle, stream)?;
+    let (port, byte_order) = read_authorized_reply(stream, auth, read_start_reply)?;
+
+    let connection = stream.open_data_connection(port)?;
+    Ok(ScanData::new(connection, byte_order))
+}
 
-    let length = length + 1;
+/// Cancel the current operation on the device opened as `handle`.
+///
+/// This must also be called once all frames of a scan have been read,
+/// to complete the scan as required by the SANE standard.
+pub fn cancel<S: Read + Write>(handle: i32, stream: &mut S) -> Result<()> {
+    info!("Cancelling operation on device: {}", handle);
 
-    stream.write_i32::<BigEndian>(length).ok();
-    stream.write_all(string.as_bytes()).ok();
-    stream.write(&vec![0x00u8]);
+    send_request(stream, |request| {
+        // Send Command
+        request.write_i32::<BigEndian>(8)?;
+
+        // Send handle
+        Ok(request.write_i32::<BigEndian>(handle)?)
+    })?;
+
+    // Receive dummy
+    let dummy = stream.read_i32::<BigEndian>()?;
+    debug!("Received dummy value {}", dummy);
 
     Ok(())
 }
 
+/// Cancel the scan on the device opened as `handle`, and close the
+/// data connection its image data was being read from through `data`.
+///
+/// The data connection is closed even if the cancel request fails.
+/// If not all data had been read, `ScanEvent::Cancelled` is reported.
+pub fn cancel_scan<S, R>(handle: i32, stream: &mut S, mut data: ScanData<R>) -> Result<()>
+where
+    S: Read + Write,
+    R: Read,
+{
+    if !data.is_finished() {
+        data.report(ScanEvent::Cancelled);
+    }
+    let result = cancel(handle, stream);
+
+    // Dropping the reader closes the data connection; anything saned
+    // sent on it after the last record we read is discarded.
+    drop(data.into_inner());
+
+    result
+}
+
+/// Tell the server that the client is done, ending the connection.
+///
+/// Any devices still open should be closed first.
+pub fn exit<S: Read + Write>(stream: &mut S) -> Result<()> {
+    info!("Exiting");
+
+    // Send Command; the server doesn't reply
+    send_request(stream, |request| Ok(request.write_i32::<BigEndian>(10)?))
+}
+
+/// Serialize a request with `write`, and send it to the server in a single write.
+///
+/// Writing each field straight to the connection would send many tiny packets
+/// per request, each of which may be delayed when Nagle's algorithm is enabled.
+pub(crate) fn send_request<S, F>(stream: &mut S, write: F) -> Result<()>
+where
+    S: Write,
+    F: FnOnce(&mut Vec<u8>) -> Result<()>,
+{
+    let mut request = Vec::new();
+    write(&mut request)?;
+
+    stream.write_all(&request)?;
+    Ok(stream.flush()?)
+}
+
 fn read_status<S: Read>(stream: &mut S) -> Result<Status> {
-    Ok(Status::from(stream.read_i32::<BigEndian>()?))
+    Status::try_from(stream.read_i32::<BigEndian>()?)
 }
 
-/// Read response status from `stream` and return Err if the status is
-/// any value other than `Status::Success`.
-fn check_success_status<S: Read + Write>(stream: &mut S) -> Result<()> {
-    match read_status(stream)? {
+/// Return Err if `status` is any value other than `Status::Success`.
+fn check_status(status: Status) -> Result<()> {
+    match status {
         Status::Success => Ok(()),
         err => Err(err.into()),
     }
@@ -180,8 +688,1023 @@ fn check_success_status<S: Read + Write>(stream: &mut S) -> Result<()> {
 
 #[cfg(test)]
 mod tests {
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use byteorder::{BigEndian, WriteBytesExt};
+
+    use super::*;
+    use crate::image::{Orientation, Rotation};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    /// A duplex stream that replays canned server responses from `input`
+    /// and records everything written by the client in `output`.
+    /// Data connections replay `data`, and record the port they were opened on.
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        /// Number of writes made to `output`.
+        writes: usize,
+        data: Vec<u8>,
+        data_ports: Vec<u16>,
+    }
+
+    impl MockStream {
+        fn new(input: Vec<u8>) -> MockStream {
+            MockStream {
+                input: Cursor::new(input),
+                output: Vec::new(),
+                writes: 0,
+                data: Vec::new(),
+                data_ports: Vec::new(),
+            }
+        }
+
+        /// Returns true if every byte of the canned response was consumed.
+        fn is_exhausted(&self) -> bool {
+            self.input.position() as usize == self.input.get_ref().len()
+        }
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for MockStream {
+        type DataConnection = Cursor<Vec<u8>>;
+
+        fn open_data_connection(&mut self, port: u16) -> Result<Cursor<Vec<u8>>> {
+            self.data_ports.push(port);
+            Ok(Cursor::new(self.data.clone()))
+        }
+    }
+
+    pub(crate) fn put_word(buf: &mut Vec<u8>, word: i32) {
+        buf.write_i32::<BigEndian>(word).unwrap();
+    }
+
+    pub(crate) fn put_string(buf: &mut Vec<u8>, string: Option<&str>) {
+        match string {
+            None => put_word(buf, 0),
+            Some(string) => {
+                put_word(buf, string.len() as i32 + 1);
+                buf.extend_from_slice(string.as_bytes());
+                buf.push(0x00);
+            }
+        }
+    }
+
+    pub(crate) fn device_list_reply(names: &[&str]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_word(&mut buf, 0); // status: success
+        put_word(&mut buf, names.len() as i32 + 1);
+        for name in names {
+            put_word(&mut buf, 0); // pointer is not null
+            put_string(&mut buf, Some(name));
+            put_string(&mut buf, Some("Vendor"));
+            put_string(&mut buf, Some("Model"));
+            put_string(&mut buf, Some("flatbed scanner"));
+        }
+        // Device lists end with a null element
+        put_word(&mut buf, 1);
+        buf
+    }
+
+    /// Encode a (non-null) group option descriptor.
+    fn put_group_descriptor(buf: &mut Vec<u8>, title: &str) {
+        put_word(buf, 0); // pointer is not null
+        put_string(buf, None); // name
+        put_string(buf, Some(title)); // title
+        put_string(buf, None); // description
+        put_word(buf, 5); // type: group
+        put_word(buf, 0); // unit: none
+        put_word(buf, 0); // size
+        put_word(buf, 0); // capabilities
+        put_word(buf, 0); // constraint: none
+    }
+
+    /// Encode a (non-null) integer option descriptor with no constraint.
+    fn put_integer_descriptor(buf: &mut Vec<u8>, name: &str, unit: i32) {
+        put_word(buf, 0); // pointer is not null
+        put_string(buf, Some(name)); // name
+        put_string(buf, Some(name)); // title
+        put_string(buf, Some(name)); // description
+        put_word(buf, 1); // type: integer
+        put_word(buf, unit);
+        put_word(buf, 4); // size
+        put_word(buf, 0); // capabilities
+        put_word(buf, 0); // constraint: none
+    }
+
+    /// The device opened by the tests, as described by the SANE test backend.
+    pub(crate) fn test_device() -> Device {
+        Device {
+            name: "test:0".into(),
+            vendor: "Noname".into(),
+            model: "frontend-tester".into(),
+            kind: "virtual device".into(),
+        }
+    }
+
+    fn put_init_reply(buf: &mut Vec<u8>, version: Version) {
+        put_word(buf, 0); // status
+        put_word(buf, version.code() as i32);
+    }
+
+    fn put_open_reply(buf: &mut Vec<u8>, handle: i32) {
+        put_word(buf, 0); // status
+        put_word(buf, handle);
+        put_string(buf, None); // resource
+    }
+
+    /// Encode the replies to initializing a session with this client's version,
+    /// and to opening a device as `handle`.
+    fn write_init_and_open(buf: &mut Vec<u8>, handle: i32) {
+        put_init_reply(buf, SANE_VERSION);
+        put_open_reply(buf, handle);
+    }
+
+    pub(crate) fn start_reply(port: i32, byte_order: i32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_word(&mut buf, 0); // status: success
+        put_word(&mut buf, port);
+        put_word(&mut buf, byte_order);
+        put_string(&mut buf, None); // resource
+        buf
+    }
+
+    pub(crate) fn control_option_reply(status: i32, info: i32, value: i32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_word(&mut buf, status);
+        put_word(&mut buf, info);
+        put_word(&mut buf, 1); // value type: integer
+        put_word(&mut buf, 4); // value size
+        put_word(&mut buf, 1); // array length
+        put_word(&mut buf, value);
+        put_string(&mut buf, None); // resource
+        buf
+    }
+
+    /// Encode a successful reply to SANE_NET_GET_PARAMETERS, describing a single
+    /// frame of 8-bit gray pixels.
+    fn parameters_reply(pixels_per_line: i32, lines: i32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_word(&mut buf, 0); // status: success
+        put_word(&mut buf, 0); // format: gray
+        put_word(&mut buf, 1); // last frame
+        put_word(&mut buf, pixels_per_line); // bytes per line
+        put_word(&mut buf, pixels_per_line);
+        put_word(&mut buf, lines);
+        put_word(&mut buf, 8); // depth
+        buf
+    }
+
+    /// Encode the data connection of a frame sent as `records`,
+    /// ending with `Status::EndOfFile`.
+    fn scan_data(records: &[&[u8]]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for record in records {
+            put_word(&mut buf, record.len() as i32);
+            buf.extend_from_slice(record);
+        }
+        buf.extend_from_slice(&[0xff; 4]); // end of frame
+        buf.push(5); // status: end of file
+        buf
+    }
+
+    /// An integer resolution option, which can be set or set automatically.
+    fn resolution_option() -> OptionDescriptor {
+        OptionDescriptor::Integer {
+            name: "resolution".into(),
+            title: "Scan resolution".into(),
+            description: "Sets the resolution of the scanned image.".into(),
+            unit: OptionUnit::DPI,
+            size: 4,
+            capabilities: Capabilities::SoftSelect
+                | Capabilities::SoftDetect
+                | Capabilities::Automatic,
+            constraint: None,
+        }
+    }
+
+    /// Unlike device lists, the descriptor array has no trailing null element.
+    fn descriptor_array_reply(titles: &[&str]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_word(&mut buf, titles.len() as i32);
+        for title in titles {
+            put_group_descriptor(&mut buf, title);
+        }
+        buf
+    }
+
+    #[test]
+    fn iter_option_descriptors_yields_each_descriptor() {
+        let mut stream = MockStream::new(descriptor_array_reply(&["Standard", "Geometry"]));
+
+        let titles: Vec<String> = iter_option_descriptors(7, &mut stream)
+            .unwrap()
+            .map(|descriptor| match descriptor.unwrap() {
+                Some(OptionDescriptor::Group { title, .. }) => title,
+                other => panic!("Unexpected descriptor {:?}", other),
+            })
+            .collect();
+
+        assert_eq!(titles, vec!["Standard", "Geometry"]);
+        assert!(stream.is_exhausted());
+
+        let mut request = Vec::new();
+        put_word(&mut request, 4);
+        put_word(&mut request, 7);
+        assert_eq!(stream.output, request);
+    }
+
+    #[test]
+    fn get_option_descriptors_returns_every_descriptor() {
+        let mut stream = MockStream::new(descriptor_array_reply(&["Standard", "Geometry"]));
+
+        let descriptors = get_option_descriptors(7, &mut stream).unwrap();
+        assert_eq!(descriptors.len(), 2);
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn iter_option_descriptors_drains_reply_when_dropped_early() {
+        let mut stream = MockStream::new(descriptor_array_reply(&[
+            "Standard",
+            "Geometry",
+            "Enhancement",
+        ]));
+
+        {
+            let mut descriptors = iter_option_descriptors(7, &mut stream).unwrap();
+            assert_eq!(descriptors.size_hint(), (3, Some(3)));
+            assert!(descriptors.next().unwrap().unwrap().is_some());
+        }
+
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn failed_device_list_reply_is_read_completely() {
+        let mut reply = Vec::new();
+        put_word(&mut reply, 10); // status: out of memory
+        put_word(&mut reply, 1); // array of one null device
+        put_word(&mut reply, 1);
+        reply.extend(device_list_reply(&["test:0"]));
+        let mut stream = MockStream::new(reply);
+
+        match request_device_list(&mut stream) {
+            Err(Error::SanedError(Status::OutOfMemory)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(request_device_list(&mut stream).unwrap().len(), 1);
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn init_returns_server_version() {
+        let mut reply = Vec::new();
+        put_init_reply(&mut reply, Version::new(1, 0, 3));
+        let mut stream = MockStream::new(reply);
+
+        assert_eq!(init(&mut stream).unwrap(), Version::new(1, 0, 3));
+        assert!(stream.is_exhausted());
+
+        let mut request = Vec::new();
+        put_word(&mut request, 0);
+        put_word(&mut request, SANE_VERSION.code() as i32);
+        put_string(&mut request, Some(&local_username()));
+        assert_eq!(stream.output, request);
+
+        match init(&mut stream) {
+            Err(Error::IOError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn init_rejects_incompatible_version() {
+        let mut reply = Vec::new();
+        put_init_reply(&mut reply, Version::new(2, 0, 0));
+        let mut stream = MockStream::new(reply);
+
+        match init(&mut stream) {
+            Err(Error::UnsupportedVersion(version)) => assert_eq!(version.major(), 2),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn set_auto_sends_empty_value_to_older_servers() {
+        let mut reply = Vec::new();
+        put_init_reply(&mut reply, Version::new(1, 0, 2));
+        put_open_reply(&mut reply, 3);
+        reply.extend(control_option_reply(0, 0, 300));
+        put_word(&mut reply, 0); // close: dummy
+        let mut stream = MockStream::new(reply);
+
+        let device = test_device();
+        let resolution = resolution_option();
+
+        {
+            let session = Session::new(&mut stream).unwrap();
+            assert_eq!(session.version(), Version::new(1, 0, 2));
+
+            let handle = session.open(&device).unwrap();
+            handle
+                .control_option(2, &resolution, &OptionAction::SetAuto)
+                .unwrap();
+        }
+        assert!(stream.is_exhausted());
+
+        // set auto(3, resolution) with an empty integer value, close(3), exit
+        let mut expected_tail = Vec::new();
+        for word in &[5, 3, 2, 2, 1, 4, 1, 0, 3, 3, 10] {
+            put_word(&mut expected_tail, *word);
+        }
+        assert!(stream.output.ends_with(&expected_tail));
+    }
+
+    #[test]
+    fn device_cache_reuses_fresh_list() {
+        use std::time::Duration;
+
+        let mut reply = device_list_reply(&["net:host:pixma:1"]);
+        reply.extend(device_list_reply(&[
+            "net:host:pixma:1",
+            "net:host:genesys:2",
+        ]));
+        let mut stream = MockStream::new(reply);
+
+        let mut cache = DeviceCache::new(Duration::from_secs(60));
+        assert_eq!(cache.devices(&mut stream).unwrap().len(), 1);
+        assert_eq!(cache.devices(&mut stream).unwrap().len(), 1);
+        assert!(!stream.is_exhausted());
+
+        cache.invalidate();
+        assert_eq!(cache.devices(&mut stream).unwrap().len(), 2);
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn unknown_unit_is_only_accepted_in_lenient_mode() {
+        let mut reply = Vec::new();
+        put_integer_descriptor(&mut reply, "resolution", 42);
+
+        let strict = <Option<OptionDescriptor>>::try_from_stream(&mut Cursor::new(reply.clone()));
+        match strict {
+            Err(Error::InvalidSaneFieldValue(_, 42)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        let options = DecodeOptions {
+            lenient: true,
+            ..DecodeOptions::default()
+        };
+        let lenient =
+            options.scope(|| <Option<OptionDescriptor>>::try_from_stream(&mut Cursor::new(reply)));
+        match lenient {
+            Ok(Some(OptionDescriptor::Integer {
+                unit: OptionUnit::Unknown(42),
+                ..
+            })) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bogus_lengths_are_rejected() {
+        let mut reply = Vec::new();
+        put_word(&mut reply, -1);
+        match <Vec<Option<Device>>>::try_from_stream(&mut Cursor::new(reply)) {
+            Err(Error::BadNetworkDataError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        // Rejected before trying to read, or allocate, 2 GiB
+        let mut reply = Vec::new();
+        put_word(&mut reply, i32::MAX);
+        match <Option<String>>::try_from_stream(&mut Cursor::new(reply.clone())) {
+            Err(Error::BadNetworkDataError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match <Vec<i32>>::try_from_stream(&mut Cursor::new(reply)) {
+            Err(Error::BadNetworkDataError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        // Limits are set per decoder
+        let mut reply = Vec::new();
+        put_string(&mut reply, Some("resolution"));
+        let options = DecodeOptions {
+            max_string_length: 4,
+            ..DecodeOptions::default()
+        };
+        match options.scope(|| <Option<String>>::try_from_stream(&mut Cursor::new(reply.clone()))) {
+            Err(Error::BadNetworkDataError(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(
+            <Option<String>>::try_from_stream(&mut Cursor::new(reply)).unwrap(),
+            Some("resolution".into())
+        );
+
+        // A string longer than the data sent ends the stream early
+        let mut reply = Vec::new();
+        put_word(&mut reply, 100);
+        reply.extend_from_slice(b"short\0");
+        match <Option<String>>::try_from_stream(&mut Cursor::new(reply)) {
+            Err(Error::IOError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn start_returns_data_port_and_byte_order() {
+        let mut stream = MockStream::new(start_reply(40123, 0x4321));
+
+        match start(3, &mut stream).unwrap() {
+            StartResult::Started { port, byte_order } => {
+                assert_eq!(port, 40123);
+                assert_eq!(byte_order, ByteOrder::BigEndian);
+            }
+            StartResult::AuthRequired(resource) => panic!("Unexpected auth resource {}", resource),
+        }
+
+        let mut request = Vec::new();
+        put_word(&mut request, 7);
+        put_word(&mut request, 3);
+        assert_eq!(stream.output, request);
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn open_device_with_auth_authorizes_and_reads_reply_again() {
+        let mut reply = Vec::new();
+        put_word(&mut reply, 0); // status
+        put_word(&mut reply, 0); // handle
+        put_string(&mut reply, Some("test:0"));
+        put_word(&mut reply, 0); // authorization acknowledged
+        put_word(&mut reply, 0); // status
+        put_word(&mut reply, 7); // handle
+        put_string(&mut reply, None);
+        let mut stream = MockStream::new(reply);
+
+        let device = test_device();
+        let mut resources = Vec::new();
+        let mut auth = |resource: &str| {
+            resources.push(resource.to_string());
+            Some(Credentials::new("user", "secret"))
+        };
+
+        let handle = open_device_with_auth(&device, &mut stream, &mut auth).unwrap();
+        assert_eq!(handle, 7);
+        assert_eq!(resources, vec!["test:0"]);
+
+        let mut request = Vec::new();
+        put_word(&mut request, 2);
+        put_string(&mut request, Some("test:0"));
+        put_word(&mut request, 9);
+        put_string(&mut request, Some("test:0"));
+        put_string(&mut request, Some("user"));
+        put_string(&mut request, Some("secret"));
+        assert_eq!(stream.output, request);
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn start_scan_opens_data_connection_through_transport() {
+        let mut stream = MockStream::new(start_reply(40123, 0x1234));
+        stream.data = scan_data(&[&[1, 2, 3, 4]]);
+
+        let mut data = start_scan(3, &mut stream).unwrap();
+        assert_eq!(data.byte_order(), ByteOrder::LittleEndian);
+        assert_eq!(data.read_all().unwrap(), vec![1, 2, 3, 4]);
+        assert_eq!(stream.data_ports, vec![40123]);
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn get_parameters_reads_reply() {
+        let mut reply = Vec::new();
+        put_word(&mut reply, 0); // status: success
+        put_word(&mut reply, 1); // format: RGB
+        put_word(&mut reply, 1); // last frame
+        put_word(&mut reply, 7650); // bytes per line
+        put_word(&mut reply, 2550); // pixels per line
+        put_word(&mut reply, 3508); // lines
+        put_word(&mut reply, 8); // depth
+        let mut stream = MockStream::new(reply);
+
+        let parameters = get_parameters(3, &mut stream).unwrap();
+        assert_eq!(
+            parameters,
+            Parameters {
+                format: FrameFormat::RGB,
+                last_frame: true,
+                bytes_per_line: 7650,
+                pixels_per_line: 2550,
+                lines: 3508,
+                depth: 8,
+            }
+        );
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn cancel_scan_leaves_control_connection_usable() {
+        let mut reply = Vec::new();
+        put_word(&mut reply, 0); // cancel: dummy
+        put_word(&mut reply, 0); // cancel: dummy
+        let mut stream = MockStream::new(reply);
+
+        let data = ScanData::new(Cursor::new(vec![0u8; 16]), ByteOrder::LittleEndian);
+        cancel_scan(3, &mut stream, data).unwrap();
+        cancel(3, &mut stream).unwrap();
+
+        let mut request = Vec::new();
+        put_word(&mut request, 8);
+        put_word(&mut request, 3);
+        put_word(&mut request, 8);
+        put_word(&mut request, 3);
+        assert_eq!(stream.output, request);
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn session_closes_device_handles_and_exits_on_drop() {
+        let mut reply = Vec::new();
+        write_init_and_open(&mut reply, 1);
+        put_open_reply(&mut reply, 2);
+        put_word(&mut reply, 0); // close: dummy
+        put_word(&mut reply, 0); // close: dummy
+        let mut stream = MockStream::new(reply);
+
+        let device = test_device();
+
+        {
+            let session = Session::new(&mut stream).unwrap();
+            let first = session.open(&device).unwrap();
+            let second = session.open(&device).unwrap();
+            assert_eq!((first.id(), second.id()), (1, 2));
+
+            first.close().unwrap();
+        }
+
+        assert!(stream.is_exhausted());
+
+        // close(1), then close(2) and exit on drop
+        let mut expected_tail = Vec::new();
+        put_word(&mut expected_tail, 3);
+        put_word(&mut expected_tail, 1);
+        put_word(&mut expected_tail, 3);
+        put_word(&mut expected_tail, 2);
+        put_word(&mut expected_tail, 10);
+        assert!(stream.output.ends_with(&expected_tail));
+    }
+
+    /// A control connection whose data connection signals `reading` when first
+    /// read, then waits for `resume` before reporting the scan as cancelled.
+    struct GatedStream {
+        control: MockStream,
+        gate: Option<(mpsc::Sender<()>, mpsc::Receiver<()>)>,
+    }
+
+    struct GatedData {
+        reading: mpsc::Sender<()>,
+        resume: mpsc::Receiver<()>,
+        data: Cursor<Vec<u8>>,
+    }
+
+    impl Read for GatedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.control.read(buf)
+        }
+    }
+
+    impl Write for GatedStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.control.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for GatedStream {
+        type DataConnection = GatedData;
+
+        fn open_data_connection(&mut self, _: u16) -> Result<GatedData> {
+            let (reading, resume) = self.gate.take().expect("Only one scan is started");
+            let mut data = Vec::new();
+            data.extend_from_slice(&[0xff; 4]); // end of frame
+            data.push(2); // status: cancelled
+            Ok(GatedData {
+                reading,
+                resume,
+                data: Cursor::new(data),
+            })
+        }
+    }
+
+    impl Read for GatedData {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.position() == 0 {
+                self.reading.send(()).unwrap();
+                self.resume.recv().unwrap();
+            }
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn scan_image_can_be_cancelled_while_reading_data() {
+        let mut reply = Vec::new();
+        write_init_and_open(&mut reply, 1);
+        put_word(&mut reply, 0); // option descriptors: none, so no resolution
+        reply.extend(start_reply(40123, 0x1234));
+        reply.extend(parameters_reply(2, 2));
+        put_word(&mut reply, 0); // cancel from the token: dummy
+        put_word(&mut reply, 0); // cancel completing the scan: dummy
+        put_word(&mut reply, 0); // close: dummy
+
+        let (reading, started) = mpsc::channel();
+        let (resume, resumed) = mpsc::channel();
+        let stream = GatedStream {
+            control: MockStream::new(reply),
+            gate: Some((reading, resumed)),
+        };
+
+        let device = test_device();
+        let session = Session::new(stream).unwrap();
+        let handle = session.open(&device).unwrap();
+        let token = handle.cancel_token();
+
+        std::thread::scope(|scope| {
+            let scan = scope.spawn(|| handle.scan_image());
+            started.recv().unwrap();
+
+            // The scan doesn't hold the connection while its data is read
+            let (cancelled, done) = mpsc::channel();
+            scope.spawn(move || cancelled.send(token.cancel()).unwrap());
+            let result = done.recv_timeout(Duration::from_secs(5));
+            resume.send(()).unwrap();
+            result.expect("Cancelling waited for the scan").unwrap();
+
+            match scan.join().unwrap() {
+                Err(Error::SanedError(Status::Canceled)) => {}
+                other => panic!("Unexpected result {:?}", other.map(|_| ())),
+            }
+        });
+
+        handle.close().unwrap();
+        let stream = session.stream();
+        assert!(stream.control.is_exhausted());
+    }
+
+    #[test]
+    fn cancel_token_cancels_from_another_thread() {
+        fn assert_sync<T: Sync>(_: &T) {}
+
+        let mut reply = Vec::new();
+        write_init_and_open(&mut reply, 4);
+        put_word(&mut reply, 0); // cancel: dummy
+        put_word(&mut reply, 0); // close: dummy
+        let mut stream = MockStream::new(reply);
+
+        let device = test_device();
+
+        {
+            let session = Session::new(&mut stream).unwrap();
+            assert_sync(&session);
+
+            let handle = session.open(&device).unwrap();
+            let token = handle.cancel_token();
+            let other = token.clone();
+            std::thread::scope(|scope| scope.spawn(move || other.cancel()).join())
+                .unwrap()
+                .unwrap();
+
+            handle.close().unwrap();
+            match token.cancel() {
+                Err(Error::DeviceClosed(ref name)) if name == "test:0" => {}
+                other => panic!("Unexpected result {:?}", other),
+            }
+        }
+
+        assert!(stream.is_exhausted());
+
+        // cancel(4), close(4), exit
+        let mut expected_tail = Vec::new();
+        put_word(&mut expected_tail, 8);
+        put_word(&mut expected_tail, 4);
+        put_word(&mut expected_tail, 3);
+        put_word(&mut expected_tail, 4);
+        put_word(&mut expected_tail, 10);
+        assert!(stream.output.ends_with(&expected_tail));
+    }
+
+    #[test]
+    fn batch_scan_stops_when_feeder_is_empty() {
+        let mut reply = Vec::new();
+        write_init_and_open(&mut reply, 1);
+        put_word(&mut reply, 0); // option descriptors: read once for every page
+        for _ in 0..2 {
+            reply.extend(start_reply(40123, 0x1234));
+            reply.extend(parameters_reply(2, 2));
+        }
+        // The feeder is empty; the full reply is still sent
+        put_word(&mut reply, 7); // start: status no documents
+        put_word(&mut reply, 0); // port
+        put_word(&mut reply, 0); // byte order
+        put_string(&mut reply, None); // resource
+        put_word(&mut reply, 0); // cancel: dummy
+        put_word(&mut reply, 0); // close: dummy
+
+        let mut stream = MockStream::new(reply);
+        stream.data = scan_data(&[&[1, 2, 3, 4]]);
+
+        let device = test_device();
+
+        {
+            let session = Session::new(&mut stream).unwrap();
+            let handle = session.open(&device).unwrap();
+            let pages = handle
+                .scan_all()
+                .orient_back_sides(Orientation::rotated(Rotation::HalfTurn))
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(pages.len(), 2);
+            assert_eq!(pages[0].data, vec![1, 2, 3, 4]);
+            assert_eq!(pages[1].data, vec![4, 3, 2, 1]);
+            handle.close().unwrap();
+        }
+
+        assert!(stream.is_exhausted());
+        assert_eq!(stream.data_ports, vec![40123, 40123]);
+    }
+
+    #[test]
+    fn batch_scan_stops_at_page_limit() {
+        let mut reply = Vec::new();
+        write_init_and_open(&mut reply, 1);
+        put_word(&mut reply, 0); // option descriptors: none, so no resolution
+        reply.extend(start_reply(40123, 0x1234));
+        reply.extend(parameters_reply(2, 2));
+        put_word(&mut reply, 0); // cancel: dummy
+        put_word(&mut reply, 0); // close: dummy
+
+        let mut stream = MockStream::new(reply);
+        stream.data = scan_data(&[&[1, 2, 3, 4]]);
+
+        let device = test_device();
+
+        {
+            let session = Session::new(&mut stream).unwrap();
+            let handle = session.open(&device).unwrap();
+            let mut pages = handle.scan_all().max_pages(1);
+            assert!(pages.next().unwrap().is_ok());
+            assert!(pages.next().is_none());
+        }
+
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn scan_image_with_stats_counts_data_received() {
+        let mut reply = Vec::new();
+        write_init_and_open(&mut reply, 1);
+        put_word(&mut reply, 1); // option descriptors
+        put_integer_descriptor(&mut reply, "resolution", 4);
+        reply.extend(control_option_reply(0, 0, 300));
+        reply.extend(start_reply(40123, 0x1234));
+        reply.extend(parameters_reply(2, 2));
+        put_word(&mut reply, 0); // cancel: dummy
+        put_word(&mut reply, 0); // close: dummy
+
+        let mut stream = MockStream::new(reply);
+        stream.data = scan_data(&[&[1, 2, 3], &[4]]);
+
+        let device = test_device();
+        {
+            let session = Session::new(&mut stream).unwrap();
+            let handle = session.open(&device).unwrap();
+            let (image, stats) = handle.scan_image_with_stats().unwrap();
+            assert_eq!(image.data, vec![1, 2, 3, 4]);
+            assert_eq!(image.resolution, Some(300.0));
+            assert_eq!((stats.bytes, stats.records, stats.frames), (4, 2, 1));
+            assert_eq!(stats.retries, 0);
+        }
+
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn scan_image_with_progress_reports_to_borrowing_callback() {
+        let mut reply = Vec::new();
+        write_init_and_open(&mut reply, 1);
+        put_word(&mut reply, 0); // option descriptors: none, so no resolution
+        reply.extend(start_reply(40123, 0x1234));
+        reply.extend(parameters_reply(2, 2));
+        put_word(&mut reply, 0); // cancel: dummy
+        put_word(&mut reply, 0); // close: dummy
+
+        let mut stream = MockStream::new(reply);
+        stream.data = scan_data(&[&[1, 2, 3], &[4]]);
+
+        let device = test_device();
+        let mut events = Vec::new();
+        {
+            let session = Session::new(&mut stream).unwrap();
+            let handle = session.open(&device).unwrap();
+            let image = handle
+                .scan_image_with_progress(|event| events.push(event.clone()))
+                .unwrap();
+            assert_eq!(image.data, vec![1, 2, 3, 4]);
+        }
+
+        assert!(matches!(events[0], ScanEvent::ScanStarted { .. }));
+        assert_eq!(
+            events[1..],
+            [
+                ScanEvent::DataRead {
+                    bytes: 3,
+                    total: Some(4)
+                },
+                ScanEvent::DataRead {
+                    bytes: 4,
+                    total: Some(4)
+                },
+                ScanEvent::PageDone,
+            ]
+        );
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn session_reconnects_and_restores_open_devices() {
+        fn open_reply(buf: &mut Vec<u8>, handle: i32) {
+            write_init_and_open(buf, handle);
+            buf.extend(control_option_reply(0, 0, 300)); // set resolution
+        }
+
+        // The first connection is lost before the reply to cancel arrives
+        let mut first = Vec::new();
+        open_reply(&mut first, 1);
+        let mut second = Vec::new();
+        open_reply(&mut second, 7);
+        put_word(&mut second, 0); // cancel: dummy
+        put_word(&mut second, 0); // close: dummy
+
+        let mut session = Session::new(MockStream::new(first)).unwrap();
+        let mut next = Some(second);
+        session.set_reconnect(
+            ReconnectPolicy {
+                max_attempts: 1,
+                delay: Duration::from_millis(0),
+                ..ReconnectPolicy::default()
+            },
+            move || {
+                next.take()
+                    .map(MockStream::new)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused).into())
+            },
+        );
+
+        let device = test_device();
+        let resolution = resolution_option();
+
+        let handle = session.open(&device).unwrap();
+        handle
+            .control_option(2, &resolution, &OptionAction::Set(300.into()))
+            .unwrap();
+
+        handle.cancel().unwrap();
+        assert_eq!(handle.id(), 7);
+        handle.close().unwrap();
+
+        let stream = session.stream();
+        assert!(stream.is_exhausted());
+
+        // set(7, resolution), cancel(7), close(7)
+        let mut expected_tail = Vec::new();
+        put_word(&mut expected_tail, 5);
+        put_word(&mut expected_tail, 7);
+        put_word(&mut expected_tail, 2);
+        put_word(&mut expected_tail, 1);
+        put_word(&mut expected_tail, 1); // value type: integer
+        put_word(&mut expected_tail, 4); // value size
+        put_word(&mut expected_tail, 1); // array length
+        put_word(&mut expected_tail, 300);
+        put_word(&mut expected_tail, 8);
+        put_word(&mut expected_tail, 7);
+        put_word(&mut expected_tail, 3);
+        put_word(&mut expected_tail, 7);
+        assert!(stream.output.ends_with(&expected_tail));
+    }
+
+    #[test]
+    fn session_builder_applies_read_timeout() {
+        // Connections are accepted by the OS, but nothing is ever sent back
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let session = SessionBuilder::new()
+            .connect_timeout(Duration::from_secs(5))
+            .read_timeout(Duration::from_millis(50))
+            .connect(listener.local_addr().unwrap());
+
+        // The server never replies to SANE_NET_INIT
+        match session {
+            Err(Error::Timeout(_)) => {}
+            Err(e) => panic!("Expected Timeout, got {:?}", e),
+            Ok(_) => panic!("Expected Timeout, got a session"),
+        }
+    }
+
+    #[test]
+    fn control_option_sets_value() {
+        let mut reply = control_option_reply(0, 1, 300);
+        // Failed requests still send a full reply
+        reply.extend(control_option_reply(4, 0, 300));
+        let mut stream = MockStream::new(reply);
+
+        let mut buf = Vec::new();
+        put_integer_descriptor(&mut buf, "resolution", 4);
+        let descriptor = <Option<OptionDescriptor>>::try_from_stream(&mut Cursor::new(buf))
+            .unwrap()
+            .unwrap();
+
+        let action = OptionAction::Set(OptionValue::Integer(307));
+        let (value, info) = control_option(3, &mut stream, 2, &descriptor, &action).unwrap();
+        assert_eq!(value, OptionValue::Integer(300));
+        assert_eq!(info, OptionInfo::Inexact);
+
+        let mut request = Vec::new();
+        for word in &[5, 3, 2, 1, 1, 4, 1, 307] {
+            put_word(&mut request, *word);
+        }
+        assert_eq!(stream.output, request);
+
+        match control_option(3, &mut stream, 2, &descriptor, &action) {
+            Err(Error::SanedError(Status::Invalid)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(stream.is_exhausted());
+    }
+
+    #[test]
+    fn control_option_pads_string_values() {
+        let mut reply = Vec::new();
+        put_word(&mut reply, 0); // status
+        put_word(&mut reply, 0); // info
+        put_word(&mut reply, 3); // value type: string
+        put_word(&mut reply, 8); // value size
+        put_word(&mut reply, 8); // array length
+        reply.extend_from_slice(b"Gray\0\0\0\0");
+        put_string(&mut reply, None); // resource
+        let mut stream = MockStream::new(reply);
+
+        let descriptor = OptionDescriptor::String {
+            name: "mode".into(),
+            title: "Scan mode".into(),
+            description: "Selects the scan mode.".into(),
+            unit: OptionUnit::None,
+            max_length: 8,
+            capabilities: Capabilities::SoftSelect,
+            constraint: None,
+        };
+
+        let action = OptionAction::Set("Gray".into());
+        let (value, _) = control_option(3, &mut stream, 4, &descriptor, &action).unwrap();
+        assert_eq!(value, OptionValue::String("Gray".into()));
+
+        let mut request = Vec::new();
+        for word in &[5, 3, 4, 1, 3, 8, 8] {
+            put_word(&mut request, *word);
+        }
+        request.extend_from_slice(b"Gray\0\0\0\0");
+        assert_eq!(stream.output, request);
+        // The whole request is sent at once, rather than field by field
+        assert_eq!(stream.writes, 1);
+    }
 }
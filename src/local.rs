@@ -0,0 +1,796 @@
+//! Access to locally attached scanners through libsane, without going through saned.
+//!
+//! `Session` and `DeviceHandle` mirror the types of the same name used with saned,
+//! and return the same `Device`, `OptionDescriptor`, `Options` and `ScanData` types,
+//! so code using them only differs in how the session is created.
+//!
+//! The structures returned by libsane are converted by encoding them as saned would
+//! send them and decoding the result, so both backends produce identical values.
+//! libsane keeps global state, so only one session should exist at a time.
+
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::io::{self, Read};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libloading::Library;
+
+use crate::error::Error;
+use crate::image::{FrameAssembler, Image};
+use crate::options::{ControlOption, Options, ScanArea};
+use crate::scan::{ByteOrder, ProgressCallback, ScanData, ScanEvent};
+use crate::status::Status;
+use crate::types::{OptionAction, OptionDescriptor, OptionInfo, OptionValue, Parameters};
+use crate::{Device, Result, TryFromStream, WriteToStream};
+
+/// Names libsane is looked up by, in order.
+const LIBRARY_NAMES: &[&str] = &["libsane.so.1", "libsane.so", "libsane.1.dylib"];
+
+/// Size of the buffer image data is read into.
+const READ_BUFFER_SIZE: usize = 32 * 1024;
+
+/// Record length marking the end of the image data, as sent by saned.
+const END_OF_DATA: u32 = 0xffffffff;
+
+/// The C structures of the SANE API.
+///
+/// See: http://www.sane-project.org/html/doc011.html
+mod ffi {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub type Handle = *mut c_void;
+
+    pub type AuthCallback = Option<unsafe extern "C" fn(*const c_char, *mut c_char, *mut c_char)>;
+
+    #[repr(C)]
+    pub struct Device {
+        pub name: *const c_char,
+        pub vendor: *const c_char,
+        pub model: *const c_char,
+        pub kind: *const c_char,
+    }
+
+    #[repr(C)]
+    pub struct Range {
+        pub min: c_int,
+        pub max: c_int,
+        pub quant: c_int,
+    }
+
+    #[repr(C)]
+    pub struct OptionDescriptor {
+        pub name: *const c_char,
+        pub title: *const c_char,
+        pub desc: *const c_char,
+        pub kind: c_int,
+        pub unit: c_int,
+        pub size: c_int,
+        pub cap: c_int,
+        pub constraint_type: c_int,
+        /// A string list, word list or range, depending on `constraint_type`.
+        pub constraint: *const c_void,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct Parameters {
+        pub format: c_int,
+        pub last_frame: c_int,
+        pub bytes_per_line: c_int,
+        pub pixels_per_line: c_int,
+        pub lines: c_int,
+        pub depth: c_int,
+    }
+}
+
+/// The libsane entry points, resolved when the library is loaded.
+struct Api {
+    init: unsafe extern "C" fn(*mut c_int, ffi::AuthCallback) -> c_int,
+    exit: unsafe extern "C" fn(),
+    get_devices: unsafe extern "C" fn(*mut *const *const ffi::Device, c_int) -> c_int,
+    open: unsafe extern "C" fn(*const c_char, *mut ffi::Handle) -> c_int,
+    close: unsafe extern "C" fn(ffi::Handle),
+    get_option_descriptor: unsafe extern "C" fn(ffi::Handle, c_int) -> *const ffi::OptionDescriptor,
+    control_option:
+        unsafe extern "C" fn(ffi::Handle, c_int, c_int, *mut c_void, *mut c_int) -> c_int,
+    get_parameters: unsafe extern "C" fn(ffi::Handle, *mut ffi::Parameters) -> c_int,
+    start: unsafe extern "C" fn(ffi::Handle) -> c_int,
+    read: unsafe extern "C" fn(ffi::Handle, *mut u8, c_int, *mut c_int) -> c_int,
+    cancel: unsafe extern "C" fn(ffi::Handle),
+    // The entry points are only valid while the library stays loaded
+    _library: Library,
+}
+
+impl From<libloading::Error> for Error {
+    fn from(error: libloading::Error) -> Error {
+        Error::LibraryError(error.to_string())
+    }
+}
+
+impl Api {
+    fn load() -> Result<Api> {
+        let mut last_error = None;
+
+        for name in LIBRARY_NAMES {
+            // Loading libsane runs no initialization code beyond that of its dependencies
+            match unsafe { Library::new(name) } {
+                Ok(library) => return unsafe { Api::resolve(library) },
+                Err(e) => {
+                    debug!("Failed to load {}: {}", name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error
+            .map(Error::from)
+            .unwrap_or_else(|| Error::LibraryError("libsane not found".into())))
+    }
+
+    /// Look up the entry points in `library`, which must be libsane,
+    /// so that they have the expected signatures.
+    unsafe fn resolve(library: Library) -> Result<Api> {
+        Ok(Api {
+            init: *library.get(b"sane_init\0")?,
+            exit: *library.get(b"sane_exit\0")?,
+            get_devices: *library.get(b"sane_get_devices\0")?,
+            open: *library.get(b"sane_open\0")?,
+            close: *library.get(b"sane_close\0")?,
+            get_option_descriptor: *library.get(b"sane_get_option_descriptor\0")?,
+            control_option: *library.get(b"sane_control_option\0")?,
+            get_parameters: *library.get(b"sane_get_parameters\0")?,
+            start: *library.get(b"sane_start\0")?,
+            read: *library.get(b"sane_read\0")?,
+            cancel: *library.get(b"sane_cancel\0")?,
+            _library: library,
+        })
+    }
+}
+
+/// Return Err unless `status` is `Status::Success`.
+fn check_status(status: c_int) -> Result<()> {
+    match Status::try_from(status)? {
+        Status::Success => Ok(()),
+        err => Err(err.into()),
+    }
+}
+
+/// Encode `string` as saned sends strings: its length including the null byte,
+/// followed by the characters. Null strings are sent with a length of 0.
+///
+/// `string` must be null or point to a null-terminated string.
+unsafe fn put_string(buf: &mut Vec<u8>, string: *const c_char) -> Result<()> {
+    if string.is_null() {
+        return 0i32.write_to_stream(buf);
+    }
+
+    let bytes = CStr::from_ptr(string).to_bytes_with_nul();
+    (bytes.len() as i32).write_to_stream(buf)?;
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Copy a device returned by libsane, which must point to a valid `SANE_Device`.
+unsafe fn convert_device(device: *const ffi::Device) -> Result<Device> {
+    let device = &*device;
+
+    let mut buf = Vec::new();
+    put_string(&mut buf, device.name)?;
+    put_string(&mut buf, device.vendor)?;
+    put_string(&mut buf, device.model)?;
+    put_string(&mut buf, device.kind)?;
+
+    Device::try_from_stream(&mut &buf[..])
+}
+
+/// Copy an option descriptor returned by libsane,
+/// which must point to a valid `SANE_Option_Descriptor`.
+unsafe fn convert_descriptor(descriptor: *const ffi::OptionDescriptor) -> Result<OptionDescriptor> {
+    let descriptor = &*descriptor;
+
+    let mut buf = Vec::new();
+    put_string(&mut buf, descriptor.name)?;
+    put_string(&mut buf, descriptor.title)?;
+    put_string(&mut buf, descriptor.desc)?;
+    descriptor.kind.write_to_stream(&mut buf)?;
+    descriptor.unit.write_to_stream(&mut buf)?;
+    descriptor.size.write_to_stream(&mut buf)?;
+    descriptor.cap.write_to_stream(&mut buf)?;
+
+    let constraint = descriptor.constraint;
+    match descriptor.constraint_type {
+        // Range: sent as a pointer to the range
+        1 if !constraint.is_null() => {
+            let range = &*(constraint as *const ffi::Range);
+            1i32.write_to_stream(&mut buf)?;
+            0i32.write_to_stream(&mut buf)?; // pointer is not null
+            range.min.write_to_stream(&mut buf)?;
+            range.max.write_to_stream(&mut buf)?;
+            range.quant.write_to_stream(&mut buf)?;
+        }
+        // Word list: the first word is the number of words following it,
+        // and saned sends all of them, including the count
+        2 if !constraint.is_null() => {
+            let words = constraint as *const c_int;
+            let length = (*words).max(0) as usize + 1;
+            2i32.write_to_stream(&mut buf)?;
+            std::slice::from_raw_parts(words, length).write_to_stream(&mut buf)?;
+        }
+        // String list: null-terminated, and sent including the null entry
+        3 if !constraint.is_null() => {
+            let strings = constraint as *const *const c_char;
+            let mut length = 0;
+            while !(*strings.add(length)).is_null() {
+                length += 1;
+            }
+
+            3i32.write_to_stream(&mut buf)?;
+            (length as i32 + 1).write_to_stream(&mut buf)?;
+            for i in 0..=length {
+                put_string(&mut buf, *strings.add(i))?;
+            }
+        }
+        1..=3 => 0i32.write_to_stream(&mut buf)?,
+        kind => kind.write_to_stream(&mut buf)?,
+    }
+
+    OptionDescriptor::try_from_stream(&mut &buf[..])
+}
+
+fn convert_parameters(parameters: &ffi::Parameters) -> Result<Parameters> {
+    let mut buf = Vec::new();
+    parameters.format.write_to_stream(&mut buf)?;
+    // SANE_TRUE is 1, but be lenient about other true values
+    (parameters.last_frame != 0).write_to_stream(&mut buf)?;
+    parameters.bytes_per_line.write_to_stream(&mut buf)?;
+    parameters.pixels_per_line.write_to_stream(&mut buf)?;
+    parameters.lines.write_to_stream(&mut buf)?;
+    parameters.depth.write_to_stream(&mut buf)?;
+
+    Parameters::try_from_stream(&mut &buf[..])
+}
+
+/// The buffer passed to `sane_control_option` for an option described by `descriptor`,
+/// holding `value` or zeroes if there is none.
+fn value_buffer(descriptor: &OptionDescriptor, value: Option<&OptionValue>) -> Vec<u8> {
+    let size = match *descriptor {
+        OptionDescriptor::Boolean { .. } => 4,
+        OptionDescriptor::Integer { size, .. } | OptionDescriptor::Fixed { size, .. } => size,
+        OptionDescriptor::String { max_length, .. } => max_length,
+        _ => 0,
+    }
+    .max(0) as usize;
+
+    let mut buf = match value {
+        Some(&OptionValue::Boolean(value)) => (value as c_int).to_ne_bytes().to_vec(),
+        Some(&OptionValue::Integer(value)) => value.to_ne_bytes().to_vec(),
+        Some(&OptionValue::Fixed(value)) => value.raw().to_ne_bytes().to_vec(),
+        Some(OptionValue::IntegerArray(values)) => values
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect(),
+        Some(OptionValue::FixedArray(values)) => values
+            .iter()
+            .flat_map(|value| value.raw().to_ne_bytes())
+            .collect(),
+        Some(OptionValue::String(value)) => {
+            let mut chars = value.clone().into_bytes();
+            chars.push(0x00);
+            chars
+        }
+        Some(OptionValue::Button) | None => Vec::new(),
+    };
+
+    if buf.len() < size {
+        buf.resize(size, 0x00);
+    }
+    buf
+}
+
+/// Encode the contents of a value buffer as saned sends option values,
+/// as an array of words or characters.
+fn encode_value(descriptor: &OptionDescriptor, value: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    match *descriptor {
+        OptionDescriptor::String { .. } => {
+            (value.len() as i32).write_to_stream(&mut buf)?;
+            buf.extend_from_slice(value);
+        }
+        OptionDescriptor::Boolean { .. }
+        | OptionDescriptor::Integer { .. }
+        | OptionDescriptor::Fixed { .. } => {
+            let words: Vec<i32> = value
+                .chunks_exact(4)
+                .map(|word| i32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+                .collect();
+            words.write_to_stream(&mut buf)?;
+        }
+        _ => 0i32.write_to_stream(&mut buf)?,
+    }
+
+    Ok(buf)
+}
+
+/// Whether a `Session` currently has libsane initialized.
+///
+/// libsane's state is global, so `sane_exit` from one session would free the
+/// devices of any other.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// libsane, loaded and initialized.
+///
+/// libsane is shut down with `sane_exit` when the session is dropped.
+/// Only one session can be live at a time in a process.
+pub struct Session {
+    api: Api,
+    exited: Cell<bool>,
+}
+
+impl Session {
+    /// Load libsane and initialize it.
+    ///
+    /// Fails if another session is still live.
+    pub fn new() -> Result<Session> {
+        if INITIALIZED
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(Error::LibraryError(
+                "libsane is already in use by another session".into(),
+            ));
+        }
+
+        let result = Session::init();
+        if result.is_err() {
+            INITIALIZED.store(false, Ordering::Release);
+        }
+        result
+    }
+
+    fn init() -> Result<Session> {
+        let api = Api::load()?;
+
+        let mut version = 0;
+        check_status(unsafe { (api.init)(&mut version, None) })?;
+        info!("Initialized libsane, version {:x}", version);
+
+        Ok(Session {
+            api,
+            exited: Cell::new(false),
+        })
+    }
+
+    /// Request the list of available devices.
+    pub fn devices(&self) -> Result<Vec<Device>> {
+        let mut list = ptr::null();
+        check_status(unsafe { (self.api.get_devices)(&mut list, 0) })?;
+
+        let mut devices = Vec::new();
+        if list.is_null() {
+            return Ok(devices);
+        }
+
+        // The list is null-terminated, and valid until the next call into libsane
+        let mut i = 0;
+        loop {
+            let device = unsafe { *list.add(i) };
+            if device.is_null() {
+                break;
+            }
+            devices.push(unsafe { convert_device(device) }?);
+            i += 1;
+        }
+
+        Ok(devices)
+    }
+
+    /// Open `device`, returning a handle that closes the device when dropped.
+    pub fn open(&self, device: &Device) -> Result<DeviceHandle<'_>> {
+        info!("Opening device '{}'", device.name);
+
+        let name = CString::new(device.name.as_str())
+            .map_err(|_| Error::InvalidDeviceName(device.name.clone()))?;
+
+        let mut handle = ptr::null_mut();
+        check_status(unsafe { (self.api.open)(name.as_ptr(), &mut handle) })?;
+
+        Ok(DeviceHandle {
+            session: self,
+            handle,
+            closed: false,
+        })
+    }
+
+    /// Shut down libsane.
+    ///
+    /// This is done automatically when the session is dropped.
+    pub fn exit(self) -> Result<()> {
+        self.finish();
+        Ok(())
+    }
+
+    fn finish(&self) {
+        if !self.exited.replace(true) {
+            unsafe { (self.api.exit)() };
+            INITIALIZED.store(false, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// A device opened through libsane. The device is closed when the handle is dropped.
+pub struct DeviceHandle<'a> {
+    session: &'a Session,
+    handle: ffi::Handle,
+    closed: bool,
+}
+
+impl<'a> DeviceHandle<'a> {
+    /// The session the device was opened through.
+    pub fn session(&self) -> &'a Session {
+        self.session
+    }
+
+    fn api(&self) -> &'a Api {
+        &self.session.api
+    }
+
+    /// Request the descriptors of all options of the device,
+    /// through which option values can be read and set by name.
+    pub fn options(&self) -> Result<Options<'_, Self>> {
+        Ok(Options::new(self, self.option_descriptors()?))
+    }
+
+    /// The descriptors of all options of the device, as returned
+    /// by `sane::get_option_descriptors`.
+    pub fn option_descriptors(&self) -> Result<Vec<Option<OptionDescriptor>>> {
+        let read = |index| {
+            let descriptor = unsafe { (self.api().get_option_descriptor)(self.handle, index) };
+            if descriptor.is_null() {
+                Ok(None)
+            } else {
+                unsafe { convert_descriptor(descriptor) }.map(Some)
+            }
+        };
+
+        // Option 0 always exists, and holds the number of options
+        let count = match read(0)? {
+            Some(descriptor) => match self.control_option(0, &descriptor, &OptionAction::Get)? {
+                (OptionValue::Integer(count), _) => count,
+                (value, _) => {
+                    return Err(Error::BadNetworkDataError(format!(
+                        "Received invalid number of options {:?}",
+                        value
+                    )))
+                }
+            },
+            None => 0,
+        };
+
+        (0..count).map(read).collect()
+    }
+
+    /// Get or set the value of option number `index`, described by `descriptor`.
+    /// See `sane::control_option`.
+    pub fn control_option(
+        &self,
+        index: i32,
+        descriptor: &OptionDescriptor,
+        action: &OptionAction,
+    ) -> Result<(OptionValue, OptionInfo)> {
+        info!("Controlling option {}: {:?}", index, action);
+
+        let mut value = match *action {
+            OptionAction::Set(ref value) => value_buffer(descriptor, Some(value)),
+            _ => value_buffer(descriptor, None),
+        };
+        let value_ptr = match *action {
+            OptionAction::SetAuto => ptr::null_mut(),
+            _ => value.as_mut_ptr() as *mut c_void,
+        };
+
+        let mut info = 0;
+        check_status(unsafe {
+            (self.api().control_option)(self.handle, index, action.code(), value_ptr, &mut info)
+        })?;
+
+        let value = descriptor.read_value(&mut &encode_value(descriptor, &value)?[..])?;
+        Ok((value, OptionInfo::from_bits_truncate(info as u32)))
+    }
+
+    /// Get the parameters of the frame about to be, or currently being, acquired.
+    pub fn parameters(&self) -> Result<Parameters> {
+        let mut parameters = ffi::Parameters::default();
+        check_status(unsafe { (self.api().get_parameters)(self.handle, &mut parameters) })?;
+        convert_parameters(&parameters)
+    }
+
+    /// Cancel the current operation. This must also be called
+    /// once all frames of a scan have been read.
+    pub fn cancel(&self) -> Result<()> {
+        unsafe { (self.api().cancel)(self.handle) };
+        Ok(())
+    }
+
+    /// Start acquiring a frame, returning a reader for its image data.
+    ///
+    /// Once all frames have been read, `cancel` must be called to complete the scan.
+    pub fn scan(&self) -> Result<ScanData<Frame<'_>>> {
+        check_status(unsafe { (self.api().start)(self.handle) })?;
+
+        Ok(ScanData::new(
+            Frame {
+                device: self,
+                pending: Vec::new(),
+                position: 0,
+                finished: false,
+            },
+            // The backend hands over the data in host byte order
+            ByteOrder::native(),
+        ))
+    }
+
+    /// Scan a complete image, assembling the frames of three-pass scans
+    /// into a single RGB image.
+    pub fn scan_image(&self) -> Result<Image> {
+        self.acquire(None)
+    }
+
+    /// Scan a quick, low-resolution preview of the whole scan area,
+    /// restoring the option values afterwards. See `sane::DeviceHandle::preview`.
+    pub fn preview(&self) -> Result<Image> {
+        let mut options = self.options()?;
+        let snapshot = options.snapshot()?;
+
+        let result = options.set_preview().and_then(|_| self.scan_image());
+        let restored = options.restore(&snapshot);
+
+        let image = result?;
+        restored?;
+        Ok(image)
+    }
+
+    /// Set the scan area in millimeters, clamped to what the device allows.
+    /// See `sane::DeviceHandle::set_scan_area_mm`.
+    pub fn set_scan_area_mm(&self, x: f64, y: f64, width: f64, height: f64) -> Result<ScanArea> {
+        self.options()?
+            .set_scan_area(ScanArea::from_mm(x, y, width, height))
+    }
+
+    /// Set the scan area in inches, clamped to what the device allows.
+    pub fn set_scan_area_inches(
+        &self,
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    ) -> Result<ScanArea> {
+        self.options()?
+            .set_scan_area(ScanArea::from_inches(x, y, width, height))
+    }
+
+    /// The largest scan area the device allows.
+    pub fn max_scan_area(&self) -> Result<ScanArea> {
+        self.options()?.max_scan_area()
+    }
+
+    /// Scan a complete image like `scan_image`, reporting the progress
+    /// of reading each frame to `callback`.
+    pub fn scan_image_with_progress<F>(&self, callback: F) -> Result<Image>
+    where
+        F: FnMut(&ScanEvent) + Send + 'static,
+    {
+        self.acquire(Some(Box::new(callback)))
+    }
+
+    fn acquire(&self, mut progress: Option<ProgressCallback>) -> Result<Image> {
+        let resolution = self.options().and_then(|options| options.resolution());
+        let result = self.acquire_frames(&mut progress).map(|mut image| {
+            match resolution {
+                Ok(dpi) => image.set_resolution(dpi),
+                Err(e) => debug!("Scan resolution unknown: {}", e),
+            }
+            image
+        });
+        let cancelled = self.cancel();
+
+        let image = result?;
+        cancelled?;
+        Ok(image)
+    }
+
+    fn acquire_frames(&self, progress: &mut Option<ProgressCallback>) -> Result<Image> {
+        let mut assembler = FrameAssembler::new();
+
+        while !assembler.is_complete() {
+            let mut data = self.scan()?;
+            let parameters = self.parameters()?;
+
+            info!("Acquiring {:?} frame", parameters.format);
+            if let Some(callback) = progress.take() {
+                data.set_progress(&parameters, callback);
+            }
+            let frame = data.read_frame(&parameters)?;
+            *progress = data.take_progress();
+
+            assembler.add_frame(parameters, frame)?;
+        }
+
+        assembler.finish()
+    }
+
+    /// Close the device.
+    ///
+    /// This is done automatically when the handle is dropped.
+    pub fn close(mut self) -> Result<()> {
+        self.finish();
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            unsafe { (self.api().close)(self.handle) };
+        }
+    }
+}
+
+impl<'a> ControlOption for DeviceHandle<'a> {
+    fn control_option(
+        &self,
+        index: i32,
+        descriptor: &OptionDescriptor,
+        action: &OptionAction,
+    ) -> Result<(OptionValue, OptionInfo)> {
+        DeviceHandle::control_option(self, index, descriptor, action)
+    }
+
+    fn option_descriptors(&self) -> Result<Vec<Option<OptionDescriptor>>> {
+        DeviceHandle::option_descriptors(self)
+    }
+}
+
+impl<'a> Drop for DeviceHandle<'a> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// The image data of a frame, read from libsane with `sane_read`.
+///
+/// The data is framed into records as saned sends it,
+/// to be read through `ScanData`.
+pub struct Frame<'h> {
+    device: &'h DeviceHandle<'h>,
+    /// Framed data not yet read.
+    pending: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<'h> Frame<'h> {
+    /// Read the next chunk of data from libsane, and frame it as a record.
+    fn read_record(&mut self) {
+        let mut data = vec![0u8; READ_BUFFER_SIZE];
+        let mut length = 0;
+        let status = unsafe {
+            (self.device.api().read)(
+                self.device.handle,
+                data.as_mut_ptr(),
+                data.len() as c_int,
+                &mut length,
+            )
+        };
+
+        self.pending.clear();
+        self.position = 0;
+
+        if status == 0 {
+            let length = length.max(0) as usize;
+            self.pending
+                .extend_from_slice(&(length as u32).to_be_bytes());
+            self.pending.extend_from_slice(&data[..length]);
+        } else {
+            // End of the data, or an error: send the status as saned would
+            self.finished = true;
+            self.pending.extend_from_slice(&END_OF_DATA.to_be_bytes());
+            self.pending.push(status as u8);
+        }
+    }
+}
+
+impl<'h> Read for Frame<'h> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            self.read_record();
+        }
+
+        let read = (&self.pending[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{NumericalConstraint, OptionUnit};
+
+    #[test]
+    fn converts_option_descriptors() {
+        let name = CString::new("resolution").unwrap();
+        let title = CString::new("Scan resolution").unwrap();
+        let range = ffi::Range {
+            min: 75,
+            max: 1200,
+            quant: 0,
+        };
+        let descriptor = ffi::OptionDescriptor {
+            name: name.as_ptr(),
+            title: title.as_ptr(),
+            desc: ptr::null(),
+            kind: 1,
+            unit: 4,
+            size: 4,
+            cap: 5,
+            constraint_type: 1,
+            constraint: &range as *const ffi::Range as *const c_void,
+        };
+
+        match unsafe { convert_descriptor(&descriptor) } {
+            Err(Error::UnexpectedNull(ref field)) if field == "option description" => {}
+            other => panic!("Expected UnexpectedNull, got {:?}", other),
+        }
+
+        let desc = CString::new("Sets the resolution of the scanned image.").unwrap();
+        let descriptor = ffi::OptionDescriptor {
+            desc: desc.as_ptr(),
+            ..descriptor
+        };
+
+        match unsafe { convert_descriptor(&descriptor) }.unwrap() {
+            OptionDescriptor::Integer {
+                name,
+                unit: OptionUnit::DPI,
+                size: 4,
+                constraint: Some(NumericalConstraint::Range(Some(range))),
+                ..
+            } => {
+                assert_eq!(name, "resolution");
+                assert_eq!((range.min(), range.max()), (75, 1200));
+            }
+            other => panic!("Unexpected descriptor {:?}", other),
+        }
+    }
+
+    #[test]
+    fn converts_option_values() {
+        let descriptor = OptionDescriptor::String {
+            name: "mode".into(),
+            title: "Scan mode".into(),
+            description: "Selects the scan mode.".into(),
+            unit: OptionUnit::None,
+            max_length: 8,
+            capabilities: Default::default(),
+            constraint: None,
+        };
+
+        let buffer = value_buffer(&descriptor, Some(&"Gray".into()));
+        assert_eq!(buffer, b"Gray\0\0\0\0");
+
+        let value = descriptor.read_value(&mut &encode_value(&descriptor, &buffer).unwrap()[..]);
+        assert_eq!(value.unwrap(), OptionValue::String("Gray".into()));
+    }
+}
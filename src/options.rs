@@ -0,0 +1,1188 @@
+use crate::error::Error;
+use crate::types::{
+    Capabilities, Fixed, NumericalConstraint, OptionAction, OptionDescriptor, OptionInfo,
+    OptionUnit, OptionValue, Word,
+};
+use crate::Result;
+
+/// An open device whose option values can be read and set,
+/// whether it was opened through saned or libsane.
+pub trait ControlOption {
+    /// Get or set the value of option number `index`, described by `descriptor`.
+    /// See `sane::control_option`.
+    fn control_option(
+        &self,
+        index: i32,
+        descriptor: &OptionDescriptor,
+        action: &OptionAction,
+    ) -> Result<(OptionValue, OptionInfo)>;
+
+    /// The descriptors of all options of the device.
+    /// See `sane::get_option_descriptors`.
+    fn option_descriptors(&self) -> Result<Vec<Option<OptionDescriptor>>>;
+}
+
+/// The options of an open device, which can be read and set by name.
+///
+/// Values are checked against the option's type and constraint before
+/// being sent to the device. When setting an option changes others, as signaled
+/// by `OptionInfo::ReloadOptions`, the descriptors are fetched again.
+pub struct Options<'h, H: ControlOption + 'h> {
+    handle: &'h H,
+    descriptors: Vec<Option<OptionDescriptor>>,
+    /// Set if values are snapped to the option's constraint by `set`.
+    snap: bool,
+    /// Names of the options whose descriptors changed on the last reload.
+    changed: Vec<String>,
+}
+
+impl<'h, H: ControlOption> Options<'h, H> {
+    pub(crate) fn new(handle: &'h H, descriptors: Vec<Option<OptionDescriptor>>) -> Options<'h, H> {
+        Options {
+            handle,
+            descriptors,
+            snap: false,
+            changed: Vec::new(),
+        }
+    }
+
+    /// The descriptors of all options, where the position of each
+    /// descriptor is the option's index on the device.
+    pub fn descriptors(&self) -> &[Option<OptionDescriptor>] {
+        &self.descriptors
+    }
+
+    /// The options, grouped under their group titles.
+    pub fn option_set(&self) -> OptionSet {
+        OptionSet::from(self.descriptors.clone())
+    }
+
+    /// Fetch the option descriptors from the device again.
+    ///
+    /// This is done by `set` and `set_auto` when the device signals
+    /// `OptionInfo::ReloadOptions`, so is only needed if options were set
+    /// by other means.
+    pub fn reload(&mut self) -> Result<()> {
+        let descriptors = self.handle.option_descriptors()?;
+        self.changed = changed_options(&self.descriptors, &descriptors);
+        self.descriptors = descriptors;
+
+        debug!("Reloaded option descriptors, changed: {:?}", self.changed);
+        Ok(())
+    }
+
+    /// The names of the options whose descriptors changed on the last reload,
+    /// including options that were added or removed. For example, changing the
+    /// scan mode can change the resolutions allowed, or make options inactive.
+    ///
+    /// Empty if the last option set didn't require a reload.
+    pub fn changed(&self) -> &[String] {
+        &self.changed
+    }
+
+    /// Snap the values given to `set` to the option's constraint instead of rejecting them,
+    /// for example rounding a resolution to the nearest one the device supports.
+    ///
+    /// Values that had to be adjusted are reported with `OptionInfo::Inexact`.
+    pub fn set_snapping(&mut self, snap: bool) {
+        self.snap = snap;
+    }
+
+    /// Find the option named `name`, returning its index and descriptor.
+    pub fn find(&self, name: &str) -> Option<(i32, &OptionDescriptor)> {
+        self.descriptors
+            .iter()
+            .enumerate()
+            .filter_map(|(index, descriptor)| descriptor.as_ref().map(|d| (index as i32, d)))
+            .find(|&(_, descriptor)| descriptor.name() == Some(name))
+    }
+
+    fn lookup(&self, name: &str) -> Result<(i32, &OptionDescriptor)> {
+        self.find(name)
+            .ok_or_else(|| Error::UnknownOption(name.into()))
+    }
+
+    /// Read the current value of the option named `name`.
+    pub fn get(&self, name: &str) -> Result<OptionValue> {
+        let (index, descriptor) = self.lookup(name)?;
+
+        self.handle
+            .control_option(index, descriptor, &OptionAction::Get)
+            .map(|(value, _)| value)
+    }
+
+    /// The current value of the `resolution` option, in dots per inch.
+    pub fn resolution(&self) -> Result<f64> {
+        let resolution = number(&self.get("resolution")?);
+        if resolution <= 0.0 {
+            return Err(Error::InvalidOptionValue(
+                "resolution".into(),
+                format!("{} dpi", resolution),
+            ));
+        }
+        Ok(resolution)
+    }
+
+    /// Set the option named `name` to `value`.
+    ///
+    /// Returns the value the option was actually set to, which can differ from
+    /// `value` if the backend had to round it, or it was snapped to the option's
+    /// constraint (see `set_snapping`), as indicated by `OptionInfo::Inexact`.
+    ///
+    /// If the device signals `OptionInfo::ReloadOptions`, the descriptors are
+    /// reloaded and the options that changed are listed by `changed`. The returned
+    /// info still carries `OptionInfo::ReloadParams`, for callers caching the
+    /// scan parameters.
+    pub fn set<V: Into<OptionValue>>(
+        &mut self,
+        name: &str,
+        value: V,
+    ) -> Result<(OptionValue, OptionInfo)> {
+        let (index, descriptor) = self.lookup(name)?;
+        let value = value.into();
+
+        let (value, snapped) = match descriptor.check_value(value.clone()) {
+            Err(_) if self.snap => (descriptor.check_value(descriptor.snap_value(value))?, true),
+            checked => (checked?, false),
+        };
+        if snapped {
+            debug!("Snapped value of option {} to {:?}", name, value);
+        }
+
+        let (value, mut info) =
+            self.handle
+                .control_option(index, descriptor, &OptionAction::Set(value))?;
+        if snapped {
+            info |= OptionInfo::Inexact;
+        }
+
+        self.reload_if_needed(info)?;
+        Ok((value, info))
+    }
+
+    /// Let the backend choose the value of the option named `name` automatically.
+    /// Descriptors are reloaded as needed, like by `set`.
+    pub fn set_auto(&mut self, name: &str) -> Result<(OptionValue, OptionInfo)> {
+        let (index, descriptor) = self.lookup(name)?;
+
+        if !descriptor.capabilities().contains(Capabilities::Automatic) {
+            return Err(Error::InvalidOptionValue(
+                name.into(),
+                "option can't be set automatically".into(),
+            ));
+        }
+
+        let (value, info) =
+            self.handle
+                .control_option(index, descriptor, &OptionAction::SetAuto)?;
+
+        self.reload_if_needed(info)?;
+        Ok((value, info))
+    }
+
+    fn reload_if_needed(&mut self, info: OptionInfo) -> Result<()> {
+        if info.contains(OptionInfo::ReloadOptions) {
+            self.reload()
+        } else {
+            self.changed.clear();
+            Ok(())
+        }
+    }
+
+    /// The names and current values of all active options that can be read and set.
+    pub fn snapshot(&self) -> Result<Vec<(String, OptionValue)>> {
+        let names: Vec<String> = self
+            .descriptors
+            .iter()
+            .flatten()
+            .filter(|descriptor| is_restorable(descriptor))
+            .filter_map(|descriptor| descriptor.name().map(String::from))
+            .collect();
+
+        names
+            .into_iter()
+            .map(|name| self.get(&name).map(|value| (name, value)))
+            .collect()
+    }
+
+    /// Set options back to the values taken by `snapshot`.
+    ///
+    /// Only options whose value changed are set, in the order they were taken,
+    /// and options that are no longer active are skipped. All options are
+    /// attempted even if one fails, in which case the first error is returned.
+    pub fn restore(&mut self, snapshot: &[(String, OptionValue)]) -> Result<()> {
+        self.reload()?;
+
+        let mut result = Ok(());
+        for (name, value) in snapshot {
+            let restorable = match self.find(name) {
+                Some((_, descriptor)) => is_restorable(descriptor),
+                None => false,
+            };
+            if !restorable {
+                debug!("Not restoring option {}, which is no longer settable", name);
+                continue;
+            }
+
+            let restored = self.get(name).and_then(|current| {
+                if current == *value {
+                    Ok(())
+                } else {
+                    self.set(name, value.clone()).map(|_| ())
+                }
+            });
+            if let Err(e) = restored {
+                warn!("Failed to restore option {}: {}", name, e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Set several options at once, such as the settings of a scan profile,
+    /// restoring the previous values if any of them can't be set.
+    ///
+    /// Options are set in the order the device lists them, which puts options such
+    /// as the scan mode and source before those they affect. Names are looked up again
+    /// after each reload, and an inactive option is set once setting another one
+    /// activates it.
+    ///
+    /// Values the device adjusted, for example by rounding, are reported as `adjusted`.
+    /// When a value is rejected, the remaining ones are still tried so that every
+    /// rejection is reported, then the options are restored to their values from
+    /// `snapshot` and the report is marked `rolled_back`. Fails only if the options
+    /// can't be read or restored.
+    pub fn apply_settings<I, K, V>(&mut self, settings: I) -> Result<SettingsReport>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<OptionValue>,
+    {
+        let mut pending: Vec<(String, OptionValue)> = settings
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect();
+        let snapshot = self.snapshot()?;
+        let mut report = SettingsReport::default();
+
+        while let Some(next) = self.next_setting(&pending) {
+            let (name, value) = pending.remove(next);
+            match self.set(&name, value.clone()) {
+                Ok((set, info)) => {
+                    if set != value || info.contains(OptionInfo::Inexact) {
+                        report.adjusted.push((name, set));
+                    }
+                }
+                Err(e) => {
+                    debug!("Option {} rejected {:?}: {}", name, value, e);
+                    report.rejected.push((name, e));
+                }
+            }
+        }
+
+        // Left over are options that don't exist, or were never activated
+        for (name, _) in pending {
+            let error = match self.find(&name) {
+                Some(_) => Error::InvalidOptionValue(name.clone(), "option is inactive".into()),
+                None => Error::UnknownOption(name.clone()),
+            };
+            report.rejected.push((name, error));
+        }
+
+        if !report.rejected.is_empty() {
+            self.restore(&snapshot)?;
+            report.rolled_back = true;
+        }
+        Ok(report)
+    }
+
+    /// The position in `pending` of the setting of the active option listed first.
+    fn next_setting(&self, pending: &[(String, OptionValue)]) -> Option<usize> {
+        pending
+            .iter()
+            .enumerate()
+            .filter_map(|(position, (name, _))| {
+                let (index, descriptor) = self.find(name)?;
+                let active = !descriptor.capabilities().contains(Capabilities::Inactive);
+                active.then_some((index, position))
+            })
+            .min()
+            .map(|(_, position)| position)
+    }
+
+    /// Configure the device for a quick preview of the whole scan area.
+    ///
+    /// The `preview` option is enabled if the device has it, and otherwise
+    /// the lowest supported resolution is selected. The scan area is set to the
+    /// largest the geometry options allow.
+    pub fn set_preview(&mut self) -> Result<()> {
+        if self.is_settable("preview") {
+            self.set("preview", OptionValue::Boolean(true))?;
+        } else if let Some(lowest) = self.bound("resolution", Bound::Lowest) {
+            self.set("resolution", lowest)?;
+        }
+
+        let geometry = [
+            ("tl-x", Bound::Lowest),
+            ("tl-y", Bound::Lowest),
+            ("br-x", Bound::Highest),
+            ("br-y", Bound::Highest),
+        ];
+        for &(name, bound) in &geometry {
+            if let Some(value) = self.bound(name, bound) {
+                self.set(name, value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Calibrate the device, by pressing its calibration button.
+    ///
+    /// Fails with `Error::UnknownOption` if the device has no such button.
+    pub fn calibrate(&mut self) -> Result<()> {
+        let name = self.maintenance_option(&CALIBRATE)?;
+        self.set(name, OptionValue::Button).map(|_| ())
+    }
+
+    /// Turn the lamp of the device on or off, through its lamp switch option
+    /// or, failing that, its buttons turning the lamp on and off.
+    pub fn set_lamp(&mut self, on: bool) -> Result<()> {
+        if let Ok(name) = self.maintenance_option(&LAMP_SWITCH) {
+            return self.set(name, on).map(|_| ());
+        }
+
+        let buttons: &[&str] = if on { &LAMP_ON } else { &LAMP_OFF };
+        let name = self.maintenance_option(buttons)?;
+        self.set(name, OptionValue::Button).map(|_| ())
+    }
+
+    /// Set the number of minutes after which the lamp is turned off
+    /// when the device is idle.
+    pub fn set_lamp_off_time(&mut self, minutes: i32) -> Result<()> {
+        let name = self.maintenance_option(&LAMP_OFF_TIME)?;
+        self.set(name, minutes).map(|_| ())
+    }
+
+    /// The first of `names` the device has an option for.
+    /// Backends don't agree on the names of maintenance options.
+    fn maintenance_option(&self, names: &[&'static str]) -> Result<&'static str> {
+        names
+            .iter()
+            .cloned()
+            .find(|name| self.find(name).is_some())
+            .ok_or_else(|| Error::UnknownOption(names[0].into()))
+    }
+
+    /// The largest scan area the geometry options allow.
+    pub fn max_scan_area(&self) -> Result<ScanArea> {
+        let mut corners = [0.0; 4];
+        for (corner, &name) in corners.iter_mut().zip(&GEOMETRY) {
+            self.lookup(name)?;
+            let bound = if name.starts_with("tl") {
+                Bound::Lowest
+            } else {
+                Bound::Highest
+            };
+            let value = self.bound(name, bound).ok_or_else(|| {
+                Error::InvalidOptionValue(name.into(), "option has no constrained range".into())
+            })?;
+            *corner = number(&value) * self.mm_per_unit(name)?;
+        }
+
+        Ok(ScanArea::from_corners(corners))
+    }
+
+    /// Set the geometry options to scan `area`.
+    ///
+    /// The area is converted to the unit of each option, using the current
+    /// resolution for options in pixels, and moved onto the nearest value the option
+    /// allows. Returns the area actually set, which can differ from `area` as a result.
+    pub fn set_scan_area(&mut self, area: ScanArea) -> Result<ScanArea> {
+        let mut corners = area.corners();
+        for (corner, &name) in corners.iter_mut().zip(&GEOMETRY) {
+            let mm_per_unit = self.mm_per_unit(name)?;
+            let units = *corner / mm_per_unit;
+
+            let value = match *self.lookup(name)?.1 {
+                ref descriptor @ OptionDescriptor::Fixed { .. } => {
+                    descriptor.snap_value(OptionValue::Fixed(Fixed::from_f64(units)))
+                }
+                ref descriptor @ OptionDescriptor::Integer { .. } => {
+                    descriptor.snap_value(OptionValue::Integer(units.round() as i32))
+                }
+                _ => {
+                    return Err(Error::InvalidOptionValue(
+                        name.into(),
+                        "option isn't numeric".into(),
+                    ))
+                }
+            };
+
+            let (value, _) = self.set(name, value)?;
+            *corner = number(&value) * mm_per_unit;
+        }
+
+        Ok(ScanArea::from_corners(corners))
+    }
+
+    /// The number of millimeters in one unit of the geometry option named `name`.
+    fn mm_per_unit(&self, name: &str) -> Result<f64> {
+        match self.lookup(name)?.1.unit() {
+            Some(OptionUnit::Millimeter) => Ok(1.0),
+            Some(OptionUnit::Pixel) => Ok(MM_PER_INCH / self.resolution()?),
+            unit => Err(Error::InvalidOptionValue(
+                name.into(),
+                format!("unsupported unit {:?} for the scan area", unit),
+            )),
+        }
+    }
+
+    fn is_settable(&self, name: &str) -> bool {
+        self.find(name)
+            .is_some_and(|(_, descriptor)| is_restorable(descriptor))
+    }
+
+    /// The lowest or highest value the settable option named `name` is constrained to.
+    fn bound(&self, name: &str, bound: Bound) -> Option<OptionValue> {
+        let descriptor = match self.find(name) {
+            Some((_, descriptor)) if is_restorable(descriptor) => descriptor,
+            _ => return None,
+        };
+        if descriptor.array_length().is_some() {
+            return None;
+        }
+
+        match *descriptor {
+            OptionDescriptor::Integer { ref constraint, .. } => {
+                constraint_bound(constraint, bound).map(OptionValue::Integer)
+            }
+            OptionDescriptor::Fixed { ref constraint, .. } => {
+                constraint_bound(constraint, bound).map(OptionValue::Fixed)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Names of the options holding the coordinates of the top-left
+/// and bottom-right corners of the scan area.
+const GEOMETRY: [&str; 4] = ["tl-x", "tl-y", "br-x", "br-y"];
+
+pub(crate) const MM_PER_INCH: f64 = 25.4;
+
+/// Names backends give the button starting a calibration.
+const CALIBRATE: [&str; 2] = ["calibrate", "calibration"];
+
+/// Names backends give the boolean option switching the lamp on and off.
+const LAMP_SWITCH: [&str; 2] = ["lamp-switch", "lamp"];
+
+/// Names backends give the buttons turning the lamp on, and off.
+const LAMP_ON: [&str; 1] = ["lamp-on"];
+const LAMP_OFF: [&str; 1] = ["lamp-off"];
+
+/// Names backends give the option holding the idle time before the lamp is turned off.
+const LAMP_OFF_TIME: [&str; 2] = ["lamp-off-time", "lamp-timeout"];
+
+/// The outcome of `Options::apply_settings`.
+#[derive(Debug, Default)]
+pub struct SettingsReport {
+    /// The options set to a different value than requested, with the value
+    /// they were set to.
+    pub adjusted: Vec<(String, OptionValue)>,
+    /// The options that couldn't be set, with the reason.
+    pub rejected: Vec<(String, Error)>,
+    /// Set if a value was rejected, and the options were restored to their
+    /// previous values.
+    pub rolled_back: bool,
+}
+
+/// A rectangular area of the scan bed, in millimeters from its top-left corner.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScanArea {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl ScanArea {
+    pub fn from_mm(x: f64, y: f64, width: f64, height: f64) -> ScanArea {
+        ScanArea {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// An area given in inches.
+    pub fn from_inches(x: f64, y: f64, width: f64, height: f64) -> ScanArea {
+        ScanArea::from_mm(
+            x * MM_PER_INCH,
+            y * MM_PER_INCH,
+            width * MM_PER_INCH,
+            height * MM_PER_INCH,
+        )
+    }
+
+    /// The area in inches, as `(x, y, width, height)`.
+    pub fn to_inches(self) -> (f64, f64, f64, f64) {
+        (
+            self.x / MM_PER_INCH,
+            self.y / MM_PER_INCH,
+            self.width / MM_PER_INCH,
+            self.height / MM_PER_INCH,
+        )
+    }
+
+    /// The area between the corners given in the order of `GEOMETRY`.
+    fn from_corners([left, top, right, bottom]: [f64; 4]) -> ScanArea {
+        ScanArea::from_mm(left, top, right - left, bottom - top)
+    }
+
+    fn corners(self) -> [f64; 4] {
+        [self.x, self.y, self.x + self.width, self.y + self.height]
+    }
+}
+
+/// The value of a numerical option as a number; 0 for any other value.
+fn number(value: &OptionValue) -> f64 {
+    match *value {
+        OptionValue::Integer(value) => f64::from(value),
+        OptionValue::Fixed(value) => f64::from(value),
+        _ => 0.0,
+    }
+}
+
+/// An option of a device, along with its index on the device.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceOption {
+    /// The index identifying the option in `control_option` requests.
+    pub index: i32,
+    pub descriptor: OptionDescriptor,
+}
+
+impl DeviceOption {
+    /// The name of the option. Options in a set always have a name.
+    pub fn name(&self) -> &str {
+        self.descriptor.name().unwrap_or("")
+    }
+}
+
+/// The options following a group option, up to the next group.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OptionGroup {
+    /// The title of the group, or None for options preceding the first group.
+    pub title: Option<String>,
+    pub options: Vec<DeviceOption>,
+}
+
+/// The options of a device, grouped under the titles of the group options
+/// they follow, as returned by `get_option_descriptors`.
+///
+/// Group options themselves, missing descriptors and option 0,
+/// which only holds the number of options, are left out.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OptionSet {
+    groups: Vec<OptionGroup>,
+}
+
+impl OptionSet {
+    /// The groups of options, in the order the device lists them.
+    pub fn groups(&self) -> &[OptionGroup] {
+        &self.groups
+    }
+
+    /// All options, in the order the device lists them.
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceOption> {
+        self.groups.iter().flat_map(|group| group.options.iter())
+    }
+
+    /// The number of options, excluding groups.
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|group| group.options.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Find the option named `name`.
+    pub fn get(&self, name: &str) -> Option<&DeviceOption> {
+        self.iter().find(|option| option.name() == name)
+    }
+
+    /// Find the option with index `index` on the device.
+    pub fn by_index(&self, index: i32) -> Option<&DeviceOption> {
+        self.iter().find(|option| option.index == index)
+    }
+
+    /// Find the group containing the option named `name`.
+    pub fn group_of(&self, name: &str) -> Option<&OptionGroup> {
+        self.groups
+            .iter()
+            .find(|group| group.options.iter().any(|option| option.name() == name))
+    }
+}
+
+impl From<Vec<Option<OptionDescriptor>>> for OptionSet {
+    fn from(descriptors: Vec<Option<OptionDescriptor>>) -> OptionSet {
+        let mut groups = vec![OptionGroup {
+            title: None,
+            options: Vec::new(),
+        }];
+
+        // Option 0 holds the number of options
+        for (index, descriptor) in descriptors.into_iter().enumerate().skip(1) {
+            match descriptor {
+                Some(OptionDescriptor::Group { title, .. }) => groups.push(OptionGroup {
+                    title: Some(title),
+                    options: Vec::new(),
+                }),
+                Some(descriptor) => {
+                    if descriptor.name().is_none() {
+                        warn!("Ignoring option {} without a name", index);
+                        continue;
+                    }
+                    if let Some(group) = groups.last_mut() {
+                        group.options.push(DeviceOption {
+                            index: index as i32,
+                            descriptor,
+                        });
+                    }
+                }
+                None => {}
+            }
+        }
+
+        // Leave out the leading group if no options precede the first group
+        if groups.len() > 1 && groups[0].options.is_empty() {
+            groups.remove(0);
+        }
+
+        OptionSet { groups }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bound {
+    Lowest,
+    Highest,
+}
+
+/// Returns true if `descriptor` describes an active option whose value can be read and set.
+fn is_restorable(descriptor: &OptionDescriptor) -> bool {
+    let capabilities = descriptor.capabilities();
+
+    let has_value = matches!(
+        *descriptor,
+        OptionDescriptor::Boolean { .. }
+            | OptionDescriptor::Integer { .. }
+            | OptionDescriptor::Fixed { .. }
+            | OptionDescriptor::String { .. }
+    );
+
+    has_value
+        && descriptor.name().is_some_and(|name| !name.is_empty())
+        && capabilities.contains(Capabilities::SoftSelect | Capabilities::SoftDetect)
+        && !capabilities.contains(Capabilities::Inactive)
+}
+
+/// The names of the options whose descriptors differ between `old` and `new`.
+fn changed_options(
+    old: &[Option<OptionDescriptor>],
+    new: &[Option<OptionDescriptor>],
+) -> Vec<String> {
+    fn find<'a>(
+        descriptors: &'a [Option<OptionDescriptor>],
+        name: &str,
+    ) -> Option<&'a OptionDescriptor> {
+        descriptors
+            .iter()
+            .flatten()
+            .find(|descriptor| descriptor.name() == Some(name))
+    }
+    let names = |descriptors: &[Option<OptionDescriptor>]| -> Vec<String> {
+        descriptors
+            .iter()
+            .flatten()
+            .filter_map(|descriptor| descriptor.name())
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()
+    };
+
+    let mut changed: Vec<String> = names(new)
+        .into_iter()
+        .filter(|name| find(old, name) != find(new, name))
+        .collect();
+    changed.extend(
+        names(old)
+            .into_iter()
+            .filter(|name| find(new, name).is_none()),
+    );
+    changed
+}
+
+fn constraint_bound<T: Word>(
+    constraint: &Option<NumericalConstraint<T>>,
+    bound: Bound,
+) -> Option<T> {
+    let pick = |a: T, b: T| match bound {
+        Bound::Lowest if b < a => b,
+        Bound::Highest if b > a => b,
+        _ => a,
+    };
+
+    match *constraint {
+        Some(NumericalConstraint::Range(Some(ref range))) => Some(match bound {
+            Bound::Lowest => range.min(),
+            Bound::Highest => range.max(),
+        }),
+        Some(NumericalConstraint::IntegerList(ref list)) => list.iter().cloned().reduce(pick),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::types::{NoConstraint, Range};
+
+    /// An in-memory device whose options have the values in `values`.
+    struct FakeDevice {
+        descriptors: Vec<Option<OptionDescriptor>>,
+        values: RefCell<Vec<OptionValue>>,
+        sets: RefCell<Vec<String>>,
+    }
+
+    impl ControlOption for FakeDevice {
+        fn control_option(
+            &self,
+            index: i32,
+            descriptor: &OptionDescriptor,
+            action: &OptionAction,
+        ) -> Result<(OptionValue, OptionInfo)> {
+            let mut values = self.values.borrow_mut();
+            if let OptionAction::Set(ref value) = *action {
+                self.sets
+                    .borrow_mut()
+                    .push(descriptor.name().unwrap().into());
+                values[index as usize] = value.clone();
+            }
+            Ok((values[index as usize].clone(), OptionInfo::empty()))
+        }
+
+        fn option_descriptors(&self) -> Result<Vec<Option<OptionDescriptor>>> {
+            Ok(self.descriptors.clone())
+        }
+    }
+
+    fn integer(name: &str, constraint: NumericalConstraint) -> Option<OptionDescriptor> {
+        Some(OptionDescriptor::Integer {
+            name: name.into(),
+            title: name.into(),
+            description: name.into(),
+            unit: OptionUnit::None,
+            size: 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(constraint),
+        })
+    }
+
+    fn fixed(name: &str, max: f64) -> Option<OptionDescriptor> {
+        Some(OptionDescriptor::Fixed {
+            name: name.into(),
+            title: name.into(),
+            description: name.into(),
+            unit: OptionUnit::Millimeter,
+            size: 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(NumericalConstraint::Range(Some(Range::new(
+                Fixed::from_int(0),
+                Fixed::from_f64(max),
+                Fixed::from_int(0),
+            )))),
+        })
+    }
+
+    #[test]
+    fn option_set_groups_options_by_title() {
+        let group = |title: &str| {
+            Some(OptionDescriptor::Group {
+                title: title.into(),
+                _no_constrainst: NoConstraint,
+            })
+        };
+        let count = Some(OptionDescriptor::Integer {
+            name: "".into(),
+            title: "Number of options".into(),
+            description: "".into(),
+            unit: OptionUnit::None,
+            size: 4,
+            capabilities: Capabilities::SoftDetect,
+            constraint: None,
+        });
+
+        let set = OptionSet::from(vec![
+            count,
+            group("Standard"),
+            integer("resolution", NumericalConstraint::IntegerList(vec![75, 150])),
+            None,
+            group("Geometry"),
+            fixed("tl-x", 215.9),
+            fixed("br-x", 215.9),
+        ]);
+
+        let titles: Vec<_> = set
+            .groups()
+            .iter()
+            .map(|group| group.title.as_deref())
+            .collect();
+        assert_eq!(titles, vec![Some("Standard"), Some("Geometry")]);
+        assert_eq!(set.len(), 3);
+        assert_eq!(set.get("br-x").unwrap().index, 6);
+        assert_eq!(set.by_index(2).unwrap().name(), "resolution");
+        assert_eq!(
+            set.group_of("tl-x").unwrap().title.as_deref(),
+            Some("Geometry")
+        );
+        assert!(set.get("").is_none());
+    }
+
+    #[test]
+    fn set_snaps_values_to_constraint() {
+        let device = FakeDevice {
+            descriptors: vec![integer(
+                "resolution",
+                NumericalConstraint::Range(Some(Range::new(75, 600, 75))),
+            )],
+            values: RefCell::new(vec![OptionValue::Integer(150)]),
+            sets: RefCell::new(Vec::new()),
+        };
+
+        let mut options = Options::new(&device, device.option_descriptors().unwrap());
+        assert!(options.set("resolution", 307).is_err());
+
+        options.set_snapping(true);
+        let (value, info) = options.set("resolution", 307).unwrap();
+        assert_eq!(value, OptionValue::Integer(300));
+        assert!(info.contains(OptionInfo::Inexact));
+
+        let (_, info) = options.set("resolution", 600).unwrap();
+        assert!(!info.contains(OptionInfo::Inexact));
+    }
+
+    #[test]
+    fn maintenance_helpers_find_options_by_backend_names() {
+        let button = |name: &str| {
+            Some(OptionDescriptor::Button {
+                name: name.into(),
+                title: name.into(),
+                description: name.into(),
+                unit: OptionUnit::None,
+                capabilities: Capabilities::SoftSelect,
+                _no_constrainst: NoConstraint,
+            })
+        };
+        let device = FakeDevice {
+            descriptors: vec![
+                button("calibration"),
+                button("lamp-on"),
+                button("lamp-off"),
+                integer(
+                    "lamp-off-time",
+                    NumericalConstraint::Range(Some(Range::new(0, 60, 0))),
+                ),
+            ],
+            values: RefCell::new(vec![
+                OptionValue::Button,
+                OptionValue::Button,
+                OptionValue::Button,
+                OptionValue::Integer(15),
+            ]),
+            sets: RefCell::new(Vec::new()),
+        };
+
+        let mut options = Options::new(&device, device.option_descriptors().unwrap());
+        options.calibrate().unwrap();
+        options.set_lamp(false).unwrap();
+        options.set_lamp_off_time(5).unwrap();
+        assert!(options.set_lamp_off_time(90).is_err());
+
+        assert_eq!(
+            *device.sets.borrow(),
+            vec!["calibration", "lamp-off", "lamp-off-time"]
+        );
+        assert_eq!(device.values.borrow()[3], OptionValue::Integer(5));
+    }
+
+    #[test]
+    fn preview_uses_lowest_resolution_and_full_area_then_restores() {
+        let device = FakeDevice {
+            descriptors: vec![
+                integer(
+                    "resolution",
+                    NumericalConstraint::IntegerList(vec![300, 75, 150]),
+                ),
+                fixed("tl-x", 215.9),
+                fixed("br-x", 215.9),
+            ],
+            values: RefCell::new(vec![
+                OptionValue::Integer(300),
+                OptionValue::Fixed(Fixed::from_int(10)),
+                OptionValue::Fixed(Fixed::from_int(100)),
+            ]),
+            sets: RefCell::new(Vec::new()),
+        };
+
+        let mut options = Options::new(&device, device.option_descriptors().unwrap());
+        let snapshot = options.snapshot().unwrap();
+
+        options.set_preview().unwrap();
+        assert_eq!(
+            *device.values.borrow(),
+            vec![
+                OptionValue::Integer(75),
+                OptionValue::Fixed(Fixed::from_int(0)),
+                OptionValue::Fixed(Fixed::from_f64(215.9)),
+            ]
+        );
+
+        device.sets.borrow_mut().clear();
+        options.restore(&snapshot).unwrap();
+        assert_eq!(
+            *device.values.borrow(),
+            vec![
+                OptionValue::Integer(300),
+                OptionValue::Fixed(Fixed::from_int(10)),
+                OptionValue::Fixed(Fixed::from_int(100)),
+            ]
+        );
+        assert_eq!(*device.sets.borrow(), vec!["resolution", "tl-x", "br-x"]);
+    }
+
+    #[test]
+    fn scan_area_is_converted_and_clamped() {
+        let device = FakeDevice {
+            descriptors: vec![
+                fixed("tl-x", 215.9),
+                fixed("tl-y", 297.0),
+                fixed("br-x", 215.9),
+                fixed("br-y", 297.0),
+            ],
+            values: RefCell::new(vec![OptionValue::Fixed(Fixed::from_int(0)); 4]),
+            sets: RefCell::new(Vec::new()),
+        };
+        let mut options = Options::new(&device, device.option_descriptors().unwrap());
+
+        let max = options.max_scan_area().unwrap();
+        assert!((max.width - 215.9).abs() < 0.001);
+        assert!((max.height - 297.0).abs() < 0.001);
+
+        let area = options
+            .set_scan_area(ScanArea::from_inches(1.0, 1.0, 4.0, 20.0))
+            .unwrap();
+        let (x, y, width, _) = area.to_inches();
+        assert!((x - 1.0).abs() < 0.001 && (y - 1.0).abs() < 0.001);
+        assert!((width - 4.0).abs() < 0.001);
+        // Clamped to the bottom of the scan bed
+        assert!((area.y + area.height - 297.0).abs() < 0.001);
+        assert_eq!(
+            device.values.borrow()[3],
+            OptionValue::Fixed(Fixed::from_f64(297.0))
+        );
+    }
+
+    /// A device where enabling `preview` limits the resolutions allowed.
+    struct PreviewDevice {
+        preview: RefCell<bool>,
+    }
+
+    impl ControlOption for PreviewDevice {
+        fn control_option(
+            &self,
+            _index: i32,
+            _descriptor: &OptionDescriptor,
+            action: &OptionAction,
+        ) -> Result<(OptionValue, OptionInfo)> {
+            match *action {
+                OptionAction::Set(OptionValue::Boolean(preview)) => {
+                    *self.preview.borrow_mut() = preview;
+                    Ok((
+                        OptionValue::Boolean(preview),
+                        OptionInfo::ReloadOptions | OptionInfo::ReloadParams,
+                    ))
+                }
+                _ => Ok((OptionValue::Integer(150), OptionInfo::empty())),
+            }
+        }
+
+        fn option_descriptors(&self) -> Result<Vec<Option<OptionDescriptor>>> {
+            let resolutions = if *self.preview.borrow() {
+                vec![75]
+            } else {
+                vec![75, 150]
+            };
+            Ok(vec![
+                Some(OptionDescriptor::Boolean {
+                    name: "preview".into(),
+                    title: "Preview".into(),
+                    description: "Request a preview-quality scan.".into(),
+                    unit: OptionUnit::None,
+                    capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+                    _no_constrainst: NoConstraint,
+                }),
+                integer("resolution", NumericalConstraint::IntegerList(resolutions)),
+            ])
+        }
+    }
+
+    /// A device whose `duplex` option is only active when the `source` is the feeder.
+    struct DuplexDevice {
+        values: RefCell<(bool, String)>,
+    }
+
+    impl ControlOption for DuplexDevice {
+        fn control_option(
+            &self,
+            _index: i32,
+            descriptor: &OptionDescriptor,
+            action: &OptionAction,
+        ) -> Result<(OptionValue, OptionInfo)> {
+            let mut values = self.values.borrow_mut();
+            match *action {
+                OptionAction::Set(OptionValue::Boolean(duplex)) => values.0 = duplex,
+                OptionAction::Set(OptionValue::String(ref source)) => {
+                    values.1 = source.clone();
+                    return Ok((source.clone().into(), OptionInfo::ReloadOptions));
+                }
+                _ => {}
+            }
+            match *descriptor {
+                OptionDescriptor::Boolean { .. } => Ok((values.0.into(), OptionInfo::empty())),
+                _ => Ok((values.1.clone().into(), OptionInfo::empty())),
+            }
+        }
+
+        fn option_descriptors(&self) -> Result<Vec<Option<OptionDescriptor>>> {
+            let mut capabilities = Capabilities::SoftSelect | Capabilities::SoftDetect;
+            if self.values.borrow().1 != "ADF" {
+                capabilities |= Capabilities::Inactive;
+            }
+            Ok(vec![
+                Some(OptionDescriptor::Boolean {
+                    name: "duplex".into(),
+                    title: "Duplex".into(),
+                    description: "Scan both sides of the page.".into(),
+                    unit: OptionUnit::None,
+                    capabilities,
+                    _no_constrainst: NoConstraint,
+                }),
+                Some(OptionDescriptor::String {
+                    name: "source".into(),
+                    title: "Source".into(),
+                    description: "Where the page is scanned from.".into(),
+                    unit: OptionUnit::None,
+                    max_length: 16,
+                    capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+                    constraint: None,
+                }),
+            ])
+        }
+    }
+
+    #[test]
+    fn apply_settings_sets_options_once_they_are_active() {
+        let device = DuplexDevice {
+            values: RefCell::new((false, "Flatbed".into())),
+        };
+        let mut options = Options::new(&device, device.option_descriptors().unwrap());
+
+        let report = options
+            .apply_settings(vec![
+                ("duplex", OptionValue::Boolean(true)),
+                ("source", "ADF".to_string().into()),
+            ])
+            .unwrap();
+        assert!(report.rejected.is_empty() && !report.rolled_back);
+        assert_eq!(*device.values.borrow(), (true, "ADF".into()));
+    }
+
+    #[test]
+    fn apply_settings_reports_adjusted_and_rejected_values_and_rolls_back() {
+        let device = FakeDevice {
+            descriptors: vec![
+                integer(
+                    "resolution",
+                    NumericalConstraint::Range(Some(Range::new(75, 600, 75))),
+                ),
+                fixed("tl-x", 215.9),
+            ],
+            values: RefCell::new(vec![
+                OptionValue::Integer(150),
+                OptionValue::Fixed(Fixed::from_int(0)),
+            ]),
+            sets: RefCell::new(Vec::new()),
+        };
+        let mut options = Options::new(&device, device.option_descriptors().unwrap());
+        options.set_snapping(true);
+
+        let report = options.apply_settings(vec![("resolution", 307)]).unwrap();
+        assert_eq!(
+            report.adjusted,
+            vec![("resolution".to_string(), OptionValue::Integer(300))]
+        );
+        assert!(!report.rolled_back);
+
+        options.set_snapping(false);
+        let report = options
+            .apply_settings(vec![
+                ("tl-x", OptionValue::Fixed(Fixed::from_int(10))),
+                ("resolution", OptionValue::Integer(1200)),
+                ("lamp", OptionValue::Boolean(true)),
+            ])
+            .unwrap();
+        let rejected: Vec<_> = report
+            .rejected
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(rejected, ["resolution", "lamp"]);
+        assert!(matches!(report.rejected[1].1, Error::UnknownOption(_)));
+        assert!(report.rolled_back);
+        assert_eq!(
+            *device.values.borrow(),
+            vec![
+                OptionValue::Integer(300),
+                OptionValue::Fixed(Fixed::from_int(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_reloads_changed_options() {
+        let device = PreviewDevice {
+            preview: RefCell::new(false),
+        };
+        let mut options = Options::new(&device, device.option_descriptors().unwrap());
+
+        let (_, info) = options.set("preview", true).unwrap();
+        assert!(info.contains(OptionInfo::ReloadParams));
+        assert_eq!(options.changed(), ["resolution"]);
+        assert!(options.set("resolution", 150).is_err());
+
+        options.set("resolution", 75).unwrap();
+        assert!(options.changed().is_empty());
+    }
+}
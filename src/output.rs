@@ -0,0 +1,324 @@
+#[cfg(feature = "tiff")]
+use std::io::Seek;
+use std::io::Write;
+
+use crate::error::Error;
+use crate::image::Image;
+use crate::scan::ByteOrder;
+use crate::types::FrameFormat;
+use crate::unpack;
+use crate::Result;
+
+/// The layout of the samples of an image, as far as the encoders are concerned.
+struct Layout {
+    color: bool,
+    depth: i32,
+    width: usize,
+    height: usize,
+    /// Number of bytes of sample data in each line, excluding any padding.
+    row_bytes: usize,
+}
+
+fn layout(image: &Image) -> Result<Layout> {
+    let parameters = &image.parameters;
+
+    let color = match parameters.format {
+        // An infrared frame is encoded as a gray image of the infrared channel
+        FrameFormat::Gray | FrameFormat::Infrared => false,
+        FrameFormat::RGB => true,
+        FrameFormat::RGBI | FrameFormat::GrayI => {
+            return Err(Error::ImageEncodingError(
+                "Can't encode an infrared channel; see Image::split_infrared".into(),
+            ))
+        }
+        format => {
+            return Err(Error::ImageEncodingError(format!(
+                "Can't encode a single {:?} frame; assemble the full image first",
+                format
+            )))
+        }
+    };
+
+    if parameters.pixels_per_line < 0 || parameters.bytes_per_line < 0 {
+        return Err(Error::ImageEncodingError(format!(
+            "Invalid image geometry {:?}",
+            parameters
+        )));
+    }
+
+    let width = parameters.pixels_per_line as usize;
+    let samples = width * if color { 3 } else { 1 };
+    let row_bytes = match parameters.depth {
+        1 => samples.div_ceil(8),
+        8 => samples,
+        16 => samples * 2,
+        depth => {
+            return Err(Error::ImageEncodingError(format!(
+                "Unsupported sample depth {}",
+                depth
+            )))
+        }
+    };
+
+    let bytes_per_line = parameters.bytes_per_line as usize;
+    if bytes_per_line < row_bytes {
+        return Err(Error::ImageEncodingError(format!(
+            "{} bytes per line is too few for {} pixels",
+            bytes_per_line, width
+        )));
+    }
+
+    // Go by the data actually received, in case the number of lines wasn't known in advance
+    let height = image.data.len().checked_div(bytes_per_line).unwrap_or(0);
+
+    Ok(Layout {
+        color,
+        depth: parameters.depth,
+        width,
+        height,
+        row_bytes,
+    })
+}
+
+/// The lines of `image`, excluding any padding at the end of each line.
+fn rows<'a>(image: &'a Image, layout: &Layout) -> Vec<&'a [u8]> {
+    let bytes_per_line = image.parameters.bytes_per_line as usize;
+    (0..layout.height)
+        .map(|line| &image.data[line * bytes_per_line..line * bytes_per_line + layout.row_bytes])
+        .collect()
+}
+
+/// Append the 16-bit samples of `row`, converted from host byte order
+/// to big-endian, to `out`.
+fn to_big_endian(row: &[u8], out: &mut Vec<u8>) {
+    let start = out.len();
+    out.extend_from_slice(row);
+    // Swapping the bytes of a sample is its own inverse
+    ByteOrder::BigEndian.to_native(&mut out[start..]);
+}
+
+/// Write `image` in binary PNM format: PBM for lineart, PGM for gray-scale
+/// and PPM for color images.
+///
+/// 16-bit samples are expected to be in host byte order.
+/// Color lineart images are written as 8-bit PPM.
+pub fn write_pnm<W: Write>(image: &Image, writer: &mut W) -> Result<()> {
+    let layout = layout(image)?;
+
+    match (layout.color, layout.depth) {
+        (false, 1) => write!(writer, "P4\n{} {}\n", layout.width, layout.height)?,
+        (false, depth) => write!(
+            writer,
+            "P5\n{} {}\n{}\n",
+            layout.width,
+            layout.height,
+            (1u32 << depth) - 1
+        )?,
+        (true, 1) => write!(writer, "P6\n{} {}\n255\n", layout.width, layout.height)?,
+        (true, depth) => write!(
+            writer,
+            "P6\n{} {}\n{}\n",
+            layout.width,
+            layout.height,
+            (1u32 << depth) - 1
+        )?,
+    }
+
+    // Rows that need converting are converted into the same buffer, one at a time
+    let mut converted = Vec::new();
+    for row in rows(image, &layout) {
+        converted.clear();
+        match (layout.color, layout.depth) {
+            // SANE and PBM agree that a set bit is black
+            (false, 1) => writer.write_all(row)?,
+            (true, 1) => {
+                unpack::expand_bits(row, layout.width * 3, 0xff, 0x00, &mut converted);
+                writer.write_all(&converted)?
+            }
+            (_, 16) => {
+                to_big_endian(row, &mut converted);
+                writer.write_all(&converted)?
+            }
+            _ => writer.write_all(row)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Write `image` in PNG format.
+///
+/// 16-bit samples are expected to be in host byte order.
+/// Color lineart images are written with 8-bit samples.
+#[cfg(feature = "png")]
+pub fn write_png<W: Write>(image: &Image, writer: W) -> Result<()> {
+    let layout = layout(image)?;
+
+    let mut encoder = ::png::Encoder::new(writer, layout.width as u32, layout.height as u32);
+    encoder.set_color(if layout.color {
+        ::png::ColorType::Rgb
+    } else {
+        ::png::ColorType::Grayscale
+    });
+    encoder.set_depth(match (layout.color, layout.depth) {
+        (false, 1) => ::png::BitDepth::One,
+        (_, 16) => ::png::BitDepth::Sixteen,
+        _ => ::png::BitDepth::Eight,
+    });
+
+    let mut data = Vec::with_capacity(image.data.len());
+    for row in rows(image, &layout) {
+        match (layout.color, layout.depth) {
+            // A set bit is black in SANE, but white in PNG
+            (false, 1) => data.extend(row.iter().map(|byte| !byte)),
+            (true, 1) => unpack::expand_bits(row, layout.width * 3, 0xff, 0x00, &mut data),
+            (_, 16) => to_big_endian(row, &mut data),
+            _ => data.extend_from_slice(row),
+        }
+    }
+
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| Error::ImageEncodingError(e.to_string()))?;
+    writer
+        .write_image_data(&data)
+        .map_err(|e| Error::ImageEncodingError(e.to_string()))
+}
+
+/// Write `image` in TIFF format.
+///
+/// 16-bit samples are expected to be in host byte order.
+/// Lineart images are written with 8-bit samples.
+#[cfg(feature = "tiff")]
+pub fn write_tiff<W: Write + Seek>(image: &Image, writer: &mut W) -> Result<()> {
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    let layout = layout(image)?;
+    let (width, height) = (layout.width as u32, layout.height as u32);
+
+    let rows = rows(image, &layout);
+    let samples = layout.width * if layout.color { 3 } else { 1 };
+
+    let mut encoder =
+        TiffEncoder::new(writer).map_err(|e| Error::ImageEncodingError(e.to_string()))?;
+
+    let result = match layout.depth {
+        1 => {
+            // A set bit is black in SANE; expand to 8-bit samples
+            let (set, unset) = if layout.color {
+                (0xff, 0x00)
+            } else {
+                (0x00, 0xff)
+            };
+            let mut data = Vec::with_capacity(samples * rows.len());
+            for row in &rows {
+                unpack::expand_bits(row, samples, set, unset, &mut data);
+            }
+
+            if layout.color {
+                encoder.write_image::<colortype::RGB8>(width, height, &data)
+            } else {
+                encoder.write_image::<colortype::Gray8>(width, height, &data)
+            }
+        }
+        16 => {
+            let data: Vec<u16> = rows
+                .iter()
+                .flat_map(|row| row.chunks(2))
+                .map(|sample| u16::from_ne_bytes([sample[0], sample[1]]))
+                .collect();
+
+            if layout.color {
+                encoder.write_image::<colortype::RGB16>(width, height, &data)
+            } else {
+                encoder.write_image::<colortype::Gray16>(width, height, &data)
+            }
+        }
+        _ => {
+            let data: Vec<u8> = rows.concat();
+
+            if layout.color {
+                encoder.write_image::<colortype::RGB8>(width, height, &data)
+            } else {
+                encoder.write_image::<colortype::Gray8>(width, height, &data)
+            }
+        }
+    };
+
+    result.map_err(|e| Error::ImageEncodingError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Parameters;
+
+    fn image(format: FrameFormat, depth: i32, bytes_per_line: i32, data: Vec<u8>) -> Image {
+        Image::new(
+            Parameters {
+                format,
+                last_frame: true,
+                bytes_per_line,
+                pixels_per_line: 2,
+                lines: 2,
+                depth,
+            },
+            data,
+        )
+    }
+
+    #[test]
+    fn writes_gray_pgm() {
+        let mut out = Vec::new();
+        write_pnm(&image(FrameFormat::Gray, 8, 2, vec![1, 2, 3, 4]), &mut out).unwrap();
+
+        assert_eq!(out, b"P5\n2 2\n255\n\x01\x02\x03\x04".to_vec());
+    }
+
+    #[test]
+    fn writes_lineart_pbm_without_padding() {
+        let mut out = Vec::new();
+        let image = image(
+            FrameFormat::Gray,
+            1,
+            2,
+            vec![0b1000_0000, 0xaa, 0b0100_0000, 0xbb],
+        );
+        write_pnm(&image, &mut out).unwrap();
+
+        assert_eq!(out, b"P4\n2 2\n\x80\x40".to_vec());
+    }
+
+    #[test]
+    fn writes_16_bit_ppm_big_endian() {
+        let mut data = Vec::new();
+        for sample in 0..12u16 {
+            data.extend_from_slice(&(sample * 0x0101 + 1).to_ne_bytes());
+        }
+
+        let mut out = Vec::new();
+        write_pnm(&image(FrameFormat::RGB, 16, 12, data), &mut out).unwrap();
+
+        let header = b"P6\n2 2\n65535\n";
+        assert!(out.starts_with(header));
+        assert_eq!(
+            &out[header.len()..header.len() + 4],
+            &[0x00, 0x01, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn rejects_single_color_frame() {
+        let mut out = Vec::new();
+        assert!(write_pnm(&image(FrameFormat::Red, 8, 2, vec![0; 4]), &mut out).is_err());
+    }
+
+    #[cfg(feature = "png")]
+    #[test]
+    fn writes_png() {
+        let mut out = Vec::new();
+        write_png(&image(FrameFormat::Gray, 1, 1, vec![0x80, 0x40]), &mut out).unwrap();
+
+        assert!(out.starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+}
@@ -0,0 +1,744 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::io::{self, Read};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::error::Error;
+use crate::status::Status;
+use crate::types::Parameters;
+use crate::unpack;
+use crate::Result;
+
+/// Record length sent on the data connection in place of a regular record,
+/// to signal the end of the image data.
+const END_OF_DATA: u32 = 0xffffffff;
+
+/// The most memory reserved up front for the data of a frame.
+/// Frames are larger than this at high resolutions, but the size comes from
+/// the network, so the rest is only allocated as data arrives.
+const MAX_FRAME_CAPACITY: usize = 64 << 20;
+
+/// The memory to reserve for the data of the frame described by `parameters`:
+/// its size, if its number of lines is known in advance, up to `MAX_FRAME_CAPACITY`.
+pub(crate) fn frame_capacity(parameters: &Parameters) -> usize {
+    if parameters.lines <= 0 || parameters.bytes_per_line <= 0 {
+        return 0;
+    }
+    cmp::min(
+        parameters.lines as usize * parameters.bytes_per_line as usize,
+        MAX_FRAME_CAPACITY,
+    )
+}
+
+/// Byte order of multi-byte samples in the image data,
+/// as announced by the server when a scan is started.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrder {
+    /// Convert the byte order word sent in the reply to SANE_NET_START.
+    pub(crate) fn from_word(word: i32) -> Result<ByteOrder> {
+        match word {
+            0x1234 => Ok(ByteOrder::LittleEndian),
+            0x4321 => Ok(ByteOrder::BigEndian),
+            x => Err(Error::InvalidSaneFieldValue(
+                "Received invalid value for byte order field".into(),
+                x,
+            )),
+        }
+    }
+
+    /// The byte order of the host.
+    pub fn native() -> ByteOrder {
+        if cfg!(target_endian = "little") {
+            ByteOrder::LittleEndian
+        } else {
+            ByteOrder::BigEndian
+        }
+    }
+
+    /// Convert the 16-bit samples in `data` from this byte order to host byte order.
+    /// A trailing odd byte is left as is.
+    pub fn to_native(self, data: &mut [u8]) {
+        if self == ByteOrder::native() {
+            return;
+        }
+
+        unpack::swap_bytes16(data);
+    }
+}
+
+/// An event reported while the image data of a scan is read.
+/// See `ScanData::set_progress`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanEvent {
+    /// Reading the data of a frame described by `parameters` has started.
+    ScanStarted { parameters: Parameters },
+    /// Image data was read. `bytes` is the number of bytes of the frame read so far,
+    /// and `total` the size of the frame, if its number of lines is known in advance.
+    DataRead { bytes: usize, total: Option<usize> },
+    /// All data of the frame has been read.
+    PageDone,
+    /// The scan was cancelled before all of its data was read.
+    Cancelled,
+    /// The scan ended with an error status, which is also returned by the read.
+    Error(Status),
+}
+
+/// Statistics of a completed scan, for tracking the performance
+/// of devices and the network over time.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScanStats {
+    /// Number of bytes of image data received, excluding the record framing.
+    pub bytes: usize,
+    /// Number of records the image data was received in.
+    pub records: usize,
+    /// Number of frames the image was assembled from.
+    pub frames: usize,
+    /// Time from starting the scan until it was completed.
+    pub duration: Duration,
+    /// Number of times the scan was made again after reconnecting to saned.
+    pub retries: u32,
+}
+
+impl ScanStats {
+    /// The rate image data was received at, in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.duration.as_secs_f64();
+        if seconds > 0.0 {
+            self.bytes as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+/// A callback receiving the events of a scan.
+pub type ProgressCallback = Box<dyn FnMut(&ScanEvent) + Send>;
+
+struct Progress {
+    callback: ProgressCallback,
+    /// Number of bytes of image data read so far.
+    bytes: usize,
+    total: Option<usize>,
+}
+
+/// The image data of a scan, read from the data connection opened
+/// after a successful call to `start`.
+///
+/// saned sends the data as a sequence of records, each prefixed by its length.
+/// The end of the data is marked by a record length of `0xffffffff`,
+/// followed by a single byte holding the final status of the scan.
+///
+/// The data can be read record by record, or through the `Read` implementation,
+/// which strips the record framing and presents the data as a continuous stream.
+pub struct ScanData<R: Read> {
+    connection: R,
+    byte_order: ByteOrder,
+    finished: bool,
+    /// Number of bytes of the current record not yet read.
+    remaining: usize,
+    progress: Option<Progress>,
+    /// Number of bytes of image data, and of records, read so far.
+    bytes_read: usize,
+    records_read: usize,
+}
+
+impl<R: Read> ScanData<R> {
+    pub fn new(connection: R, byte_order: ByteOrder) -> ScanData<R> {
+        ScanData {
+            connection,
+            byte_order,
+            finished: false,
+            remaining: 0,
+            progress: None,
+            bytes_read: 0,
+            records_read: 0,
+        }
+    }
+
+    /// Report the progress of reading the data of the frame described by
+    /// `parameters` to `callback`, starting with `ScanEvent::ScanStarted`.
+    ///
+    /// Events are reported as the data is read, through any of the reading methods.
+    pub fn set_progress(&mut self, parameters: &Parameters, callback: ProgressCallback) {
+        let total = if parameters.lines >= 0 && parameters.bytes_per_line >= 0 {
+            Some(parameters.lines as usize * parameters.bytes_per_line as usize)
+        } else {
+            None
+        };
+
+        self.progress = Some(Progress {
+            callback,
+            bytes: 0,
+            total,
+        });
+        self.report(ScanEvent::ScanStarted {
+            parameters: parameters.clone(),
+        });
+    }
+
+    /// Stop reporting progress, returning the callback set by `set_progress`
+    /// so it can be used for the next frame.
+    pub fn take_progress(&mut self) -> Option<ProgressCallback> {
+        self.progress.take().map(|progress| progress.callback)
+    }
+
+    pub(crate) fn report(&mut self, event: ScanEvent) {
+        if let Some(ref mut progress) = self.progress {
+            (progress.callback)(&event);
+        }
+    }
+
+    fn report_read(&mut self, read: usize) {
+        self.bytes_read += read;
+        if let Some(ref mut progress) = self.progress {
+            progress.bytes += read;
+            (progress.callback)(&ScanEvent::DataRead {
+                bytes: progress.bytes,
+                total: progress.total,
+            });
+        }
+    }
+
+    /// The byte order of multi-byte samples in the data.
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+
+    /// Returns true once the end of the data has been reached.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The number of bytes of image data read so far, excluding the record framing.
+    pub fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+
+    /// The number of records started so far.
+    pub fn records_read(&self) -> usize {
+        self.records_read
+    }
+
+    /// Read the length of the next record.
+    ///
+    /// Returns false once the end of the data has been reached. If the scan
+    /// ended with any status other than `Status::EndOfFile`, that status is returned
+    /// as an error instead.
+    fn start_record(&mut self) -> Result<bool> {
+        if self.finished {
+            return Ok(false);
+        }
+
+        let length = self.connection.read_u32::<BigEndian>()?;
+
+        if length == END_OF_DATA {
+            self.finished = true;
+
+            let status = Status::try_from(i32::from(self.connection.read_u8()?))?;
+            debug!("Reached end of scan data with status {:?}", status);
+
+            return match status {
+                Status::EndOfFile => {
+                    self.report(ScanEvent::PageDone);
+                    Ok(false)
+                }
+                Status::Canceled => {
+                    self.report(ScanEvent::Cancelled);
+                    Err(status.into())
+                }
+                err => {
+                    self.report(ScanEvent::Error(err));
+                    Err(err.into())
+                }
+            };
+        }
+
+        trace!("Reading record of {} bytes", length);
+        self.remaining = length as usize;
+        self.records_read += 1;
+        Ok(true)
+    }
+
+    /// Read the next record of image data, or whatever is left of the current
+    /// record if it has been partially read through the `Read` implementation.
+    ///
+    /// Returns `Ok(None)` once the end of the data has been reached. If the scan
+    /// ended with any status other than `Status::EndOfFile`, that status is returned
+    /// as an error instead.
+    pub fn next_record(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut record = Vec::new();
+        Ok(self.read_record_into(&mut record)?.map(|_| record))
+    }
+
+    /// Append the next record of image data to `buf`, like `next_record`,
+    /// returning its length, or `Ok(None)` once the end of the data has been reached.
+    ///
+    /// The data is read from the connection straight into `buf`, so reusing a buffer
+    /// across records, or reading a whole frame into one, copies each byte only once.
+    pub fn read_record_into(&mut self, buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        if self.remaining == 0 && !self.start_record()? {
+            return Ok(None);
+        }
+
+        // The length comes from the network, so only allocate as data arrives
+        let length = self.remaining;
+        let read = (&mut self.connection)
+            .take(length as u64)
+            .read_to_end(buf)?;
+        self.remaining -= read;
+        self.report_read(read);
+        if read != length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Data connection closed in the middle of a record",
+            )
+            .into());
+        }
+        Ok(Some(read))
+    }
+
+    /// Read image data into `buf`, across record boundaries.
+    /// Returns the number of bytes read, which is 0 only at the end of the data.
+    fn read_data(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.remaining == 0 {
+            if !self.start_record()? {
+                return Ok(0);
+            }
+        }
+
+        let max = cmp::min(buf.len(), self.remaining);
+        let read = self.connection.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Data connection closed in the middle of a record",
+            )
+            .into());
+        }
+
+        self.remaining -= read;
+        self.report_read(read);
+        Ok(read)
+    }
+
+    /// Read the image data line by line, where each line
+    /// is `parameters.bytes_per_line` long.
+    ///
+    /// If `parameters.depth` is 16, samples are converted to host byte order.
+    pub fn lines(self, parameters: &Parameters) -> Result<ScanLines<R>> {
+        if parameters.bytes_per_line <= 0 {
+            return Err(Error::BadNetworkDataError(format!(
+                "Invalid bytes per line {}",
+                parameters.bytes_per_line
+            )));
+        }
+
+        Ok(ScanLines {
+            data: self,
+            bytes_per_line: parameters.bytes_per_line as usize,
+            wide_samples: parameters.depth == 16,
+            index: 0,
+            done: false,
+        })
+    }
+
+    /// Read all remaining image data, with the record framing removed.
+    pub fn read_all(&mut self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_all_into(&mut data)?;
+        Ok(data)
+    }
+
+    /// Append all remaining image data to `buf`, with the record framing removed,
+    /// returning the number of bytes appended.
+    pub fn read_all_into(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let start = buf.len();
+        while self.read_record_into(buf)?.is_some() {}
+        Ok(buf.len() - start)
+    }
+
+    /// Read all remaining image data of the frame described by `parameters`.
+    ///
+    /// Unlike `read_all`, which returns the data as sent, 16-bit samples are
+    /// converted to host byte order, as expected by `Image` and the encoders in `output`.
+    pub fn read_frame(&mut self, parameters: &Parameters) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(frame_capacity(parameters));
+        self.read_all_into(&mut data)?;
+        if parameters.depth == 16 {
+            self.byte_order.to_native(&mut data);
+        }
+        Ok(data)
+    }
+
+    /// Consume the reader, returning the underlying data connection.
+    pub fn into_inner(self) -> R {
+        self.connection
+    }
+}
+
+impl<R: Read> Read for ScanData<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_data(buf).map_err(|e| match e {
+            Error::IOError(e) | Error::Timeout(e) => e,
+            e => io::Error::other(e),
+        })
+    }
+}
+
+/// A single line of image data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanLine {
+    /// The number of the line within the frame, starting at 0.
+    pub index: usize,
+    pub data: Vec<u8>,
+}
+
+/// Iterator over the lines of image data of a scan, returned by `ScanData::lines`.
+pub struct ScanLines<R: Read> {
+    data: ScanData<R>,
+    bytes_per_line: usize,
+    /// Set if samples are 16 bits wide, and must be converted to host byte order.
+    wide_samples: bool,
+    index: usize,
+    /// Set once the end of the data or an error has been reached.
+    done: bool,
+}
+
+impl<R: Read> ScanLines<R> {
+    /// Consume the iterator, returning the underlying reader.
+    pub fn into_inner(self) -> ScanData<R> {
+        self.data
+    }
+
+    /// Read the next line into `line`, replacing its contents, and return its number,
+    /// or `Ok(None)` at the end of the data.
+    ///
+    /// Unlike the `Iterator` implementation, which allocates each line,
+    /// this lets a single buffer be reused for every line of the frame.
+    pub fn read_line_into(&mut self, line: &mut Vec<u8>) -> Result<Option<usize>> {
+        line.resize(self.bytes_per_line, 0);
+        let mut filled = 0;
+
+        while filled < line.len() {
+            match self.data.read_data(&mut line[filled..])? {
+                0 if filled == 0 => return Ok(None),
+                0 => {
+                    return Err(Error::BadNetworkDataError(format!(
+                        "Scan data ended part way through line {}",
+                        self.index
+                    )))
+                }
+                read => filled += read,
+            }
+        }
+
+        if self.wide_samples {
+            self.data.byte_order.to_native(line);
+        }
+
+        let index = self.index;
+        self.index += 1;
+        Ok(Some(index))
+    }
+
+    fn read_line(&mut self) -> Result<Option<ScanLine>> {
+        let mut data = Vec::new();
+        Ok(self
+            .read_line_into(&mut data)?
+            .map(|index| ScanLine { index, data }))
+    }
+}
+
+impl<R: Read> Iterator for ScanLines<R> {
+    type Item = Result<ScanLine>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.read_line() {
+            Ok(Some(line)) => Some(Ok(line)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::types::FrameFormat;
+
+    fn record(buf: &mut Vec<u8>, data: &[u8]) {
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+    }
+
+    fn end_of_data(buf: &mut Vec<u8>, status: u8) {
+        buf.extend_from_slice(&END_OF_DATA.to_be_bytes());
+        buf.push(status);
+    }
+
+    #[test]
+    fn reads_records_until_end_of_data() {
+        let mut buf = Vec::new();
+        record(&mut buf, &[1, 2, 3]);
+        record(&mut buf, &[]);
+        record(&mut buf, &[4]);
+        end_of_data(&mut buf, 5);
+
+        let mut data = ScanData::new(Cursor::new(buf), ByteOrder::BigEndian);
+        assert_eq!(data.read_all().unwrap(), vec![1, 2, 3, 4]);
+        assert!(data.is_finished());
+        assert_eq!(data.next_record().unwrap(), None);
+        assert_eq!((data.bytes_read(), data.records_read()), (4, 3));
+    }
+
+    #[test]
+    fn returns_final_status_as_error() {
+        let mut buf = Vec::new();
+        record(&mut buf, &[1, 2, 3]);
+        end_of_data(&mut buf, 6);
+
+        let mut data = ScanData::new(Cursor::new(buf), ByteOrder::LittleEndian);
+        assert_eq!(data.next_record().unwrap(), Some(vec![1, 2, 3]));
+        match data.next_record() {
+            Err(Error::SanedError(Status::Jammed)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_record_is_an_error() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&0x7fff_fff0u32.to_be_bytes());
+        buf.extend_from_slice(&[1, 2, 3]);
+
+        let mut data = ScanData::new(Cursor::new(buf), ByteOrder::BigEndian);
+        match data.next_record() {
+            Err(Error::IOError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_progress_events() {
+        let mut buf = Vec::new();
+        record(&mut buf, &[1, 2, 3]);
+        record(&mut buf, &[4]);
+        end_of_data(&mut buf, 5);
+
+        let parameters = Parameters {
+            format: FrameFormat::Gray,
+            last_frame: true,
+            bytes_per_line: 2,
+            pixels_per_line: 2,
+            lines: 2,
+            depth: 8,
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        let mut data = ScanData::new(Cursor::new(buf), ByteOrder::BigEndian);
+        data.set_progress(
+            &parameters,
+            Box::new(move |event: &ScanEvent| sender.send(event.clone()).unwrap()),
+        );
+        data.read_all().unwrap();
+        drop(data);
+
+        assert_eq!(
+            receiver.iter().collect::<Vec<_>>(),
+            vec![
+                ScanEvent::ScanStarted { parameters },
+                ScanEvent::DataRead {
+                    bytes: 3,
+                    total: Some(4),
+                },
+                ScanEvent::DataRead {
+                    bytes: 4,
+                    total: Some(4),
+                },
+                ScanEvent::PageDone,
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_error_status() {
+        let mut buf = Vec::new();
+        end_of_data(&mut buf, 8);
+
+        let (sender, receiver) = mpsc::channel();
+        let mut data = ScanData::new(Cursor::new(buf), ByteOrder::BigEndian);
+        data.set_progress(
+            &Parameters {
+                format: FrameFormat::Gray,
+                last_frame: true,
+                bytes_per_line: 2,
+                pixels_per_line: 2,
+                lines: -1,
+                depth: 8,
+            },
+            Box::new(move |event: &ScanEvent| sender.send(event.clone()).unwrap()),
+        );
+        assert!(data.read_all().is_err());
+        drop(data);
+
+        assert_eq!(
+            receiver.iter().last(),
+            Some(ScanEvent::Error(Status::CoverOpen))
+        );
+    }
+
+    #[test]
+    fn read_strips_record_framing() {
+        let mut buf = Vec::new();
+        record(&mut buf, &[1, 2, 3]);
+        record(&mut buf, &[4, 5]);
+        end_of_data(&mut buf, 5);
+
+        let mut data = ScanData::new(Cursor::new(buf), ByteOrder::BigEndian);
+        let mut first = [0u8; 2];
+        data.read_exact(&mut first).unwrap();
+        assert_eq!(first, [1, 2]);
+
+        // The rest of a partially read record is returned by next_record
+        assert_eq!(data.next_record().unwrap(), Some(vec![3]));
+
+        let mut rest = Vec::new();
+        data.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, vec![4, 5]);
+    }
+
+    #[test]
+    fn wide_samples_are_converted_to_host_order() {
+        let mut buf = Vec::new();
+        record(&mut buf, &[0x12, 0x34, 0x56]);
+        record(&mut buf, &[0x78]);
+        end_of_data(&mut buf, 5);
+
+        let parameters = Parameters {
+            format: FrameFormat::Gray,
+            last_frame: true,
+            bytes_per_line: 4,
+            pixels_per_line: 2,
+            lines: 1,
+            depth: 16,
+        };
+        let expected: Vec<u8> = [0x1234u16, 0x5678]
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes().to_vec())
+            .collect();
+
+        let mut data = ScanData::new(Cursor::new(buf.clone()), ByteOrder::BigEndian);
+        assert_eq!(data.read_frame(&parameters).unwrap(), expected);
+
+        let data = ScanData::new(Cursor::new(buf.clone()), ByteOrder::BigEndian);
+        let line = data.lines(&parameters).unwrap().next().unwrap().unwrap();
+        assert_eq!(line.data, expected);
+
+        // 8-bit samples are left alone
+        let parameters = Parameters {
+            depth: 8,
+            ..parameters
+        };
+        let mut data = ScanData::new(Cursor::new(buf), ByteOrder::LittleEndian);
+        assert_eq!(
+            data.read_frame(&parameters).unwrap(),
+            vec![0x12, 0x34, 0x56, 0x78]
+        );
+    }
+
+    #[test]
+    fn lines_span_records() {
+        let mut buf = Vec::new();
+        record(&mut buf, &[1, 2, 3]);
+        record(&mut buf, &[4, 5, 6, 7]);
+        record(&mut buf, &[8]);
+        end_of_data(&mut buf, 5);
+
+        let parameters = Parameters {
+            format: crate::types::FrameFormat::Gray,
+            last_frame: true,
+            bytes_per_line: 4,
+            pixels_per_line: 4,
+            lines: 2,
+            depth: 8,
+        };
+
+        let data = ScanData::new(Cursor::new(buf), ByteOrder::BigEndian);
+        let lines: Vec<ScanLine> = data
+            .lines(&parameters)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+
+        assert_eq!(
+            lines,
+            vec![
+                ScanLine {
+                    index: 0,
+                    data: vec![1, 2, 3, 4],
+                },
+                ScanLine {
+                    index: 1,
+                    data: vec![5, 6, 7, 8],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn records_and_lines_are_read_into_reused_buffers() {
+        let mut buf = Vec::new();
+        record(&mut buf, &[1, 2, 3]);
+        record(&mut buf, &[4, 5]);
+        end_of_data(&mut buf, 5);
+
+        let mut data = ScanData::new(Cursor::new(buf.clone()), ByteOrder::BigEndian);
+        let mut frame = vec![0];
+        assert_eq!(data.read_record_into(&mut frame).unwrap(), Some(3));
+        assert_eq!(data.read_all_into(&mut frame).unwrap(), 2);
+        assert_eq!(frame, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(data.read_record_into(&mut frame).unwrap(), None);
+
+        let parameters = Parameters {
+            format: FrameFormat::Gray,
+            last_frame: true,
+            bytes_per_line: 1,
+            pixels_per_line: 1,
+            lines: 5,
+            depth: 8,
+        };
+        let data = ScanData::new(Cursor::new(buf), ByteOrder::BigEndian);
+        let mut lines = data.lines(&parameters).unwrap();
+        let mut line = Vec::new();
+        let mut read = Vec::new();
+        while let Some(index) = lines.read_line_into(&mut line).unwrap() {
+            read.push((index, line[0]));
+        }
+        assert_eq!(read, vec![(0, 1), (1, 2), (2, 3), (3, 4), (4, 5)]);
+    }
+}
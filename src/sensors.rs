@@ -0,0 +1,239 @@
+//! Watching the buttons and sensors of a device, for instance to start a scan
+//! when the scan button on the device is pressed.
+//!
+//! Buttons and sensors are read-only options, which saned can only report
+//! by being asked for their values, so they are polled.
+
+use std::thread;
+use std::time::Duration;
+
+use crate::options::{ControlOption, Options};
+use crate::types::{Capabilities, OptionDescriptor, OptionValue};
+use crate::Result;
+
+/// Names of the buttons and sensors defined by the SANE standard.
+/// Backends name other buttons `button-*`.
+const WELL_KNOWN_SENSORS: [&str; 8] = [
+    "scan",
+    "email",
+    "faxsend",
+    "copy",
+    "pdf",
+    "cancel",
+    "page-loaded",
+    "cover-open",
+];
+
+/// Returns true if `descriptor` describes an active button or sensor:
+/// a read-only option with a well-known sensor name or a name starting with `button-`.
+pub fn is_sensor(descriptor: &OptionDescriptor) -> bool {
+    let capabilities = descriptor.capabilities();
+    let read_only = capabilities.contains(Capabilities::SoftDetect)
+        && !capabilities.intersects(Capabilities::SoftSelect | Capabilities::Inactive);
+
+    let has_value = matches!(
+        *descriptor,
+        OptionDescriptor::Boolean { .. }
+            | OptionDescriptor::Integer { .. }
+            | OptionDescriptor::Fixed { .. }
+            | OptionDescriptor::String { .. }
+    );
+
+    read_only
+        && has_value
+        && descriptor
+            .name()
+            .is_some_and(|name| name.starts_with("button-") || WELL_KNOWN_SENSORS.contains(&name))
+}
+
+/// The value of a button or sensor changed between two polls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorEvent {
+    /// The name of the option.
+    pub name: String,
+    /// The value on the previous poll.
+    pub old: OptionValue,
+    /// The value read on this poll.
+    pub value: OptionValue,
+}
+
+impl SensorEvent {
+    /// Returns true if the event is a button being pressed,
+    /// or a boolean sensor becoming true.
+    pub fn is_pressed(&self) -> bool {
+        self.value == OptionValue::Boolean(true)
+    }
+}
+
+/// Polls the buttons and sensors of a device, reporting the ones whose value changed.
+#[derive(Debug, Clone)]
+pub struct SensorWatcher {
+    interval: Duration,
+    /// The value of each sensor on the last poll.
+    values: Vec<(String, OptionValue)>,
+}
+
+impl SensorWatcher {
+    /// A watcher polling every `interval` when run with `watch`.
+    pub fn new(interval: Duration) -> SensorWatcher {
+        SensorWatcher {
+            interval,
+            values: Vec::new(),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Read all buttons and sensors of the device once, returning an event for
+    /// each whose value changed since the last poll.
+    ///
+    /// The first poll of a sensor only records its value, so doesn't report it.
+    pub fn poll<H: ControlOption>(&mut self, options: &Options<'_, H>) -> Result<Vec<SensorEvent>> {
+        let names: Vec<&str> = options
+            .descriptors()
+            .iter()
+            .flatten()
+            .filter(|descriptor| is_sensor(descriptor))
+            .filter_map(|descriptor| descriptor.name())
+            .collect();
+
+        let mut events = Vec::new();
+        let mut values = Vec::with_capacity(names.len());
+        for name in names {
+            let value = options.get(name)?;
+
+            let old = self
+                .values
+                .iter()
+                .find(|(known, _)| known == name)
+                .map(|(_, old)| old);
+            if let Some(old) = old {
+                if *old != value {
+                    debug!("Sensor {} changed from {:?} to {:?}", name, old, value);
+                    events.push(SensorEvent {
+                        name: name.into(),
+                        old: old.clone(),
+                        value: value.clone(),
+                    });
+                }
+            }
+
+            values.push((name.into(), value));
+        }
+
+        self.values = values;
+        Ok(events)
+    }
+
+    /// Poll the device every interval, passing each change to `callback`,
+    /// until `callback` returns false or polling fails.
+    ///
+    /// This is the only way to stop watching: on a device without buttons or
+    /// sensors, or whose sensors never change, it polls forever without calling
+    /// `callback`. Use `poll` to decide when to stop some other way.
+    pub fn watch<H, F>(&mut self, options: &Options<'_, H>, mut callback: F) -> Result<()>
+    where
+        H: ControlOption,
+        F: FnMut(&SensorEvent) -> bool,
+    {
+        loop {
+            for event in self.poll(options)? {
+                if !callback(&event) {
+                    return Ok(());
+                }
+            }
+            thread::sleep(self.interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::types::{NoConstraint, OptionAction, OptionInfo, OptionUnit};
+
+    /// A device whose scan button is pressed on the third time it is read.
+    struct ButtonDevice {
+        reads: Cell<u32>,
+    }
+
+    impl ControlOption for ButtonDevice {
+        fn control_option(
+            &self,
+            _: i32,
+            _: &OptionDescriptor,
+            _: &OptionAction,
+        ) -> Result<(OptionValue, OptionInfo)> {
+            self.reads.set(self.reads.get() + 1);
+            let pressed = self.reads.get() == 3;
+            Ok((OptionValue::Boolean(pressed), OptionInfo::empty()))
+        }
+
+        fn option_descriptors(&self) -> Result<Vec<Option<OptionDescriptor>>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn boolean(name: &str, capabilities: Capabilities) -> Option<OptionDescriptor> {
+        Some(OptionDescriptor::Boolean {
+            name: name.into(),
+            title: name.into(),
+            description: name.into(),
+            unit: OptionUnit::None,
+            capabilities,
+            _no_constrainst: NoConstraint,
+        })
+    }
+
+    #[test]
+    fn only_read_only_buttons_and_sensors_are_watched() {
+        let read_only = Capabilities::SoftDetect | Capabilities::HardSelect;
+        let settable = Capabilities::SoftDetect | Capabilities::SoftSelect;
+
+        assert!(is_sensor(&boolean("scan", read_only).unwrap()));
+        assert!(is_sensor(&boolean("button-film", read_only).unwrap()));
+        assert!(!is_sensor(&boolean("preview", read_only).unwrap()));
+        assert!(!is_sensor(&boolean("button-film", settable).unwrap()));
+        assert!(!is_sensor(
+            &boolean("scan", read_only | Capabilities::Inactive).unwrap()
+        ));
+    }
+
+    #[test]
+    fn poll_reports_changed_values() {
+        let device = ButtonDevice {
+            reads: Cell::new(0),
+        };
+        let descriptors = vec![
+            None,
+            boolean(
+                "preview",
+                Capabilities::SoftDetect | Capabilities::SoftSelect,
+            ),
+            boolean("scan", Capabilities::SoftDetect),
+        ];
+        let options = Options::new(&device, descriptors);
+
+        let mut events = Vec::new();
+        SensorWatcher::new(Duration::from_millis(0))
+            .watch(&options, |event| {
+                events.push(event.clone());
+                !event.is_pressed()
+            })
+            .unwrap();
+
+        assert_eq!(
+            events,
+            vec![SensorEvent {
+                name: "scan".into(),
+                old: OptionValue::Boolean(false),
+                value: OptionValue::Boolean(true),
+            }]
+        );
+        assert_eq!(device.reads.get(), 3);
+    }
+}
@@ -0,0 +1,183 @@
+//! Serde implementations for types whose representation can't be derived:
+//! fixed-point numbers are serialized as floating-point numbers,
+//! and flags as lists of flag names.
+
+use std::fmt;
+
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::{SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Capabilities, Fixed, OptionInfo};
+
+impl Serialize for Fixed {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(self.to_f64())
+    }
+}
+
+impl<'de> Deserialize<'de> for Fixed {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Fixed, D::Error> {
+        f64::deserialize(deserializer).map(Fixed::from_f64)
+    }
+}
+
+const CAPABILITIES: &[(&str, Capabilities)] = &[
+    ("SoftSelect", Capabilities::SoftSelect),
+    ("HardSelect", Capabilities::HardSelect),
+    ("SoftDetect", Capabilities::SoftDetect),
+    ("Emulated", Capabilities::Emulated),
+    ("Automatic", Capabilities::Automatic),
+    ("Inactive", Capabilities::Inactive),
+    ("Advanced", Capabilities::Advanced),
+];
+
+const OPTION_INFO: &[(&str, OptionInfo)] = &[
+    ("Inexact", OptionInfo::Inexact),
+    ("ReloadOptions", OptionInfo::ReloadOptions),
+    ("ReloadParams", OptionInfo::ReloadParams),
+];
+
+fn serialize_flags<S, F>(flags: F, names: &[(&str, F)], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    F: Copy + PartialEq + ::std::ops::BitAnd<Output = F>,
+{
+    let set: Vec<&str> = names
+        .iter()
+        .filter(|&&(_, flag)| flags & flag == flag)
+        .map(|&(name, _)| name)
+        .collect();
+
+    let mut seq = serializer.serialize_seq(Some(set.len()))?;
+    for name in set {
+        seq.serialize_element(name)?;
+    }
+    seq.end()
+}
+
+struct FlagsVisitor<F: 'static> {
+    names: &'static [(&'static str, F)],
+    empty: F,
+}
+
+impl<'de, F> Visitor<'de> for FlagsVisitor<F>
+where
+    F: Copy + ::std::ops::BitOr<Output = F>,
+{
+    type Value = F;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of flag names")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<F, A::Error> {
+        let mut flags = self.empty;
+        while let Some(name) = seq.next_element::<String>()? {
+            match self.names.iter().find(|&&(known, _)| known == name) {
+                Some(&(_, flag)) => flags = flags | flag,
+                None => return Err(de::Error::custom(format!("unknown flag {:?}", name))),
+            }
+        }
+        Ok(flags)
+    }
+}
+
+impl Serialize for Capabilities {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_flags(*self, CAPABILITIES, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Capabilities {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Capabilities, D::Error> {
+        deserializer.deserialize_seq(FlagsVisitor {
+            names: CAPABILITIES,
+            empty: Capabilities::empty(),
+        })
+    }
+}
+
+impl Serialize for OptionInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_flags(*self, OPTION_INFO, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for OptionInfo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<OptionInfo, D::Error> {
+        deserializer.deserialize_seq(FlagsVisitor {
+            names: OPTION_INFO,
+            empty: OptionInfo::empty(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{NumericalConstraint, OptionDescriptor, OptionUnit, OptionValue, Range};
+
+    #[test]
+    fn option_descriptor_serializes_with_type_tag() {
+        let descriptor = OptionDescriptor::Fixed {
+            name: "br-x".into(),
+            title: "Bottom-right x".into(),
+            description: "Bottom-right x position of scan area.".into(),
+            unit: OptionUnit::Millimeter,
+            size: 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(NumericalConstraint::Range(Some(Range::new(
+                Fixed::from_int(0),
+                Fixed::from_f64(215.5),
+                Fixed::from_int(0),
+            )))),
+        };
+
+        let json = serde_json::to_value(&descriptor).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "fixed",
+                "name": "br-x",
+                "title": "Bottom-right x",
+                "description": "Bottom-right x position of scan area.",
+                "unit": "millimeter",
+                "size": 4,
+                "capabilities": ["SoftSelect", "SoftDetect"],
+                "constraint": { "range": { "min": 0.0, "max": 215.5, "quant": 0.0 } },
+            })
+        );
+
+        let decoded: OptionDescriptor = serde_json::from_value(json).unwrap();
+        assert_eq!(decoded.name(), Some("br-x"));
+        assert_eq!(decoded.capabilities(), descriptor.capabilities());
+    }
+
+    #[test]
+    fn option_value_round_trips() {
+        for value in [
+            OptionValue::Integer(300),
+            OptionValue::Fixed(Fixed::from_f64(1.5)),
+            OptionValue::String("Color".into()),
+            OptionValue::Button,
+        ] {
+            let json = serde_json::to_string(&value).unwrap();
+            assert_eq!(serde_json::from_str::<OptionValue>(&json).unwrap(), value);
+        }
+
+        assert_eq!(
+            serde_json::to_string(&OptionValue::Integer(300)).unwrap(),
+            r#"{"type":"integer","value":300}"#
+        );
+    }
+
+    #[test]
+    fn unknown_flag_is_rejected() {
+        assert!(serde_json::from_str::<Capabilities>(r#"["SoftSelect", "Bogus"]"#).is_err());
+        assert_eq!(
+            serde_json::from_str::<OptionInfo>(r#"["Inexact"]"#).unwrap(),
+            OptionInfo::Inexact
+        );
+    }
+}
@@ -0,0 +1,369 @@
+//! A scanning service shared by several clients, such as the components of a desktop,
+//! as exposed over D-Bus by the `sane-dbus` binary.
+//!
+//! Devices are opened for each request rather than held open, so that clients
+//! don't lock each other out of a scanner. The options set through the service
+//! are remembered for each device, and set again whenever it is opened.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use crate::error::Error;
+use crate::options::Options;
+use crate::output;
+use crate::scan::ScanEvent;
+use crate::types::OptionValue;
+use crate::{Device, DeviceHandle, Result, Session, Transport};
+
+/// An event of a scan job started with `ScanService::start_job`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JobEvent {
+    /// Image data of page number `page` of the job was read, counting from 1.
+    /// See `ScanEvent::DataRead`.
+    Progress {
+        job: u32,
+        page: usize,
+        bytes: usize,
+        total: Option<usize>,
+    },
+    /// Page number `page` was scanned, and saved to `path`.
+    PageScanned {
+        job: u32,
+        page: usize,
+        path: PathBuf,
+    },
+    /// The job is over after scanning `pages` pages, with `error` if it failed.
+    Finished {
+        job: u32,
+        pages: usize,
+        error: Option<String>,
+    },
+}
+
+/// The devices of a session to saned, offered to several clients.
+pub struct ScanService<S: Read + Write> {
+    session: Arc<Session<S>>,
+    /// The option values set on each device, by device name, in the order they were set.
+    settings: Mutex<HashMap<String, Vec<(String, OptionValue)>>>,
+    next_job: AtomicU32,
+}
+
+impl<S: Transport + Send + 'static> ScanService<S> {
+    pub fn new(session: Session<S>) -> ScanService<S> {
+        ScanService {
+            session: Arc::new(session),
+            settings: Mutex::new(HashMap::new()),
+            next_job: AtomicU32::new(1),
+        }
+    }
+
+    pub fn session(&self) -> &Session<S> {
+        &self.session
+    }
+
+    /// The devices available through the session.
+    pub fn devices(&self) -> Result<Vec<Device>> {
+        self.session.devices()
+    }
+
+    /// The current values of the options of the device named `device`
+    /// that can be read, as they are set for scan jobs.
+    pub fn options(&self, device: &str) -> Result<Vec<(String, OptionValue)>> {
+        let device = self.find(device)?;
+        let handle = self.session.open(&device)?;
+        let mut options = handle.options()?;
+        apply(&mut options, &self.settings(&device.name))?;
+        options.snapshot()
+    }
+
+    /// Set the option `name` of the device named `device` to `text`,
+    /// parsed as by `OptionDescriptor::parse_value`, for the scan jobs started from now on.
+    /// Returns the value the device accepted, which it may have adjusted.
+    pub fn set_option(&self, device: &str, name: &str, text: &str) -> Result<OptionValue> {
+        let device = self.find(device)?;
+        let handle = self.session.open(&device)?;
+        let mut options = handle.options()?;
+
+        let value = match options.find(name) {
+            Some((_, descriptor)) => descriptor.parse_value(text)?,
+            None => return Err(Error::UnknownOption(name.into())),
+        };
+        let mut settings = self.settings(&device.name);
+        settings.retain(|(set, _)| set != name);
+        settings.push((name.into(), value));
+        apply(&mut options, &settings)?;
+
+        // Remember the value as adjusted, so it's set the same way next time
+        let value = options.get(name)?;
+        if let Some(setting) = settings.last_mut() {
+            setting.1 = value.clone();
+        }
+        lock(&self.settings).insert(device.name, settings);
+        Ok(value)
+    }
+
+    /// Start scanning pages from the device named `device` in the background,
+    /// until its document feeder is empty or after `max_pages` pages,
+    /// saving each page to `directory` in PNM format. Returns the number of the job.
+    ///
+    /// The progress of the job, the pages scanned and its outcome are reported
+    /// to `events`, from the thread the job runs on.
+    pub fn start_job<F>(
+        &self,
+        device: &str,
+        directory: &Path,
+        max_pages: Option<usize>,
+        events: F,
+    ) -> Result<u32>
+    where
+        F: FnMut(&JobEvent) + Send + 'static,
+    {
+        let device = self.find(device)?;
+        fs::create_dir_all(directory)?;
+
+        let job = Job {
+            number: self.next_job.fetch_add(1, Ordering::SeqCst),
+            settings: self.settings(&device.name),
+            device,
+            directory: directory.to_path_buf(),
+            max_pages,
+            pages: Arc::new(AtomicUsize::new(0)),
+            events: Arc::new(Mutex::new(events)),
+        };
+        let number = job.number;
+        info!("Starting scan job {} on {}", number, job.device.name);
+
+        let session = Arc::clone(&self.session);
+        thread::spawn(move || {
+            let result = job.run(&session);
+            if let Err(ref e) = result {
+                warn!("Scan job {} failed: {}", job.number, e);
+            }
+            job.report(&JobEvent::Finished {
+                job: job.number,
+                pages: job.pages.load(Ordering::SeqCst),
+                error: result.err().map(|e| e.to_string()),
+            });
+        });
+
+        Ok(number)
+    }
+
+    fn find(&self, name: &str) -> Result<Device> {
+        self.session
+            .devices()?
+            .into_iter()
+            .find(|device| device.name == name)
+            .ok_or_else(|| Error::InvalidDeviceName(name.into()))
+    }
+
+    fn settings(&self, device: &str) -> Vec<(String, OptionValue)> {
+        lock(&self.settings)
+            .get(device)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// A scan job, as run on its own thread.
+struct Job<F> {
+    number: u32,
+    device: Device,
+    settings: Vec<(String, OptionValue)>,
+    directory: PathBuf,
+    max_pages: Option<usize>,
+    /// The number of pages scanned so far.
+    pages: Arc<AtomicUsize>,
+    events: Arc<Mutex<F>>,
+}
+
+impl<F: FnMut(&JobEvent) + Send + 'static> Job<F> {
+    fn run<S: Transport>(&self, session: &Session<S>) -> Result<()> {
+        let handle = session.open(&self.device)?;
+        apply(&mut handle.options()?, &self.settings)?;
+        self.scan(&handle)?;
+        handle.close()
+    }
+
+    fn scan<S: Transport>(&self, handle: &DeviceHandle<'_, S>) -> Result<()> {
+        let (job, pages, events) = (
+            self.number,
+            Arc::clone(&self.pages),
+            Arc::clone(&self.events),
+        );
+        let mut batch = handle.scan_all().report_progress(Box::new(move |event| {
+            if let ScanEvent::DataRead { bytes, total } = *event {
+                let page = pages.load(Ordering::SeqCst) + 1;
+                (lock(&events))(&JobEvent::Progress {
+                    job,
+                    page,
+                    bytes,
+                    total,
+                });
+            }
+        }));
+        if let Some(max_pages) = self.max_pages {
+            batch = batch.max_pages(max_pages);
+        }
+
+        for image in batch {
+            let image = image?;
+            let page = self.pages.load(Ordering::SeqCst) + 1;
+            let path = self
+                .directory
+                .join(format!("job-{}-page-{:03}.pnm", self.number, page));
+
+            let mut out = BufWriter::new(File::create(&path)?);
+            output::write_pnm(&image, &mut out)?;
+            out.flush()?;
+
+            self.pages.store(page, Ordering::SeqCst);
+            self.report(&JobEvent::PageScanned { job, page, path });
+        }
+        Ok(())
+    }
+
+    fn report(&self, event: &JobEvent) {
+        (lock(&self.events))(event)
+    }
+}
+
+/// Set the options of a device to `settings`, failing with the first value rejected.
+fn apply<S: Transport>(
+    options: &mut Options<'_, DeviceHandle<'_, S>>,
+    settings: &[(String, OptionValue)],
+) -> Result<()> {
+    if settings.is_empty() {
+        return Ok(());
+    }
+    let report = options.apply_settings(settings.iter().cloned())?;
+    match report.rejected.into_iter().next() {
+        Some((_, e)) => Err(e),
+        None => Ok(()),
+    }
+}
+
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(all(test, feature = "testing"))]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::testing::{FakeDevice, FakeSaned};
+    use crate::types::{
+        Capabilities, FrameFormat, NumericalConstraint, OptionDescriptor, OptionUnit, Parameters,
+        Range,
+    };
+
+    fn service() -> ScanService<FakeSaned> {
+        let resolution = OptionDescriptor::Integer {
+            name: "resolution".into(),
+            title: "Scan resolution".into(),
+            description: "Sets the resolution of the scanned image.".into(),
+            unit: OptionUnit::DPI,
+            size: 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(NumericalConstraint::Range(Some(Range::new(75, 600, 25)))),
+        };
+        let parameters = Parameters {
+            format: FrameFormat::Gray,
+            last_frame: true,
+            bytes_per_line: 2,
+            pixels_per_line: 2,
+            lines: 1,
+            depth: 8,
+        };
+
+        let saned = FakeSaned::new().device(
+            FakeDevice::new("test:0")
+                .option(resolution, OptionValue::Integer(150))
+                .frame(parameters.clone(), vec![1, 2])
+                .frame(parameters, vec![3, 4]),
+        );
+        ScanService::new(Session::new(saned).unwrap())
+    }
+
+    #[test]
+    fn options_set_are_remembered_for_the_device() {
+        let service = service();
+        assert_eq!(
+            service.options("test:0").unwrap(),
+            vec![("resolution".to_string(), OptionValue::Integer(150))]
+        );
+
+        assert_eq!(
+            service.set_option("test:0", "resolution", "300").unwrap(),
+            OptionValue::Integer(300)
+        );
+        assert_eq!(
+            service.settings("test:0"),
+            vec![("resolution".to_string(), OptionValue::Integer(300))]
+        );
+
+        // Values that are refused leave the settings as they were
+        assert!(service.set_option("test:0", "resolution", "310").is_err());
+        assert_eq!(
+            service.options("test:0").unwrap(),
+            vec![("resolution".to_string(), OptionValue::Integer(300))]
+        );
+        match service.set_option("test:0", "depth", "8") {
+            Err(Error::UnknownOption(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match service.options("test:1") {
+            Err(Error::InvalidDeviceName(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn job_saves_pages_until_the_feeder_is_empty() {
+        let service = service();
+        let directory = std::env::temp_dir().join(format!("sane-service-{}", std::process::id()));
+
+        let (sender, events) = mpsc::channel();
+        let job = service
+            .start_job("test:0", &directory, None, move |event: &JobEvent| {
+                sender.send(event.clone()).unwrap();
+            })
+            .unwrap();
+
+        let mut pages = Vec::new();
+        loop {
+            match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+                JobEvent::Progress { page, total, .. } => {
+                    assert_eq!(total, Some(2));
+                    assert_eq!(page, pages.len() + 1);
+                }
+                JobEvent::PageScanned { path, .. } => pages.push(fs::read(path).unwrap()),
+                JobEvent::Finished {
+                    pages: count,
+                    error,
+                    ..
+                } => {
+                    assert_eq!((count, error), (2, None));
+                    break;
+                }
+            }
+        }
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(job, 1);
+        assert_eq!(
+            pages,
+            vec![
+                b"P5\n2 1\n255\n\x01\x02".to_vec(),
+                b"P5\n2 1\n255\n\x03\x04".to_vec()
+            ]
+        );
+    }
+}
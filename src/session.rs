@@ -0,0 +1,1013 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::auth::{Authenticator, NoCredentials};
+use crate::error::Error;
+use crate::image::{acquire_frames_with, feeder_page, Image, Orientation};
+use crate::options::{ControlOption, Options, ScanArea};
+use crate::scan::{frame_capacity, ProgressCallback, ScanData, ScanEvent, ScanStats};
+use crate::types::{
+    Capabilities, DecodeOptions, OptionAction, OptionDescriptor, OptionInfo, OptionUnit,
+    OptionValue, Parameters,
+};
+use crate::{
+    cancel, close_device, control_option_with, control_option_with_auth, exit,
+    get_option_descriptors, get_parameters, init, open_device_with_auth, request_device_list,
+    start_scan_with_auth, Device, Result, Transport, Version,
+};
+
+/// How a session recovers when its connection to saned is lost.
+///
+/// The connection is considered lost when a request fails because it was
+/// closed or reset; timeouts don't trigger a reconnect.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Number of times to try connecting again before giving up.
+    pub max_attempts: u32,
+    /// Time to wait between attempts.
+    pub delay: Duration,
+    /// Open the devices that were open on the lost connection again,
+    /// so their `DeviceHandle`s remain usable.
+    pub reopen_devices: bool,
+    /// Set the options of reopened devices back to the values
+    /// set through their handles on the lost connection.
+    pub restore_options: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts: 3,
+            delay: Duration::from_secs(1),
+            reopen_devices: true,
+            restore_options: true,
+        }
+    }
+}
+
+struct Reconnect<S> {
+    connect: Box<dyn FnMut() -> Result<S> + Send>,
+    policy: ReconnectPolicy,
+}
+
+/// A device opened through the session, as needed to open it again after reconnecting.
+struct OpenDevice {
+    key: usize,
+    device: Device,
+    handle: i32,
+    /// False if the device couldn't be opened again after reconnecting.
+    open: bool,
+    /// The last value set on each option through the handle, by option number.
+    options: Vec<(i32, OptionDescriptor, OptionAction)>,
+}
+
+/// A connection to saned.
+///
+/// Devices are opened through the session as `DeviceHandle`s, which borrow
+/// the session, so a handle can't outlive or be used with another connection.
+/// When the session is dropped, the server is told the session is over
+/// with SANE_NET_EXIT.
+///
+/// Requests are serialized on the connection, so the session and its handles
+/// can be shared between threads, for instance to cancel a scan with a
+/// `CancelToken` while another thread is blocked reading its data.
+pub struct Session<S: Read + Write> {
+    stream: Mutex<S>,
+    /// The code of the version spoken by the server.
+    version: AtomicU32,
+    exited: AtomicBool,
+    reconnect: Mutex<Option<Reconnect<S>>>,
+    auth: Mutex<Box<dyn Authenticator + Send>>,
+    devices: Mutex<Vec<OpenDevice>>,
+    next_key: AtomicUsize,
+    decode_options: DecodeOptions,
+    /// Number of times the session reconnected to saned.
+    reconnects: AtomicU32,
+}
+
+impl<S: Read + Write> Session<S> {
+    /// Initialize the connection to saned over `stream`, and start a session.
+    pub fn new(mut stream: S) -> Result<Session<S>> {
+        let version = init(&mut stream)?;
+
+        Ok(Session {
+            stream: Mutex::new(stream),
+            version: AtomicU32::new(version.code()),
+            exited: AtomicBool::new(false),
+            reconnect: Mutex::new(None),
+            auth: Mutex::new(Box::new(NoCredentials)),
+            devices: Mutex::new(Vec::new()),
+            next_key: AtomicUsize::new(0),
+            decode_options: DecodeOptions::default(),
+            reconnects: AtomicU32::new(0),
+        })
+    }
+
+    /// Reconnect according to `policy` when the connection to saned is lost,
+    /// using `connect` to open a new connection.
+    ///
+    /// The request that found the connection lost is sent again once reconnected.
+    pub fn set_reconnect<F>(&mut self, policy: ReconnectPolicy, connect: F)
+    where
+        F: FnMut() -> Result<S> + Send + 'static,
+    {
+        *lock_mut(&mut self.reconnect) = Some(Reconnect {
+            connect: Box::new(connect),
+            policy,
+        });
+    }
+
+    /// Request credentials from `authenticator` whenever a request needs
+    /// authorization, such as opening a password-protected device.
+    ///
+    /// Without an authenticator, such requests fail with `Status::AccessDenied`.
+    /// Wrap the authenticator with `AuthPolicy::apply` to restrict which resources
+    /// it is asked for credentials for, or to refuse sending plaintext passwords.
+    pub fn set_authenticator<A: Authenticator + Send + 'static>(&mut self, authenticator: A) {
+        *lock_mut(&mut self.auth) = Box::new(authenticator);
+    }
+
+    /// Decode replies from saned with `options`.
+    pub fn set_decode_options(&mut self, options: DecodeOptions) {
+        self.decode_options = options;
+    }
+
+    /// The options replies from saned are decoded with.
+    pub fn decode_options(&self) -> DecodeOptions {
+        self.decode_options
+    }
+
+    fn auth(&self) -> MutexGuard<'_, Box<dyn Authenticator + Send>> {
+        lock(&self.auth)
+    }
+
+    /// The connection to saned, for making requests not covered by the session.
+    ///
+    /// Other requests through the session wait until the guard is dropped.
+    pub fn stream(&self) -> MutexGuard<'_, S> {
+        lock(&self.stream)
+    }
+
+    /// The version of the protocol spoken by the server, as negotiated
+    /// when connecting. Requests are encoded for this version.
+    pub fn version(&self) -> Version {
+        Version::from_code(self.version.load(Ordering::SeqCst))
+    }
+
+    /// Request the list of available devices.
+    pub fn devices(&self) -> Result<Vec<Device>> {
+        self.request(|stream| request_device_list(stream))
+    }
+
+    /// Open `device`, returning a handle that closes the device when dropped.
+    ///
+    /// If the device requires authentication, credentials are requested
+    /// from the authenticator set with `set_authenticator`.
+    pub fn open(&self, device: &Device) -> Result<DeviceHandle<'_, S>> {
+        let handle =
+            self.request(|stream| open_device_with_auth(device, stream, &mut **self.auth()))?;
+
+        let key = self.next_key.fetch_add(1, Ordering::SeqCst);
+
+        lock(&self.devices).push(OpenDevice {
+            key,
+            device: device.clone(),
+            handle,
+            open: true,
+            options: Vec::new(),
+        });
+
+        Ok(DeviceHandle {
+            session: self,
+            key,
+            closed: false,
+        })
+    }
+
+    /// Check that saned is still responding.
+    ///
+    /// If a device is open, the number of its options is requested,
+    /// otherwise the list of devices. If the connection was lost and a
+    /// reconnect policy is set, the session reconnects first.
+    pub fn ping(&self) -> Result<()> {
+        let device = lock(&self.devices)
+            .iter()
+            .find(|device| device.open)
+            .map(|device| device.key);
+
+        match device {
+            Some(key) => {
+                let descriptor = OptionDescriptor::Integer {
+                    name: String::new(),
+                    title: "Number of options".into(),
+                    description: String::new(),
+                    unit: OptionUnit::None,
+                    size: 4,
+                    capabilities: Capabilities::SoftDetect,
+                    constraint: None,
+                };
+                self.device_request(key, |handle, stream| {
+                    let action = OptionAction::Get;
+                    let auth = &mut **self.auth();
+                    control_option_with_auth(handle, stream, 0, &descriptor, &action, auth)
+                })
+                .map(|_| ())
+            }
+            None => self.devices().map(|_| ()),
+        }
+    }
+
+    /// Open a new connection to saned as set with `set_reconnect`, and open
+    /// the devices that were open on the previous connection again.
+    ///
+    /// Devices that can't be opened again are left closed, and requests
+    /// through their handles fail with `Error::DeviceClosed`.
+    pub fn reconnect(&self) -> Result<()> {
+        self.decode_options.scope(|| self.reconnect_devices())
+    }
+
+    fn reconnect_devices(&self) -> Result<()> {
+        // Held throughout, so no other request is sent on the lost connection
+        let mut current = self.stream();
+        let mut reconnect = lock(&self.reconnect);
+        let reconnect = reconnect.as_mut().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "No way to reconnect was set")
+        })?;
+        let policy = reconnect.policy.clone();
+
+        let mut attempt = 1;
+        let (mut stream, version) = loop {
+            let stream = (reconnect.connect)().and_then(|mut stream| {
+                let version = init(&mut stream)?;
+                Ok((stream, version))
+            });
+
+            match stream {
+                Ok(stream) => break stream,
+                Err(e) if attempt < policy.max_attempts => {
+                    warn!("Reconnect attempt {} failed: {}", attempt, e);
+                    attempt += 1;
+                    thread::sleep(policy.delay);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        info!("Reconnected after {} attempt(s)", attempt);
+        self.reconnects.fetch_add(1, Ordering::SeqCst);
+        self.version.store(version.code(), Ordering::SeqCst);
+
+        for device in lock(&self.devices).iter_mut() {
+            device.open = false;
+
+            if !policy.reopen_devices {
+                continue;
+            }
+
+            match open_device_with_auth(&device.device, &mut stream, &mut **self.auth()) {
+                Ok(handle) => {
+                    device.handle = handle;
+                    device.open = true;
+                }
+                Err(e) => {
+                    warn!("Failed to reopen {}: {}", device.device.name, e);
+                    continue;
+                }
+            }
+
+            if !policy.restore_options {
+                continue;
+            }
+
+            for &(index, ref descriptor, ref action) in &device.options {
+                let restored = control_option_with(
+                    device.handle,
+                    &mut stream,
+                    index,
+                    descriptor,
+                    action,
+                    version,
+                    &mut **self.auth(),
+                );
+                if let Err(e) = restored {
+                    warn!(
+                        "Failed to restore option {} of {}: {}",
+                        index, device.device.name, e
+                    );
+                }
+            }
+        }
+
+        *current = stream;
+        Ok(())
+    }
+
+    /// Make a request with `request`, reconnecting and making it again
+    /// if the connection turns out to be lost.
+    fn request<T, F>(&self, mut request: F) -> Result<T>
+    where
+        F: FnMut(&mut S) -> Result<T>,
+    {
+        self.decode_options.scope(|| {
+            let result = request(&mut *self.stream());
+
+            match result {
+                Err(ref e) if is_connection_lost(e) && lock(&self.reconnect).is_some() => {
+                    warn!("Connection to saned lost ({}), reconnecting", e);
+                }
+                result => return result,
+            }
+
+            self.reconnect_devices()?;
+            request(&mut *self.stream())
+        })
+    }
+
+    /// Make a request concerning the device opened as `key`,
+    /// with the handle it currently has on the server.
+    fn device_request<T, F>(&self, key: usize, mut request: F) -> Result<T>
+    where
+        F: FnMut(i32, &mut S) -> Result<T>,
+    {
+        self.request(|stream| {
+            let handle = self.device(key, |device| {
+                if device.open {
+                    Ok(device.handle)
+                } else {
+                    Err(Error::DeviceClosed(device.device.name.clone()))
+                }
+            })?;
+            request(handle, stream)
+        })
+    }
+
+    fn device<T, F>(&self, key: usize, f: F) -> T
+    where
+        F: FnOnce(&mut OpenDevice) -> T,
+    {
+        self.find_device(key, f)
+            .expect("DeviceHandle outlived its device")
+    }
+
+    fn find_device<T, F>(&self, key: usize, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut OpenDevice) -> T,
+    {
+        lock(&self.devices)
+            .iter_mut()
+            .find(|device| device.key == key)
+            .map(f)
+    }
+
+    /// End the session.
+    ///
+    /// This is done automatically when the session is dropped,
+    /// but calling it directly allows errors to be handled.
+    pub fn exit(self) -> Result<()> {
+        self.finish()
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.exited.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        exit(&mut *self.stream())
+    }
+}
+
+/// Lock `mutex`, even if a thread panicked while holding it.
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock_mut<T: ?Sized>(mutex: &mut Mutex<T>) -> &mut T {
+    mutex.get_mut().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn is_connection_lost(error: &Error) -> bool {
+    match *error {
+        Error::IOError(ref e) => matches!(
+            e.kind(),
+            io::ErrorKind::BrokenPipe
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+impl<S: Read + Write> Drop for Session<S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("Failed to end session cleanly: {:?}", e);
+        }
+    }
+}
+
+/// Configuration for opening a `Session` to saned over TCP.
+///
+/// By default, no timeouts are set, and requests to an unresponsive
+/// server block indefinitely.
+#[derive(Debug, Clone, Default)]
+pub struct SessionBuilder {
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    reconnect: Option<ReconnectPolicy>,
+    decode_options: DecodeOptions,
+}
+
+impl SessionBuilder {
+    pub fn new() -> SessionBuilder {
+        SessionBuilder::default()
+    }
+
+    /// Limit the time spent establishing the connection to saned.
+    pub fn connect_timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Limit the time spent waiting for data from saned, on both the
+    /// control connection and the data connections of scans.
+    pub fn read_timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Limit the time spent waiting to send data to saned.
+    pub fn write_timeout(mut self, timeout: Duration) -> SessionBuilder {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Reconnect to the same address according to `policy`
+    /// if the connection is lost. See `Session::set_reconnect`.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> SessionBuilder {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Decode replies from saned with `options`. See `Session::set_decode_options`.
+    pub fn decode_options(mut self, options: DecodeOptions) -> SessionBuilder {
+        self.decode_options = options;
+        self
+    }
+
+    /// Connect to saned at `address` and start a session.
+    ///
+    /// Every address `address` resolves to is tried in turn, and the error for
+    /// the last one is returned if none can be connected to. Requests that run
+    /// past a timeout fail with `Error::Timeout`.
+    pub fn connect<A: ToSocketAddrs>(&self, address: A) -> Result<Session<TcpStream>> {
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        let mut session = Session::new(self.open_stream(&addresses)?)?;
+        session.set_decode_options(self.decode_options);
+
+        if let Some(ref policy) = self.reconnect {
+            let builder = self.clone();
+            session.set_reconnect(policy.clone(), move || builder.open_stream(&addresses));
+        }
+
+        Ok(session)
+    }
+
+    fn open_stream(&self, addresses: &[SocketAddr]) -> Result<TcpStream> {
+        let mut last_error = None;
+
+        for &address in addresses {
+            let stream = match self.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+                None => TcpStream::connect(address),
+            };
+
+            match stream {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(self.read_timeout)?;
+                    stream.set_write_timeout(self.write_timeout)?;
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    debug!("Failed to connect to {}: {}", address, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error
+            .unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Address resolved to nothing")
+            })
+            .into())
+    }
+}
+
+/// A device opened through a `Session`. The device is closed when the handle is dropped.
+///
+/// If the session reconnects to saned, the handle refers to the device as opened
+/// again on the new connection.
+pub struct DeviceHandle<'a, S: Read + Write + 'a> {
+    session: &'a Session<S>,
+    key: usize,
+    closed: bool,
+}
+
+impl<'a, S: Read + Write> DeviceHandle<'a, S> {
+    /// The raw handle assigned to the device by the server.
+    pub fn id(&self) -> i32 {
+        self.session.device(self.key, |device| device.handle)
+    }
+
+    /// The session the device was opened through.
+    pub fn session(&self) -> &'a Session<S> {
+        self.session
+    }
+
+    /// Request the descriptors of all options of the device,
+    /// through which option values can be read and set by name.
+    pub fn options(&self) -> Result<Options<'_, Self>> {
+        Ok(Options::new(self, self.option_descriptors()?))
+    }
+
+    /// The descriptors of all options of the device.
+    /// See `get_option_descriptors`.
+    pub fn option_descriptors(&self) -> Result<Vec<Option<OptionDescriptor>>> {
+        self.request(get_option_descriptors)
+    }
+
+    /// Get or set the value of option number `index`, described by `descriptor`.
+    /// See `control_option`.
+    pub fn control_option(
+        &self,
+        index: i32,
+        descriptor: &OptionDescriptor,
+        action: &OptionAction,
+    ) -> Result<(OptionValue, OptionInfo)> {
+        let version = self.session.version();
+        let result = self.request(|handle, stream| {
+            let auth = &mut **self.session.auth();
+            control_option_with(handle, stream, index, descriptor, action, version, auth)
+        })?;
+
+        if let OptionAction::Get = *action {
+            return Ok(result);
+        }
+
+        // Remember the value, to set it again if the device is reopened
+        self.session.device(self.key, |device| {
+            device.options.retain(|&(i, _, _)| i != index);
+            device
+                .options
+                .push((index, descriptor.clone(), action.clone()));
+        });
+
+        Ok(result)
+    }
+
+    /// Get the parameters of the frame about to be, or currently being, acquired.
+    pub fn parameters(&self) -> Result<Parameters> {
+        self.request(get_parameters)
+    }
+
+    /// Cancel the current operation. This must also be called
+    /// once all frames of a scan have been read.
+    pub fn cancel(&self) -> Result<()> {
+        self.request(cancel)
+    }
+
+    /// A token that cancels the current operation of the device, which can be
+    /// sent to another thread to cancel a scan while its data is being read.
+    pub fn cancel_token(&self) -> CancelToken<'a, S> {
+        let device = self
+            .session
+            .device(self.key, |device| device.device.name.clone());
+        CancelToken {
+            session: self.session,
+            key: self.key,
+            device,
+        }
+    }
+
+    /// Close the device.
+    ///
+    /// This is done automatically when the handle is dropped,
+    /// but calling it directly allows errors to be handled.
+    pub fn close(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn request<T, F>(&self, request: F) -> Result<T>
+    where
+        F: FnMut(i32, &mut S) -> Result<T>,
+    {
+        self.session.device_request(self.key, request)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+
+        let key = self.key;
+        let open = self.session.device(key, |device| device.open);
+        let result = if open {
+            self.request(close_device)
+        } else {
+            Ok(())
+        };
+
+        lock(&self.session.devices).retain(|device| device.key != key);
+
+        result
+    }
+}
+
+impl<'a, S: Read + Write> ControlOption for DeviceHandle<'a, S> {
+    fn control_option(
+        &self,
+        index: i32,
+        descriptor: &OptionDescriptor,
+        action: &OptionAction,
+    ) -> Result<(OptionValue, OptionInfo)> {
+        DeviceHandle::control_option(self, index, descriptor, action)
+    }
+
+    fn option_descriptors(&self) -> Result<Vec<Option<OptionDescriptor>>> {
+        DeviceHandle::option_descriptors(self)
+    }
+}
+
+impl<'a, S: Transport> DeviceHandle<'a, S> {
+    /// Start acquiring a frame, returning a reader for its image data.
+    ///
+    /// Once all frames have been read, `cancel` must be called to complete the scan.
+    pub fn scan(&self) -> Result<ScanData<S::DataConnection>> {
+        self.request(|handle, stream| {
+            start_scan_with_auth(handle, stream, &mut **self.session.auth())
+        })
+    }
+
+    /// Scan a complete image, assembling the frames of three-pass scans
+    /// into a single RGB image.
+    pub fn scan_image(&self) -> Result<Image> {
+        self.scan_image_with_stats().map(|(image, _)| image)
+    }
+
+    /// Scan a complete image like `scan_image`, along with statistics of the scan.
+    ///
+    /// If the connection was lost while starting a frame and the session reconnected,
+    /// the frame was started again, and the reconnects are counted as retries.
+    pub fn scan_image_with_stats(&self) -> Result<(Image, ScanStats)> {
+        let started = Instant::now();
+        let reconnects = self.session.reconnects.load(Ordering::SeqCst);
+
+        let mut stats = ScanStats::default();
+        let image = self.acquire(|data, parameters| data.read_frame(parameters), &mut stats)?;
+
+        stats.duration = started.elapsed();
+        stats.retries = self
+            .session
+            .reconnects
+            .load(Ordering::SeqCst)
+            .wrapping_sub(reconnects);
+        Ok((image, stats))
+    }
+
+    /// Scan a quick, low-resolution preview of the whole scan area.
+    ///
+    /// The current option values are saved, the device is configured with
+    /// `Options::set_preview`, and the options are restored after scanning,
+    /// including when the scan fails.
+    pub fn preview(&self) -> Result<Image> {
+        let mut options = self.options()?;
+        let snapshot = options.snapshot()?;
+
+        let result = options.set_preview().and_then(|_| self.scan_image());
+        let restored = options.restore(&snapshot);
+
+        let image = result?;
+        restored?;
+        Ok(image)
+    }
+
+    /// Set the scan area to `width` by `height` millimeters, starting `x` and `y`
+    /// millimeters from the top-left corner of the scan bed.
+    ///
+    /// Values outside what the device allows are clamped, and the area actually set
+    /// is returned. See `Options::set_scan_area`.
+    pub fn set_scan_area_mm(&self, x: f64, y: f64, width: f64, height: f64) -> Result<ScanArea> {
+        self.options()?
+            .set_scan_area(ScanArea::from_mm(x, y, width, height))
+    }
+
+    /// Set the scan area like `set_scan_area_mm`, in inches.
+    pub fn set_scan_area_inches(
+        &self,
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+    ) -> Result<ScanArea> {
+        self.options()?
+            .set_scan_area(ScanArea::from_inches(x, y, width, height))
+    }
+
+    /// The largest scan area the device allows.
+    pub fn max_scan_area(&self) -> Result<ScanArea> {
+        self.options()?.max_scan_area()
+    }
+
+    /// Scan every page in the document feeder, yielding one image per page.
+    ///
+    /// Iteration ends cleanly once the feeder is empty. Any other error, such as
+    /// `Status::Jammed` or `Status::CoverOpen`, is yielded and ends the iteration.
+    /// The scan is completed with `cancel` when iteration ends or the iterator is dropped.
+    ///
+    /// To keep long batches out of memory, write the pages to disk as they are
+    /// scanned with `spool::Spool::add_all`.
+    pub fn scan_all(&self) -> BatchScan<'_, 'a, S> {
+        BatchScan {
+            handle: self,
+            resolution: None,
+            orientation: Orientation::default(),
+            back_orientation: None,
+            max_pages: None,
+            progress: None,
+            pages: 0,
+            done: false,
+        }
+    }
+
+    /// Scan a complete image like `scan_image`, reporting the progress
+    /// of reading each frame to `callback`.
+    pub fn scan_image_with_progress<F>(&self, mut callback: F) -> Result<Image>
+    where
+        F: FnMut(&ScanEvent),
+    {
+        // The data is read on this thread, but `ScanData` only takes callbacks that
+        // could be sent elsewhere, so it queues the events to be passed on from here
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let read = |data: &mut ScanData<S::DataConnection>, parameters: &Parameters| {
+            let queue = Arc::clone(&events);
+            data.set_progress(
+                parameters,
+                Box::new(move |event: &ScanEvent| lock(&queue).push(event.clone())),
+            );
+
+            let mut frame = Vec::with_capacity(frame_capacity(parameters));
+            loop {
+                let record = data.read_record_into(&mut frame);
+                for event in lock(&events).drain(..) {
+                    callback(&event);
+                }
+
+                if record?.is_none() {
+                    break;
+                }
+            }
+
+            if parameters.depth == 16 {
+                data.byte_order().to_native(&mut frame);
+            }
+            Ok(frame)
+        };
+
+        self.acquire(read, &mut ScanStats::default())
+    }
+
+    /// Acquire a complete image, reading the data of each frame with `read`,
+    /// and complete the scan with `cancel`, including when acquisition fails.
+    fn acquire<F>(&self, read: F, stats: &mut ScanStats) -> Result<Image>
+    where
+        F: FnMut(&mut ScanData<S::DataConnection>, &Parameters) -> Result<Vec<u8>>,
+    {
+        let resolution = self.scan_resolution();
+        let result = self.acquire_frames(read, resolution, stats);
+        let cancelled = self.cancel();
+
+        let image = result?;
+        cancelled?;
+        Ok(image)
+    }
+
+    /// Acquire the frames of an image like `image::acquire`, without completing the scan,
+    /// and record `resolution` on it.
+    ///
+    /// The connection is only held while starting each frame, not while its data
+    /// is read, so the scan can be cancelled meanwhile with a `CancelToken`.
+    fn acquire_frames<F>(
+        &self,
+        read: F,
+        resolution: Option<f64>,
+        stats: &mut ScanStats,
+    ) -> Result<Image>
+    where
+        F: FnMut(&mut ScanData<S::DataConnection>, &Parameters) -> Result<Vec<u8>>,
+    {
+        let start = || {
+            self.request(|handle, stream| {
+                let data = start_scan_with_auth(handle, stream, &mut **self.session.auth())?;
+                let parameters = get_parameters(handle, stream)?;
+                Ok((data, parameters))
+            })
+        };
+        let mut image = acquire_frames_with(start, read, stats)?;
+        if let Some(dpi) = resolution {
+            image.set_resolution(dpi);
+        }
+        Ok(image)
+    }
+
+    /// The resolution the device is set to scan at, or None if it
+    /// has no usable `resolution` option.
+    fn scan_resolution(&self) -> Option<f64> {
+        match self.options().and_then(|options| options.resolution()) {
+            Ok(resolution) => Some(resolution),
+            Err(e) => {
+                debug!("Scan resolution unknown: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Cancels the current operation of a device opened through a `Session`,
+/// returned by `DeviceHandle::cancel_token`.
+///
+/// Scans through the handle, such as `DeviceHandle::scan_image` and `DeviceHandle::scan`,
+/// only use the connection to start each frame and leave it free while its data
+/// is read, so they can be cancelled from another thread. The reader then fails
+/// with `Status::Canceled`.
+pub struct CancelToken<'a, S: Read + Write + 'a> {
+    session: &'a Session<S>,
+    key: usize,
+    device: String,
+}
+
+impl<'a, S: Read + Write> CancelToken<'a, S> {
+    /// Cancel the current operation of the device.
+    ///
+    /// Fails with `Error::DeviceClosed` once the device has been closed.
+    pub fn cancel(&self) -> Result<()> {
+        self.session.request(|stream| {
+            let handle = self
+                .session
+                .find_device(self.key, |device| device.open.then_some(device.handle))
+                .flatten()
+                .ok_or_else(|| Error::DeviceClosed(self.device.clone()))?;
+            cancel(handle, stream)
+        })
+    }
+}
+
+impl<'a, S: Read + Write> Clone for CancelToken<'a, S> {
+    fn clone(&self) -> CancelToken<'a, S> {
+        CancelToken {
+            session: self.session,
+            key: self.key,
+            device: self.device.clone(),
+        }
+    }
+}
+
+/// Iterator over the pages scanned from a document feeder,
+/// returned by `DeviceHandle::scan_all`.
+pub struct BatchScan<'h, 'a: 'h, S: Transport + 'a> {
+    handle: &'h DeviceHandle<'a, S>,
+    /// The resolution recorded on each page, read before the first one.
+    resolution: Option<Option<f64>>,
+    orientation: Orientation,
+    /// The orientation of even pages, if it differs from that of odd pages.
+    back_orientation: Option<Orientation>,
+    max_pages: Option<usize>,
+    progress: Option<ProgressCallback>,
+    pages: usize,
+    /// Set once the scan has been completed.
+    done: bool,
+}
+
+impl<'h, 'a, S: Transport> BatchScan<'h, 'a, S> {
+    /// Stop after `max_pages` pages, even if the feeder isn't empty.
+    pub fn max_pages(mut self, max_pages: usize) -> BatchScan<'h, 'a, S> {
+        self.max_pages = Some(max_pages);
+        self
+    }
+
+    /// Turn every page to `orientation` as soon as it is acquired,
+    /// such as pages fed into the scanner sideways.
+    pub fn orient(mut self, orientation: Orientation) -> BatchScan<'h, 'a, S> {
+        self.orientation = orientation;
+        self
+    }
+
+    /// Turn the second page and every other page after it to `orientation`,
+    /// such as the back sides of duplex scans, which some scanners deliver
+    /// upside down. Other pages are turned as set by `orient`.
+    pub fn orient_back_sides(mut self, orientation: Orientation) -> BatchScan<'h, 'a, S> {
+        self.back_orientation = Some(orientation);
+        self
+    }
+
+    /// Report the progress of reading each frame of each page to `callback`.
+    /// See `ScanData::set_progress`.
+    pub fn report_progress(mut self, callback: ProgressCallback) -> BatchScan<'h, 'a, S> {
+        self.progress = Some(callback);
+        self
+    }
+
+    /// The number of pages scanned so far.
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        self.handle.cancel()
+    }
+}
+
+impl<'h, 'a, S: Transport> Iterator for BatchScan<'h, 'a, S> {
+    type Item = Result<Image>;
+
+    fn next(&mut self) -> Option<Result<Image>> {
+        if self.done {
+            return None;
+        }
+
+        let pages = self.pages;
+        if self.max_pages.is_some_and(|max_pages| pages >= max_pages) {
+            return self.finish().err().map(Err);
+        }
+
+        let handle = self.handle;
+        let resolution = *self
+            .resolution
+            .get_or_insert_with(|| handle.scan_resolution());
+        let progress = &mut self.progress;
+        let page = handle.acquire_frames(
+            |data, parameters| {
+                if let Some(callback) = progress.take() {
+                    data.set_progress(parameters, callback);
+                }
+                let frame = data.read_frame(parameters);
+                *progress = data.take_progress();
+                frame
+            },
+            resolution,
+            &mut ScanStats::default(),
+        );
+        let orientation = match self.back_orientation {
+            Some(orientation) if pages % 2 == 1 => orientation,
+            _ => self.orientation,
+        };
+        let page = page.and_then(|image| {
+            if orientation.is_upright() {
+                Ok(image)
+            } else {
+                image.orient(orientation)
+            }
+        });
+
+        match feeder_page(page) {
+            Ok(Some(image)) => {
+                self.pages += 1;
+                Some(Ok(image))
+            }
+            Ok(None) => self.finish().err().map(Err),
+            Err(e) => {
+                if let Err(cancel_error) = self.finish() {
+                    warn!("Failed to cancel scan: {:?}", cancel_error);
+                }
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'h, 'a, S: Transport> Drop for BatchScan<'h, 'a, S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("Failed to cancel scan: {:?}", e);
+        }
+    }
+}
+
+impl<'a, S: Read + Write> Drop for DeviceHandle<'a, S> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("Failed to close device {}: {:?}", self.key, e);
+        }
+    }
+}
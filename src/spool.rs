@@ -0,0 +1,328 @@
+//! Spooling the pages of long batch scans to disk as they complete, so that
+//! scanning a full document feeder doesn't hold every page in memory.
+//!
+//! Pages are written to a temporary directory, which is removed with
+//! everything in it when the `Spool` is dropped, whether the batch succeeded or not.
+//! With the `zstd` feature, the image data can be compressed on its way to disk.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::error::Error;
+use crate::image::Image;
+use crate::types::Parameters;
+use crate::{Result, TryFromStream, WriteToStream};
+
+/// Distinguishes the spool directories created by this process.
+static SPOOL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes taken by the header of a page file: its parameters,
+/// resolution and data length.
+const HEADER_LENGTH: u64 = 6 * 4 + 8 + 8;
+
+/// A page written to the spool directory.
+#[derive(Debug)]
+struct SpooledPage {
+    path: PathBuf,
+    compressed: bool,
+}
+
+/// A temporary directory the pages of a batch scan are written to.
+///
+/// ```no_run
+/// # fn scan(handle: &sane::DeviceHandle<std::net::TcpStream>) -> sane::Result<()> {
+/// use sane::spool::Spool;
+///
+/// let mut spool = Spool::new()?.max_bytes(2 << 30);
+/// spool.add_all(handle.scan_all())?;
+/// for page in spool.pages() {
+///     let page = page?;
+///     // ...
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    max_bytes: Option<u64>,
+    /// The zstd compression level of page data, if it's compressed.
+    compression: Option<i32>,
+    pages: Vec<SpooledPage>,
+    used: u64,
+}
+
+impl Spool {
+    /// Create a spool directory in the system's temporary directory.
+    pub fn new() -> Result<Spool> {
+        Spool::new_in(::std::env::temp_dir())
+    }
+
+    /// Create a spool directory in `parent`.
+    pub fn new_in<P: AsRef<Path>>(parent: P) -> Result<Spool> {
+        let dir = loop {
+            let count = SPOOL_COUNT.fetch_add(1, Ordering::SeqCst);
+            let dir =
+                parent
+                    .as_ref()
+                    .join(format!("sane-spool-{}-{}", ::std::process::id(), count));
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                // Left over by an earlier process with the same id
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        debug!("Spooling pages to {}", dir.display());
+        Ok(Spool {
+            dir,
+            max_bytes: None,
+            compression: None,
+            pages: Vec::new(),
+            used: 0,
+        })
+    }
+
+    /// Limit the space taken by the spooled pages to `max_bytes`.
+    ///
+    /// Adding a page that doesn't fit fails with `Error::SpoolFull`,
+    /// and leaves the pages spooled before it.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Spool {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Compress the data of the pages added from now on with zstd at `level`,
+    /// from 1 to 22, where 0 selects the default level.
+    ///
+    /// Scans of text documents typically compress several times over.
+    /// The limit set by `max_bytes` applies to the compressed pages.
+    #[cfg(feature = "zstd")]
+    pub fn compress(mut self, level: i32) -> Spool {
+        self.compression = Some(level);
+        self
+    }
+
+    /// The directory the pages are spooled to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The number of pages spooled.
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// The bytes taken by the spooled pages on disk.
+    pub fn used_bytes(&self) -> u64 {
+        self.used
+    }
+
+    /// Write `image` to the spool as its next page.
+    pub fn add(&mut self, image: &Image) -> Result<()> {
+        let compressed = self.compression.is_some();
+        let raw_size = HEADER_LENGTH + image.data.len() as u64;
+        if !compressed {
+            self.check_space(raw_size)?;
+        }
+
+        let extension = if compressed { "raw.zst" } else { "raw" };
+        let path = self
+            .dir
+            .join(format!("page-{:04}.{}", self.pages.len() + 1, extension));
+        // The size of a compressed page is only known once it's written
+        let written = write_page(&path, image, self.compression).and_then(|()| {
+            let size = fs::metadata(&path)?.len();
+            self.check_space(size).map(|()| size)
+        });
+
+        let size = match written {
+            Ok(size) => size,
+            Err(e) => {
+                if let Err(remove_error) = fs::remove_file(&path) {
+                    debug!("Failed to remove {}: {}", path.display(), remove_error);
+                }
+                return Err(e);
+            }
+        };
+        debug!(
+            "Spooled {} ({} of {} bytes)",
+            path.display(),
+            size,
+            raw_size
+        );
+
+        self.used += size;
+        self.pages.push(SpooledPage { path, compressed });
+        Ok(())
+    }
+
+    /// Spool every page of `pages`, such as those yielded by `DeviceHandle::scan_all`,
+    /// returning the number of pages added.
+    ///
+    /// Stops at the first page that fails to scan or spool, returning its error.
+    pub fn add_all<I: IntoIterator<Item = Result<Image>>>(&mut self, pages: I) -> Result<usize> {
+        let mut added = 0;
+        for page in pages {
+            self.add(&page?)?;
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    /// Read page number `index` back from the spool, counting from 0.
+    pub fn page(&self, index: usize) -> Result<Image> {
+        let page = self.pages.get(index).ok_or_else(|| {
+            Error::IOError(::std::io::Error::new(
+                ::std::io::ErrorKind::NotFound,
+                format!("no page {} in the spool", index),
+            ))
+        })?;
+        read_page(page)
+    }
+
+    /// The spooled pages, read back one at a time.
+    pub fn pages(&self) -> impl Iterator<Item = Result<Image>> + '_ {
+        self.pages.iter().map(read_page)
+    }
+
+    fn check_space(&self, size: u64) -> Result<()> {
+        match self.max_bytes {
+            Some(max_bytes) if self.used + size > max_bytes => Err(Error::SpoolFull(max_bytes)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!(
+                "Failed to remove spool directory {}: {}",
+                self.dir.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Write `image` to `path`, compressing its data at the zstd `compression` level, if any.
+fn write_page(path: &Path, image: &Image, compression: Option<i32>) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    image.parameters.write_to_stream(&mut file)?;
+    file.write_u64::<BigEndian>(image.resolution.unwrap_or(0.0).to_bits())?;
+    file.write_u64::<BigEndian>(image.data.len() as u64)?;
+
+    #[cfg(feature = "zstd")]
+    {
+        if let Some(level) = compression {
+            let mut encoder = zstd::stream::Encoder::new(file, level)?;
+            encoder.write_all(&image.data)?;
+            return Ok(encoder.finish()?.flush()?);
+        }
+    }
+    #[cfg(not(feature = "zstd"))]
+    let _ = compression;
+
+    file.write_all(&image.data)?;
+    Ok(file.flush()?)
+}
+
+fn read_page(page: &SpooledPage) -> Result<Image> {
+    let mut file = BufReader::new(File::open(&page.path)?);
+    let parameters = Parameters::try_from_stream(&mut file)?;
+    let resolution = f64::from_bits(file.read_u64::<BigEndian>()?);
+    let length = file.read_u64::<BigEndian>()?;
+
+    let data = match page.compressed {
+        #[cfg(feature = "zstd")]
+        true => read_data(zstd::stream::Decoder::with_buffer(file)?, length)?,
+        _ => read_data(file, length)?,
+    };
+
+    let mut image = Image::new(parameters, data);
+    if resolution > 0.0 {
+        image.set_resolution(resolution);
+    }
+    Ok(image)
+}
+
+/// Read the `length` bytes of data of a page.
+fn read_data<R: Read>(reader: R, length: u64) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(length).read_to_end(&mut data)?;
+    if data.len() as u64 != length {
+        return Err(Error::IOError(::std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::FrameFormat;
+
+    fn page(lines: i32, fill: u8) -> Image {
+        let mut image = Image::new(
+            Parameters {
+                format: FrameFormat::Gray,
+                last_frame: true,
+                bytes_per_line: 100,
+                pixels_per_line: 100,
+                lines,
+                depth: 8,
+            },
+            vec![fill; 100 * lines as usize],
+        );
+        image.set_resolution(300.0);
+        image
+    }
+
+    #[test]
+    fn pages_are_read_back_and_removed_on_drop() {
+        let mut spool = Spool::new().unwrap();
+        let pages = vec![Ok(page(2, 1)), Ok(page(3, 2))];
+        assert_eq!(spool.add_all(pages).unwrap(), 2);
+
+        let dir = spool.dir().to_path_buf();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        let read: Vec<Image> = spool.pages().collect::<Result<_>>().unwrap();
+        assert_eq!(read, vec![page(2, 1), page(3, 2)]);
+
+        drop(spool);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn page_past_the_limit_is_refused() {
+        let mut spool = Spool::new().unwrap().max_bytes(2 * (HEADER_LENGTH + 200));
+        spool.add(&page(2, 0)).unwrap();
+        spool.add(&page(2, 0)).unwrap();
+
+        match spool.add(&page(1, 0)) {
+            Err(Error::SpoolFull(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(spool.len(), 2);
+        assert_eq!(fs::read_dir(spool.dir()).unwrap().count(), 2);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_pages_take_less_space() {
+        let mut spool = Spool::new().unwrap().compress(0);
+        spool.add(&page(100, 0xff)).unwrap();
+
+        assert!(spool.used_bytes() < 1000);
+        assert_eq!(spool.page(0).unwrap(), page(100, 0xff));
+    }
+}
@@ -1,6 +1,11 @@
-use std::convert::From;
+use std::convert::TryFrom;
+use std::fmt;
 
-#[derive(Debug, PartialEq)]
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
 pub enum Status {
     Success,
     Unsupported,
@@ -16,22 +21,87 @@ pub enum Status {
     AccessDenied,
 }
 
-impl From<i32> for Status {
-    fn from(val: i32) -> Status {
+impl TryFrom<i32> for Status {
+    type Error = Error;
+
+    fn try_from(val: i32) -> Result<Status, Error> {
         match val {
-            00 => Status::Success,
-            01 => Status::Unsupported,
-            02 => Status::Canceled,
-            03 => Status::DeviceBusy,
-            04 => Status::Invalid,
-            05 => Status::EndOfFile,
-            06 => Status::Jammed,
-            07 => Status::NoDocuments,
-            08 => Status::CoverOpen,
-            09 => Status::IOError,
-            10 => Status::OutOfMemory,
-            11 => Status::AccessDenied,
-            n => panic!("Unknown status {}!", n),
+            0 => Ok(Status::Success),
+            1 => Ok(Status::Unsupported),
+            2 => Ok(Status::Canceled),
+            3 => Ok(Status::DeviceBusy),
+            4 => Ok(Status::Invalid),
+            5 => Ok(Status::EndOfFile),
+            6 => Ok(Status::Jammed),
+            7 => Ok(Status::NoDocuments),
+            8 => Ok(Status::CoverOpen),
+            9 => Ok(Status::IOError),
+            10 => Ok(Status::OutOfMemory),
+            11 => Ok(Status::AccessDenied),
+            n => Err(Error::InvalidSaneFieldValue(
+                "Received invalid value for Status field".into(),
+                n,
+            )),
+        }
+    }
+}
+
+impl From<Status> for i32 {
+    fn from(status: Status) -> i32 {
+        match status {
+            Status::Success => 0,
+            Status::Unsupported => 1,
+            Status::Canceled => 2,
+            Status::DeviceBusy => 3,
+            Status::Invalid => 4,
+            Status::EndOfFile => 5,
+            Status::Jammed => 6,
+            Status::NoDocuments => 7,
+            Status::CoverOpen => 8,
+            Status::IOError => 9,
+            Status::OutOfMemory => 10,
+            Status::AccessDenied => 11,
+        }
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Same messages as sane_strstatus()
+        f.write_str(match *self {
+            Status::Success => "Success",
+            Status::Unsupported => "Operation not supported",
+            Status::Canceled => "Operation was cancelled",
+            Status::DeviceBusy => "Device busy",
+            Status::Invalid => "Invalid argument",
+            Status::EndOfFile => "End of file reached",
+            Status::Jammed => "Document feeder jammed",
+            Status::NoDocuments => "Document feeder out of documents",
+            Status::CoverOpen => "Scanner cover is open",
+            Status::IOError => "Error during device I/O",
+            Status::OutOfMemory => "Out of memory",
+            Status::AccessDenied => "Access to resource has been denied",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_round_trips_through_i32() {
+        for code in 0..12 {
+            let status = Status::try_from(code).unwrap();
+            assert_eq!(i32::from(status), code);
+        }
+    }
+
+    #[test]
+    fn unknown_status_is_an_error() {
+        match Status::try_from(42) {
+            Err(Error::InvalidSaneFieldValue(_, 42)) => {}
+            other => panic!("Expected InvalidSaneFieldValue, got {:?}", other),
         }
     }
 }
@@ -0,0 +1,795 @@
+//! A fake saned running in-process, for testing frontends without a scanner.
+//!
+//! `FakeSaned` decodes the requests written to it and answers them the way saned
+//! would, from scripted devices, option values and scan data. Since it is itself
+//! the stream, a `Session` can be run directly on top of it:
+//!
+//! ```
+//! use sane::testing::{FakeDevice, FakeSaned};
+//! use sane::Session;
+//!
+//! let saned = FakeSaned::new().device(FakeDevice::new("test:0"));
+//! let session = Session::new(saned).unwrap();
+//!
+//! let devices = session.devices().unwrap();
+//! assert_eq!(devices[0].name, "test:0");
+//! ```
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{self, Cursor, Read, Write};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::error::Error;
+use crate::scan::ByteOrder;
+use crate::status::Status;
+use crate::types::*;
+use crate::{read_value_bytes, Device, Result, Transport, TryFromStream, Version, WriteToStream};
+
+/// Record length sent on the data connection to signal the end of the image data.
+const END_OF_DATA: u32 = 0xffffffff;
+
+/// First port handed out for data connections; the ports are never actually bound.
+const FIRST_DATA_PORT: u16 = 40000;
+
+/// The requests that can be made to fail with `FakeSaned::fail_next`,
+/// being those whose reply carries a status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rpc {
+    Init,
+    GetDevices,
+    Open,
+    ControlOption,
+    GetParameters,
+    Start,
+}
+
+/// A request received by `FakeSaned`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Init {
+        version: Version,
+        username: Option<String>,
+    },
+    GetDevices,
+    Open(String),
+    Close(i32),
+    GetOptionDescriptors(i32),
+    ControlOption {
+        handle: i32,
+        index: i32,
+        action: OptionAction,
+    },
+    GetParameters(i32),
+    Start(i32),
+    Cancel(i32),
+    Authorize {
+        resource: Option<String>,
+        username: Option<String>,
+    },
+    Exit,
+}
+
+/// A device served by `FakeSaned`, with its options and the frames it scans.
+#[derive(Debug, Clone)]
+pub struct FakeDevice {
+    device: Device,
+    options: Vec<(OptionDescriptor, OptionValue)>,
+    frames: VecDeque<(Parameters, Vec<u8>)>,
+    /// Parameters of the frame being scanned.
+    scanning: Option<Parameters>,
+}
+
+impl FakeDevice {
+    /// A device named `name`, with no options and nothing to scan.
+    pub fn new(name: &str) -> FakeDevice {
+        FakeDevice {
+            device: Device {
+                name: name.into(),
+                vendor: "Noname".into(),
+                model: "frontend-tester".into(),
+                kind: "virtual device".into(),
+            },
+            options: Vec::new(),
+            frames: VecDeque::new(),
+            scanning: None,
+        }
+    }
+
+    /// Add an option described by `descriptor`, holding `value`.
+    /// Options are numbered from 1 in the order they are added.
+    pub fn option(mut self, descriptor: OptionDescriptor, value: OptionValue) -> FakeDevice {
+        self.options.push((descriptor, value));
+        self
+    }
+
+    /// Add a frame to be scanned, described by `parameters`.
+    ///
+    /// Each SANE_NET_START scans the next frame, and fails with
+    /// `Status::NoDocuments` once there are none left, like an empty
+    /// document feeder. Multi-pass images are added as one frame per pass.
+    pub fn frame(mut self, parameters: Parameters, data: Vec<u8>) -> FakeDevice {
+        self.frames.push_back((parameters, data));
+        self
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// The current value of the option named `name`.
+    pub fn value(&self, name: &str) -> Option<&OptionValue> {
+        self.options
+            .iter()
+            .find(|(descriptor, _)| descriptor.name() == Some(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Parameters of the frame being scanned, or else of the next frame.
+    fn parameters(&self) -> Parameters {
+        self.scanning
+            .clone()
+            .or_else(|| {
+                self.frames
+                    .front()
+                    .map(|(parameters, _)| parameters.clone())
+            })
+            .unwrap_or(Parameters {
+                format: FrameFormat::Gray,
+                last_frame: true,
+                bytes_per_line: 0,
+                pixels_per_line: 0,
+                lines: 0,
+                depth: 8,
+            })
+    }
+
+    /// The descriptor and value of option number `index`, including option 0,
+    /// which holds the number of options.
+    fn option_at(&self, index: i32) -> Option<(OptionDescriptor, OptionValue)> {
+        if index == 0 {
+            let count = self.options.len() as i32 + 1;
+            return Some((option_count_descriptor(), OptionValue::Integer(count)));
+        }
+
+        let index = usize::try_from(index).ok()?.checked_sub(1)?;
+        self.options.get(index).cloned()
+    }
+}
+
+fn option_count_descriptor() -> OptionDescriptor {
+    OptionDescriptor::Integer {
+        name: String::new(),
+        title: "Number of options".into(),
+        description: String::new(),
+        unit: OptionUnit::None,
+        size: 4,
+        capabilities: Capabilities::SoftDetect,
+        constraint: None,
+    }
+}
+
+/// A fake saned, answering the requests written to it with replies read back from it.
+///
+/// Requests are answered as soon as they have been completely written, and reading
+/// past the end of the replies sent so far returns end of file. Data connections
+/// opened through `Transport` serve the image data of the frame just started.
+#[derive(Debug)]
+pub struct FakeSaned {
+    version: Version,
+    byte_order: ByteOrder,
+    devices: Vec<FakeDevice>,
+    /// The device opened as each handle, or None once closed.
+    handles: Vec<Option<usize>>,
+    failures: Vec<(Rpc, Status)>,
+    requests: Vec<Request>,
+    /// Data written by the client, not yet decoded into a request.
+    input: Vec<u8>,
+    /// Replies not yet read by the client.
+    output: VecDeque<u8>,
+    /// Image data of started frames, by data port, until their connection is opened.
+    data: Vec<(u16, Vec<u8>)>,
+    next_port: u16,
+}
+
+impl Default for FakeSaned {
+    fn default() -> FakeSaned {
+        FakeSaned {
+            version: Version::new(1, 0, 3),
+            byte_order: ByteOrder::LittleEndian,
+            devices: Vec::new(),
+            handles: Vec::new(),
+            failures: Vec::new(),
+            requests: Vec::new(),
+            input: Vec::new(),
+            output: VecDeque::new(),
+            data: Vec::new(),
+            next_port: FIRST_DATA_PORT,
+        }
+    }
+}
+
+impl FakeSaned {
+    /// A server with no devices, speaking protocol version 1.0.3
+    /// and sending scan data in little-endian byte order.
+    pub fn new() -> FakeSaned {
+        FakeSaned::default()
+    }
+
+    /// Reply to SANE_NET_INIT with `version`.
+    pub fn version(mut self, version: Version) -> FakeSaned {
+        self.version = version;
+        self
+    }
+
+    /// Announce `byte_order` as the byte order of scan data. Frame data is sent as given,
+    /// so 16-bit samples are expected to be in this byte order already.
+    pub fn byte_order(mut self, byte_order: ByteOrder) -> FakeSaned {
+        self.byte_order = byte_order;
+        self
+    }
+
+    /// Serve `device`.
+    pub fn device(mut self, device: FakeDevice) -> FakeSaned {
+        self.devices.push(device);
+        self
+    }
+
+    /// Fail the next request of kind `rpc` with `status`.
+    ///
+    /// Failures are used up in the order they were added.
+    pub fn fail_next(&mut self, rpc: Rpc, status: Status) {
+        self.failures.push((rpc, status));
+    }
+
+    /// The requests received so far, in order.
+    pub fn requests(&self) -> &[Request] {
+        &self.requests
+    }
+
+    /// The device named `name`, including changes made to its options.
+    pub fn find_device(&self, name: &str) -> Option<&FakeDevice> {
+        self.devices
+            .iter()
+            .find(|device| device.device.name == name)
+    }
+
+    /// Returns true if every reply sent has been read.
+    pub fn is_drained(&self) -> bool {
+        self.output.is_empty()
+    }
+
+    /// Decode and answer every complete request written so far.
+    fn process(&mut self) -> Result<()> {
+        loop {
+            let mut cursor = Cursor::new(&self.input[..]);
+            let request = match read_request(&mut cursor, self.version) {
+                Ok(request) => request,
+                Err(Error::IOError(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e),
+            };
+            let consumed = cursor.position() as usize;
+            self.input.drain(..consumed);
+
+            debug!("Fake saned received {:?}", request);
+            let mut reply = Vec::new();
+            self.reply(&request, &mut reply)?;
+            self.output.extend(reply);
+            self.requests.push(request);
+        }
+    }
+
+    /// Take the failure injected for the next request of kind `rpc`, if any.
+    fn failure(&mut self, rpc: Rpc) -> Option<Status> {
+        let position = self.failures.iter().position(|&(kind, _)| kind == rpc)?;
+        Some(self.failures.remove(position).1)
+    }
+
+    fn device_mut(&mut self, handle: i32) -> Option<&mut FakeDevice> {
+        let index = usize::try_from(handle).ok()?;
+        let device = (*self.handles.get(index)?)?;
+        self.devices.get_mut(device)
+    }
+
+    fn reply(&mut self, request: &Request, reply: &mut Vec<u8>) -> Result<()> {
+        match *request {
+            Request::Init { .. } => {
+                let status = self.failure(Rpc::Init).unwrap_or(Status::Success);
+                write_status(status, reply)?;
+                reply.write_u32::<BigEndian>(self.version.code())?;
+            }
+            Request::GetDevices => {
+                let status = self.failure(Rpc::GetDevices).unwrap_or(Status::Success);
+                let devices = match status {
+                    Status::Success => self.devices.iter().map(|d| d.device.clone()).collect(),
+                    _ => Vec::new(),
+                };
+
+                write_status(status, reply)?;
+                (devices.len() as i32 + 1).write_to_stream(reply)?;
+                for device in &devices {
+                    0i32.write_to_stream(reply)?; // pointer is not null
+                    device.write_to_stream(reply)?;
+                }
+                1i32.write_to_stream(reply)?; // trailing null element
+            }
+            Request::Open(ref name) => {
+                let device = self
+                    .devices
+                    .iter()
+                    .position(|device| &device.device.name == name);
+                let (status, handle) = match (self.failure(Rpc::Open), device) {
+                    (Some(status), _) => (status, 0),
+                    (None, Some(device)) => {
+                        self.handles.push(Some(device));
+                        (Status::Success, self.handles.len() as i32 - 1)
+                    }
+                    (None, None) => (Status::Invalid, 0),
+                };
+
+                write_status(status, reply)?;
+                handle.write_to_stream(reply)?;
+                None::<&str>.write_to_stream(reply)?; // resource
+            }
+            Request::Close(handle) => {
+                if let Some(device) = usize::try_from(handle)
+                    .ok()
+                    .and_then(|index| self.handles.get_mut(index))
+                {
+                    *device = None;
+                }
+                0i32.write_to_stream(reply)?; // dummy
+            }
+            Request::GetOptionDescriptors(handle) => {
+                let descriptors: Vec<OptionDescriptor> = match self.device_mut(handle) {
+                    Some(device) => (0..=device.options.len() as i32)
+                        .filter_map(|index| device.option_at(index))
+                        .map(|(descriptor, _)| descriptor)
+                        .collect(),
+                    None => Vec::new(),
+                };
+
+                // Unlike device lists, there is no trailing null element
+                (descriptors.len() as i32).write_to_stream(reply)?;
+                for descriptor in &descriptors {
+                    0i32.write_to_stream(reply)?; // pointer is not null
+                    descriptor.write_to_stream(reply)?;
+                }
+            }
+            Request::ControlOption {
+                handle,
+                index,
+                ref action,
+            } => {
+                // An injected failure leaves the option untouched
+                let failure = self.failure(Rpc::ControlOption);
+                let option = match failure {
+                    Some(_) => None,
+                    None => self
+                        .device_mut(handle)
+                        .and_then(|device| control_option(device, index, action)),
+                };
+
+                let (status, value) = match (failure, option) {
+                    (Some(status), _) => (status, None),
+                    (None, Some(Ok(value))) => (Status::Success, Some(value)),
+                    (None, Some(Err(status))) => (status, None),
+                    (None, None) => (Status::Invalid, None),
+                };
+
+                write_status(status, reply)?;
+                0i32.write_to_stream(reply)?; // info
+                match value {
+                    Some((ref descriptor, ref value)) => descriptor.write_value(value, reply)?,
+                    None => {
+                        // value type, size, and an empty array
+                        0i32.write_to_stream(reply)?;
+                        0i32.write_to_stream(reply)?;
+                        <[i32]>::write_to_stream(&[], reply)?;
+                    }
+                }
+                None::<&str>.write_to_stream(reply)?; // resource
+            }
+            Request::GetParameters(handle) => {
+                let failure = self.failure(Rpc::GetParameters);
+                let parameters = self.device_mut(handle).map(|device| device.parameters());
+
+                let status = match (failure, &parameters) {
+                    (Some(status), _) => status,
+                    (None, Some(_)) => Status::Success,
+                    (None, None) => Status::Invalid,
+                };
+
+                write_status(status, reply)?;
+                parameters
+                    .unwrap_or_else(|| FakeDevice::new("").parameters())
+                    .write_to_stream(reply)?;
+            }
+            Request::Start(handle) => {
+                let failure = self.failure(Rpc::Start);
+                let frame = match (failure, self.device_mut(handle)) {
+                    (Some(status), _) => Err(status),
+                    (None, Some(device)) => match device.frames.pop_front() {
+                        Some((parameters, data)) => {
+                            device.scanning = Some(parameters);
+                            Ok(data)
+                        }
+                        None => Err(Status::NoDocuments),
+                    },
+                    (None, None) => Err(Status::Invalid),
+                };
+
+                let port = match frame {
+                    Ok(data) => {
+                        let port = self.next_port;
+                        self.next_port = self.next_port.wrapping_add(1).max(FIRST_DATA_PORT);
+                        self.data.push((port, encode_image_data(&data)));
+                        Ok(port)
+                    }
+                    Err(status) => Err(status),
+                };
+
+                match port {
+                    Ok(port) => {
+                        write_status(Status::Success, reply)?;
+                        i32::from(port).write_to_stream(reply)?;
+                    }
+                    Err(status) => {
+                        write_status(status, reply)?;
+                        0i32.write_to_stream(reply)?;
+                    }
+                }
+                match self.byte_order {
+                    ByteOrder::LittleEndian => 0x1234i32,
+                    ByteOrder::BigEndian => 0x4321,
+                }
+                .write_to_stream(reply)?;
+                None::<&str>.write_to_stream(reply)?; // resource
+            }
+            Request::Cancel(handle) => {
+                if let Some(device) = self.device_mut(handle) {
+                    device.scanning = None;
+                }
+                0i32.write_to_stream(reply)?; // dummy
+            }
+            Request::Authorize { .. } => {
+                0i32.write_to_stream(reply)?; // dummy
+            }
+            // The server doesn't reply
+            Request::Exit => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Perform `action` on option number `index` of `device`, returning the descriptor
+/// and value of the option afterwards, or None if there is no such option.
+fn control_option(
+    device: &mut FakeDevice,
+    index: i32,
+    action: &OptionAction,
+) -> Option<::std::result::Result<(OptionDescriptor, OptionValue), Status>> {
+    let (descriptor, value) = device.option_at(index)?;
+
+    let value = match *action {
+        OptionAction::Get => Ok(value),
+        OptionAction::Set(ref value) => descriptor
+            .check_value(value.clone())
+            .map_err(|_| Status::Invalid),
+        OptionAction::SetAuto if descriptor.capabilities().contains(Capabilities::Automatic) => {
+            Ok(value)
+        }
+        OptionAction::SetAuto => Err(Status::Invalid),
+    };
+
+    Some(value.map(|value| {
+        if index > 0 {
+            device.options[index as usize - 1].1 = value.clone();
+        }
+        (descriptor, value)
+    }))
+}
+
+fn write_status(status: Status, reply: &mut Vec<u8>) -> Result<()> {
+    Ok(reply.write_i32::<BigEndian>(i32::from(status))?)
+}
+
+/// Encode `data` as sent on a data connection: as a single record,
+/// followed by the end of data marker and the final status of the scan.
+fn encode_image_data(data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 9);
+    if !data.is_empty() {
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+    }
+    buf.extend_from_slice(&END_OF_DATA.to_be_bytes());
+    buf.push(5); // status: end of file
+    buf
+}
+
+/// Read a request, as sent by a client talking to a server of `version`.
+fn read_request<S: Read>(stream: &mut S, version: Version) -> Result<Request> {
+    let request = match i32::try_from_stream(stream)? {
+        0 => Request::Init {
+            version: Version::from_code(u32::try_from_stream(stream)?),
+            username: <_>::try_from_stream(stream)?,
+        },
+        1 => Request::GetDevices,
+        2 => Request::Open(<Option<String>>::try_from_stream(stream)?.unwrap_or_default()),
+        3 => Request::Close(i32::try_from_stream(stream)?),
+        4 => Request::GetOptionDescriptors(i32::try_from_stream(stream)?),
+        5 => {
+            let handle = i32::try_from_stream(stream)?;
+            let index = i32::try_from_stream(stream)?;
+            let action = match i32::try_from_stream(stream)? {
+                0 => {
+                    read_value(stream)?;
+                    OptionAction::Get
+                }
+                1 => OptionAction::Set(read_value(stream)?),
+                // As of protocol version 3, no value is sent when setting automatically
+                2 => {
+                    if version.set_auto_sends_value() {
+                        read_value(stream)?;
+                    }
+                    OptionAction::SetAuto
+                }
+                action => {
+                    return Err(Error::InvalidSaneFieldValue(
+                        "Received invalid option action".into(),
+                        action,
+                    ))
+                }
+            };
+            Request::ControlOption {
+                handle,
+                index,
+                action,
+            }
+        }
+        6 => Request::GetParameters(i32::try_from_stream(stream)?),
+        7 => Request::Start(i32::try_from_stream(stream)?),
+        8 => Request::Cancel(i32::try_from_stream(stream)?),
+        9 => {
+            let resource = <_>::try_from_stream(stream)?;
+            let username = <_>::try_from_stream(stream)?;
+            <Option<String>>::try_from_stream(stream)?; // password
+            Request::Authorize { resource, username }
+        }
+        10 => Request::Exit,
+        code => {
+            return Err(Error::InvalidSaneFieldValue(
+                "Received unknown request".into(),
+                code,
+            ))
+        }
+    };
+
+    Ok(request)
+}
+
+/// Read an option value sent with SANE_NET_CONTROL_OPTION: its type, size and
+/// array of elements, decoded as the value of an option of that type and size.
+fn read_value<S: Read>(stream: &mut S) -> Result<OptionValue> {
+    let value_type = i32::try_from_stream(stream)?;
+    let size = i32::try_from_stream(stream)?;
+    let value = read_value_bytes(stream, value_type)?;
+
+    let (name, title, description) = (String::new(), String::new(), String::new());
+    let unit = OptionUnit::None;
+    let capabilities = Capabilities::empty();
+    let descriptor = match value_type {
+        0 => OptionDescriptor::Boolean {
+            name,
+            title,
+            description,
+            unit,
+            capabilities,
+            _no_constrainst: NoConstraint,
+        },
+        1 => OptionDescriptor::Integer {
+            name,
+            title,
+            description,
+            unit,
+            size,
+            capabilities,
+            constraint: None,
+        },
+        2 => OptionDescriptor::Fixed {
+            name,
+            title,
+            description,
+            unit,
+            size,
+            capabilities,
+            constraint: None,
+        },
+        3 => OptionDescriptor::String {
+            name,
+            title,
+            description,
+            unit,
+            max_length: size,
+            capabilities,
+            constraint: None,
+        },
+        _ => return Ok(OptionValue::Button),
+    };
+
+    descriptor.read_value(&mut Cursor::new(value))
+}
+
+impl Read for FakeSaned {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (front, _) = self.output.as_slices();
+        let read = Read::read(&mut &front[..], buf)?;
+        self.output.drain(..read);
+        Ok(read)
+    }
+}
+
+impl Write for FakeSaned {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.input.extend_from_slice(buf);
+        self.process()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for FakeSaned {
+    type DataConnection = Cursor<Vec<u8>>;
+
+    fn open_data_connection(&mut self, port: u16) -> Result<Cursor<Vec<u8>>> {
+        let position = self
+            .data
+            .iter()
+            .position(|&(data_port, _)| data_port == port)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(Cursor::new(self.data.remove(position).1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Session;
+
+    fn resolution() -> OptionDescriptor {
+        OptionDescriptor::Integer {
+            name: "resolution".into(),
+            title: "Scan resolution".into(),
+            description: "Sets the resolution of the scanned image.".into(),
+            unit: OptionUnit::DPI,
+            size: 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(NumericalConstraint::Range(Some(Range::new(75, 600, 0)))),
+        }
+    }
+
+    fn gray_frame() -> (Parameters, Vec<u8>) {
+        let parameters = Parameters {
+            format: FrameFormat::Gray,
+            last_frame: true,
+            bytes_per_line: 2,
+            pixels_per_line: 2,
+            lines: 2,
+            depth: 8,
+        };
+        (parameters, vec![1, 2, 3, 4])
+    }
+
+    #[test]
+    fn session_sets_options_and_scans() {
+        let (parameters, data) = gray_frame();
+        let saned = FakeSaned::new().device(
+            FakeDevice::new("test:0")
+                .option(resolution(), OptionValue::Integer(150))
+                .frame(parameters.clone(), data.clone()),
+        );
+
+        let session = Session::new(saned).unwrap();
+        let device = session.devices().unwrap().remove(0);
+        let handle = session.open(&device).unwrap();
+
+        let mut options = handle.options().unwrap();
+        assert_eq!(
+            options.get("resolution").unwrap(),
+            OptionValue::Integer(150)
+        );
+        options.set("resolution", 300).unwrap();
+        assert!(options.set("resolution", 1200).is_err());
+
+        let image = handle.scan_image().unwrap();
+        assert_eq!(image.parameters, parameters);
+        assert_eq!(image.data, data);
+
+        // The feeder is now empty
+        assert_eq!(handle.scan_all().count(), 0);
+        handle.close().unwrap();
+
+        let saned = session.stream();
+        let device = saned.find_device("test:0").unwrap();
+        assert_eq!(device.value("resolution"), Some(&OptionValue::Integer(300)));
+        assert!(saned.requests().contains(&Request::Start(0)));
+        assert!(saned.is_drained());
+    }
+
+    #[test]
+    fn injected_failures_are_replied() {
+        let saned = FakeSaned::new().device(FakeDevice::new("test:0"));
+        let session = Session::new(saned).unwrap();
+
+        session
+            .stream()
+            .fail_next(Rpc::GetDevices, Status::DeviceBusy);
+        match session.devices() {
+            Err(Error::SanedError(Status::DeviceBusy)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        // Only the next request fails, and the connection stays in sync
+        assert_eq!(session.devices().unwrap().len(), 1);
+        assert!(session.stream().is_drained());
+    }
+
+    #[test]
+    fn failed_option_control_keeps_connection_in_sync() {
+        let saned = FakeSaned::new()
+            .device(FakeDevice::new("test:0").option(resolution(), OptionValue::Integer(150)));
+        let session = Session::new(saned).unwrap();
+        let device = session.devices().unwrap().remove(0);
+        let handle = session.open(&device).unwrap();
+        let mut options = handle.options().unwrap();
+
+        session
+            .stream()
+            .fail_next(Rpc::ControlOption, Status::DeviceBusy);
+        match options.set("resolution", 300) {
+            Err(Error::SanedError(Status::DeviceBusy)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+
+        assert_eq!(
+            options.get("resolution").unwrap(),
+            OptionValue::Integer(150)
+        );
+        assert!(session.stream().is_drained());
+    }
+
+    #[test]
+    fn set_auto_value_is_read_before_version_3() {
+        let mut descriptor = resolution();
+        if let OptionDescriptor::Integer {
+            ref mut capabilities,
+            ..
+        } = descriptor
+        {
+            *capabilities |= Capabilities::Automatic;
+        }
+        let saned = FakeSaned::new()
+            .version(Version::new(1, 0, 2))
+            .device(FakeDevice::new("test:0").option(descriptor, OptionValue::Integer(150)));
+        let session = Session::new(saned).unwrap();
+        let device = session.devices().unwrap().remove(0);
+        let handle = session.open(&device).unwrap();
+        let mut options = handle.options().unwrap();
+
+        let (value, _) = options.set_auto("resolution").unwrap();
+        assert_eq!(value, OptionValue::Integer(150));
+        assert_eq!(
+            options.get("resolution").unwrap(),
+            OptionValue::Integer(150)
+        );
+        assert!(session.stream().is_drained());
+    }
+}
@@ -0,0 +1,46 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use crate::Result;
+
+/// A connection to saned that requests are sent over, and that can open
+/// the separate data connections image data is sent over.
+///
+/// Implemented for `TcpStream`; wrap other connections (such as a TLS tunnel
+/// or an in-process test double) by implementing it for the wrapper.
+pub trait Transport: Read + Write {
+    /// The connection image data is read from.
+    type DataConnection: Read;
+
+    /// Open the data connection of a scan, which saned is
+    /// waiting for on `port` on the same host.
+    fn open_data_connection(&mut self, port: u16) -> Result<Self::DataConnection>;
+}
+
+/// The data connection is made to the same host, with the same read and write
+/// timeouts; the read timeout also limits the time spent connecting.
+impl Transport for TcpStream {
+    type DataConnection = TcpStream;
+
+    fn open_data_connection(&mut self, port: u16) -> Result<TcpStream> {
+        let address = SocketAddr::new(self.peer_addr()?.ip(), port);
+
+        info!("Opening data connection to {}", address);
+        let connection = match self.read_timeout()? {
+            Some(timeout) => TcpStream::connect_timeout(&address, timeout)?,
+            None => TcpStream::connect(address)?,
+        };
+        connection.set_read_timeout(self.read_timeout()?)?;
+        connection.set_write_timeout(self.write_timeout()?)?;
+
+        Ok(connection)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    type DataConnection = T::DataConnection;
+
+    fn open_data_connection(&mut self, port: u16) -> Result<T::DataConnection> {
+        (**self).open_data_connection(port)
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use crate::{Result, TryFromStream, WriteToStream};
+
+/// Number of fractional bits in a SANE fixed-point value.
+const SCALE_SHIFT: u32 = 16;
+
+/// A SANE fixed-point number, with 16 integer and 16 fractional bits.
+///
+/// See: http://www.sane-project.org/html/doc011.html#s4.2.3
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Fixed(i32);
+
+impl Fixed {
+    /// Create a fixed-point value from its raw 16.16 representation.
+    pub fn from_raw(raw: i32) -> Fixed {
+        Fixed(raw)
+    }
+
+    /// The raw 16.16 representation of the value.
+    pub fn raw(self) -> i32 {
+        self.0
+    }
+
+    /// Create a fixed-point value from an integer, saturating at the
+    /// bounds of the representable range (-32768 to 32767).
+    pub fn from_int(value: i32) -> Fixed {
+        let value = value.max(i32::from(i16::MIN)).min(i32::from(i16::MAX));
+        Fixed(value << SCALE_SHIFT)
+    }
+
+    /// Create a fixed-point value from `value`, rounded to the nearest representable value
+    /// and saturating at the bounds of the representable range.
+    pub fn from_f64(value: f64) -> Fixed {
+        let raw = (value * f64::from(1 << SCALE_SHIFT)).round();
+        Fixed(raw.max(f64::from(i32::MIN)).min(f64::from(i32::MAX)) as i32)
+    }
+
+    /// Convert to a floating-point value; this conversion is exact.
+    pub fn to_f64(self) -> f64 {
+        f64::from(self.0) / f64::from(1 << SCALE_SHIFT)
+    }
+}
+
+impl From<Fixed> for f64 {
+    fn from(value: Fixed) -> f64 {
+        value.to_f64()
+    }
+}
+
+impl fmt::Display for Fixed {
+    /// Formats the value as a decimal number, respecting the formatter's precision.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.to_f64(), f)
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0 + other.0)
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0 - other.0)
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed(((i64::from(self.0) * i64::from(other.0)) >> SCALE_SHIFT) as i32)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+
+    fn div(self, other: Fixed) -> Fixed {
+        Fixed(((i64::from(self.0) << SCALE_SHIFT) / i64::from(other.0)) as i32)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Fixed {
+        Fixed(-self.0)
+    }
+}
+
+impl TryFromStream for Fixed {
+    fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
+        i32::try_from_stream(stream).map(Fixed)
+    }
+}
+
+impl WriteToStream for Fixed {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        self.0.write_to_stream(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_and_from_f64() {
+        assert_eq!(Fixed::from_f64(1.5).raw(), 0x0001_8000);
+        assert_eq!(Fixed::from_raw(-0x0002_4000).to_f64(), -2.25);
+        assert_eq!(Fixed::from_int(215), Fixed::from_f64(215.0));
+        assert_eq!(format!("{:.1}", Fixed::from_f64(297.04)), "297.0");
+    }
+
+    #[test]
+    fn arithmetic_keeps_fixed_point_scale() {
+        let a = Fixed::from_f64(2.5);
+        let b = Fixed::from_f64(0.5);
+
+        assert_eq!(a + b, Fixed::from_int(3));
+        assert_eq!(a - b, Fixed::from_int(2));
+        assert_eq!(a * b, Fixed::from_f64(1.25));
+        assert_eq!(a / b, Fixed::from_int(5));
+        assert_eq!(-a, Fixed::from_f64(-2.5));
+    }
+}
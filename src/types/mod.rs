@@ -1,14 +1,90 @@
+mod fixed;
+mod parameters;
 mod std;
-pub use self::std::*;
-use std::io::Read;
+mod value;
+pub use self::fixed::*;
+pub use self::parameters::*;
+pub(crate) use self::std::{
+    check_array_length, check_string_length, non_null, read_bytes, read_null_terminated,
+};
+pub use self::value::*;
+use ::std::cell::Cell;
+use ::std::io::Read;
 
-use error::Error;
-use {Result, TryFromStream};
+use crate::error::Error;
+use crate::{Result, TryFromStream, WriteToStream};
+
+/// How data received from the network is decoded.
+///
+/// Set per connection with `SessionBuilder::decode_options` or
+/// `Session::set_decode_options`. Requests made through the request functions
+/// directly are decoded with the defaults, unless made within `DecodeOptions::scope`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Decode protocol enum values leniently.
+    ///
+    /// By default, a unit, value type or constraint type that isn't defined by the
+    /// SANE standard fails the whole parse with `Error::InvalidSaneFieldValue`.
+    /// In lenient mode such values are decoded into `Unknown` variants instead,
+    /// so a newer or quirky server degrades gracefully rather than making every
+    /// option descriptor unreadable.
+    pub lenient: bool,
+    /// The maximum number of elements accepted in an array, such as a device
+    /// list or the value of a word array option.
+    ///
+    /// Longer arrays fail to decode with `Error::BadNetworkDataError`, rather than
+    /// trusting a corrupted or malicious length. Defaults to 1048576, enough
+    /// for the largest gamma tables.
+    pub max_array_length: usize,
+    /// The maximum length in bytes of strings, including the null terminator,
+    /// like `max_array_length`. Defaults to 65536.
+    pub max_string_length: usize,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> DecodeOptions {
+        DecodeOptions {
+            lenient: false,
+            max_array_length: 1 << 20,
+            max_string_length: 1 << 16,
+        }
+    }
+}
+
+thread_local! {
+    static DECODE_OPTIONS: Cell<DecodeOptions> = Cell::new(DecodeOptions::default());
+}
+
+impl DecodeOptions {
+    /// Call `f`, decoding everything it reads on this thread with these options.
+    pub fn scope<T, F: FnOnce() -> T>(self, f: F) -> T {
+        struct Restore(DecodeOptions);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                DECODE_OPTIONS.with(|options| options.set(self.0));
+            }
+        }
+
+        let _restore = Restore(DECODE_OPTIONS.with(|options| options.replace(self)));
+        f()
+    }
+
+    /// The options data is currently decoded with on this thread.
+    pub(crate) fn current() -> DecodeOptions {
+        DECODE_OPTIONS.with(Cell::get)
+    }
+}
+
+/// Returns true if enum values are currently decoded leniently.
+pub(crate) fn is_lenient() -> bool {
+    DecodeOptions::current().lenient
+}
 
 /// The type of an option value, in an OptionDescriptor.
 ///
 /// See: http://www.sane-project.org/html/doc011.html#s4.2.9.4
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum OptionValueType {
     Boolean,
     Integer,
@@ -29,6 +105,10 @@ pub enum OptionValueType {
     /// For group options, only members `title` and `type` are valid
     /// in the option descriptor.
     Group,
+
+    /// A type not defined by the SANE standard, holding the raw value.
+    /// Only produced in lenient mode.
+    Unknown(i32),
 }
 
 impl TryFromStream for OptionValueType {
@@ -41,6 +121,10 @@ impl TryFromStream for OptionValueType {
             3 => Ok(OptionValueType::String),
             4 => Ok(OptionValueType::Button),
             5 => Ok(OptionValueType::Group),
+            x if is_lenient() => {
+                warn!("Received unknown OptionValueType {}", x);
+                Ok(OptionValueType::Unknown(x))
+            }
             x => Err(Error::InvalidSaneFieldValue(
                 "Received invalid value for OptionValueType field".into(),
                 x,
@@ -58,7 +142,9 @@ impl TryFromStream for OptionValueType {
 /// > quantities in a customary unit (e.g., inches or centimeters).
 ///
 /// See: http://www.sane-project.org/html/doc011.html#s4.2.9.5
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum OptionUnit {
     None,
     Pixel,
@@ -67,6 +153,9 @@ pub enum OptionUnit {
     DPI,
     Percent,
     Microsecond,
+    /// A unit not defined by the SANE standard, holding the raw value.
+    /// Only produced in lenient mode.
+    Unknown(i32),
 }
 
 impl TryFromStream for OptionUnit {
@@ -80,6 +169,10 @@ impl TryFromStream for OptionUnit {
             4 => Ok(OptionUnit::DPI),
             5 => Ok(OptionUnit::Percent),
             6 => Ok(OptionUnit::Microsecond),
+            x if is_lenient() => {
+                warn!("Received unknown OptionUnit {}", x);
+                Ok(OptionUnit::Unknown(x))
+            }
             x => Err(Error::InvalidSaneFieldValue(
                 "Received invalid value for OptionUnit field".into(),
                 x,
@@ -88,31 +181,206 @@ impl TryFromStream for OptionUnit {
     }
 }
 
+impl WriteToStream for OptionUnit {
+    fn write_to_stream<S: ::std::io::Write>(&self, stream: &mut S) -> Result<()> {
+        let code = match *self {
+            OptionUnit::None => 0,
+            OptionUnit::Pixel => 1,
+            OptionUnit::Bit => 2,
+            OptionUnit::Millimeter => 3,
+            OptionUnit::DPI => 4,
+            OptionUnit::Percent => 5,
+            OptionUnit::Microsecond => 6,
+            OptionUnit::Unknown(code) => code,
+        };
+        code.write_to_stream(stream)
+    }
+}
+
 pub trait OptionConstraint {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct NoConstraint;
-#[derive(Debug)]
+/// The strings allowed as the value of a string option; serialized as a plain list.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct StringListConstraint(Vec<String>);
-#[derive(Debug)]
-pub enum NumericalConstraint {
-    IntegerList(Vec<i32>),
-    Range(Option<Range>),
+
+impl StringListConstraint {
+    /// The strings the option's value must be one of.
+    pub fn values(&self) -> &[String] {
+        &self.0
+    }
+
+    /// The allowed string `value` stands for: the one equal to it, or else
+    /// the one equal to it ignoring ASCII case.
+    pub fn matches(&self, value: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|allowed| *allowed == value)
+            .or_else(|| {
+                self.0
+                    .iter()
+                    .find(|allowed| allowed.eq_ignore_ascii_case(value))
+            })
+            .map(String::as_str)
+    }
+}
+
+/// A constraint on the value of a numerical option, where `T` is `i32`
+/// for integer options and `Fixed` for fixed-point options.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum NumericalConstraint<T: Word = i32> {
+    /// The value must be one of the listed values.
+    IntegerList(Vec<T>),
+    Range(Option<Range<T>>),
+    /// A constraint type not defined by the SANE standard, holding the raw value.
+    /// Only produced in lenient mode.
+    Unknown(i32),
+}
+
+impl<T: Word> NumericalConstraint<T> {
+    /// The allowed value nearest to `value`: the closest listed value, with ties
+    /// going to the lower one, or `value` moved into the range by `Range::clamp_quantize`.
+    /// Without a known constraint, `value` itself.
+    pub fn nearest(&self, value: T) -> T {
+        match *self {
+            NumericalConstraint::IntegerList(ref list) => list
+                .iter()
+                .cloned()
+                .min_by_key(|allowed| {
+                    let distance =
+                        (i64::from(allowed.to_word()) - i64::from(value.to_word())).abs();
+                    (distance, allowed.to_word())
+                })
+                .unwrap_or(value),
+            NumericalConstraint::Range(Some(ref range)) => range.clamp_quantize(value),
+            _ => value,
+        }
+    }
+}
+
+/// Returns true if `kind` is one of the constraint types defined by the SANE standard.
+///
+/// See: http://www.sane-project.org/html/doc011.html#s4.2.9.8
+fn is_known_constraint_type(kind: i32) -> bool {
+    (0..=3).contains(&kind)
+}
+
+/// Read and discard a constraint of any type, for options whose
+/// value type is unknown and so can't be matched to a constraint kind.
+fn skip_constraint<S: Read>(stream: &mut S) -> Result<()> {
+    match i32::try_from_stream(stream)? {
+        1 => {
+            <Option<Range<i32>>>::try_from_stream(stream)?;
+        }
+        2 => {
+            <Vec<i32>>::try_from_stream(stream)?;
+        }
+        3 => {
+            read_null_terminated::<String, _>(stream)?;
+        }
+        // No constraint, or an unknown constraint type, neither of which carry any data
+        _ => {}
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-pub struct Range {
-    min: i32,
-    max: i32,
-    quant: i32,
+/// A numerical value sent on the wire as a single word: `i32` or `Fixed`.
+pub trait Word: Copy + PartialOrd + ::std::fmt::Debug {
+    /// The raw value of the word.
+    fn to_word(self) -> i32;
+
+    /// The value whose raw word is `word`.
+    fn from_word(word: i32) -> Self;
+}
+
+impl Word for i32 {
+    fn to_word(self) -> i32 {
+        self
+    }
+
+    fn from_word(word: i32) -> i32 {
+        word
+    }
+}
+
+impl Word for Fixed {
+    fn to_word(self) -> i32 {
+        self.raw()
+    }
+
+    fn from_word(word: i32) -> Fixed {
+        Fixed::from_raw(word)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Range<T: Word = i32> {
+    min: T,
+    max: T,
+    quant: T,
+}
+
+impl<T: Word> Range<T> {
+    pub fn new(min: T, max: T, quant: T) -> Range<T> {
+        Range { min, max, quant }
+    }
+
+    pub fn min(&self) -> T {
+        self.min
+    }
+
+    pub fn max(&self) -> T {
+        self.max
+    }
+
+    /// The quantization step of the range; values must be `min` plus a multiple
+    /// of `quant`. A quantization of 0 means any value in the range is allowed.
+    pub fn quant(&self) -> T {
+        self.quant
+    }
+
+    /// Returns true if `value` lies within the range and on a quantization step.
+    pub fn contains(&self, value: T) -> bool {
+        let in_range = value >= self.min && value <= self.max;
+
+        let quant = i64::from(self.quant.to_word());
+        let quantized =
+            quant == 0 || (i64::from(value.to_word()) - i64::from(self.min.to_word())) % quant == 0;
+
+        in_range && quantized
+    }
+
+    /// The value of the range nearest to `value`: clamped to the range,
+    /// then rounded to the nearest quantization step that doesn't exceed `max`.
+    pub fn clamp_quantize(&self, value: T) -> T {
+        let min = i64::from(self.min.to_word());
+        let max = i64::from(self.max.to_word()).max(min);
+        let quant = i64::from(self.quant.to_word());
+
+        let mut word = i64::from(value.to_word()).max(min).min(max);
+        if quant > 0 {
+            word = min + (word - min + quant / 2) / quant * quant;
+            if word > max {
+                word -= quant;
+            }
+        }
+
+        T::from_word(word as i32)
+    }
 }
 
-impl TryFromStream for Range {
+impl<T: Word + TryFromStream> TryFromStream for Range<T> {
     fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
         Ok(Range {
-            min: i32::try_from_stream(stream)?,
-            max: i32::try_from_stream(stream)?,
-            quant: i32::try_from_stream(stream)?,
+            min: T::try_from_stream(stream)?,
+            max: T::try_from_stream(stream)?,
+            quant: T::try_from_stream(stream)?,
         })
     }
 }
@@ -123,16 +391,17 @@ impl TryFromStream for Option<StringListConstraint> {
         match i32::try_from_stream(stream)? {
             0 => Ok(None), // There is no constraint
             3 => {
-                let opts = <Vec<Option<String>>>::try_from_stream(stream).map(|str_list| {
-                    str_list.into_iter()
-                        // Filter out any None strings
-                        .filter(|s| s.is_some())
-                        // None Strings are gone, so unwrap all values
-                        .map(|s| s.unwrap()).collect()
-                })?;
+                let opts: Vec<String> = read_null_terminated(stream)?;
                 debug!("String constraint options: {:?}", opts);
                 Ok(Some(StringListConstraint(opts)))
             }
+            x if !is_known_constraint_type(x) && is_lenient() => {
+                warn!(
+                    "Received unknown constraint type {}, ignoring constraint",
+                    x
+                );
+                Ok(None)
+            }
             x => Err(Error::InvalidSaneFieldValue(
                 "Received invalid value for String Contraint field".into(),
                 x,
@@ -141,7 +410,7 @@ impl TryFromStream for Option<StringListConstraint> {
     }
 }
 
-impl TryFromStream for Option<NumericalConstraint> {
+impl<T: Word + TryFromStream> TryFromStream for Option<NumericalConstraint<T>> {
     fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
         // See: http://www.sane-project.org/html/doc011.html#s4.2.9.8
         match i32::try_from_stream(stream)? {
@@ -149,9 +418,13 @@ impl TryFromStream for Option<NumericalConstraint> {
             1 => Ok(Some(NumericalConstraint::Range(<_>::try_from_stream(
                 stream,
             )?))),
-            2 => Ok(Some(NumericalConstraint::IntegerList(
-                <_>::try_from_stream(stream)?,
-            ))),
+            2 => Ok(Some(NumericalConstraint::IntegerList(read_word_list(
+                stream,
+            )?))),
+            x if !is_known_constraint_type(x) && is_lenient() => {
+                warn!("Received unknown constraint type {}", x);
+                Ok(Some(NumericalConstraint::Unknown(x)))
+            }
             x => Err(Error::InvalidSaneFieldValue(
                 "Received invalid value for Numerical Contraint field".into(),
                 x,
@@ -160,11 +433,41 @@ impl TryFromStream for Option<NumericalConstraint> {
     }
 }
 
+/// Read the values of a word list constraint.
+///
+/// saned sends the list as an array whose first word is the number of values
+/// following it. Unlike other arrays, it has no trailing null element.
+fn read_word_list<T: Word + TryFromStream, S: Read>(stream: &mut S) -> Result<Vec<T>> {
+    let size = check_array_length(i32::try_from_stream(stream)?)?;
+    let mut words = (0..size)
+        .map(|_| T::try_from_stream(stream))
+        .collect::<Result<Vec<T>>>()?;
+
+    if !words.is_empty() {
+        let count = words.remove(0).to_word();
+        if count as usize != words.len() {
+            warn!(
+                "Word list claims {} values, but {} were sent",
+                count,
+                words.len()
+            );
+        }
+    }
+    Ok(words)
+}
+
 impl TryFromStream for NoConstraint {
     fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
         // See: http://www.sane-project.org/html/doc011.html#s4.2.9.8
         match i32::try_from_stream(stream)? {
             0 => Ok(NoConstraint), // There is no constraint
+            x if !is_known_constraint_type(x) && is_lenient() => {
+                warn!(
+                    "Received unknown constraint type {}, ignoring constraint",
+                    x
+                );
+                Ok(NoConstraint)
+            }
             x => Err(Error::InvalidSaneFieldValue(
                 "Received a constraint on an option field that should not have constraints!".into(),
                 x,
@@ -226,7 +529,11 @@ impl TryFromStream for Capabilities {
     }
 }
 
-#[derive(Debug)]
+/// When serialized, the kind of option is given by a `type` field
+/// alongside the fields of the variant.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
 pub enum OptionDescriptor {
     Boolean {
         name: String,
@@ -235,6 +542,7 @@ pub enum OptionDescriptor {
         unit: OptionUnit,
         // size is 4 bytes
         capabilities: Capabilities,
+        #[cfg_attr(feature = "serde", serde(skip))]
         _no_constrainst: NoConstraint,
     },
     Integer {
@@ -246,6 +554,7 @@ pub enum OptionDescriptor {
         capabilities: Capabilities,
         constraint: Option<NumericalConstraint>,
     },
+    /// A fixed-point option; values and constraints are `Fixed`.
     Fixed {
         name: String,
         title: String,
@@ -253,7 +562,7 @@ pub enum OptionDescriptor {
         unit: OptionUnit,
         size: i32,
         capabilities: Capabilities,
-        constraint: Option<NumericalConstraint>,
+        constraint: Option<NumericalConstraint<Fixed>>,
     },
     String {
         name: String,
@@ -271,12 +580,26 @@ pub enum OptionDescriptor {
         unit: OptionUnit,
         // size is ignored
         capabilities: Capabilities,
+        #[cfg_attr(feature = "serde", serde(skip))]
         _no_constrainst: NoConstraint,
     },
     Group {
         title: String,
+        #[cfg_attr(feature = "serde", serde(skip))]
         _no_constrainst: NoConstraint,
     },
+    /// An option with a value type not defined by the SANE standard.
+    /// Its constraint is skipped, and nothing is assumed about which fields are valid.
+    /// Only produced in lenient mode.
+    Unknown {
+        kind: i32,
+        name: Option<String>,
+        title: Option<String>,
+        description: Option<String>,
+        unit: OptionUnit,
+        size: i32,
+        capabilities: Capabilities,
+    },
 }
 
 impl TryFromStream for OptionDescriptor {
@@ -294,52 +617,64 @@ impl TryFromStream for OptionDescriptor {
 
         let opt = match kind {
             OptionValueType::Boolean => Ok(OptionDescriptor::Boolean {
-                name: name?,
-                title: title?,
-                description: description?,
+                name: non_null(name, "option name")?,
+                title: non_null(title, "option title")?,
+                description: non_null(description, "option description")?,
                 unit,
                 capabilities,
                 _no_constrainst: NoConstraint::try_from_stream(stream)?,
             }),
             OptionValueType::Integer => Ok(OptionDescriptor::Integer {
-                name: name?,
-                title: title?,
-                description: description?,
+                name: non_null(name, "option name")?,
+                title: non_null(title, "option title")?,
+                description: non_null(description, "option description")?,
                 unit,
                 size,
                 capabilities,
                 constraint: <_>::try_from_stream(stream)?,
             }),
             OptionValueType::Fixed => Ok(OptionDescriptor::Fixed {
-                name: name?,
-                title: title?,
-                description: description?,
+                name: non_null(name, "option name")?,
+                title: non_null(title, "option title")?,
+                description: non_null(description, "option description")?,
                 unit,
                 size,
                 capabilities,
                 constraint: <_>::try_from_stream(stream)?,
             }),
             OptionValueType::String => Ok(OptionDescriptor::String {
-                name: name?,
-                title: title?,
-                description: description?,
+                name: non_null(name, "option name")?,
+                title: non_null(title, "option title")?,
+                description: non_null(description, "option description")?,
                 unit,
                 max_length: size,
                 capabilities,
                 constraint: <_>::try_from_stream(stream)?,
             }),
             OptionValueType::Button => Ok(OptionDescriptor::Button {
-                name: name?,
-                title: title?,
-                description: description?,
+                name: non_null(name, "option name")?,
+                title: non_null(title, "option title")?,
+                description: non_null(description, "option description")?,
                 unit,
                 capabilities,
                 _no_constrainst: NoConstraint::try_from_stream(stream)?,
             }),
             OptionValueType::Group => Ok(OptionDescriptor::Group {
-                title: title?,
+                title: non_null(title, "option title")?,
                 _no_constrainst: NoConstraint::try_from_stream(stream)?,
             }),
+            OptionValueType::Unknown(kind) => {
+                skip_constraint(stream)?;
+                Ok(OptionDescriptor::Unknown {
+                    kind,
+                    name,
+                    title,
+                    description,
+                    unit,
+                    size,
+                    capabilities,
+                })
+            }
         };
 
         debug!("{:?}", opt);
@@ -347,3 +682,771 @@ impl TryFromStream for OptionDescriptor {
         opt
     }
 }
+
+/// Descriptors are written as saned sends them, for the fake server in `testing`.
+impl WriteToStream for OptionDescriptor {
+    fn write_to_stream<S: ::std::io::Write>(&self, stream: &mut S) -> Result<()> {
+        self.name().write_to_stream(stream)?;
+        self.title().write_to_stream(stream)?;
+        self.description().write_to_stream(stream)?;
+        self.type_code().write_to_stream(stream)?;
+
+        match *self {
+            OptionDescriptor::Boolean { ref unit, .. }
+            | OptionDescriptor::Integer { ref unit, .. }
+            | OptionDescriptor::Fixed { ref unit, .. }
+            | OptionDescriptor::String { ref unit, .. }
+            | OptionDescriptor::Button { ref unit, .. }
+            | OptionDescriptor::Unknown { ref unit, .. } => unit.write_to_stream(stream)?,
+            OptionDescriptor::Group { .. } => OptionUnit::None.write_to_stream(stream)?,
+        }
+
+        let size = match *self {
+            OptionDescriptor::Boolean { .. } => 4,
+            OptionDescriptor::Integer { size, .. }
+            | OptionDescriptor::Fixed { size, .. }
+            | OptionDescriptor::Unknown { size, .. } => size,
+            OptionDescriptor::String { max_length, .. } => max_length,
+            OptionDescriptor::Button { .. } | OptionDescriptor::Group { .. } => 0,
+        };
+        size.write_to_stream(stream)?;
+        (self.capabilities().bits() as i32).write_to_stream(stream)?;
+
+        match *self {
+            OptionDescriptor::Integer { ref constraint, .. } => {
+                write_numerical_constraint(constraint, stream)
+            }
+            OptionDescriptor::Fixed { ref constraint, .. } => {
+                write_numerical_constraint(constraint, stream)
+            }
+            OptionDescriptor::String {
+                constraint: Some(ref constraint),
+                ..
+            } => {
+                3i32.write_to_stream(stream)?;
+                // The list is null-terminated, and sent including the null entry
+                let mut strings: Vec<Option<&str>> = constraint
+                    .values()
+                    .iter()
+                    .map(|s| Some(s.as_str()))
+                    .collect();
+                strings.push(None);
+                strings.write_to_stream(stream)
+            }
+            _ => 0i32.write_to_stream(stream),
+        }
+    }
+}
+
+fn write_numerical_constraint<T, S>(
+    constraint: &Option<NumericalConstraint<T>>,
+    stream: &mut S,
+) -> Result<()>
+where
+    T: Word + WriteToStream,
+    S: ::std::io::Write,
+{
+    match *constraint {
+        None => 0i32.write_to_stream(stream),
+        Some(NumericalConstraint::Range(ref range)) => {
+            1i32.write_to_stream(stream)?;
+            match *range {
+                Some(ref range) => {
+                    0i32.write_to_stream(stream)?; // pointer is not null
+                    range.min.write_to_stream(stream)?;
+                    range.max.write_to_stream(stream)?;
+                    range.quant.write_to_stream(stream)
+                }
+                None => 1i32.write_to_stream(stream),
+            }
+        }
+        Some(NumericalConstraint::IntegerList(ref values)) => {
+            // The first word is the number of words following it,
+            // and saned sends all of them, including the count
+            2i32.write_to_stream(stream)?;
+            (values.len() as i32 + 1).write_to_stream(stream)?;
+            (values.len() as i32).write_to_stream(stream)?;
+            for value in values {
+                value.write_to_stream(stream)?;
+            }
+            Ok(())
+        }
+        Some(NumericalConstraint::Unknown(kind)) => kind.write_to_stream(stream),
+    }
+}
+
+impl OptionDescriptor {
+    /// The name uniquely identifying the option, or None for group options.
+    pub fn name(&self) -> Option<&str> {
+        match *self {
+            OptionDescriptor::Boolean { ref name, .. }
+            | OptionDescriptor::Integer { ref name, .. }
+            | OptionDescriptor::Fixed { ref name, .. }
+            | OptionDescriptor::String { ref name, .. }
+            | OptionDescriptor::Button { ref name, .. } => Some(name),
+            OptionDescriptor::Group { .. } => None,
+            OptionDescriptor::Unknown { ref name, .. } => name.as_ref().map(|n| n.as_str()),
+        }
+    }
+
+    /// The title of the option, suitable for displaying to users.
+    pub fn title(&self) -> Option<&str> {
+        match *self {
+            OptionDescriptor::Boolean { ref title, .. }
+            | OptionDescriptor::Integer { ref title, .. }
+            | OptionDescriptor::Fixed { ref title, .. }
+            | OptionDescriptor::String { ref title, .. }
+            | OptionDescriptor::Button { ref title, .. }
+            | OptionDescriptor::Group { ref title, .. } => Some(title),
+            OptionDescriptor::Unknown { ref title, .. } => title.as_ref().map(|t| t.as_str()),
+        }
+    }
+
+    /// A longer description of the option, or None for group options.
+    pub fn description(&self) -> Option<&str> {
+        match *self {
+            OptionDescriptor::Boolean {
+                ref description, ..
+            }
+            | OptionDescriptor::Integer {
+                ref description, ..
+            }
+            | OptionDescriptor::Fixed {
+                ref description, ..
+            }
+            | OptionDescriptor::String {
+                ref description, ..
+            }
+            | OptionDescriptor::Button {
+                ref description, ..
+            } => Some(description),
+            OptionDescriptor::Group { .. } => None,
+            OptionDescriptor::Unknown {
+                ref description, ..
+            } => description.as_ref().map(|d| d.as_str()),
+        }
+    }
+
+    /// The unit of the option's value; group options have none.
+    pub fn unit(&self) -> Option<&OptionUnit> {
+        match *self {
+            OptionDescriptor::Boolean { ref unit, .. }
+            | OptionDescriptor::Integer { ref unit, .. }
+            | OptionDescriptor::Fixed { ref unit, .. }
+            | OptionDescriptor::String { ref unit, .. }
+            | OptionDescriptor::Button { ref unit, .. }
+            | OptionDescriptor::Unknown { ref unit, .. } => Some(unit),
+            OptionDescriptor::Group { .. } => None,
+        }
+    }
+
+    /// The capabilities of the option; group options have none.
+    pub fn capabilities(&self) -> Capabilities {
+        match *self {
+            OptionDescriptor::Boolean { capabilities, .. }
+            | OptionDescriptor::Integer { capabilities, .. }
+            | OptionDescriptor::Fixed { capabilities, .. }
+            | OptionDescriptor::String { capabilities, .. }
+            | OptionDescriptor::Button { capabilities, .. }
+            | OptionDescriptor::Unknown { capabilities, .. } => capabilities,
+            OptionDescriptor::Group { .. } => Capabilities::empty(),
+        }
+    }
+
+    /// The SANE value type code of the option.
+    fn type_code(&self) -> i32 {
+        match *self {
+            OptionDescriptor::Boolean { .. } => 0,
+            OptionDescriptor::Integer { .. } => 1,
+            OptionDescriptor::Fixed { .. } => 2,
+            OptionDescriptor::String { .. } => 3,
+            OptionDescriptor::Button { .. } => 4,
+            OptionDescriptor::Group { .. } => 5,
+            OptionDescriptor::Unknown { kind, .. } => kind,
+        }
+    }
+
+    /// Write the placeholder value sent when requesting the current value of the option:
+    /// the value type, size, and a zero-filled array of the option's size.
+    pub(crate) fn write_empty_value<S: ::std::io::Write>(&self, stream: &mut S) -> Result<()> {
+        self.type_code().write_to_stream(stream)?;
+
+        match *self {
+            OptionDescriptor::Boolean { .. } => {
+                4i32.write_to_stream(stream)?;
+                1i32.write_to_stream(stream)?;
+                0i32.write_to_stream(stream)
+            }
+            OptionDescriptor::Integer { size, .. } | OptionDescriptor::Fixed { size, .. } => {
+                size.write_to_stream(stream)?;
+                (size / 4).write_to_stream(stream)?;
+                for _ in 0..size / 4 {
+                    0i32.write_to_stream(stream)?;
+                }
+                Ok(())
+            }
+            OptionDescriptor::String { max_length, .. } => {
+                max_length.write_to_stream(stream)?;
+                max_length.write_to_stream(stream)?;
+                for _ in 0..max_length {
+                    0u8.write_to_stream(stream)?;
+                }
+                Ok(())
+            }
+            _ => {
+                0i32.write_to_stream(stream)?;
+                0i32.write_to_stream(stream)
+            }
+        }
+    }
+
+    /// Write `value` as the new value of this option in a SANE_NET_CONTROL_OPTION request:
+    /// the value type, size, and the value as an array of words or characters.
+    ///
+    /// Strings are padded with null bytes to the maximum length of the option.
+    pub(crate) fn write_value<S: ::std::io::Write>(
+        &self,
+        value: &OptionValue,
+        stream: &mut S,
+    ) -> Result<()> {
+        value.type_code().write_to_stream(stream)?;
+
+        match *value {
+            OptionValue::Boolean(value) => {
+                4i32.write_to_stream(stream)?;
+                [value].write_to_stream(stream)
+            }
+            OptionValue::Integer(value) => {
+                4i32.write_to_stream(stream)?;
+                [value].write_to_stream(stream)
+            }
+            OptionValue::Fixed(value) => {
+                4i32.write_to_stream(stream)?;
+                [value].write_to_stream(stream)
+            }
+            OptionValue::IntegerArray(ref values) => {
+                (values.len() as i32 * 4).write_to_stream(stream)?;
+                values.write_to_stream(stream)
+            }
+            OptionValue::FixedArray(ref values) => {
+                (values.len() as i32 * 4).write_to_stream(stream)?;
+                values.write_to_stream(stream)
+            }
+            OptionValue::String(ref value) => {
+                let max_length = match *self {
+                    OptionDescriptor::String { max_length, .. } => max_length.max(0) as usize,
+                    _ => 0,
+                };
+
+                let mut chars = value.clone().into_bytes();
+                chars.push(0x00);
+                if chars.len() < max_length {
+                    chars.resize(max_length, 0x00);
+                }
+
+                (chars.len() as i32).write_to_stream(stream)?;
+                chars.write_to_stream(stream)
+            }
+            OptionValue::Button => {
+                0i32.write_to_stream(stream)?;
+                <[i32]>::write_to_stream(&[], stream)
+            }
+        }
+    }
+
+    /// Read a value of this option, as sent in the reply to SANE_NET_CONTROL_OPTION.
+    pub fn read_value<S: Read>(&self, stream: &mut S) -> Result<OptionValue> {
+        match *self {
+            OptionDescriptor::String { .. } => Ok(OptionValue::String(read_char_array(stream)?)),
+            OptionDescriptor::Boolean { .. }
+            | OptionDescriptor::Integer { .. }
+            | OptionDescriptor::Fixed { .. } => {
+                let words = read_word_array(stream)?;
+
+                if let Some(length) = self.array_length() {
+                    if words.len() != length {
+                        return Err(Error::BadNetworkDataError(format!(
+                            "Expected an array of {} values, received {}",
+                            length,
+                            words.len()
+                        )));
+                    }
+
+                    return Ok(match *self {
+                        OptionDescriptor::Integer { .. } => OptionValue::IntegerArray(words),
+                        _ => OptionValue::FixedArray(
+                            words.into_iter().map(Fixed::from_raw).collect(),
+                        ),
+                    });
+                }
+
+                let word = *words.first().ok_or_else(|| {
+                    Error::BadNetworkDataError("Received empty option value".into())
+                })?;
+
+                Ok(match *self {
+                    OptionDescriptor::Boolean { .. } => OptionValue::Boolean(word != 0),
+                    OptionDescriptor::Integer { .. } => OptionValue::Integer(word),
+                    _ => OptionValue::Fixed(Fixed::from_raw(word)),
+                })
+            }
+            _ => {
+                // Buttons and groups have no value, but an empty array is still sent
+                read_word_array(stream)?;
+                Ok(OptionValue::Button)
+            }
+        }
+    }
+
+    /// For integer and fixed-point options whose value is an array of words
+    /// (such as gamma tables), the number of elements in the array.
+    /// Returns None for options holding a single value.
+    pub fn array_length(&self) -> Option<usize> {
+        match *self {
+            OptionDescriptor::Integer { size, .. } | OptionDescriptor::Fixed { size, .. }
+                if size > 4 =>
+            {
+                Some(size as usize / 4)
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse a value of this option from text, as given on a command line.
+    ///
+    /// Booleans are `yes`/`no`, `true`/`false` or `1`/`0`, fixed-point values
+    /// are decimal numbers, and the elements of word arrays are separated by commas.
+    /// The value is not checked against the option's constraint; see `check_value`.
+    pub fn parse_value(&self, text: &str) -> Result<OptionValue> {
+        let name = self.name().unwrap_or("");
+        let invalid = |expected: &str| {
+            Error::InvalidOptionValue(
+                name.into(),
+                format!("expected {}, got {:?}", expected, text),
+            )
+        };
+        let parse_int = |text: &str| {
+            text.trim()
+                .parse::<i32>()
+                .map_err(|_| invalid("an integer"))
+        };
+        let parse_fixed = |text: &str| {
+            text.trim()
+                .parse::<f64>()
+                .map(Fixed::from_f64)
+                .map_err(|_| invalid("a number"))
+        };
+
+        match *self {
+            OptionDescriptor::Boolean { .. } => match text.trim().to_lowercase().as_str() {
+                "yes" | "true" | "1" => Ok(OptionValue::Boolean(true)),
+                "no" | "false" | "0" => Ok(OptionValue::Boolean(false)),
+                _ => Err(invalid("yes or no")),
+            },
+            OptionDescriptor::Integer { .. } if self.array_length().is_some() => text
+                .split(',')
+                .map(parse_int)
+                .collect::<Result<Vec<_>>>()
+                .map(OptionValue::IntegerArray),
+            OptionDescriptor::Fixed { .. } if self.array_length().is_some() => text
+                .split(',')
+                .map(parse_fixed)
+                .collect::<Result<Vec<_>>>()
+                .map(OptionValue::FixedArray),
+            OptionDescriptor::Integer { .. } => parse_int(text).map(OptionValue::Integer),
+            OptionDescriptor::Fixed { .. } => parse_fixed(text).map(OptionValue::Fixed),
+            OptionDescriptor::String { .. } => Ok(OptionValue::String(text.into())),
+            OptionDescriptor::Button { .. } => Ok(OptionValue::Button),
+            OptionDescriptor::Group { .. } | OptionDescriptor::Unknown { .. } => Err(
+                Error::InvalidOptionValue(name.into(), "option has no settable value".into()),
+            ),
+        }
+    }
+
+    /// The value nearest to `value` that satisfies this option's constraint,
+    /// as chosen by `NumericalConstraint::nearest` and `StringListConstraint::matches`.
+    ///
+    /// Values of the wrong type, and strings matching none of the allowed strings,
+    /// are returned unchanged, to be rejected by `check_value`.
+    pub fn snap_value(&self, value: OptionValue) -> OptionValue {
+        match (self, value) {
+            (
+                OptionDescriptor::Integer {
+                    constraint: Some(constraint),
+                    ..
+                },
+                value,
+            ) => match value {
+                OptionValue::Integer(value) => OptionValue::Integer(constraint.nearest(value)),
+                OptionValue::IntegerArray(values) => OptionValue::IntegerArray(
+                    values.into_iter().map(|v| constraint.nearest(v)).collect(),
+                ),
+                value => value,
+            },
+            (
+                OptionDescriptor::Fixed {
+                    constraint: Some(constraint),
+                    ..
+                },
+                value,
+            ) => match value {
+                OptionValue::Fixed(value) => OptionValue::Fixed(constraint.nearest(value)),
+                OptionValue::Integer(value) if (-(1 << 15)..(1 << 15)).contains(&value) => {
+                    OptionValue::Fixed(constraint.nearest(Fixed::from_int(value)))
+                }
+                OptionValue::FixedArray(values) => OptionValue::FixedArray(
+                    values.into_iter().map(|v| constraint.nearest(v)).collect(),
+                ),
+                value => value,
+            },
+            (
+                OptionDescriptor::String {
+                    constraint: Some(constraint),
+                    ..
+                },
+                OptionValue::String(value),
+            ) => match constraint.matches(&value) {
+                Some(allowed) => OptionValue::String(allowed.into()),
+                None => OptionValue::String(value),
+            },
+            (_, value) => value,
+        }
+    }
+
+    /// Check that `value` can be assigned to this option, returning the value
+    /// to send to the device.
+    ///
+    /// The option must be software-settable and active, `value` must be of
+    /// the option's type, and it must satisfy the option's constraint.
+    /// Integer values given for fixed-point options are converted.
+    pub fn check_value(&self, value: OptionValue) -> Result<OptionValue> {
+        let name = self.name().unwrap_or("").to_string();
+        let invalid = |reason: String| Error::InvalidOptionValue(name.clone(), reason);
+
+        let capabilities = self.capabilities();
+        if !capabilities.contains(Capabilities::SoftSelect) {
+            return Err(invalid("option can't be set by software".into()));
+        }
+        if capabilities.contains(Capabilities::Inactive) {
+            return Err(invalid("option is inactive".into()));
+        }
+
+        let array_length = self.array_length();
+        let check_length = |length: usize| match array_length {
+            Some(expected) if expected != length => Err(format!(
+                "expected an array of {} values, got {}",
+                expected, length
+            )),
+            None => Err("expected a single value, got an array".to_string()),
+            _ => Ok(()),
+        };
+
+        if let Some(length) = array_length {
+            match value {
+                OptionValue::IntegerArray(_) | OptionValue::FixedArray(_) => {}
+                value => {
+                    return Err(invalid(format!(
+                        "expected an array of {} values, got {:?}",
+                        length, value
+                    )))
+                }
+            }
+        }
+
+        match (self, value) {
+            (&OptionDescriptor::Boolean { .. }, value @ OptionValue::Boolean(_)) => Ok(value),
+            (OptionDescriptor::Integer { constraint, .. }, OptionValue::IntegerArray(values)) => {
+                check_length(values.len()).map_err(invalid)?;
+                for &value in &values {
+                    check_numerical_constraint(constraint, value).map_err(invalid)?;
+                }
+                Ok(OptionValue::IntegerArray(values))
+            }
+            (OptionDescriptor::Fixed { constraint, .. }, OptionValue::FixedArray(values)) => {
+                check_length(values.len()).map_err(invalid)?;
+                for &value in &values {
+                    check_numerical_constraint(constraint, value).map_err(invalid)?;
+                }
+                Ok(OptionValue::FixedArray(values))
+            }
+            (&OptionDescriptor::Button { .. }, OptionValue::Button) => Ok(OptionValue::Button),
+            (OptionDescriptor::Integer { constraint, .. }, OptionValue::Integer(value)) => {
+                check_numerical_constraint(constraint, value).map_err(invalid)?;
+                Ok(OptionValue::Integer(value))
+            }
+            (OptionDescriptor::Fixed { constraint, .. }, value) => {
+                let value = match value {
+                    OptionValue::Fixed(value) => value,
+                    OptionValue::Integer(value) if (-(1 << 15)..(1 << 15)).contains(&value) => {
+                        Fixed::from_int(value)
+                    }
+                    value => {
+                        return Err(invalid(format!(
+                            "expected a fixed-point value, got {:?}",
+                            value
+                        )))
+                    }
+                };
+                check_numerical_constraint(constraint, value).map_err(invalid)?;
+                Ok(OptionValue::Fixed(value))
+            }
+            (
+                &OptionDescriptor::String {
+                    max_length,
+                    ref constraint,
+                    ..
+                },
+                OptionValue::String(value),
+            ) => {
+                if value.len() as i64 + 1 > i64::from(max_length) {
+                    return Err(invalid(format!(
+                        "string is longer than the maximum of {} bytes",
+                        max_length - 1
+                    )));
+                }
+                if let Some(StringListConstraint(ref allowed)) = *constraint {
+                    if !allowed.contains(&value) {
+                        return Err(invalid(format!("expected one of {:?}", allowed)));
+                    }
+                }
+                Ok(OptionValue::String(value))
+            }
+            (&OptionDescriptor::Group { .. }, _) => {
+                Err(invalid("group options have no value".into()))
+            }
+            (&OptionDescriptor::Unknown { kind, .. }, _) => {
+                Err(invalid(format!("option has unknown value type {}", kind)))
+            }
+            (_, value) => Err(invalid(format!("value {:?} has the wrong type", value))),
+        }
+    }
+}
+
+fn check_numerical_constraint<T: Word + ::std::fmt::Display>(
+    constraint: &Option<NumericalConstraint<T>>,
+    value: T,
+) -> ::std::result::Result<(), String> {
+    match *constraint {
+        Some(NumericalConstraint::Range(Some(ref range))) if !range.contains(value) => {
+            Err(format!(
+                "{} is outside the range {}..={} (step {})",
+                value, range.min, range.max, range.quant
+            ))
+        }
+        Some(NumericalConstraint::IntegerList(ref list)) if !list.contains(&value) => {
+            Err(format!("expected one of {:?}", list))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolution(constraint: NumericalConstraint) -> OptionDescriptor {
+        OptionDescriptor::Integer {
+            name: "resolution".into(),
+            title: "Scan resolution".into(),
+            description: "Sets the resolution of the scanned image.".into(),
+            unit: OptionUnit::DPI,
+            size: 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(constraint),
+        }
+    }
+
+    #[test]
+    fn check_value_validates_constraints() {
+        let range = resolution(NumericalConstraint::Range(Some(Range {
+            min: 75,
+            max: 600,
+            quant: 75,
+        })));
+        assert_eq!(
+            range.check_value(300.into()).unwrap(),
+            OptionValue::Integer(300)
+        );
+        assert!(range.check_value(307.into()).is_err());
+        assert!(range.check_value(1200.into()).is_err());
+        assert!(range.check_value("300".into()).is_err());
+
+        let list = resolution(NumericalConstraint::IntegerList(vec![150, 300]));
+        assert!(list.check_value(150.into()).is_ok());
+        assert!(list.check_value(200.into()).is_err());
+    }
+
+    #[test]
+    fn values_snap_to_constraints() {
+        let range = Range::new(75, 600, 75);
+        assert_eq!(range.clamp_quantize(307), 300);
+        assert_eq!(range.clamp_quantize(340), 375);
+        assert_eq!(range.clamp_quantize(10), 75);
+        assert_eq!(range.clamp_quantize(1200), 600);
+        // The last step may fall short of the maximum
+        assert_eq!(Range::new(0, 10, 4).clamp_quantize(11), 8);
+
+        let list = NumericalConstraint::IntegerList(vec![150, 300, 600]);
+        assert_eq!(list.nearest(307), 300);
+        assert_eq!(list.nearest(225), 150);
+        assert_eq!(list.nearest(5000), 600);
+
+        let modes = StringListConstraint(vec!["Color".into(), "Gray".into()]);
+        assert_eq!(modes.matches("Gray"), Some("Gray"));
+        assert_eq!(modes.matches("gray"), Some("Gray"));
+        assert_eq!(modes.matches("Lineart"), None);
+
+        let resolution = resolution(list);
+        assert_eq!(resolution.snap_value(307.into()), OptionValue::Integer(300));
+        assert_eq!(resolution.snap_value("high".into()), "high".into());
+
+        let brightness = OptionDescriptor::Fixed {
+            name: "brightness".into(),
+            title: "Brightness".into(),
+            description: "Controls the brightness of the acquired image.".into(),
+            unit: OptionUnit::Percent,
+            size: 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(NumericalConstraint::Range(Some(Range::new(
+                Fixed::from_int(-100),
+                Fixed::from_int(100),
+                Fixed::from_int(1),
+            )))),
+        };
+        assert_eq!(
+            brightness.snap_value(150.into()),
+            OptionValue::Fixed(Fixed::from_int(100))
+        );
+        assert_eq!(brightness.snap_value(i32::MIN.into()), i32::MIN.into());
+    }
+
+    #[test]
+    fn word_list_constraint_is_decoded() {
+        let mut buf = Vec::new();
+        // constraint type, array length, then the number of values and the values
+        for word in &[2i32, 4, 3, 150, 300, 600] {
+            buf.extend_from_slice(&word.to_be_bytes());
+        }
+
+        match <Option<NumericalConstraint>>::try_from_stream(&mut &buf[..]).unwrap() {
+            Some(NumericalConstraint::IntegerList(list)) => assert_eq!(list, vec![150, 300, 600]),
+            other => panic!("Unexpected constraint {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_value_follows_option_type() {
+        let resolution = resolution(NumericalConstraint::IntegerList(vec![150, 300]));
+        assert_eq!(
+            resolution.parse_value("300").unwrap(),
+            OptionValue::Integer(300)
+        );
+        assert!(resolution.parse_value("high").is_err());
+
+        let preview = OptionDescriptor::Boolean {
+            name: "preview".into(),
+            title: "Preview".into(),
+            description: "Request a preview-quality scan.".into(),
+            unit: OptionUnit::None,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            _no_constrainst: NoConstraint,
+        };
+        assert_eq!(
+            preview.parse_value("yes").unwrap(),
+            OptionValue::Boolean(true)
+        );
+        assert_eq!(
+            preview.parse_value("false").unwrap(),
+            OptionValue::Boolean(false)
+        );
+
+        let gamma = OptionDescriptor::Fixed {
+            name: "gamma".into(),
+            title: "Gamma".into(),
+            description: "Gamma values per channel.".into(),
+            unit: OptionUnit::None,
+            size: 3 * 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: None,
+        };
+        assert_eq!(
+            gamma.parse_value("1.5, 2,0.25").unwrap(),
+            OptionValue::FixedArray(vec![
+                Fixed::from_f64(1.5),
+                Fixed::from_int(2),
+                Fixed::from_f64(0.25),
+            ])
+        );
+    }
+
+    #[test]
+    fn check_value_rejects_integers_outside_the_fixed_range() {
+        let brightness = OptionDescriptor::Fixed {
+            name: "brightness".into(),
+            title: "Brightness".into(),
+            description: "Controls the brightness of the acquired image.".into(),
+            unit: OptionUnit::Percent,
+            size: 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: None,
+        };
+
+        assert_eq!(
+            brightness.check_value((-100).into()).unwrap(),
+            OptionValue::Fixed(Fixed::from_int(-100))
+        );
+        assert!(brightness.check_value(i32::MIN.into()).is_err());
+        assert!(brightness.check_value((1 << 15).into()).is_err());
+    }
+
+    #[test]
+    fn check_value_validates_string_list() {
+        let mode = OptionDescriptor::String {
+            name: "mode".into(),
+            title: "Scan mode".into(),
+            description: "Selects the scan mode.".into(),
+            unit: OptionUnit::None,
+            max_length: 8,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(StringListConstraint(vec!["Color".into(), "Gray".into()])),
+        };
+
+        assert!(mode.check_value("Gray".into()).is_ok());
+        match mode.check_value("Lineart".into()) {
+            Err(Error::InvalidOptionValue(ref name, _)) if name == "mode" => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_value_validates_word_arrays() {
+        let gamma = OptionDescriptor::Integer {
+            name: "gamma-table".into(),
+            title: "Gamma table".into(),
+            description: "Gamma-correction table.".into(),
+            unit: OptionUnit::None,
+            size: 4 * 4,
+            capabilities: Capabilities::SoftSelect | Capabilities::SoftDetect,
+            constraint: Some(NumericalConstraint::Range(Some(Range {
+                min: 0,
+                max: 255,
+                quant: 0,
+            }))),
+        };
+
+        assert_eq!(gamma.array_length(), Some(4));
+        assert!(gamma.check_value(vec![0, 64, 128, 255].into()).is_ok());
+        assert!(gamma.check_value(vec![0, 64, 128].into()).is_err());
+        assert!(gamma.check_value(vec![0, 64, 128, 256].into()).is_err());
+        assert!(gamma.check_value(128.into()).is_err());
+
+        let mut reply = Vec::new();
+        for word in &[4i32, 0, 64, 128, 255] {
+            reply.extend_from_slice(&word.to_be_bytes());
+        }
+        assert_eq!(
+            gamma.read_value(&mut &reply[..]).unwrap(),
+            OptionValue::IntegerArray(vec![0, 64, 128, 255])
+        );
+    }
+}
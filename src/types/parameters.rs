@@ -0,0 +1,162 @@
+use std::io::{Cursor, Read, Write};
+
+use crate::error::Error;
+use crate::types::is_lenient;
+use crate::{Result, TryFromStream, WriteToStream};
+
+/// The format of a frame of image data.
+///
+/// See: http://www.sane-project.org/html/doc012.html#s4.3.8
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum FrameFormat {
+    /// A single channel of gray-scale (or lineart, if `depth` is 1) samples.
+    Gray,
+    /// Interleaved red, green and blue samples, in that order.
+    RGB,
+    /// Only the red channel of a three-pass color scan.
+    Red,
+    /// Only the green channel of a three-pass color scan.
+    Green,
+    /// Only the blue channel of a three-pass color scan.
+    Blue,
+    /// A single channel of infrared samples, as scanned by film scanners to detect
+    /// dust and scratches. Sent as a separate frame after the color frames.
+    Infrared,
+    /// Interleaved red, green, blue and infrared samples, in that order.
+    RGBI,
+    /// Interleaved gray and infrared samples, in that order.
+    GrayI,
+    /// A format not defined by the SANE standard, holding the raw value.
+    /// Only produced in lenient mode.
+    Unknown(i32),
+}
+
+impl FrameFormat {
+    /// The number of channels of each pixel in a frame of this format,
+    /// or None for an unknown format.
+    pub fn channels(self) -> Option<usize> {
+        match self {
+            FrameFormat::Gray
+            | FrameFormat::Red
+            | FrameFormat::Green
+            | FrameFormat::Blue
+            | FrameFormat::Infrared => Some(1),
+            FrameFormat::GrayI => Some(2),
+            FrameFormat::RGB => Some(3),
+            FrameFormat::RGBI => Some(4),
+            FrameFormat::Unknown(_) => None,
+        }
+    }
+}
+
+impl TryFromStream for FrameFormat {
+    fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
+        match i32::try_from_stream(stream)? {
+            0 => Ok(FrameFormat::Gray),
+            1 => Ok(FrameFormat::RGB),
+            2 => Ok(FrameFormat::Red),
+            3 => Ok(FrameFormat::Green),
+            4 => Ok(FrameFormat::Blue),
+            // Extended frame types, proposed for SANE 1.1
+            0x0f => Ok(FrameFormat::Infrared),
+            0x10 => Ok(FrameFormat::RGBI),
+            0x11 => Ok(FrameFormat::GrayI),
+            x if is_lenient() => {
+                warn!("Received unknown FrameFormat {}", x);
+                Ok(FrameFormat::Unknown(x))
+            }
+            x => Err(Error::InvalidSaneFieldValue(
+                "Received invalid value for FrameFormat field".into(),
+                x,
+            )),
+        }
+    }
+}
+
+impl WriteToStream for FrameFormat {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        let code = match *self {
+            FrameFormat::Gray => 0,
+            FrameFormat::RGB => 1,
+            FrameFormat::Red => 2,
+            FrameFormat::Green => 3,
+            FrameFormat::Blue => 4,
+            FrameFormat::Infrared => 0x0f,
+            FrameFormat::RGBI => 0x10,
+            FrameFormat::GrayI => 0x11,
+            FrameFormat::Unknown(code) => code,
+        };
+        code.write_to_stream(stream)
+    }
+}
+
+/// Scan parameters describing the frame that is about to be,
+/// or is currently being, acquired.
+///
+/// See: http://www.sane-project.org/html/doc012.html#s4.3.8
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Parameters {
+    pub format: FrameFormat,
+    /// True if this is the last frame of the image.
+    /// Only false for the first frames of a three-pass color scan.
+    pub last_frame: bool,
+    pub bytes_per_line: i32,
+    pub pixels_per_line: i32,
+    /// Number of lines in the frame, or -1 if it is not known in advance
+    /// (e.g. for a hand-held scanner).
+    pub lines: i32,
+    /// Number of bits per sample.
+    pub depth: i32,
+}
+
+impl Parameters {
+    /// Encode the parameters as sent in the reply to SANE_NET_GET_PARAMETERS,
+    /// after its status, for instance to save them along with captured scan data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(24);
+        self.write_to_stream(&mut bytes)
+            .expect("Writing to a Vec can't fail");
+        bytes
+    }
+
+    /// Decode parameters encoded by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Parameters> {
+        let mut cursor = Cursor::new(bytes);
+        let parameters = Parameters::try_from_stream(&mut cursor)?;
+        if cursor.position() as usize != bytes.len() {
+            return Err(Error::BadNetworkDataError(format!(
+                "Expected {} bytes of parameters, got {}",
+                cursor.position(),
+                bytes.len()
+            )));
+        }
+        Ok(parameters)
+    }
+}
+
+impl TryFromStream for Parameters {
+    fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
+        Ok(Parameters {
+            format: FrameFormat::try_from_stream(stream)?,
+            last_frame: bool::try_from_stream(stream)?,
+            bytes_per_line: i32::try_from_stream(stream)?,
+            pixels_per_line: i32::try_from_stream(stream)?,
+            lines: i32::try_from_stream(stream)?,
+            depth: i32::try_from_stream(stream)?,
+        })
+    }
+}
+
+impl WriteToStream for Parameters {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        self.format.write_to_stream(stream)?;
+        self.last_frame.write_to_stream(stream)?;
+        self.bytes_per_line.write_to_stream(stream)?;
+        self.pixels_per_line.write_to_stream(stream)?;
+        self.lines.write_to_stream(stream)?;
+        self.depth.write_to_stream(stream)
+    }
+}
@@ -1,9 +1,11 @@
-use std::io::prelude::*;
+use std::convert::TryFrom;
+use std::io::{self, prelude::*};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use TryFromStream;
-use Result;
+use crate::error::Error;
+use crate::types::DecodeOptions;
+use crate::{Result, TryFromStream, WriteToStream};
 
 impl TryFromStream for i32 {
     fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
@@ -17,27 +19,163 @@ impl TryFromStream for u32 {
     }
 }
 
-impl TryFromStream for Option<String> {
+impl WriteToStream for i32 {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        stream.write_i32::<BigEndian>(*self).map_err(|e| e.into())
+    }
+}
+
+impl WriteToStream for u8 {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        stream.write_u8(*self).map_err(|e| e.into())
+    }
+}
+
+impl WriteToStream for bool {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        (*self as i32).write_to_stream(stream)
+    }
+}
+
+/// Strings are sent as an array of characters, including the terminating null byte.
+impl WriteToStream for str {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        // Make sure the length of the string, including the null byte, fits into 32 bits
+        if self.len() >= i32::MAX as usize {
+            return Err(Error::BadNetworkDataError(format!(
+                "String length of {} exceeds maximum possible length of {}!",
+                self.len(),
+                i32::MAX - 1
+            )));
+        }
+
+        (self.len() as i32 + 1).write_to_stream(stream)?;
+        stream.write_all(self.as_bytes())?;
+        0u8.write_to_stream(stream)
+    }
+}
+
+impl WriteToStream for String {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        self.as_str().write_to_stream(stream)
+    }
+}
+
+/// Null strings are sent with a length of 0.
+impl WriteToStream for Option<&str> {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        match *self {
+            Some(string) => string.write_to_stream(stream),
+            None => 0i32.write_to_stream(stream),
+        }
+    }
+}
+
+/// Arrays are sent as their length, followed by each element.
+impl<T: WriteToStream> WriteToStream for [T] {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        if self.len() > i32::MAX as usize {
+            return Err(Error::BadNetworkDataError(format!(
+                "Array length of {} exceeds maximum possible length of {}!",
+                self.len(),
+                i32::MAX
+            )));
+        }
+
+        (self.len() as i32).write_to_stream(stream)?;
+        for element in self {
+            element.write_to_stream(stream)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: WriteToStream> WriteToStream for Vec<T> {
+    fn write_to_stream<S: Write>(&self, stream: &mut S) -> Result<()> {
+        self.as_slice().write_to_stream(stream)
+    }
+}
+
+/// Read `count` words.
+pub(crate) fn read_words<S: Read>(stream: &mut S, count: i32) -> Result<Vec<i32>> {
+    (0..check_array_length(count)?)
+        .map(|_| i32::try_from_stream(stream))
+        .collect()
+}
+
+/// Check the length of an array received from the network,
+/// which must not be negative nor exceed the current `DecodeOptions::max_array_length`.
+pub(crate) fn check_array_length(length: i32) -> Result<usize> {
+    check_length(length, DecodeOptions::current().max_array_length, "array")
+}
+
+/// Check the length of a string received from the network,
+/// which must not be negative nor exceed the current `DecodeOptions::max_string_length`.
+pub(crate) fn check_string_length(length: i32) -> Result<usize> {
+    check_length(length, DecodeOptions::current().max_string_length, "string")
+}
+
+fn check_length(length: i32, max: usize, kind: &str) -> Result<usize> {
+    match usize::try_from(length) {
+        Ok(length) if length <= max => Ok(length),
+        Ok(_) => Err(Error::BadNetworkDataError(format!(
+            "Received {} length {} exceeds the maximum of {}",
+            kind, length, max
+        ))),
+        Err(_) => Err(Error::BadNetworkDataError(format!(
+            "Received negative {} length {}",
+            kind, length
+        ))),
+    }
+}
+
+/// Read `length` bytes. The buffer grows as the data arrives, so a bogus
+/// length can't allocate more memory than the data actually sent.
+pub(crate) fn read_bytes<S: Read>(stream: &mut S, length: usize) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    stream.take(length as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != length {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(bytes)
+}
+
+/// Unwrap a string or value received as a pointer that the protocol requires to be
+/// non-null, describing `field` in the error otherwise.
+pub(crate) fn non_null<T>(value: Option<T>, field: &str) -> Result<T> {
+    value.ok_or_else(|| Error::UnexpectedNull(field.into()))
+}
+
+impl TryFromStream for bool {
     fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
-        let size = stream.read_i32::<BigEndian>().unwrap();
+        match i32::try_from_stream(stream)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            x => Err(Error::InvalidSaneFieldValue(
+                "Received invalid value for boolean field".into(),
+                x,
+            )),
+        }
+    }
+}
 
-        if size <= 0 {
+impl TryFromStream for Option<String> {
+    fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
+        // A null string is sent with a length of 0
+        let size = check_string_length(stream.read_i32::<BigEndian>()?)?;
+        if size == 0 {
             return Ok(None);
         }
 
-        String::from_utf8(
-            stream
-                // Read the number of bytes equal to the given size
-                .take(u64::from(size as u32))
-                .bytes()
-                // Stop reading if we encounter an error or a null byte
-                .take_while(|byte| byte.is_ok() && byte.as_ref().unwrap() != &0x00u8)
-                // We're now guaranteed to not have an Err result, so unwrap to just a u8
-                .map(|byte| byte.unwrap())
-                // Collect into a Vec<u8>
-                .collect(),
-        ).map_err(|err| err.into())
-            .map(|s| Some(s)) // Convert our Result<String> into Result<Option<String>>
+        // Read the number of bytes equal to the given size, so the stream stays
+        // in sync even if the string ends early, then stop at the first null byte
+        let mut bytes = read_bytes(stream, size)?;
+        if let Some(end) = bytes.iter().position(|&byte| byte == 0x00) {
+            bytes.truncate(end);
+        }
+
+        // Convert our Result<String> into Result<Option<String>>
+        String::from_utf8(bytes).map_err(|err| err.into()).map(Some)
     }
 }
 
@@ -46,7 +184,7 @@ where
     T: TryFromStream,
 {
     fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
-        let is_null = stream.read_i32::<BigEndian>().unwrap();
+        let is_null = stream.read_i32::<BigEndian>()?;
 
         match is_null {
             0 => Ok(Some(T::try_from_stream(stream)?)),
@@ -61,23 +199,31 @@ where
 {
     fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
         // Read pointer list:
-        let size = stream.read_i32::<BigEndian>().unwrap();
+        let size = check_array_length(stream.read_i32::<BigEndian>()?)?;
 
         info!("Received array of size {}", size);
 
         (0..size)
-            .map(|i| T::try_from_stream(stream))
+            .map(|_| T::try_from_stream(stream))
             .try_fold(Vec::new(), |mut arr, element| {
                 // Propagate an Err values up to the outer Result,
                 debug!("Folding element: {:?}", element);
                 arr.push(element?);
                 Ok(arr)
             })
-            .map(|mut vec| {
-                // Remove the trailing empty value
-                debug!("Dropping trailing null value from vec: {:?}", vec.last());
-                vec.truncate((size - 1) as usize);
-                vec
-            })
     }
 }
+
+/// Read an array of pointers ending with a null element, as sent for device lists
+/// and string lists, returning the elements that aren't null.
+pub(crate) fn read_null_terminated<T, S>(stream: &mut S) -> Result<Vec<T>>
+where
+    Option<T>: TryFromStream + ::std::fmt::Debug,
+    S: Read,
+{
+    let elements = <Vec<Option<T>>>::try_from_stream(stream)?;
+    if elements.last().is_some_and(Option::is_some) {
+        warn!("Received array without a trailing null element");
+    }
+    Ok(elements.into_iter().flatten().collect())
+}
@@ -0,0 +1,156 @@
+use std::io::Read;
+
+use crate::types::std::{check_string_length, read_bytes, read_words};
+use crate::types::Fixed;
+use crate::{Result, TryFromStream};
+
+/// The value of an option, as read from or written to a device.
+/// When serialized, the value is given by a `value` field
+/// alongside a `type` field holding the kind of value.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(tag = "type", content = "value", rename_all = "snake_case")
+)]
+pub enum OptionValue {
+    Boolean(bool),
+    Integer(i32),
+    Fixed(Fixed),
+    /// The value of an integer option whose size covers more than one word,
+    /// such as a gamma table.
+    IntegerArray(Vec<i32>),
+    /// The value of a fixed-point option whose size covers more than one word.
+    FixedArray(Vec<Fixed>),
+    String(String),
+    /// Buttons have no value; setting one triggers its side-effect.
+    Button,
+}
+
+impl OptionValue {
+    /// The SANE value type code of this value.
+    pub(crate) fn type_code(&self) -> i32 {
+        match *self {
+            OptionValue::Boolean(_) => 0,
+            OptionValue::Integer(_) | OptionValue::IntegerArray(_) => 1,
+            OptionValue::Fixed(_) | OptionValue::FixedArray(_) => 2,
+            OptionValue::String(_) => 3,
+            OptionValue::Button => 4,
+        }
+    }
+}
+
+impl From<bool> for OptionValue {
+    fn from(value: bool) -> OptionValue {
+        OptionValue::Boolean(value)
+    }
+}
+
+impl From<i32> for OptionValue {
+    fn from(value: i32) -> OptionValue {
+        OptionValue::Integer(value)
+    }
+}
+
+impl From<Vec<i32>> for OptionValue {
+    fn from(values: Vec<i32>) -> OptionValue {
+        OptionValue::IntegerArray(values)
+    }
+}
+
+impl From<Vec<Fixed>> for OptionValue {
+    fn from(values: Vec<Fixed>) -> OptionValue {
+        OptionValue::FixedArray(values)
+    }
+}
+
+impl From<Fixed> for OptionValue {
+    fn from(value: Fixed) -> OptionValue {
+        OptionValue::Fixed(value)
+    }
+}
+
+/// Floating-point values are converted to `Fixed`, the only non-integral type SANE supports.
+impl From<f64> for OptionValue {
+    fn from(value: f64) -> OptionValue {
+        OptionValue::Fixed(Fixed::from_f64(value))
+    }
+}
+
+impl From<String> for OptionValue {
+    fn from(value: String) -> OptionValue {
+        OptionValue::String(value)
+    }
+}
+
+impl<'a> From<&'a str> for OptionValue {
+    fn from(value: &'a str) -> OptionValue {
+        OptionValue::String(value.into())
+    }
+}
+
+/// The operation performed by `control_option`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionAction {
+    /// Read the current value of the option.
+    Get,
+    /// Set the option to the given value.
+    Set(OptionValue),
+    /// Let the backend pick a value automatically; only valid for
+    /// options with the `Automatic` capability.
+    SetAuto,
+}
+
+impl OptionAction {
+    pub(crate) fn code(&self) -> i32 {
+        match *self {
+            OptionAction::Get => 0,
+            OptionAction::Set(_) => 1,
+            OptionAction::SetAuto => 2,
+        }
+    }
+}
+
+bitflags! {
+    /// Additional information returned by the backend after setting an option.
+    #[derive(Default)]
+    pub struct OptionInfo: u32 {
+        /// The value was rounded to fit the option's constraint
+        /// (e.g. a resolution snapped to one the device supports).
+        const Inexact       = 0b00000001;
+
+        /// Setting the option changed the value or descriptor of other options,
+        /// so all option descriptors should be reloaded.
+        const ReloadOptions = 0b00000010;
+
+        /// Setting the option changed the scan parameters.
+        const ReloadParams  = 0b00000100;
+    }
+}
+
+impl TryFromStream for OptionInfo {
+    fn try_from_stream<S: Read>(stream: &mut S) -> Result<Self> {
+        Ok(OptionInfo::from_bits_truncate(<u32>::try_from_stream(
+            stream,
+        )?))
+    }
+}
+
+/// Read an array of characters, as sent for string option values,
+/// returning the characters up to the first null byte.
+pub(crate) fn read_char_array<S: Read>(stream: &mut S) -> Result<String> {
+    let length = check_string_length(i32::try_from_stream(stream)?)?;
+    let mut bytes = read_bytes(stream, length)?;
+
+    if let Some(end) = bytes.iter().position(|&byte| byte == 0x00) {
+        bytes.truncate(end);
+    }
+
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Read an array of words, as sent for boolean, integer and fixed option values.
+pub(crate) fn read_word_array<S: Read>(stream: &mut S) -> Result<Vec<i32>> {
+    let length = i32::try_from_stream(stream)?;
+    read_words(stream, length)
+}
@@ -0,0 +1,232 @@
+//! The loops that touch every sample of a scan: swapping the bytes of 16-bit samples,
+//! expanding 1-bit samples to bytes, and interleaving the planes of three-pass scans.
+//!
+//! They work on whole machine words or pixels at a time, in a form the compiler
+//! vectorizes. With the `simd` feature, byte swapping and bit expansion use SSSE3
+//! instructions directly on x86-64, when the processor supports them.
+
+/// Expanded bytes of each 1-bit sample of a byte, most significant bit first:
+/// 0xff for a set bit and 0x00 for an unset one.
+static BIT_MASKS: [u64; 256] = bit_masks();
+
+const fn bit_masks() -> [u64; 256] {
+    let mut masks = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut mask = [0u8; 8];
+        let mut bit = 0;
+        while bit < 8 {
+            if byte & (0x80 >> bit) != 0 {
+                mask[bit] = 0xff;
+            }
+            bit += 1;
+        }
+        masks[byte] = u64::from_ne_bytes(mask);
+        byte += 1;
+    }
+    masks
+}
+
+/// Swap the bytes of each 16-bit sample in `data`. A trailing odd byte is left as is.
+pub(crate) fn swap_bytes16(data: &mut [u8]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    let data = {
+        if is_x86_feature_detected!("ssse3") {
+            let swapped = unsafe { x86::swap_bytes16(data) };
+            &mut data[swapped..]
+        } else {
+            data
+        }
+    };
+
+    let mut words = data.chunks_exact_mut(8);
+    for word in &mut words {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(word);
+        let value = u64::from_ne_bytes(bytes);
+        let swapped =
+            ((value & 0x00ff_00ff_00ff_00ff) << 8) | ((value >> 8) & 0x00ff_00ff_00ff_00ff);
+        word.copy_from_slice(&swapped.to_ne_bytes());
+    }
+    for sample in words.into_remainder().chunks_exact_mut(2) {
+        sample.swap(0, 1);
+    }
+}
+
+/// Expand the first `samples` bits of `row` into one byte per sample,
+/// `set` or `unset`, appended to `out`. Samples are packed most significant bit first.
+pub(crate) fn expand_bits(row: &[u8], samples: usize, set: u8, unset: u8, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.resize(start + samples, 0);
+    let expanded = &mut out[start..];
+    let whole = samples / 8;
+
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    let done = if is_x86_feature_detected!("ssse3") {
+        unsafe { x86::expand_bits(&row[..whole], set, unset, expanded) }
+    } else {
+        0
+    };
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    let done = 0;
+
+    let set_bytes = u64::from_ne_bytes([set; 8]);
+    let unset_bytes = u64::from_ne_bytes([unset; 8]);
+    for (&byte, out) in row[done..whole]
+        .iter()
+        .zip(expanded[done * 8..whole * 8].chunks_exact_mut(8))
+    {
+        let mask = BIT_MASKS[byte as usize];
+        out.copy_from_slice(&((set_bytes & mask) | (unset_bytes & !mask)).to_ne_bytes());
+    }
+
+    for (i, out) in expanded.iter_mut().enumerate().skip(whole * 8) {
+        *out = if row[i / 8] & (0x80 >> (i % 8)) != 0 {
+            set
+        } else {
+            unset
+        };
+    }
+}
+
+/// Copy the first `pixels` pixels of `input`, `in_size` bytes each, into `output`,
+/// where pixels are `out_size` bytes apart, starting `offset` bytes into each.
+pub(crate) fn interleave(
+    input: &[u8],
+    in_size: usize,
+    output: &mut [u8],
+    out_size: usize,
+    offset: usize,
+    pixels: usize,
+) {
+    // Copies of a size known at compile time make the common cases much faster
+    match in_size {
+        1 => interleave_pixels::<1>(input, output, out_size, offset, pixels),
+        2 => interleave_pixels::<2>(input, output, out_size, offset, pixels),
+        3 => interleave_pixels::<3>(input, output, out_size, offset, pixels),
+        6 => interleave_pixels::<6>(input, output, out_size, offset, pixels),
+        _ => {
+            for (from, to) in input[..pixels * in_size]
+                .chunks_exact(in_size)
+                .zip(output[offset..].chunks_mut(out_size))
+            {
+                to[..in_size].copy_from_slice(from);
+            }
+        }
+    }
+}
+
+fn interleave_pixels<const N: usize>(
+    input: &[u8],
+    output: &mut [u8],
+    out_size: usize,
+    offset: usize,
+    pixels: usize,
+) {
+    for (from, to) in input[..pixels * N]
+        .chunks_exact(N)
+        .zip(output[offset..].chunks_mut(out_size))
+    {
+        to[..N].copy_from_slice(from);
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    /// Swap the bytes of the 16-bit samples of `data` 16 bytes at a time,
+    /// returning the number of bytes swapped.
+    #[target_feature(enable = "ssse3")]
+    pub(super) unsafe fn swap_bytes16(data: &mut [u8]) -> usize {
+        let order = _mm_setr_epi8(1, 0, 3, 2, 5, 4, 7, 6, 9, 8, 11, 10, 13, 12, 15, 14);
+        let mut blocks = data.chunks_exact_mut(16);
+        for block in &mut blocks {
+            let pointer = block.as_mut_ptr() as *mut __m128i;
+            _mm_storeu_si128(pointer, _mm_shuffle_epi8(_mm_loadu_si128(pointer), order));
+        }
+        data.len() - data.len() % 16
+    }
+
+    /// Expand the bits of `bytes`, 16 bytes at a time, into the first bytes of `out`,
+    /// returning the number of bytes of `bytes` expanded.
+    #[target_feature(enable = "ssse3")]
+    pub(super) unsafe fn expand_bits(bytes: &[u8], set: u8, unset: u8, out: &mut [u8]) -> usize {
+        let bits = _mm_set1_epi64x(i64::from_ne_bytes([
+            0x80, 0x40, 0x20, 0x10, 0x08, 0x04, 0x02, 0x01,
+        ]));
+        let set = _mm_set1_epi8(set as i8);
+        let unset = _mm_set1_epi8(unset as i8);
+        let two = _mm_set1_epi8(2);
+
+        let blocks = bytes.chunks_exact(16);
+        let expanded = blocks.len() * 16;
+        for (block, out) in blocks.zip(out.chunks_exact_mut(128)) {
+            let block = _mm_loadu_si128(block.as_ptr() as *const __m128i);
+            // Each pass spreads two of the bytes over eight bytes each
+            let mut spread = _mm_set_epi64x(0x0101_0101_0101_0101, 0);
+            for out in out.chunks_exact_mut(16) {
+                let samples = _mm_shuffle_epi8(block, spread);
+                let mask = _mm_cmpeq_epi8(_mm_and_si128(samples, bits), bits);
+                let values = _mm_or_si128(_mm_and_si128(mask, set), _mm_andnot_si128(mask, unset));
+                _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, values);
+                spread = _mm_add_epi8(spread, two);
+            }
+        }
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swaps_bytes_of_every_sample() {
+        for length in 0..40 {
+            let mut data: Vec<u8> = (0..length as u8).collect();
+            swap_bytes16(&mut data);
+
+            let mut expected: Vec<u8> = (0..length as u8).collect();
+            for sample in expected.chunks_exact_mut(2) {
+                sample.swap(0, 1);
+            }
+            assert_eq!(data, expected, "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn expands_bits_most_significant_first() {
+        let row: Vec<u8> = (0..37).map(|i| 0b1010_0110u8.rotate_left(i)).collect();
+        for samples in 0..=row.len() * 8 {
+            let mut out = vec![7];
+            expand_bits(&row, samples, 0xff, 0x00, &mut out);
+
+            let expected: Vec<u8> = (0..samples)
+                .map(|i| {
+                    if row[i / 8] & (0x80 >> (i % 8)) != 0 {
+                        0xff
+                    } else {
+                        0x00
+                    }
+                })
+                .collect();
+            assert_eq!(out[0], 7);
+            assert_eq!(&out[1..], &expected[..], "{} samples", samples);
+        }
+    }
+
+    #[test]
+    fn interleaves_pixels_of_any_size() {
+        for &size in &[1, 2, 3, 4, 6] {
+            let input: Vec<u8> = (1..=3 * size as u8).collect();
+            let mut output = vec![0; 3 * (size + 1)];
+            interleave(&input, size, &mut output, size + 1, 1, 3);
+
+            for (pixel, out) in output.chunks(size + 1).enumerate() {
+                assert_eq!(out[0], 0);
+                assert_eq!(&out[1..], &input[pixel * size..(pixel + 1) * size]);
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::fmt;
+
+/// A SANE version code, as exchanged with SANE_NET_INIT.
+///
+/// The major and minor numbers are those of the SANE standard, and the
+/// build number is the revision of the network protocol spoken by saned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Version {
+    major: u8,
+    minor: u8,
+    build: u16,
+}
+
+impl Version {
+    pub const fn new(major: u8, minor: u8, build: u16) -> Version {
+        Version {
+            major,
+            minor,
+            build,
+        }
+    }
+
+    /// Decode a version code, as sent over the network.
+    pub fn from_code(code: u32) -> Version {
+        Version {
+            major: (code >> 24) as u8,
+            minor: (code >> 16) as u8,
+            build: code as u16,
+        }
+    }
+
+    /// Encode the version, as sent over the network.
+    pub fn code(self) -> u32 {
+        u32::from(self.major) << 24 | u32::from(self.minor) << 16 | u32::from(self.build)
+    }
+
+    pub fn major(self) -> u8 {
+        self.major
+    }
+
+    pub fn minor(self) -> u8 {
+        self.minor
+    }
+
+    /// The revision of the network protocol.
+    pub fn build(self) -> u16 {
+        self.build
+    }
+
+    /// Returns true if a client speaking this version can talk to a server
+    /// speaking `other`, which requires the same major version.
+    pub fn is_compatible(self, other: Version) -> bool {
+        self.major == other.major
+    }
+
+    /// Returns true if setting an option automatically carries an (empty) value,
+    /// as it did before protocol revision 3.
+    pub(crate) fn set_auto_sends_value(self) -> bool {
+        self.build < 3
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.build)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_code_round_trips() {
+        let version = Version::from_code(0x0100_0003);
+        assert_eq!(version, Version::new(1, 0, 3));
+        assert_eq!(version.code(), 0x0100_0003);
+        assert_eq!(version.to_string(), "1.0.3");
+
+        assert!(version > Version::new(1, 0, 2));
+        assert!(!version.is_compatible(Version::new(2, 0, 0)));
+    }
+}